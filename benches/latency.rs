@@ -0,0 +1,74 @@
+//! Latency of device enumeration, input switching and RPC round trips, against simulated
+//! devices so the numbers show the cost of this crate rather than of the serial link.
+
+use control_dsc::client::Client;
+use control_dsc::extron::Input;
+use control_dsc::server::{ServerBuilder, ServerEvent};
+use control_dsc::sim::{self, SimDevice};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use tokio_util::sync::CancellationToken;
+
+fn devices() -> Vec<SimDevice> {
+    (1..=4)
+        .map(|n| {
+            SimDevice::new(&format!("DSC {}", n), &["HDMI", "DisplayPort", "VGA"]).with_audio()
+        })
+        .collect()
+}
+
+/// Starts a server on `devices` in its own thread and returns a client connected to it.
+fn serve(devices: Vec<SimDevice>, cancel: CancellationToken) -> Client {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(
+            ServerBuilder::new()
+                .listen("127.0.0.1:0".parse().unwrap())
+                .device_source(move || sim::device_list(&devices))
+                .on_event(move |event| {
+                    if let ServerEvent::Listening(addr) = event {
+                        tx.lock().unwrap().send(*addr).unwrap();
+                    }
+                })
+                .cancel_token(cancel)
+                .serve(),
+        )
+    });
+    let addr = rx.recv().expect("server did not start");
+    Client::with_servers(&addr.to_string()).unwrap()
+}
+
+fn enumeration(c: &mut Criterion) {
+    let cancel = CancellationToken::new();
+    let client = serve(devices(), cancel.clone());
+    c.bench_function("rpc rescan", |b| b.iter(|| client.rescan().unwrap()));
+    c.bench_function("rpc list", |b| b.iter(|| client.list().unwrap()));
+    cancel.cancel();
+}
+
+fn switching(c: &mut Criterion) {
+    let device = SimDevice::new("DSC", &["HDMI", "DisplayPort", "VGA"]).device();
+    let by_number = Input::Number(2);
+    let by_name = Input::Name("VGA".to_string());
+    c.bench_function("select by number", |b| {
+        b.iter(|| device.select(&by_number).unwrap())
+    });
+    c.bench_function("select by name", |b| {
+        b.iter(|| device.select(&by_name).unwrap())
+    });
+    c.bench_function("status", |b| b.iter(|| device.status().unwrap()));
+
+    let cancel = CancellationToken::new();
+    let client = serve(devices(), cancel.clone());
+    c.bench_function("rpc select", |b| {
+        b.iter(|| client.select("DSC 1", &by_number).unwrap())
+    });
+    c.bench_function("rpc status", |b| b.iter(|| client.status("DSC 1").unwrap()));
+    cancel.cancel();
+}
+
+criterion_group!(benches, enumeration, switching);
+criterion_main!(benches);
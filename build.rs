@@ -5,4 +5,10 @@ fn main() {
         .file("extron.capnp")
         .run()
         .unwrap();
+
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/control_dsc.proto"], &["proto"])
+        .unwrap();
 }
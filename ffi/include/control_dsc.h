@@ -0,0 +1,48 @@
+/*
+ * C interface to a control-dsc server, implemented by libcontrol_dsc_ffi.
+ *
+ * All functions return CONTROL_DSC_OK or one of the other status codes, which
+ * match the exit codes of the command line client. control_dsc_last_error()
+ * then tells what went wrong. A client must only be used from one thread at a
+ * time.
+ */
+
+#ifndef CONTROL_DSC_H
+#define CONTROL_DSC_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CONTROL_DSC_OK 0
+#define CONTROL_DSC_FAILURE 1
+#define CONTROL_DSC_CONNECTION 3
+#define CONTROL_DSC_DEVICE_NOT_FOUND 4
+#define CONTROL_DSC_DEVICE 5
+#define CONTROL_DSC_SERVER 6
+
+typedef struct ControlDscClient ControlDscClient;
+
+typedef void (*control_dsc_device_cb)(const char *name, const char *path, void *context);
+
+/* Creates a client for a comma separated list of "host:port" servers. */
+int control_dsc_connect(const char *servers, ControlDscClient **client);
+
+/* Closes the connection and frees the client. Accepts NULL. */
+void control_dsc_free(ControlDscClient *client);
+
+/* Calls callback for every device of the server. */
+int control_dsc_list(const ControlDscClient *client, control_dsc_device_cb callback,
+                     void *context);
+
+/* Selects input, a number or a name stored in the device, on device. */
+int control_dsc_select(const ControlDscClient *client, const char *device, const char *input);
+
+/* Message of the last failure on this thread, valid until the next call. */
+const char *control_dsc_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
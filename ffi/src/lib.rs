@@ -0,0 +1,162 @@
+//! C bindings for talking to a control-dsc server, see `include/control_dsc.h`.
+//!
+//! Every function returns one of the `CONTROL_DSC_*` status codes, which are the exit codes of
+//! the command line client. The message of the last failure on the calling thread is kept for
+//! `control_dsc_last_error`.
+//!
+//! Pointer arguments must be NULL or valid for the duration of the call, strings NUL terminated
+//! and clients created by `control_dsc_connect`. That holds for all functions, so they carry no
+//! safety sections of their own.
+
+#![allow(clippy::missing_safety_doc)]
+
+use control_dsc::client::Client;
+use control_dsc::error::ControlError;
+use control_dsc::extron::Input;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+pub const CONTROL_DSC_OK: c_int = 0;
+pub const CONTROL_DSC_FAILURE: c_int = 1;
+pub const CONTROL_DSC_CONNECTION: c_int = 3;
+pub const CONTROL_DSC_DEVICE_NOT_FOUND: c_int = 4;
+pub const CONTROL_DSC_DEVICE: c_int = 5;
+pub const CONTROL_DSC_SERVER: c_int = 6;
+
+/// Connection to a server, opaque to C.
+pub struct ControlDscClient(Client);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn status_for(e: &ControlError) -> c_int {
+    match e {
+        ControlError::DeviceNotFound(_) => CONTROL_DSC_DEVICE_NOT_FOUND,
+        ControlError::SerialIo(_)
+        | ControlError::InvalidInput { .. }
+        | ControlError::UnexpectedReply(_)
+        | ControlError::Timeout(_)
+        | ControlError::Unsupported(_)
+        | ControlError::Busy(_) => CONTROL_DSC_DEVICE,
+        ControlError::MalformedInput(_) => CONTROL_DSC_FAILURE,
+        ControlError::Connection(_) | ControlError::Cancelled => CONTROL_DSC_CONNECTION,
+        ControlError::Rpc(e) if e.kind == capnp::ErrorKind::Disconnected => CONTROL_DSC_CONNECTION,
+        ControlError::OldServer { .. }
+        | ControlError::NotAllowed(_)
+        | ControlError::Unauthorized(_)
+        | ControlError::Maintenance(_)
+        | ControlError::Rpc(_) => CONTROL_DSC_SERVER,
+    }
+}
+
+/// Runs `f`, turning errors and panics into a status code and the last error message.
+fn guard<F>(f: F) -> c_int
+where
+    F: FnOnce() -> Result<(), ControlError>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => CONTROL_DSC_OK,
+        Ok(Err(e)) => {
+            set_last_error(&e.to_string());
+            status_for(&e)
+        }
+        Err(_) => {
+            set_last_error("Internal error");
+            CONTROL_DSC_FAILURE
+        }
+    }
+}
+
+/// Borrows a C string argument, failing on NULL and invalid UTF-8.
+unsafe fn arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, ControlError> {
+    if s.is_null() {
+        return Err(ControlError::MalformedInput(format!("NULL {}", what)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| ControlError::MalformedInput(format!("non UTF-8 {}", what)))
+}
+
+unsafe fn client<'a>(client: *const ControlDscClient) -> Result<&'a Client, ControlError> {
+    client
+        .as_ref()
+        .map(|c| &c.0)
+        .ok_or_else(|| ControlError::MalformedInput("NULL client".to_string()))
+}
+
+/// Creates a client for a comma separated list of servers and stores it in `*out`. The
+/// connection is made on the first call.
+#[no_mangle]
+pub unsafe extern "C" fn control_dsc_connect(
+    servers: *const c_char,
+    out: *mut *mut ControlDscClient,
+) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(ControlError::MalformedInput("NULL client".to_string()));
+        }
+        *out = ptr::null_mut();
+        let client = Client::with_servers(arg(servers, "server list")?)?;
+        *out = Box::into_raw(Box::new(ControlDscClient(client)));
+        Ok(())
+    })
+}
+
+/// Closes the connection and frees the client. Accepts NULL.
+#[no_mangle]
+pub unsafe extern "C" fn control_dsc_free(client: *mut ControlDscClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Calls `callback` with the name and path of every device of the server.
+#[no_mangle]
+pub unsafe extern "C" fn control_dsc_list(
+    client: *const ControlDscClient,
+    callback: Option<extern "C" fn(*const c_char, *const c_char, *mut c_void)>,
+    context: *mut c_void,
+) -> c_int {
+    guard(|| {
+        let client = self::client(client)?;
+        let devices = client.list()?;
+        if let Some(callback) = callback {
+            for device in devices {
+                let name = CString::new(device.name).unwrap_or_default();
+                let path = CString::new(device.device_path).unwrap_or_default();
+                callback(name.as_ptr(), path.as_ptr(), context);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Selects `input`, a number or a name stored in the device, on `device`.
+#[no_mangle]
+pub unsafe extern "C" fn control_dsc_select(
+    client: *const ControlDscClient,
+    device: *const c_char,
+    input: *const c_char,
+) -> c_int {
+    guard(|| {
+        let client = self::client(client)?;
+        let input: Input = arg(input, "input")?.parse()?;
+        client.select(arg(device, "device name")?, &input)
+    })
+}
+
+/// Message of the last failure on this thread, empty if there was none. Valid until the next
+/// call on this thread.
+#[no_mangle]
+pub extern "C" fn control_dsc_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
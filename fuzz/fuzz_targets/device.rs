@@ -0,0 +1,39 @@
+#![no_main]
+//! Feeds the input to the device commands as if the device had sent it, to check that
+//! whatever comes back over the serial port ends in a result, not a panic.
+
+use control_dsc::extron::{ExtronDevice, Input, Port};
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Cursor, Read, Write};
+
+struct Replay(Cursor<Vec<u8>>);
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for Replay {}
+
+fuzz_target!(|data: &[u8]| {
+    let data = data.to_vec();
+    let device = ExtronDevice::with_port("fuzz", "fuzz", move || {
+        Ok(Box::new(Replay(Cursor::new(data.clone()))))
+    });
+    let _ = device.select(&Input::Number(2));
+    let _ = device.select(&Input::Name("HDMI".to_string()));
+    let _ = device.set_volume(60);
+    let _ = device.set_mute(true);
+    let _ = device.status();
+});
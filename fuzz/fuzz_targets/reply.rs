@@ -0,0 +1,8 @@
+#![no_main]
+use control_dsc::sis::{self, Reply};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Reply::parse(data).number();
+    let _ = sis::text(data);
+});
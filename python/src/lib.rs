@@ -0,0 +1,198 @@
+//! Python module `control_dsc`, for talking to a control-dsc server from automation scripts.
+//!
+//! ```python
+//! import control_dsc
+//!
+//! client = control_dsc.Client("av1:14000")
+//! for device in client.list():
+//!     print(device["name"], client.status(device["name"]))
+//! client.select("DSC 301 HD", 2)
+//! for event in client.events():
+//!     print(event)
+//! ```
+//!
+//! Lists and statuses are dicts shaped like the `--format json` output of the command line
+//! client.
+
+use control_dsc::client::{self, Event};
+use control_dsc::error::ControlError;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+create_exception!(control_dsc, Error, PyException);
+create_exception!(control_dsc, ConnectionError, Error);
+create_exception!(control_dsc, DeviceNotFoundError, Error);
+create_exception!(control_dsc, DeviceError, Error);
+
+/// How often a wait for events checks for Ctrl-C.
+const SIGNAL_CHECK: Duration = Duration::from_millis(100);
+
+/// Raises the exception for the class of failure, as the command line client picks its exit
+/// code.
+fn py_err(e: ControlError) -> PyErr {
+    let message = e.to_string();
+    match e {
+        ControlError::DeviceNotFound(_) => DeviceNotFoundError::new_err(message),
+        ControlError::SerialIo(_)
+        | ControlError::InvalidInput { .. }
+        | ControlError::UnexpectedReply(_)
+        | ControlError::Timeout(_)
+        | ControlError::Unsupported(_)
+        | ControlError::Busy(_) => DeviceError::new_err(message),
+        ControlError::Connection(_) | ControlError::Cancelled => ConnectionError::new_err(message),
+        ControlError::Rpc(e) if e.kind == capnp::ErrorKind::Disconnected => {
+            ConnectionError::new_err(message)
+        }
+        ControlError::MalformedInput(_)
+        | ControlError::OldServer { .. }
+        | ControlError::NotAllowed(_)
+        | ControlError::Unauthorized(_)
+        | ControlError::Maintenance(_)
+        | ControlError::Rpc(_) => Error::new_err(message),
+    }
+}
+
+/// Blocking connection to a server, made on the first call. Calls hold the GIL.
+#[pyclass(unsendable)]
+struct Client(client::Client);
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[pyo3(signature = (servers, connect_timeout = 5.0, retries = 2))]
+    fn new(servers: &str, connect_timeout: f64, retries: u32) -> PyResult<Self> {
+        let client = client::Client::with_servers(servers)
+            .map_err(py_err)?
+            .connect_timeout(Duration::from_secs_f64(connect_timeout))
+            .retries(retries);
+        Ok(Client(client))
+    }
+
+    /// Devices of the server, as dicts with `name` and `path`.
+    fn list<'py>(&self, py: Python<'py>) -> PyResult<Vec<&'py PyDict>> {
+        let devices = self.0.list().map_err(py_err)?;
+        devices
+            .into_iter()
+            .map(|device| {
+                let dict = PyDict::new(py);
+                dict.set_item("name", device.name)?;
+                dict.set_item("path", device.device_path)?;
+                Ok(dict)
+            })
+            .collect()
+    }
+
+    /// Selects `input`, an input number or a name stored in the device.
+    fn select(&self, device: &str, input: &PyAny) -> PyResult<()> {
+        let input = input.str()?.to_str()?.parse().map_err(py_err)?;
+        self.0.select(device, &input).map_err(py_err)
+    }
+
+    /// Selected input and audio state of `device`, as a dict with `name`, `input`, `volume`,
+    /// `mute`, `pip` and `sources`. The audio entries are `None` for devices without audio
+    /// output, and `pip` for devices without picture-in-picture.
+    fn status<'py>(&self, py: Python<'py>, device: &str) -> PyResult<&'py PyDict> {
+        let status = self.0.status(device).map_err(py_err)?;
+        let dict = PyDict::new(py);
+        dict.set_item("name", device)?;
+        dict.set_item("input", status.input)?;
+        dict.set_item("volume", status.volume)?;
+        dict.set_item("mute", status.mute)?;
+        dict.set_item("pip", status.pip.map(|pip| pip.to_string()))?;
+        dict.set_item("sources", status.sources)?;
+        Ok(dict)
+    }
+
+    /// Iterator over the events of the server, as dicts with `event`, `device` and the value
+    /// that changed. Waiting for the next one releases the GIL, so from asyncio run it in an
+    /// executor.
+    fn events(&self) -> PyResult<Events> {
+        let events = self.0.events().map_err(py_err)?;
+        Ok(Events(Mutex::new(events)))
+    }
+
+    /// Iterator over the events of the server as with `events`, which survives the server
+    /// restarting: the connection is made again and a `reconnected` event tells that events
+    /// were missed in between. It only ends when dropped.
+    fn watch(&self) -> PyResult<Events> {
+        let events = self.0.watch().map_err(py_err)?;
+        Ok(Events(Mutex::new(events)))
+    }
+}
+
+#[pyclass]
+struct Events(Mutex<mpsc::Receiver<Event>>);
+
+#[pymethods]
+impl Events {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    /// The next event, ending the iteration when the server closes the connection.
+    fn __next__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        loop {
+            let next = py.allow_threads(|| self.0.lock().unwrap().recv_timeout(SIGNAL_CHECK));
+            match next {
+                Ok(event) => return event_dict(py, event).map(|dict| Some(dict.to_object(py))),
+                Err(RecvTimeoutError::Timeout) => py.check_signals()?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+}
+
+fn event_dict<'py>(py: Python<'py>, event: Event) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    match event {
+        Event::DevicesScanned => dict.set_item("event", "devices_scanned")?,
+        Event::InputSelected { device, input } => {
+            dict.set_item("event", "input_selected")?;
+            dict.set_item("device", device)?;
+            dict.set_item("input", input.to_string())?;
+        }
+        Event::VolumeChanged { device, level } => {
+            dict.set_item("event", "volume_changed")?;
+            dict.set_item("device", device)?;
+            dict.set_item("volume", level)?;
+        }
+        Event::MuteChanged { device, mute } => {
+            dict.set_item("event", "mute_changed")?;
+            dict.set_item("device", device)?;
+            dict.set_item("mute", mute)?;
+        }
+        Event::SignalLost { device, input } => {
+            dict.set_item("event", "signal_lost")?;
+            dict.set_item("device", device)?;
+            dict.set_item("input", input)?;
+        }
+        Event::SignalRestored { device, input } => {
+            dict.set_item("event", "signal_restored")?;
+            dict.set_item("device", device)?;
+            dict.set_item("input", input)?;
+        }
+        Event::MaintenanceStarted { message } => {
+            dict.set_item("event", "maintenance_started")?;
+            dict.set_item("message", message)?;
+        }
+        Event::MaintenanceEnded => dict.set_item("event", "maintenance_ended")?,
+        Event::Reconnected => dict.set_item("event", "reconnected")?,
+    }
+    Ok(dict)
+}
+
+#[pymodule]
+#[pyo3(name = "control_dsc")]
+fn control_dsc_python(py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<Client>()?;
+    module.add("Error", py.get_type::<Error>())?;
+    module.add("ConnectionError", py.get_type::<ConnectionError>())?;
+    module.add("DeviceNotFoundError", py.get_type::<DeviceNotFoundError>())?;
+    module.add("DeviceError", py.get_type::<DeviceError>())?;
+    Ok(())
+}
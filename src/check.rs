@@ -0,0 +1,154 @@
+//! A check of a server and its devices for Nagios, Icinga and other monitoring running
+//! Nagios plugins: one line with the state and what is wrong, the temperatures as perfdata,
+//! and the state in the exit status.
+
+use crate::client::Client;
+use anyhow::Result;
+use control_dsc::error::ControlError;
+use std::fmt;
+
+/// The states of Nagios plugins, worst last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok,
+    Warning,
+    Unknown,
+    Critical,
+}
+
+impl State {
+    /// The exit status standing for the state.
+    pub fn code(self) -> i32 {
+        match self {
+            State::Ok => 0,
+            State::Warning => 1,
+            State::Critical => 2,
+            State::Unknown => 3,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        })
+    }
+}
+
+/// Temperatures in degrees Celsius above which a device is flagged. Unset limits are not
+/// checked.
+pub struct Limits {
+    pub warning: Option<u32>,
+    pub critical: Option<u32>,
+}
+
+/// What was found so far.
+struct Report {
+    state: State,
+    problems: Vec<String>,
+    /// Devices checked, and how many of them answered.
+    checked: usize,
+    answering: usize,
+    perfdata: Vec<String>,
+}
+
+impl Report {
+    fn flag(&mut self, state: State, problem: String) {
+        self.state = self.state.max(state);
+        self.problems.push(problem);
+    }
+}
+
+/// Checks that the server `remote` answers, and that its devices, or only `device`, answer,
+/// stay within the temperature `limits` and have a signal on their selected input. Prints
+/// the outcome and returns the state to exit with.
+pub fn run(remote: Result<Client>, device: Option<&str>, limits: &Limits) -> State {
+    let mut report = Report {
+        state: State::Ok,
+        problems: Vec::new(),
+        checked: 0,
+        answering: 0,
+        perfdata: Vec::new(),
+    };
+    check(remote, device, limits, &mut report);
+
+    let summary = if report.problems.is_empty() {
+        format!("{} of {} devices answer", report.answering, report.checked)
+    } else {
+        report.problems.join(", ")
+    };
+    if report.checked == 0 {
+        println!("CONTROL-DSC {} - {}", report.state, summary);
+    } else {
+        let devices = format!("devices={};;;0;{}", report.answering, report.checked);
+        let perfdata = std::iter::once(devices).chain(report.perfdata);
+        println!(
+            "CONTROL-DSC {} - {} | {}",
+            report.state,
+            summary,
+            perfdata.collect::<Vec<_>>().join(" ")
+        );
+    }
+    report.state
+}
+
+fn check(remote: Result<Client>, device: Option<&str>, limits: &Limits, report: &mut Report) {
+    let remote = match remote {
+        Ok(remote) => remote,
+        Err(e) => return report.flag(State::Unknown, format!("{:#}", e)),
+    };
+    let listed = match remote.list().map_err(anyhow::Error::from) {
+        Ok(listed) => listed,
+        Err(e) => return report.flag(State::Critical, format!("Cannot reach the server: {:#}", e)),
+    };
+    let mut names: Vec<_> = listed.into_iter().map(|device| device.name).collect();
+    names.sort();
+    if let Some(device) = device {
+        if !names.iter().any(|name| name == device) {
+            return report.flag(State::Critical, format!("{} not found", device));
+        }
+        names = vec![device.to_string()];
+    } else if names.is_empty() {
+        return report.flag(State::Warning, "No devices on the server".to_string());
+    }
+
+    report.checked = names.len();
+    for name in &names {
+        match remote.device_health(name).map_err(anyhow::Error::from) {
+            Ok(health) => {
+                report.answering += 1;
+                if let Some(celsius) = health.temperature {
+                    if limits.critical.is_some_and(|max| celsius > max) {
+                        report.flag(State::Critical, format!("{} at {} °C", name, celsius));
+                    } else if limits.warning.is_some_and(|max| celsius > max) {
+                        report.flag(State::Warning, format!("{} at {} °C", name, celsius));
+                    }
+                    report.perfdata.push(format!(
+                        "'{} temperature'={};{};{}",
+                        name,
+                        celsius,
+                        limits.warning.map_or(String::new(), |max| max.to_string()),
+                        limits.critical.map_or(String::new(), |max| max.to_string())
+                    ));
+                }
+                if let Some((input, false)) = health.signal {
+                    // Usually a source that is off, rather than the device.
+                    report.flag(
+                        State::Warning,
+                        format!("{}: no signal on input {}", name, input),
+                    );
+                }
+            }
+            Err(e) => match e.downcast_ref::<ControlError>() {
+                Some(ControlError::OldServer { .. }) => {
+                    return report.flag(State::Unknown, format!("{:#}", e))
+                }
+                _ => report.flag(State::Critical, format!("{} not answering: {:#}", name, e)),
+            },
+        }
+    }
+}
@@ -0,0 +1,1068 @@
+use crate::config::OutputFormat;
+use crate::zabbix::Metric;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use control_dsc::endpoint;
+use control_dsc::extron::Input;
+use control_dsc::tokens::Scope;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "control-dsc",
+    author = "Peter De Schrijver <p2@psychaos.be>",
+    version,
+    about = "Control Extron scalers/switchers",
+    subcommand_required = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    /// Give up connecting to the server after this many seconds [default: 5]
+    #[arg(long, global = true, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub connect_timeout: Option<Duration>,
+
+    /// Retry a failed connection this many times [default: 2]
+    #[arg(long, global = true, value_name = "COUNT")]
+    pub retries: Option<u32>,
+
+    /// Output format for device lists
+    #[arg(long, global = true, value_name = "FORMAT", value_enum)]
+    pub format: Option<OutputFormat>,
+
+    /// Wait up to this many seconds for a device another process is using
+    #[arg(long, global = true, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub wait: Option<Duration>,
+
+    /// Probe connections to and from the server after this many seconds without traffic,
+    /// dropping them when the other end is gone [default: 30]
+    #[arg(long, global = true, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub keepalive: Option<Duration>,
+
+    /// Never probe connections, leaving ones to a vanished peer open
+    #[arg(long, global = true, conflicts_with = "keepalive")]
+    pub no_keepalive: bool,
+
+    /// API token to authenticate with, for servers that require one; better given in
+    /// CONTROL_RS_TOKEN, where other users cannot see it
+    #[arg(long, global = true, value_name = "TOKEN")]
+    pub token: Option<String>,
+
+    /// Reach the server through this SSH jump host, e.g. on an AV network only it can reach;
+    /// it must be in ~/.ssh/known_hosts and take a key of the SSH agent or ~/.ssh
+    #[cfg(feature = "ssh")]
+    #[arg(long, global = true, value_name = "USER@HOST[:PORT]")]
+    pub ssh: Option<control_dsc::ssh::SshTunnel>,
+
+    /// Log every call to and from the server with its parameters and results, to stderr or
+    /// to the log of the server
+    #[cfg(feature = "server")]
+    #[arg(long, global = true)]
+    pub trace_rpc: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// list available devices
+    List(ListArgs),
+    /// list the devices with their model, firmware, serial number and location, as CSV
+    /// unless --format says otherwise
+    Export(ExportArgs),
+    /// select input
+    Select(SelectArgs),
+    /// show selected input and audio state
+    Status(StatusArgs),
+    /// set audio output volume
+    Volume(VolumeArgs),
+    /// mute or unmute audio output
+    Mute(MuteArgs),
+    /// power the display on or off over HDMI CEC
+    Display(DisplayArgs),
+    /// send bytes to the display through the display-control port of a device
+    DisplayRaw(DisplayRawArgs),
+    /// run a script of commands
+    Run(RunArgs),
+    /// type SIS commands to a device and see its answers
+    Console(ConsoleArgs),
+    /// run as server
+    #[cfg(feature = "server")]
+    Server(ServerArgs),
+    /// write systemd units running the server on this machine
+    #[cfg(all(feature = "server", unix))]
+    InstallService(InstallServiceArgs),
+    /// force rescan on server
+    Rescan(ServerAddressArgs),
+    /// wait until a device is available
+    WaitForDevice(WaitArgs),
+    /// keep the schedule on the server from switching a device
+    Hold(HoldArgs),
+    /// fail commands changing devices on the server while the rack is worked on
+    #[command(subcommand)]
+    Maintenance(MaintenanceCommand),
+    /// show what a device said on its own, as kept by the server
+    Log(LogArgs),
+    /// show who changed the input of a device and when, as kept by the server
+    History(HistoryArgs),
+    /// undo the last input, volume or mute change made through the server on a device
+    Undo(UndoArgs),
+    /// show how long devices on the server take to answer and how often they fail
+    Stats(ServerAddressArgs),
+    /// list the clients connected to the server, or disconnect one
+    Connections(ConnectionsArgs),
+    /// create, revoke and list the API tokens of the server
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// watch the devices on the server and switch their inputs from the terminal
+    #[cfg(feature = "tui")]
+    Tui(ServerAddressArgs),
+    /// find the servers advertised on the local network, and pick one to use
+    #[cfg(feature = "mdns")]
+    Discover(DiscoverArgs),
+    /// check this machine and the server for what keeps the devices from working
+    Doctor(ServerAddressArgs),
+    /// check the server and its devices for Nagios or Icinga, with perfdata
+    Check(CheckArgs),
+    /// discover the devices and read their metrics for Zabbix
+    #[command(subcommand)]
+    Zabbix(ZabbixCommand),
+    /// print udev rules giving access to the devices and stable links to them
+    #[cfg(target_os = "linux")]
+    GenUdev(GenUdevArgs),
+    /// look inside the server
+    #[command(subcommand)]
+    Debug(DebugCommand),
+    /// manage device firmware
+    #[command(subcommand)]
+    Firmware(FirmwareCommand),
+    /// control the video wall of a multi-window processor
+    #[command(subcommand)]
+    Wall(WallCommand),
+    /// recall a window layout preset and show the inputs in its windows
+    Layout(LayoutArgs),
+    /// control an Annotator
+    #[command(subcommand)]
+    Annotate(AnnotateCommand),
+    /// picture-in-picture and quad view
+    #[command(subcommand)]
+    Pip(PipCommand),
+    /// output sync format and genlock
+    #[command(subcommand)]
+    Sync(SyncCommand),
+    /// halt server
+    #[command(name = "stop_server")]
+    StopServer(ServerAddressArgs),
+    /// print the Cap'n Proto schema of the server interface
+    Schema,
+    /// add a device on the network to the configuration, to control it by its address
+    AddDevice(AddDeviceArgs),
+    /// show or change the devices, scenes and groups of the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+/// Talk to a server or to the devices on this machine.
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub struct Mode {
+    /// Remote server to connect to, or a comma separated list to try in order
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+
+    /// Ignore the configured server and use local devices
+    #[arg(short, long)]
+    pub local: bool,
+}
+
+/// One device, several devices matching a pattern or in a group, or all of them.
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub struct Targets {
+    /// Extron device to control, or a glob pattern matching several devices
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Control every device
+    #[arg(long)]
+    pub all: bool,
+
+    /// Control the devices of a group from the configuration
+    #[arg(short, long, value_name = "GROUP")]
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Only list the devices whose name or path matches this glob pattern
+    #[arg(long, value_name = "PATTERN")]
+    pub filter: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct SelectArgs {
+    #[command(flatten)]
+    pub targets: Targets,
+
+    /// input number or label
+    #[arg(
+        value_name = "INPUT",
+        value_parser = parse_input,
+        required_unless_present = "previous"
+    )]
+    pub input: Option<Input>,
+
+    /// Switch back to the input selected before the current one, as the server saw them
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "input", "all", "group", "plane", "with_audio", "video_only", "audio_only"
+        ]
+    )]
+    pub previous: bool,
+
+    /// Signals to switch, on devices that switch audio apart from video
+    #[arg(long, value_enum, default_value = "all")]
+    pub plane: Plane,
+
+    /// Switch the audio along with the video, as --plane all does
+    #[arg(long, conflicts_with_all = ["plane", "video_only", "audio_only"])]
+    pub with_audio: bool,
+
+    /// Switch only the video, leaving the audio on the input it is on, as --plane video does
+    #[arg(long, conflicts_with_all = ["plane", "audio_only"])]
+    pub video_only: bool,
+
+    /// Switch only the audio, e.g. program audio for a conference, as --plane audio does
+    #[arg(long, conflicts_with = "plane")]
+    pub audio_only: bool,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    /// Extron device to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct ConsoleArgs {
+    /// Extron device to talk to
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct VolumeArgs {
+    #[command(flatten)]
+    pub targets: Targets,
+
+    /// volume level, 0-100
+    #[arg(value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub level: u8,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PipMode {
+    Off,
+    Pip,
+    Quad,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SyncFormat {
+    /// separate horizontal and vertical sync
+    Hv,
+    Composite,
+    SyncOnGreen,
+    TriLevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Genlock {
+    /// lock to nothing
+    FreeRun,
+    /// lock to the selected input
+    Input,
+    /// lock to the reference input
+    Reference,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Plane {
+    Video,
+    Audio,
+    All,
+}
+
+#[cfg(feature = "dbus")]
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DbusBus {
+    Session,
+    System,
+}
+
+#[derive(Debug, Args)]
+pub struct MuteArgs {
+    #[command(flatten)]
+    pub targets: Targets,
+
+    #[arg(value_name = "STATE", value_enum)]
+    pub state: Switch,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct DisplayArgs {
+    #[command(flatten)]
+    pub targets: Targets,
+
+    #[arg(value_name = "STATE", value_enum)]
+    pub state: Switch,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct DisplayRawArgs {
+    /// Extron device the display is attached to
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// bytes to send, with escapes as in the console, e.g. 'PWR ON\r'
+    #[arg(value_name = "DATA")]
+    pub data: String,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Extron device to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// script with one command per line
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+
+    /// Abort the script at the first failing command
+    #[arg(long)]
+    pub stop_on_error: bool,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[cfg(feature = "server")]
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    /// Adress:Port to listen to
+    #[arg(
+        value_name = "LISTEN ADDRESS",
+        default_value = "0.0.0.0:14000",
+        value_parser = parse_address
+    )]
+    pub address: SocketAddr,
+
+    /// Also serve gRPC on this address, for clients that cannot use Cap'n Proto
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "GRPC ADDRESS", value_parser = parse_address)]
+    pub grpc: Vec<SocketAddr>,
+
+    /// Also serve the stats of the devices for Prometheus on this address, at /metrics
+    #[arg(long, value_name = "METRICS ADDRESS", value_parser = parse_address)]
+    pub metrics: Vec<SocketAddr>,
+
+    /// Also serve a dashboard of the devices on the --metrics addresses, with buttons for
+    /// their inputs and the scenes of the configuration
+    #[arg(long, requires = "metrics")]
+    pub dashboard: bool,
+
+    /// Also offer the devices as a D-Bus service on this bus
+    #[cfg(feature = "dbus")]
+    #[arg(long, value_name = "BUS", value_enum)]
+    pub dbus: Option<DbusBus>,
+
+    /// Save the input, volume and mute last set on every device to this file
+    #[arg(long, value_name = "FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// Set devices back to their saved state on start and when they reappear on a rescan
+    #[arg(long, requires = "state_file")]
+    pub restore: bool,
+
+    /// Let clients undo a change for this many seconds after it was made [default: 300]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub undo_window: Option<Duration>,
+
+    /// Only serve stopping the server, rescans, raw commands and firmware uploads to
+    /// connections from this machine, leaving other machines to list, select and read
+    #[arg(long)]
+    pub local_admin: bool,
+
+    /// Require clients to authenticate with one of the API tokens kept in this file
+    #[arg(long, value_name = "FILE")]
+    pub tokens: Option<PathBuf>,
+
+    /// Also serve on this Unix socket, which only the user of the server may open, with no
+    /// token needed, e.g. for creating the first ones
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Serve over TLS with the certificate chain in this PEM file
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file of the key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Only serve clients with a certificate signed by the CA in this PEM file; the
+    /// [[certificates]] of the configuration say what each may do
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub client_ca: Option<PathBuf>,
+
+    /// Stand by for the server at this address, passing requests on to it and taking over
+    /// the devices while it is down
+    #[arg(long, value_name = "PRIMARY ADDRESS", value_parser = parse_address)]
+    pub standby_of: Option<SocketAddr>,
+
+    /// Name of this server, for telling its log apart from those of other servers on this
+    /// machine
+    #[arg(long, value_name = "NAME", value_parser = parse_instance)]
+    pub instance: Option<String>,
+
+    /// Only use the devices on serial ports matching this pattern, e.g.
+    /// '/dev/serial/by-path/*usb-0:1*', leaving the others to other servers on this machine
+    #[arg(long, value_name = "PATTERN", value_parser = parse_pattern)]
+    pub ports: Vec<glob::Pattern>,
+
+    /// Serve the simulated devices described in this TOML file instead of the devices on
+    /// the serial ports, for trying clients and scripts without hardware
+    #[arg(long, value_name = "FILE", conflicts_with = "ports")]
+    pub simulate: Option<PathBuf>,
+
+    /// Also log debug messages to files in this directory
+    #[arg(long = "debug", value_name = "DEBUG LOG DIRECTORY")]
+    pub debug_dir: Option<PathBuf>,
+
+    /// Dump every byte sent to and received from each device to a file in the debug log
+    /// directory
+    #[arg(long, requires = "debug_dir")]
+    pub trace_serial: bool,
+
+    /// Stay in the foreground and log to stdout, always the case on Windows or without the
+    /// daemon feature
+    #[arg(long)]
+    pub no_daemonize: bool,
+}
+
+#[cfg(all(feature = "server", unix))]
+#[derive(Debug, Args)]
+pub struct InstallServiceArgs {
+    /// Adress:Port for the server to listen to
+    #[arg(
+        value_name = "LISTEN ADDRESS",
+        default_value = "0.0.0.0:14000",
+        value_parser = parse_address
+    )]
+    pub address: SocketAddr,
+
+    /// User to run the server as
+    #[arg(long, value_name = "USER", default_value = "daemon")]
+    pub user: String,
+
+    /// Group to run the server as, one that may open the serial ports
+    #[arg(long, value_name = "GROUP", default_value = "dialout")]
+    pub group: String,
+
+    /// Have systemd restart the server when it has not heard from it for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub watchdog: Option<Duration>,
+
+    /// Have systemd listen on the address and start the server on the first connection
+    #[arg(long)]
+    pub socket: bool,
+
+    /// Name of the server, for running several on this machine; also names the units
+    #[arg(long, value_name = "NAME", value_parser = parse_instance)]
+    pub instance: Option<String>,
+
+    /// Directory to write the units to
+    #[arg(long, value_name = "DIR", default_value = "/etc/systemd/system")]
+    pub dir: PathBuf,
+
+    /// Print the units instead of writing them
+    #[arg(long)]
+    pub print: bool,
+
+    /// Further arguments for the server, e.g. -- --state-file /var/lib/control-dsc/state
+    #[arg(last = true, value_name = "SERVER ARGS")]
+    pub server_args: Vec<String>,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Args)]
+pub struct GenUdevArgs {
+    /// Group to give the devices to, the one the server runs as
+    #[arg(long, value_name = "GROUP", default_value = "dialout")]
+    pub group: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ServerAddressArgs {
+    /// Adress:Port to connect to
+    #[arg(value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TokenCommand {
+    /// create a token and print its secret, which is not shown again
+    Create(TokenCreateArgs),
+    /// revoke a token, disconnecting the clients using it
+    Revoke(TokenRevokeArgs),
+    /// list the tokens, without their secrets
+    List(TokenServer),
+}
+
+/// The server whose tokens to manage.
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub struct TokenServer {
+    /// Admin socket of the server on this machine
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pub socket: Option<PathBuf>,
+
+    /// Server to connect to instead, with an admin token
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct TokenCreateArgs {
+    /// What the token allows: read, control (also read) or admin (everything)
+    #[arg(value_name = "SCOPE")]
+    pub scope: Scope,
+
+    /// Only allow the token for devices matching this glob pattern; may be repeated
+    #[arg(short, long = "device", value_name = "PATTERN")]
+    pub devices: Vec<String>,
+
+    #[command(flatten)]
+    pub server: TokenServer,
+}
+
+#[derive(Debug, Args)]
+pub struct TokenRevokeArgs {
+    /// Id of the token, as listed
+    #[arg(value_name = "ID")]
+    pub id: String,
+
+    #[command(flatten)]
+    pub server: TokenServer,
+}
+
+#[cfg(feature = "mdns")]
+#[derive(Debug, Args)]
+pub struct DiscoverArgs {
+    /// Listen for servers this many seconds [default: 3]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    pub listen: Option<Duration>,
+
+    /// Print the address of the first server found, for $(...) in scripts
+    #[arg(long, conflicts_with = "choose")]
+    pub first: bool,
+
+    /// Ask which of the servers found to use, and print its address
+    #[arg(long)]
+    pub choose: bool,
+
+    /// Write the address of the server picked to the configuration, as the one to connect to
+    /// from then on; asks which one unless --first is given
+    #[arg(long)]
+    pub save: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Server to check, or a comma separated list to try in order
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+
+    /// Check this device only, which must be on the server
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Warn above this temperature in degrees Celsius
+    #[arg(short, long, value_name = "CELSIUS")]
+    pub warning: Option<u32>,
+
+    /// Critical above this temperature in degrees Celsius [default: max_temperature from the
+    /// configuration]
+    #[arg(short, long, value_name = "CELSIUS")]
+    pub critical: Option<u32>,
+}
+
+#[derive(Debug, Args)]
+pub struct WaitArgs {
+    /// Extron device to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Give up after this many seconds
+    #[arg(long, value_name = "SECONDS", default_value = "60", value_parser = parse_seconds)]
+    pub timeout: Duration,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ZabbixCommand {
+    /// print the devices and their metrics as the JSON of a low-level discovery rule
+    Discovery(ExportArgs),
+    /// print a metric of a device, for an item
+    Get(ZabbixGetArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ZabbixGetArgs {
+    /// Extron device to read
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[arg(value_enum)]
+    pub metric: Metric,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DebugCommand {
+    /// print what the server knows as JSON, for diagnosing a server that seems stuck
+    Dump(ServerAddressArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FirmwareCommand {
+    /// send a new firmware image to a device
+    Upload(FirmwareUploadArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct FirmwareUploadArgs {
+    /// Firmware image as published by Extron
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+
+    /// Extron device to update
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Fail unless the device reports this firmware version afterwards
+    #[arg(long, value_name = "VERSION")]
+    pub expect_version: Option<String>,
+
+    /// Do not ask for confirmation
+    #[arg(long)]
+    pub yes: bool,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WallCommand {
+    /// manage video wall presets
+    #[command(subcommand)]
+    Preset(WallPresetCommand),
+    /// show the preset recalled last and its windows
+    Layout(WallArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WallPresetCommand {
+    /// recall a video wall preset
+    Recall(WallRecallArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct WallArgs {
+    /// Extron processor to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct WallRecallArgs {
+    /// Preset number
+    #[arg(value_name = "PRESET", value_parser = clap::value_parser!(u32).range(1..))]
+    pub preset: u32,
+
+    #[command(flatten)]
+    pub wall: WallArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct LayoutArgs {
+    /// Window layout preset number
+    #[arg(value_name = "LAYOUT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub layout: u32,
+
+    /// Extron processor to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AnnotateCommand {
+    /// clear everything drawn
+    Clear(AnnotatorArgs),
+    /// select the pointer tool
+    Pointer(AnnotatorArgs),
+    /// freeze the annotated picture or let it go
+    Freeze(FreezeArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct AnnotatorArgs {
+    /// Extron Annotator to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct FreezeArgs {
+    #[arg(value_name = "STATE", value_enum)]
+    pub state: Switch,
+
+    #[command(flatten)]
+    pub annotator: AnnotatorArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PipCommand {
+    /// switch picture-in-picture or quad view on, or both off
+    Mode(PipModeArgs),
+    /// show an input in a window
+    Source(PipSourceArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct PipArgs {
+    /// Extron device to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct PipModeArgs {
+    #[arg(value_name = "MODE", value_enum)]
+    pub mode: PipMode,
+
+    #[command(flatten)]
+    pub pip: PipArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct PipSourceArgs {
+    /// main, secondary, or window number 1 to 4
+    #[arg(value_name = "WINDOW", value_parser = parse_window)]
+    pub window: u32,
+
+    /// input number or label
+    #[arg(value_name = "INPUT", value_parser = parse_input)]
+    pub input: Input,
+
+    #[command(flatten)]
+    pub pip: PipArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SyncCommand {
+    /// show the output sync format and what the output is locked to
+    Show(SyncArgs),
+    /// set the output sync format
+    Format(SyncFormatArgs),
+    /// lock the output to an input or let it run free
+    Genlock(GenlockArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct SyncArgs {
+    /// Extron scaler to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    #[command(flatten)]
+    pub mode: Mode,
+}
+
+#[derive(Debug, Args)]
+pub struct SyncFormatArgs {
+    #[arg(value_name = "FORMAT", value_enum)]
+    pub format: SyncFormat,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct GenlockArgs {
+    #[arg(value_name = "REFERENCE", value_enum)]
+    pub genlock: Genlock,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// print the devices, scenes and groups, as YAML unless --format json
+    Show,
+    /// write the devices, scenes and groups of a YAML or JSON document to the configuration,
+    /// replacing those of the same names
+    Apply(ConfigApplyArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct AddDeviceArgs {
+    /// name to control the device by
+    #[arg(value_name = "NAME")]
+    pub name: String,
+
+    /// host name or IP address of the device, with the port if it is not 23
+    #[arg(value_name = "ADDRESS", value_parser = parse_device_address)]
+    pub address: String,
+
+    /// password of the Telnet port of the device, if it has one
+    #[arg(long)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigApplyArgs {
+    /// document as `config show` prints it, or - for standard input
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MaintenanceCommand {
+    /// start maintenance, holding the schedules
+    On(MaintenanceOnArgs),
+    /// end maintenance
+    Off(ServerAddressArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct MaintenanceOnArgs {
+    /// What commands fail with, e.g. who to ask or when it will be over
+    #[arg(short, long, value_name = "TEXT", default_value = "")]
+    pub message: String,
+
+    #[command(flatten)]
+    pub server: ServerAddressArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HoldArgs {
+    /// Extron device to control
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// How long to keep the schedule off the device
+    #[arg(
+        value_name = "MINUTES",
+        required_unless_present = "release",
+        value_parser = parse_minutes
+    )]
+    pub duration: Option<Duration>,
+
+    /// Put the device back on its schedule
+    #[arg(long, conflicts_with = "duration")]
+    pub release: bool,
+
+    /// Adress:Port to connect to
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ConnectionsArgs {
+    /// Close the connection from this peer, as listed
+    #[arg(long, value_name = "PEER")]
+    pub disconnect: Option<String>,
+
+    #[command(flatten)]
+    pub server: ServerAddressArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct LogArgs {
+    /// Extron device to show the messages of
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Adress:Port to connect to
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Extron device to show the input changes of
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Adress:Port to connect to
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct UndoArgs {
+    /// Extron device to undo the last change on
+    #[arg(short, long, value_name = "NAME")]
+    pub device: Option<String>,
+
+    /// Adress:Port to connect to
+    #[arg(short, long, value_name = "SERVER ADDRESS", value_parser = parse_servers)]
+    pub remote: Option<String>,
+}
+
+/// The first address `s` stands for, with the default port if it has none.
+fn parse_address(s: &str) -> Result<SocketAddr, String> {
+    endpoint::resolve(s)
+        .map_err(|e| format!("'{}' does not contain a valid address: {}", s, e))
+        .map(|addrs| addrs[0])
+}
+
+/// Checks the address of a device on the network, without looking it up, as the device may
+/// not be reachable from here yet.
+fn parse_device_address(s: &str) -> Result<String, String> {
+    endpoint::split_or(s, control_dsc::network::SIS_PORT)
+        .map_err(|e| e.to_string())
+        .map(|_| s.to_string())
+}
+
+/// Checks every address of a comma separated list without looking it up, and keeps the list
+/// as given. Names are resolved when connecting, so that a server whose name does not resolve
+/// leaves the others to fail over to, and names behind an SSH jump host resolve over there.
+fn parse_servers(s: &str) -> Result<String, String> {
+    for server in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        endpoint::split(server)
+            .map_err(|e| format!("'{}' does not contain a valid address: {}", server, e))?;
+    }
+    Ok(s.to_string())
+}
+
+/// Instance names end up in file names, so they are kept to letters, digits, `-` and `_`.
+#[cfg(feature = "server")]
+fn parse_instance(s: &str) -> Result<String, String> {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "'{}' is not a name of letters, digits, '-' and '_'",
+            s
+        ))
+    }
+}
+
+#[cfg(feature = "server")]
+fn parse_pattern(s: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(s).map_err(|e| e.to_string())
+}
+
+fn parse_input(s: &str) -> Result<Input, String> {
+    s.parse::<Input>().map_err(|e| e.to_string())
+}
+
+fn parse_window(s: &str) -> Result<u32, String> {
+    match s {
+        "main" => Ok(1),
+        "secondary" => Ok(2),
+        _ => match s.parse::<u32>() {
+            Ok(n) if (1..=4).contains(&n) => Ok(n),
+            _ => Err(format!(
+                "'{}' is not main, secondary or a window from 1 to 4",
+                s
+            )),
+        },
+    }
+}
+
+/// A duration of `s` seconds, greater than zero. Numbers too large for a duration, like
+/// `inf`, are refused rather than panicking.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|v| Duration::try_from_secs_f64(v).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("'{}' is not a valid number of seconds", s))
+}
+
+/// A duration of `s` minutes, like [`parse_seconds`].
+fn parse_minutes(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|v| Duration::try_from_secs_f64(v * 60.0).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("'{}' is not a valid number of minutes", s))
+}
@@ -1,145 +1,1682 @@
+use crate::endpoint;
+use crate::error::{ControlError, Result};
+use crate::extron::{
+    Annotation, DeviceHealth, DeviceInfo, DeviceMessage, DeviceStatus, ExtronDevice,
+    FirmwareUpdate, Genlock, Input, InputChange, PipMode, SyncFormat, SyncSettings, WallLayout,
+    WindowLayout,
+};
 use crate::extron_capnp::control_extron;
-use anyhow::Result;
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use crate::keepalive;
+use crate::metrics::DeviceStats;
+use crate::rpc_trace;
+use crate::sis::Plane;
+use crate::tokens::{Scope, Token};
+use capnp::capability::Promise;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use futures::future::{LocalBoxFuture, Shared};
 use futures::{AsyncReadExt, FutureExt};
-use std::net;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::mpsc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
 
-pub struct Client {
-    addr: std::net::SocketAddr,
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RETRIES: u32 = 2;
+pub(crate) const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between attempts to connect again in [`Client::watch`].
+pub const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// Something that happened on the server, see [`AsyncClient::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The server scanned for devices, which may have changed what [`AsyncClient::list`]
+    /// returns.
+    DevicesScanned,
+    InputSelected {
+        device: String,
+        input: Input,
+    },
+    VolumeChanged {
+        device: String,
+        level: u8,
+    },
+    MuteChanged {
+        device: String,
+        mute: bool,
+    },
+    /// The input selected on `device` has had no signal for a while, so a black screen is
+    /// down to the source.
+    SignalLost {
+        device: String,
+        input: u32,
+    },
+    SignalRestored {
+        device: String,
+        input: u32,
+    },
+    /// The server went into maintenance, failing calls that change a device with `message`
+    /// until [`Event::MaintenanceEnded`].
+    MaintenanceStarted {
+        message: String,
+    },
+    MaintenanceEnded,
+    /// The connection to the server dropped and was made again by [`Client::watch`]. Events in
+    /// between were missed, so what was read from the server before may be out of date.
+    Reconnected,
+}
+
+impl Event {
+    /// `None` for kinds of events added by newer servers.
+    fn from_wire(event: control_extron::event::Reader) -> Result<Option<Self>> {
+        use control_extron::event::Which;
+
+        let device = event.get_device()?.to_str()?.to_string();
+        Ok(match event.which() {
+            Ok(Which::DevicesScanned(())) => Some(Event::DevicesScanned),
+            Ok(Which::InputSelected(input)) => Some(Event::InputSelected {
+                device,
+                input: input?.to_str()?.parse()?,
+            }),
+            Ok(Which::VolumeChanged(level)) => Some(Event::VolumeChanged { device, level }),
+            Ok(Which::MuteChanged(mute)) => Some(Event::MuteChanged { device, mute }),
+            Ok(Which::SignalLost(input)) => Some(Event::SignalLost { device, input }),
+            Ok(Which::SignalRestored(input)) => Some(Event::SignalRestored { device, input }),
+            Ok(Which::MaintenanceStarted(message)) => Some(Event::MaintenanceStarted {
+                message: message?.to_str()?.to_string(),
+            }),
+            Ok(Which::MaintenanceEnded(())) => Some(Event::MaintenanceEnded),
+            Err(_) => None,
+        })
+    }
+}
+
+/// A client connected to the server, see [`AsyncClient::sessions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// Address and port the client connects from, which [`AsyncClient::disconnect`] takes.
+    pub peer: String,
+    /// Schema version the client announced, 0 if it did not.
+    pub version: u32,
+    /// Time since the client connected.
+    pub connected: Duration,
+    /// Time since its last call.
+    pub idle: Duration,
+    pub calls: u64,
+    /// Calls that failed.
+    pub errors: u64,
+    /// Whether it is the connection of this client.
+    pub caller: bool,
+    /// Name in the certificate of the client, on servers requiring one, see [`crate::tls`].
+    /// Servers before schema version 35 never tell.
+    pub identity: Option<String>,
 }
 
-fn setup_tokio_streams(
-    stream: std::net::TcpStream,
-) -> Result<(control_extron::Client, RpcSystem<rpc_twoparty_capnp::Side>)> {
-    use tokio::net::TcpStream;
+/// Receives events from the server for [`AsyncClient::subscribe`].
+struct Listener<F>(F);
 
-    stream.set_nonblocking(true)?;
-    let stream = TcpStream::from_std(stream)?;
+impl<F: FnMut(Event) + 'static> control_extron::event_listener::Server for Listener<F> {
+    fn event(
+        &mut self,
+        params: control_extron::event_listener::EventParams,
+        _results: control_extron::event_listener::EventResults,
+    ) -> Promise<(), capnp::Error> {
+        let event = pry!(pry!(params.get()).get_event());
+        if let Some(event) = pry!(Event::from_wire(event)) {
+            (self.0)(event);
+        }
+        Promise::ok(())
+    }
+}
 
-    let (reader, writer) = tokio_util::compat::Tokio02AsyncReadCompatExt::compat(stream).split();
-    let rpc_network = Box::new(twoparty::VatNetwork::new(
-        reader,
-        writer,
-        rpc_twoparty_capnp::Side::Client,
-        Default::default(),
-    ));
-    let mut rpc_system = RpcSystem::new(rpc_network, None);
-    let extron_client: control_extron::Client =
-        rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+/// Receives the progress of [`AsyncClient::upload_firmware`].
+struct Progress<F>(F);
 
-    Ok((extron_client, rpc_system))
+impl<F: FnMut(usize, usize) + 'static> control_extron::progress_listener::Server for Progress<F> {
+    fn progress(
+        &mut self,
+        params: control_extron::progress_listener::ProgressParams,
+        _results: control_extron::progress_listener::ProgressResults,
+    ) -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        (self.0)(params.get_done() as usize, params.get_total() as usize);
+        Promise::ok(())
+    }
 }
 
-async fn do_list(stream: std::net::TcpStream) -> Result<()> {
-    let (extron_client, rpc_system) = setup_tokio_streams(stream)?;
-    let local = tokio::task::LocalSet::new();
-    local
-        .run_until(async move {
-            tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
+/// A scan the server runs in the background, see [`AsyncClient::start_rescan`]. It is only
+/// good on the connection it was started on.
+#[derive(Clone)]
+pub struct ScanTicket(Option<control_extron::scan_ticket::Client>);
 
-            let request = extron_client.list_devices_request();
-            let reply = request.send().promise.await.unwrap();
+/// Connection to a server for use from async code.
+///
+/// The RPC system is not `Send`, so an `AsyncClient` has to be created and used from within a
+/// `tokio::task::LocalSet`. All calls share the one connection, also those made through clones.
+#[derive(Clone)]
+pub struct AsyncClient {
+    extron_client: control_extron::Client,
+    /// Schema version the server answered the negotiation with.
+    server_version: Shared<LocalBoxFuture<'static, Option<u32>>>,
+}
 
-            println!("{:<32}Device","Name");
+impl AsyncClient {
+    pub async fn connect<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .map_err(ControlError::Connection)?;
+        keepalive::enable(&stream).map_err(ControlError::Connection)?;
+        Self::from_stream(stream)
+    }
+
+    /// Connects to `server`, a host with an optional port as [`endpoint::split`] takes them,
+    /// over TLS as `tls` says.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls(server: &str, tls: &crate::tls::ClientTls) -> Result<Self> {
+        let addr = endpoint::split(server).map_err(ControlError::Connection)?;
+        let stream = tokio::net::TcpStream::connect(addr)
+            .await
+            .map_err(ControlError::Connection)?;
+        keepalive::enable(&stream).map_err(ControlError::Connection)?;
+        stream.set_nodelay(true).map_err(ControlError::Connection)?;
+        let stream = tls
+            .connect(server, stream)
+            .await
+            .map_err(ControlError::Connection)?;
+        Ok(Self::start(stream)?.0)
+    }
+
+    pub fn from_stream(stream: tokio::net::TcpStream) -> Result<Self> {
+        stream.set_nodelay(true).map_err(ControlError::Connection)?;
+        Ok(Self::start(stream)?.0)
+    }
 
-            for device in reply.get().unwrap().get_reply().unwrap().iter() {
-                println!(
-                    "{:<32}{}",
-                    device.get_name().unwrap(),
-                    device.get_path().unwrap()
-                );
+    /// Connects to the admin socket of a server on this machine, see
+    /// [`crate::server::ServerBuilder::admin_socket`].
+    #[cfg(unix)]
+    pub async fn connect_socket<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(ControlError::Connection)?;
+        Ok(Self::start(stream)?.0)
+    }
+
+    /// Sets up the connection, returning the client and the task serving it, which ends when
+    /// the connection does.
+    fn start<S>(stream: S) -> Result<(Self, tokio::task::JoinHandle<()>)>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+    {
+        let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+        let rpc_network = Box::new(twoparty::VatNetwork::new(
+            reader,
+            writer,
+            rpc_twoparty_capnp::Side::Client,
+            Default::default(),
+        ));
+        let mut rpc_system = RpcSystem::new(rpc_network, None);
+        let extron_client: control_extron::Client =
+            rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+        let connection = tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
+
+        // Calls on one capability are delivered in order, so the server has seen this before
+        // any call made through the client. Servers older than version 2 do not know it and
+        // keep raising exceptions, which is just as well.
+        let mut request = extron_client.negotiate_request();
+        request.get().set_version(crate::SCHEMA_VERSION);
+        let server_version = request
+            .send()
+            .promise
+            .map(|reply| Some(reply.ok()?.get().ok()?.get_version()))
+            .boxed_local()
+            .shared();
+        tokio::task::spawn_local(server_version.clone());
+
+        Ok((
+            AsyncClient {
+                extron_client,
+                server_version,
+            },
+            connection,
+        ))
+    }
+
+    /// Schema version of the server, `None` for a server from before version 2, which does not
+    /// say.
+    pub async fn server_version(&self) -> Option<u32> {
+        self.server_version.clone().await
+    }
+
+    /// Waits for the reply to a call of `method`, which came with schema version `since`. A
+    /// server too old to know the method fails the call with [`ControlError::OldServer`] rather
+    /// than the bare unimplemented method error of the RPC system.
+    async fn reply<T>(
+        &self,
+        method: &str,
+        since: u32,
+        promise: impl Future<Output = capnp::Result<T>>,
+    ) -> Result<T> {
+        let reply = promise.await;
+        if let Err(e) = &reply {
+            rpc_trace::failed(method, e);
+        }
+        match reply {
+            Err(e) if e.kind == capnp::ErrorKind::Unimplemented => {
+                let server = self.server_version().await;
+                if server.is_some_and(|server| server >= since) {
+                    return Err(e.into());
+                }
+                Err(ControlError::OldServer {
+                    method: method.to_string(),
+                    since,
+                    server,
+                })
             }
+            reply => Ok(reply?),
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<ExtronDevice>> {
+        Ok(self.list_page("", 0, 0).await?.0)
+    }
+
+    /// The devices, with whether the server is still scanning for more, in which case they
+    /// are those it found so far. Servers before schema version 28 never tell.
+    pub async fn list_scanning(&self) -> Result<(Vec<ExtronDevice>, bool)> {
+        let (devices, _, scanning) = self.list_devices("", 0, 0).await?;
+        Ok((devices, scanning))
+    }
+
+    /// The devices whose name or path matches the glob pattern `filter`, all for an empty one,
+    /// sorted by name, skipping the first `offset` and at most `limit` of them, all for 0, with
+    /// the number that matched. Servers before schema version 21 return all devices, which are
+    /// then picked out here.
+    pub async fn list_page(
+        &self,
+        filter: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<ExtronDevice>, u32)> {
+        let (devices, total, _) = self.list_devices(filter, offset, limit).await?;
+        Ok((devices, total))
+    }
+
+    async fn list_devices(
+        &self,
+        filter: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<ExtronDevice>, u32, bool)> {
+        let mut request = self.extron_client.list_devices_request();
+        let mut request_builder = request.get();
+        request_builder.set_filter(filter);
+        request_builder.set_offset(offset);
+        request_builder.set_limit(limit);
+        rpc_trace::call("listDevices", request.get().into_reader());
+        let reply = self.reply("listDevices", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("listDevices", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut devices = Vec::new();
+        for device in results.get_reply()?.iter() {
+            let mut labels = std::collections::BTreeMap::new();
+            for label in device.get_labels()?.iter() {
+                labels.insert(label.get_label()?.to_str()?.to_string(), label.get_input());
+            }
+            devices.push(
+                ExtronDevice::new(device.get_name()?.to_str()?, device.get_path()?.to_str()?)
+                    .with_labels(labels),
+            );
+        }
+        let total = results.get_total();
+        let scanning = results.get_scanning();
+        if self.server_version().await.is_some_and(|v| v >= 21) {
+            return Ok((devices, total, scanning));
+        }
+        let (devices, total) = crate::extron::page_of(devices, filter, offset, limit)?;
+        Ok((devices, total, scanning))
+    }
+
+    pub async fn select(&self, device: &str, input: &Input) -> Result<()> {
+        let mut request = self.extron_client.select_input_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_input(&input.to_string());
+        rpc_trace::call("selectInput", request.get().into_reader());
+        let reply = self.reply("selectInput", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("selectInput", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Selects the input `device` was on before the one it is on, as the server saw them
+    /// selected, and returns it.
+    pub async fn toggle_input(&self, device: &str) -> Result<Input> {
+        let mut request = self.extron_client.toggle_input_request();
+        request.get().set_name(device);
+        rpc_trace::call("toggleInput", request.get().into_reader());
+        let reply = self
+            .reply("toggleInput", 32, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("toggleInput", results);
+        check(results.has_error(), || results.get_error())?;
+        results.get_input()?.to_str()?.parse()
+    }
+
+    /// Selects `input` on `device` for the signals of `plane` only. Servers before schema
+    /// version 14 do not know planes other than all, which [`AsyncClient::select`] takes.
+    pub async fn select_plane(&self, device: &str, input: &Input, plane: Plane) -> Result<()> {
+        let mut request = self.extron_client.select_plane_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_input(&input.to_string());
+        request_builder.set_plane(wire_plane(plane));
+        rpc_trace::call("selectPlane", request.get().into_reader());
+        let reply = self
+            .reply("selectPlane", 14, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("selectPlane", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    pub async fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        let mut request = self.extron_client.set_volume_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_level(level);
+        rpc_trace::call("setVolume", request.get().into_reader());
+        let reply = self.reply("setVolume", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setVolume", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    pub async fn set_mute(&self, device: &str, mute: bool) -> Result<()> {
+        let mut request = self.extron_client.set_mute_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_mute(mute);
+        rpc_trace::call("setMute", request.get().into_reader());
+        let reply = self.reply("setMute", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setMute", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    pub async fn status(&self, device: &str) -> Result<DeviceStatus> {
+        let mut request = self.extron_client.get_status_request();
+        request.get().set_name(device);
+        rpc_trace::call("getStatus", request.get().into_reader());
+        let reply = self.reply("getStatus", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getStatus", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let status = results.get_status()?;
+        let label = status.get_label()?.to_str()?;
+        Ok(DeviceStatus {
+            input: status.get_input(),
+            label: Some(label.to_string()).filter(|_| !label.is_empty()),
+            volume: Some(status.get_volume()).filter(|_| status.get_has_audio()),
+            mute: Some(status.get_mute()).filter(|_| status.get_has_audio()),
+            pip: match status.get_pip_mode().map_err(capnp::Error::from)? {
+                _ if !status.get_has_pip() => None,
+                control_extron::PipMode::Off => Some(PipMode::Off),
+                control_extron::PipMode::Pip => Some(PipMode::Pip),
+                control_extron::PipMode::Quad => Some(PipMode::Quad),
+            },
+            sources: status.get_sources()?.iter().collect(),
         })
-        .await;
-    Ok(())
-}
-
-async fn do_select(stream: std::net::TcpStream, device: &str, input: &str) -> Result<()> {
-    let (extron_client, rpc_system) = setup_tokio_streams(stream)?;
-    let local = tokio::task::LocalSet::new();
-    local
-        .run_until(async move {
-            tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
-
-            let mut request = extron_client.select_input_request();
-            let mut request_builder = request.get();
-            request_builder.set_name(device);
-            request_builder.set_input(input);
-            if let Err(e) = request.send().promise.await {
-                println!("{}",e);
+    }
+
+    /// Selects `input` on all `devices` at once. Failures on single devices are returned
+    /// with their name rather than failing the call.
+    pub async fn select_group(
+        &self,
+        devices: &[String],
+        input: &Input,
+    ) -> Result<Vec<(String, Result<()>)>> {
+        self.select_group_plane(devices, input, Plane::All).await
+    }
+
+    /// Selects `input` on all `devices` at once for the signals of `plane` only, see
+    /// [`AsyncClient::select_plane`]. Servers before schema version 31 know no planes for
+    /// groups, so there the devices are switched one by one.
+    pub async fn select_group_plane(
+        &self,
+        devices: &[String],
+        input: &Input,
+        plane: Plane,
+    ) -> Result<Vec<(String, Result<()>)>> {
+        if plane != Plane::All && !self.server_version().await.is_some_and(|v| v >= 31) {
+            let mut results = Vec::new();
+            for device in devices {
+                let result = self.select_plane(device, input, plane).await;
+                results.push((device.clone(), result));
             }
+            return Ok(results);
+        }
+        let mut request = self.extron_client.select_group_request();
+        let mut request_builder = request.get();
+        let mut names = request_builder.reborrow().init_names(devices.len() as u32);
+        for (i, device) in devices.iter().enumerate() {
+            names.set(i as u32, device);
+        }
+        request_builder.set_input(&input.to_string());
+        request_builder.set_plane(wire_plane(plane));
+        rpc_trace::call("selectGroup", request.get().into_reader());
+        let reply = self.reply("selectGroup", 5, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("selectGroup", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut devices = Vec::new();
+        for device in results.get_results()?.iter() {
+            devices.push((
+                device.get_name()?.to_str()?.to_string(),
+                check(device.has_error(), || device.get_error()),
+            ));
+        }
+        Ok(devices)
+    }
+
+    /// Sends `command` to `device` as it is and returns what the device answers, see
+    /// [`ExtronDevice::send_raw`].
+    pub async fn send_raw(&self, device: &str, command: &[u8]) -> Result<Vec<u8>> {
+        let mut request = self.extron_client.send_raw_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_command(command);
+        rpc_trace::call("sendRaw", request.get().into_reader());
+        let reply = self.reply("sendRaw", 6, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("sendRaw", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_reply()?.to_vec())
+    }
+
+    /// Sends `image` to `device` as its new firmware, see [`ExtronDevice::upload_firmware`].
+    /// `progress` gets the bytes sent so far and the total.
+    pub async fn upload_firmware<F>(
+        &self,
+        device: &str,
+        image: &[u8],
+        progress: F,
+    ) -> Result<FirmwareUpdate>
+    where
+        F: FnMut(usize, usize) + 'static,
+    {
+        let mut request = self.extron_client.upload_firmware_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_image(image);
+        request_builder.set_progress(capnp_rpc::new_client(Progress(progress)));
+        rpc_trace::call("uploadFirmware", request.get().into_reader());
+        let reply = self
+            .reply("uploadFirmware", 7, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("uploadFirmware", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(FirmwareUpdate {
+            before: results.get_before()?.to_str()?.to_string(),
+            after: results.get_after()?.to_str()?.to_string(),
+        })
+    }
+
+    /// The messages `device` sent on its own that the server kept, oldest first.
+    pub async fn device_log(&self, device: &str) -> Result<Vec<DeviceMessage>> {
+        let mut request = self.extron_client.get_device_log_request();
+        request.get().set_name(device);
+        rpc_trace::call("getDeviceLog", request.get().into_reader());
+        let reply = self
+            .reply("getDeviceLog", 8, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getDeviceLog", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut messages = Vec::new();
+        for message in results.get_messages()?.iter() {
+            messages.push(DeviceMessage {
+                time: UNIX_EPOCH + Duration::from_millis(message.get_time()),
+                text: message.get_text()?.to_str()?.to_string(),
+            });
+        }
+        Ok(messages)
+    }
+
+    /// The input changes of `device` that the server kept, oldest first, with who made them.
+    pub async fn history(&self, device: &str) -> Result<Vec<InputChange>> {
+        let mut request = self.extron_client.get_history_request();
+        request.get().set_name(device);
+        rpc_trace::call("getHistory", request.get().into_reader());
+        let reply = self.reply("getHistory", 33, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getHistory", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut changes = Vec::new();
+        for change in results.get_changes()?.iter() {
+            let from = match change.get_from()?.to_str()? {
+                "" => None,
+                from => Some(from.parse()?),
+            };
+            changes.push(InputChange {
+                time: UNIX_EPOCH + Duration::from_millis(change.get_time()),
+                by: change.get_by()?.to_str()?.to_string(),
+                from,
+                to: change.get_to()?.to_str()?.parse()?,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Puts `device` back as it was before the last input, volume or mute change made through
+    /// the server, within the undo window of the server. Returns what it set, e.g. `input 2`.
+    pub async fn undo(&self, device: &str) -> Result<String> {
+        let mut request = self.extron_client.undo_request();
+        request.get().set_name(device);
+        rpc_trace::call("undo", request.get().into_reader());
+        let reply = self.reply("undo", 34, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("undo", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_undone()?.to_str()?.to_string())
+    }
+
+    /// Powers the display on the output of `device` on or off over HDMI CEC.
+    pub async fn set_display_power(&self, device: &str, on: bool) -> Result<()> {
+        let mut request = self.extron_client.set_display_power_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_on(on);
+        rpc_trace::call("setDisplayPower", request.get().into_reader());
+        let reply = self
+            .reply("setDisplayPower", 10, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setDisplayPower", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Passes `data` on to the display behind `device`, see [`ExtronDevice::send_to_display`].
+    pub async fn send_to_display(&self, device: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut request = self.extron_client.send_to_display_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_data(data);
+        rpc_trace::call("sendToDisplay", request.get().into_reader());
+        let reply = self
+            .reply("sendToDisplay", 11, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("sendToDisplay", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_reply()?.to_vec())
+    }
+
+    /// Recalls video wall preset `preset` on `device`, see [`ExtronDevice::recall_preset`].
+    pub async fn recall_preset(&self, device: &str, preset: u32) -> Result<()> {
+        let mut request = self.extron_client.recall_preset_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_preset(preset);
+        rpc_trace::call("recallPreset", request.get().into_reader());
+        let reply = self
+            .reply("recallPreset", 12, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("recallPreset", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    pub async fn wall_layout(&self, device: &str) -> Result<WallLayout> {
+        let mut request = self.extron_client.get_wall_layout_request();
+        request.get().set_name(device);
+        rpc_trace::call("getWallLayout", request.get().into_reader());
+        let reply = self
+            .reply("getWallLayout", 12, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getWallLayout", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let layout = results.get_layout()?;
+        Ok(WallLayout {
+            preset: layout.get_preset(),
+            windows: layout.get_windows(),
         })
-        .await;
-    Ok(())
-}
-
-async fn do_rescan(stream: std::net::TcpStream) -> Result<()> {
-    let (extron_client, rpc_system) = setup_tokio_streams(stream)?;
-    let local = tokio::task::LocalSet::new();
-    local
-        .run_until(async move {
-            tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
-            let request = extron_client.rescan_request();
-            request.send().promise.await.unwrap();
+    }
+
+    /// Recalls window layout preset `layout` on `device`, see [`ExtronDevice::recall_layout`].
+    pub async fn recall_layout(&self, device: &str, layout: u32) -> Result<WindowLayout> {
+        let mut request = self.extron_client.recall_layout_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_layout(layout);
+        rpc_trace::call("recallLayout", request.get().into_reader());
+        let reply = self
+            .reply("recallLayout", 15, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("recallLayout", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let windows = results.get_windows()?;
+        Ok(WindowLayout {
+            layout: windows.get_layout(),
+            inputs: windows.get_inputs()?.iter().collect(),
         })
-        .await;
-    Ok(())
-}
-
-async fn do_stop(stream: std::net::TcpStream) -> Result<()> {
-    let (extron_client, rpc_system) = setup_tokio_streams(stream)?;
-    let local = tokio::task::LocalSet::new();
-    local
-        .run_until(async move {
-            tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
-            let request = extron_client.stop_server_request();
-            request.send().promise.await.unwrap();
+    }
+
+    /// Sends `annotation` to `device`, see [`ExtronDevice::annotate`].
+    pub async fn annotate(&self, device: &str, annotation: Annotation) -> Result<()> {
+        let mut request = self.extron_client.annotate_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        let mut builder = request_builder.init_annotation();
+        match annotation {
+            Annotation::Clear => builder.set_clear(()),
+            Annotation::Pointer => builder.set_pointer(()),
+            Annotation::Freeze(on) => builder.set_freeze(on),
+        }
+        rpc_trace::call("annotate", request.get().into_reader());
+        let reply = self.reply("annotate", 13, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("annotate", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Switches picture-in-picture on `device` to `mode`, see [`ExtronDevice::set_pip_mode`].
+    pub async fn set_pip_mode(&self, device: &str, mode: PipMode) -> Result<()> {
+        let mut request = self.extron_client.set_pip_mode_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_mode(match mode {
+            PipMode::Off => control_extron::PipMode::Off,
+            PipMode::Pip => control_extron::PipMode::Pip,
+            PipMode::Quad => control_extron::PipMode::Quad,
+        });
+        rpc_trace::call("setPipMode", request.get().into_reader());
+        let reply = self.reply("setPipMode", 16, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setPipMode", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Shows `input` in picture-in-picture window `window` of `device`, see
+    /// [`ExtronDevice::assign_source`].
+    pub async fn assign_source(&self, device: &str, window: u32, input: &Input) -> Result<()> {
+        let mut request = self.extron_client.assign_source_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_window(window);
+        request_builder.set_input(&input.to_string());
+        rpc_trace::call("assignSource", request.get().into_reader());
+        let reply = self
+            .reply("assignSource", 16, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("assignSource", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Output sync settings of `device`, see [`ExtronDevice::sync`].
+    pub async fn sync(&self, device: &str) -> Result<SyncSettings> {
+        use control_extron::sync_settings;
+
+        let mut request = self.extron_client.get_sync_request();
+        request.get().set_name(device);
+        rpc_trace::call("getSync", request.get().into_reader());
+        let reply = self.reply("getSync", 17, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getSync", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let sync = results.get_sync()?;
+        let format = match sync.get_format().map_err(capnp::Error::from)? {
+            sync_settings::Format::SeparateHv => SyncFormat::SeparateHv,
+            sync_settings::Format::Composite => SyncFormat::Composite,
+            sync_settings::Format::SyncOnGreen => SyncFormat::SyncOnGreen,
+            sync_settings::Format::TriLevel => SyncFormat::TriLevel,
+        };
+        let genlock = match sync.get_genlock().map_err(capnp::Error::from)? {
+            sync_settings::Genlock::FreeRun => Genlock::FreeRun,
+            sync_settings::Genlock::SelectedInput => Genlock::SelectedInput,
+            sync_settings::Genlock::Reference => Genlock::Reference,
+        };
+        Ok(SyncSettings { format, genlock })
+    }
+
+    /// Sets the output sync format of `device`, see [`ExtronDevice::set_sync_format`].
+    pub async fn set_sync_format(&self, device: &str, format: SyncFormat) -> Result<()> {
+        use control_extron::sync_settings;
+
+        let mut request = self.extron_client.set_sync_format_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_format(match format {
+            SyncFormat::SeparateHv => sync_settings::Format::SeparateHv,
+            SyncFormat::Composite => sync_settings::Format::Composite,
+            SyncFormat::SyncOnGreen => sync_settings::Format::SyncOnGreen,
+            SyncFormat::TriLevel => sync_settings::Format::TriLevel,
+        });
+        rpc_trace::call("setSyncFormat", request.get().into_reader());
+        let reply = self
+            .reply("setSyncFormat", 17, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setSyncFormat", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Locks the output of `device` to `genlock`, see [`ExtronDevice::set_genlock`].
+    pub async fn set_genlock(&self, device: &str, genlock: Genlock) -> Result<()> {
+        use control_extron::sync_settings;
+
+        let mut request = self.extron_client.set_genlock_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_genlock(match genlock {
+            Genlock::FreeRun => sync_settings::Genlock::FreeRun,
+            Genlock::SelectedInput => sync_settings::Genlock::SelectedInput,
+            Genlock::Reference => sync_settings::Genlock::Reference,
+        });
+        rpc_trace::call("setGenlock", request.get().into_reader());
+        let reply = self.reply("setGenlock", 17, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setGenlock", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Keeps the schedule of `device` on the server from switching it for `duration`, or
+    /// puts it back on its schedule for `None`.
+    pub async fn hold(&self, device: &str, duration: Option<Duration>) -> Result<()> {
+        let mut request = self.extron_client.hold_request();
+        let mut request_builder = request.get();
+        request_builder.set_name(device);
+        request_builder.set_seconds(match duration {
+            // 0 puts the device back on its schedule, so shorter holds last a second.
+            Some(duration) => u32::try_from(duration.as_secs()).unwrap_or(u32::MAX).max(1),
+            None => 0,
+        });
+        rpc_trace::call("hold", request.get().into_reader());
+        let reply = self.reply("hold", 4, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("hold", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Puts the server in maintenance, failing calls that change a device with
+    /// [`ControlError::Maintenance`] carrying `message`, or takes it out again for `None`.
+    pub async fn set_maintenance(&self, message: Option<&str>) -> Result<()> {
+        let mut request = self.extron_client.set_maintenance_request();
+        let mut request_builder = request.get();
+        request_builder.set_on(message.is_some());
+        request_builder.set_message(message.unwrap_or(""));
+        rpc_trace::call("setMaintenance", request.get().into_reader());
+        let reply = self
+            .reply("setMaintenance", 23, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("setMaintenance", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// The clients connected to the server, this one included.
+    pub async fn sessions(&self) -> Result<Vec<Session>> {
+        let mut request = self.extron_client.list_sessions_request();
+        rpc_trace::call("listSessions", request.get().into_reader());
+        let reply = self
+            .reply("listSessions", 24, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("listSessions", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut sessions = Vec::new();
+        for session in results.get_sessions()?.iter() {
+            sessions.push(Session {
+                peer: session.get_peer()?.to_str()?.to_string(),
+                version: session.get_version(),
+                connected: Duration::from_secs(session.get_connected_seconds()),
+                idle: Duration::from_secs(session.get_idle_seconds()),
+                calls: session.get_calls(),
+                errors: session.get_errors(),
+                caller: session.get_caller(),
+                identity: Some(session.get_identity()?.to_str()?.to_string())
+                    .filter(|identity| !identity.is_empty()),
+            });
+        }
+        Ok(sessions)
+    }
+
+    /// Closes the connection from `peer`, as listed by [`AsyncClient::sessions`].
+    pub async fn disconnect(&self, peer: &str) -> Result<()> {
+        let mut request = self.extron_client.disconnect_request();
+        request.get().set_peer(peer);
+        rpc_trace::call("disconnect", request.get().into_reader());
+        let reply = self.reply("disconnect", 24, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("disconnect", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// The part number and firmware of `device`, see [`ExtronDevice::info`].
+    pub async fn device_info(&self, device: &str) -> Result<DeviceInfo> {
+        let mut request = self.extron_client.get_device_info_request();
+        request.get().set_name(device);
+        rpc_trace::call("getDeviceInfo", request.get().into_reader());
+        let reply = self
+            .reply("getDeviceInfo", 25, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getDeviceInfo", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let info = results.get_info()?;
+        let text = |text: capnp::text::Reader| -> Result<Option<String>> {
+            Ok(Some(text.to_str()?.to_string()).filter(|text| !text.is_empty()))
+        };
+        Ok(DeviceInfo {
+            part_number: text(info.get_part_number()?)?,
+            firmware: text(info.get_firmware()?)?,
         })
-        .await;
-    Ok(())
+    }
+
+    /// The temperature of `device` and the signal on its selected input, see
+    /// [`ExtronDevice::health`].
+    pub async fn device_health(&self, device: &str) -> Result<DeviceHealth> {
+        let mut request = self.extron_client.get_device_health_request();
+        request.get().set_name(device);
+        rpc_trace::call("getDeviceHealth", request.get().into_reader());
+        let reply = self
+            .reply("getDeviceHealth", 26, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getDeviceHealth", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let health = results.get_health()?;
+        Ok(DeviceHealth {
+            temperature: Some(health.get_temperature()).filter(|_| health.get_has_temperature()),
+            signal: Some((health.get_input(), health.get_signal()))
+                .filter(|_| health.get_has_signal()),
+        })
+    }
+
+    /// Authenticates the connection with the API token `token`, on a server that requires
+    /// them, see [`crate::tokens`]. Returns the scope of the token.
+    pub async fn authenticate(&self, token: &str) -> Result<Scope> {
+        let mut request = self.extron_client.authenticate_request();
+        request.get().set_token(token);
+        // Not traced with its params, which hold the secret.
+        let reply = self
+            .reply("authenticate", 27, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("authenticate", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_scope()?.into())
+    }
+
+    /// Creates an API token with `scope` for the devices matching the glob patterns
+    /// `devices`, all if there are none. Returns it with its secret, which the server does
+    /// not keep.
+    pub async fn create_token(&self, scope: Scope, devices: &[String]) -> Result<(Token, String)> {
+        let mut request = self.extron_client.create_token_request();
+        let mut builder = request.get();
+        builder.set_scope(scope.into());
+        let mut patterns = builder.init_devices(devices.len() as u32);
+        for (i, pattern) in devices.iter().enumerate() {
+            patterns.set(i as u32, pattern);
+        }
+        rpc_trace::call("createToken", request.get().into_reader());
+        let reply = self
+            .reply("createToken", 27, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        // The reply is not traced, it holds the secret.
+        check(results.has_error(), || results.get_error())?;
+        Ok((
+            Token::from_wire(results.get_token()?)?,
+            results.get_secret()?.to_str()?.to_string(),
+        ))
+    }
+
+    /// Revokes the API token `id`, closing the connections that authenticated with it.
+    pub async fn revoke_token(&self, id: &str) -> Result<()> {
+        let mut request = self.extron_client.revoke_token_request();
+        request.get().set_id(id);
+        rpc_trace::call("revokeToken", request.get().into_reader());
+        let reply = self
+            .reply("revokeToken", 27, request.send().promise)
+            .await?;
+        let results = reply.get()?;
+        rpc_trace::reply("revokeToken", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    pub async fn list_tokens(&self) -> Result<Vec<Token>> {
+        let mut request = self.extron_client.list_tokens_request();
+        rpc_trace::call("listTokens", request.get().into_reader());
+        let reply = self.reply("listTokens", 27, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("listTokens", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut tokens = Vec::new();
+        for token in results.get_tokens()?.iter() {
+            tokens.push(Token::from_wire(token)?);
+        }
+        Ok(tokens)
+    }
+
+    /// How every device the server has seen fared since it started, by name.
+    pub async fn stats(&self) -> Result<Vec<DeviceStats>> {
+        let mut request = self.extron_client.get_stats_request();
+        rpc_trace::call("getStats", request.get().into_reader());
+        let reply = self.reply("getStats", 19, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("getStats", results);
+        check(results.has_error(), || results.get_error())?;
+
+        let mut devices = Vec::new();
+        for stats in results.get_stats()?.iter() {
+            let mut errors = BTreeMap::new();
+            for error in stats.get_errors()?.iter() {
+                errors.insert(error.get_code(), error.get_count());
+            }
+            devices.push(DeviceStats {
+                name: stats.get_name()?.to_str()?.to_string(),
+                online: stats.get_online(),
+                latencies: stats.get_latencies()?.iter().collect(),
+                latency_total: Duration::from_micros(stats.get_latency_total()),
+                errors,
+                timeouts: stats.get_timeouts(),
+                reconnects: stats.get_reconnects(),
+            });
+        }
+        Ok(devices)
+    }
+
+    /// What the server knows, as pretty-printed JSON, see `dumpState` in the schema.
+    pub async fn dump_state(&self) -> Result<String> {
+        let mut request = self.extron_client.dump_state_request();
+        rpc_trace::call("dumpState", request.get().into_reader());
+        let reply = self.reply("dumpState", 20, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("dumpState", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_state()?.to_str()?.to_string())
+    }
+
+    pub async fn rescan(&self) -> Result<()> {
+        let mut request = self.extron_client.rescan_request();
+        rpc_trace::call("rescan", request.get().into_reader());
+        let reply = self.reply("rescan", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("rescan", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Has the server scan for devices again in the background, returning a ticket to wait
+    /// for the scan or poll it with. Meanwhile the server goes on serving the devices the scan
+    /// does not probe. Servers before schema version 29 return once the scan is over.
+    pub async fn start_rescan(&self) -> Result<ScanTicket> {
+        let mut request = self.extron_client.rescan_request();
+        request.get().set_background(true);
+        rpc_trace::call("rescan", request.get().into_reader());
+        let reply = self.reply("rescan", 1, request.send().promise).await?;
+        let results = reply.get()?;
+        rpc_trace::reply("rescan", results);
+        check(results.has_error(), || results.get_error())?;
+        if self.server_version().await.is_some_and(|v| v >= 29) {
+            return Ok(ScanTicket(Some(results.get_ticket()?)));
+        }
+        Ok(ScanTicket(None))
+    }
+
+    /// Waits for the scan of `ticket` to be over.
+    pub async fn wait_scan(&self, ticket: &ScanTicket) -> Result<()> {
+        let ticket = match &ticket.0 {
+            Some(ticket) => ticket,
+            None => return Ok(()),
+        };
+        let mut request = ticket.wait_request();
+        rpc_trace::call("ScanTicket.wait", request.get().into_reader());
+        let reply = request.send().promise.await?;
+        let results = reply.get()?;
+        rpc_trace::reply("ScanTicket.wait", results);
+        check(results.has_error(), || results.get_error())
+    }
+
+    /// Whether the scan of `ticket` is over, without waiting for it.
+    pub async fn scan_done(&self, ticket: &ScanTicket) -> Result<bool> {
+        let ticket = match &ticket.0 {
+            Some(ticket) => ticket,
+            None => return Ok(true),
+        };
+        let mut request = ticket.poll_request();
+        rpc_trace::call("ScanTicket.poll", request.get().into_reader());
+        let reply = request.send().promise.await?;
+        let results = reply.get()?;
+        rpc_trace::reply("ScanTicket.poll", results);
+        check(results.has_error(), || results.get_error())?;
+        Ok(results.get_done())
+    }
+
+    /// Calls `hook` for every event on the server from now on, until the connection closes.
+    /// Events arrive while the `LocalSet` runs, in between and during other calls.
+    pub async fn subscribe<F>(&self, hook: F) -> Result<()>
+    where
+        F: FnMut(Event) + 'static,
+    {
+        let mut request = self.extron_client.subscribe_request();
+        request
+            .get()
+            .set_listener(capnp_rpc::new_client(Listener(hook)));
+        rpc_trace::call("subscribe", request.get().into_reader());
+        self.reply("subscribe", 3, request.send().promise).await?;
+        Ok(())
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        let mut request = self.extron_client.stop_server_request();
+        rpc_trace::call("stopServer", request.get().into_reader());
+        // The server may go away before the reply makes it back to us.
+        match request.send().promise.await {
+            Err(e) if e.kind != capnp::ErrorKind::Disconnected => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Turns the error a server put in the results of a call back into a `ControlError`.
+fn check<'a>(
+    has_error: bool,
+    error: impl FnOnce() -> capnp::Result<control_extron::error::Reader<'a>>,
+) -> Result<()> {
+    if has_error {
+        Err(ControlError::from_wire(error()?)?)
+    } else {
+        Ok(())
+    }
+}
+
+fn wire_plane(plane: Plane) -> control_extron::Plane {
+    match plane {
+        Plane::All => control_extron::Plane::All,
+        Plane::Video => control_extron::Plane::Video,
+        Plane::Audio => control_extron::Plane::Audio,
+    }
+}
+
+/// Connects to the first of `servers` that accepts, looking up their names again for every
+/// attempt. Returns the connection with the server it is to.
+fn connect(
+    servers: &[String],
+    timeout: Duration,
+    retries: u32,
+) -> Result<(std::net::TcpStream, String)> {
+    let mut attempt = 0;
+    loop {
+        let mut last_error = None;
+        for server in servers {
+            let addrs = match endpoint::resolve(server) {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            for addr in &addrs {
+                match std::net::TcpStream::connect_timeout(addr, timeout) {
+                    Ok(stream) => {
+                        keepalive::enable(&stream).map_err(ControlError::Connection)?;
+                        return Ok((stream, server.clone()));
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+        if attempt >= retries {
+            return Err(ControlError::Connection(last_error.unwrap()));
+        }
+        attempt += 1;
+        std::thread::sleep(RETRY_DELAY);
+    }
+}
+
+/// Subscribes to the events of `client`, passing them on to `tx`. Returns `connection`, the
+/// task serving the connection of the client, and a token cancelled once the receiver of `tx`
+/// is gone.
+async fn subscribe_on(
+    client: AsyncClient,
+    connection: tokio::task::JoinHandle<()>,
+    tx: mpsc::Sender<Event>,
+) -> Result<(tokio::task::JoinHandle<()>, CancellationToken)> {
+    let done = CancellationToken::new();
+    let hook_done = done.clone();
+    let hook = move |event: Event| {
+        if tx.send(event).is_err() {
+            hook_done.cancel();
+        }
+    };
+    client.subscribe(hook).await?;
+    Ok((connection, done))
+}
+
+/// What the connections of a [`Client`] are set up with once they are made.
+#[derive(Clone, Default)]
+struct Setup {
+    /// API token to authenticate with.
+    token: Option<String>,
+    #[cfg(feature = "tls")]
+    tls: Option<crate::tls::ClientTls>,
+    /// Jump host to reach the servers through.
+    #[cfg(feature = "ssh")]
+    ssh: Option<crate::ssh::SshTunnel>,
+}
+
+impl Setup {
+    /// Connects to the first of `servers` that accepts, as [`connect`] does, and sets up the
+    /// connection. Returns the client and the task serving the connection, which ends when the
+    /// connection does.
+    async fn connect(
+        &self,
+        servers: &[String],
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<(AsyncClient, tokio::task::JoinHandle<()>)> {
+        #[cfg(feature = "ssh")]
+        if let Some(ssh) = &self.ssh {
+            let (stream, server) = ssh.connect(servers, timeout, retries).await?;
+            return self.start(stream, &server).await;
+        }
+        let (stream, server) = connect(servers, timeout, retries)?;
+        stream
+            .set_nonblocking(true)
+            .map_err(ControlError::Connection)?;
+        let stream = tokio::net::TcpStream::from_std(stream).map_err(ControlError::Connection)?;
+        stream.set_nodelay(true).map_err(ControlError::Connection)?;
+        self.start(stream, &server).await
+    }
+
+    /// Sets up the connection to `server` on `stream`.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    async fn start<S>(
+        &self,
+        stream: S,
+        server: &str,
+    ) -> Result<(AsyncClient, tokio::task::JoinHandle<()>)>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+    {
+        #[cfg(feature = "tls")]
+        let (client, connection) = match &self.tls {
+            Some(tls) => {
+                let stream = tls
+                    .connect(server, stream)
+                    .await
+                    .map_err(ControlError::Connection)?;
+                AsyncClient::start(stream)?
+            }
+            None => AsyncClient::start(stream)?,
+        };
+        #[cfg(not(feature = "tls"))]
+        let (client, connection) = AsyncClient::start(stream)?;
+        if let Some(token) = &self.token {
+            client.authenticate(token).await?;
+        }
+        Ok((client, connection))
+    }
+}
+
+struct Connection {
+    runtime: tokio::runtime::Runtime,
+    local: tokio::task::LocalSet,
+    client: AsyncClient,
+}
+
+/// Blocking client, for use outside of async code.
+///
+/// The connection is made on the first call and reused by the calls after it, until the server
+/// drops it.
+pub struct Client {
+    /// Servers in order of preference, resolved on every connection.
+    servers: Vec<String>,
+    /// Admin socket to connect to instead, see [`Client::with_socket`].
+    #[cfg(unix)]
+    socket: Option<std::path::PathBuf>,
+    connect_timeout: Duration,
+    retries: u32,
+    /// How every connection is set up.
+    setup: Setup,
+    connection: RefCell<Option<Connection>>,
 }
 
 impl Client {
-    pub fn new<A: net::ToSocketAddrs>(addr: &A) -> Result<Self> {
-        let addr = addr.to_socket_addrs()?.next().ok_or(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Host not found",
-        ))?;
-        Ok(Client { addr })
-    }
-
-    pub fn list(&self) -> Result<()> {
-        use tokio::runtime;
-        let rt = runtime::Runtime::new()?;
-        let stream = std::net::TcpStream::connect(self.addr)?;
-        let result = rt.block_on(do_list(stream));
+    /// Creates a client for a comma separated list of servers, which are tried in order until
+    /// one accepts the connection. A server is a host name or an IP address, with
+    /// [`endpoint::DEFAULT_PORT`] if no port is given; each address of a name is tried in
+    /// turn. Names are looked up on every connection, so a server that moved is found at its
+    /// new address.
+    pub fn with_servers(servers: &str) -> Result<Self> {
+        let mut checked = Vec::new();
+        for server in servers.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            endpoint::split(server).map_err(ControlError::Connection)?;
+            checked.push(server.to_string());
+        }
+        if checked.is_empty() {
+            return Err(ControlError::Connection(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                "Host not found",
+            )));
+        }
+        Ok(Client {
+            servers: checked,
+            #[cfg(unix)]
+            socket: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            setup: Setup::default(),
+            connection: RefCell::new(None),
+        })
+    }
+
+    /// Creates a client for the admin socket at `path` of a server on this machine, see
+    /// [`crate::server::ServerBuilder::admin_socket`]. It cannot receive events.
+    #[cfg(unix)]
+    pub fn with_socket<P: Into<std::path::PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        if !path.exists() {
+            return Err(ControlError::Connection(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No admin socket at {}", path.display()),
+            )));
+        }
+        Ok(Client {
+            servers: Vec::new(),
+            socket: Some(path),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            setup: Setup::default(),
+            connection: RefCell::new(None),
+        })
+    }
+
+    /// Authenticates every connection with the API token `token`, for servers that require
+    /// them.
+    pub fn token(mut self, token: &str) -> Self {
+        self.setup.token = Some(token.to_string());
+        self
+    }
+
+    /// Connects to the servers over TLS as `tls` says, see [`crate::tls`].
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: crate::tls::ClientTls) -> Self {
+        self.setup.tls = Some(tls);
+        self
+    }
+
+    /// Reaches the servers through the SSH jump host `ssh`, which looks up their names, see
+    /// [`crate::ssh`].
+    #[cfg(feature = "ssh")]
+    pub fn ssh(mut self, ssh: crate::ssh::SshTunnel) -> Self {
+        self.setup.ssh = Some(ssh);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Runtime for the calls of one connection.
+    fn runtime() -> Result<tokio::runtime::Runtime> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(ControlError::Connection)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let runtime = Self::runtime()?;
+        let local = tokio::task::LocalSet::new();
+        #[cfg(unix)]
+        if let Some(path) = &self.socket {
+            let client = local.block_on(&runtime, AsyncClient::connect_socket(path))?;
+            return Ok(Connection {
+                runtime,
+                local,
+                client,
+            });
+        }
+        let connected = self
+            .setup
+            .connect(&self.servers, self.connect_timeout, self.retries);
+        let (client, _) = local.block_on(&runtime, connected)?;
+        Ok(Connection {
+            runtime,
+            local,
+            client,
+        })
+    }
+
+    /// Runs `f` to completion on the shared connection, connecting first if needed.
+    fn call<F, Fut, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(AsyncClient) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut connection = self.connection.borrow_mut();
+        if connection.is_none() {
+            *connection = Some(self.open()?);
+        }
+        let c = connection.as_ref().unwrap();
+        let result = c.local.block_on(&c.runtime, f(c.client.clone()));
+
+        if let Err(ControlError::Rpc(e)) = &result {
+            if e.kind == capnp::ErrorKind::Disconnected {
+                *connection = None;
+            }
+        }
         result
     }
 
-    pub fn select(&self, device: &str, input: &str) -> Result<()> {
-        use tokio::runtime;
-        let rt = runtime::Runtime::new()?;
-        let stream = std::net::TcpStream::connect(self.addr)?;
+    pub fn list(&self) -> Result<Vec<ExtronDevice>> {
+        self.call(|client| async move { client.list().await })
+    }
+
+    pub fn list_scanning(&self) -> Result<(Vec<ExtronDevice>, bool)> {
+        self.call(|client| async move { client.list_scanning().await })
+    }
+
+    pub fn list_page(
+        &self,
+        filter: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(Vec<ExtronDevice>, u32)> {
+        self.call(|client| async move { client.list_page(filter, offset, limit).await })
+    }
+
+    pub fn select(&self, device: &str, input: &Input) -> Result<()> {
+        self.call(|client| async move { client.select(device, input).await })
+    }
+
+    pub fn select_plane(&self, device: &str, input: &Input, plane: Plane) -> Result<()> {
+        self.call(|client| async move { client.select_plane(device, input, plane).await })
+    }
+
+    pub fn set_volume(&self, device: &str, level: u8) -> Result<()> {
+        self.call(|client| async move { client.set_volume(device, level).await })
+    }
+
+    pub fn set_mute(&self, device: &str, mute: bool) -> Result<()> {
+        self.call(|client| async move { client.set_mute(device, mute).await })
+    }
+
+    pub fn status(&self, device: &str) -> Result<DeviceStatus> {
+        self.call(|client| async move { client.status(device).await })
+    }
+
+    pub fn toggle_input(&self, device: &str) -> Result<Input> {
+        self.call(|client| async move { client.toggle_input(device).await })
+    }
+
+    pub fn select_group(
+        &self,
+        devices: &[String],
+        input: &Input,
+    ) -> Result<Vec<(String, Result<()>)>> {
+        self.call(|client| async move { client.select_group(devices, input).await })
+    }
+
+    pub fn select_group_plane(
+        &self,
+        devices: &[String],
+        input: &Input,
+        plane: Plane,
+    ) -> Result<Vec<(String, Result<()>)>> {
+        self.call(|client| async move { client.select_group_plane(devices, input, plane).await })
+    }
+
+    pub fn send_raw(&self, device: &str, command: &[u8]) -> Result<Vec<u8>> {
+        self.call(|client| async move { client.send_raw(device, command).await })
+    }
+
+    pub fn upload_firmware<F>(
+        &self,
+        device: &str,
+        image: &[u8],
+        progress: F,
+    ) -> Result<FirmwareUpdate>
+    where
+        F: FnMut(usize, usize) + 'static,
+    {
+        self.call(|client| async move { client.upload_firmware(device, image, progress).await })
+    }
+
+    pub fn device_log(&self, device: &str) -> Result<Vec<DeviceMessage>> {
+        self.call(|client| async move { client.device_log(device).await })
+    }
+
+    pub fn history(&self, device: &str) -> Result<Vec<InputChange>> {
+        self.call(|client| async move { client.history(device).await })
+    }
+
+    pub fn undo(&self, device: &str) -> Result<String> {
+        self.call(|client| async move { client.undo(device).await })
+    }
+
+    pub fn set_display_power(&self, device: &str, on: bool) -> Result<()> {
+        self.call(|client| async move { client.set_display_power(device, on).await })
+    }
+
+    pub fn send_to_display(&self, device: &str, data: &[u8]) -> Result<Vec<u8>> {
+        self.call(|client| async move { client.send_to_display(device, data).await })
+    }
+
+    pub fn recall_preset(&self, device: &str, preset: u32) -> Result<()> {
+        self.call(|client| async move { client.recall_preset(device, preset).await })
+    }
 
-        rt.block_on(do_select(stream, device, input))
+    pub fn wall_layout(&self, device: &str) -> Result<WallLayout> {
+        self.call(|client| async move { client.wall_layout(device).await })
+    }
+
+    pub fn recall_layout(&self, device: &str, layout: u32) -> Result<WindowLayout> {
+        self.call(|client| async move { client.recall_layout(device, layout).await })
+    }
+
+    pub fn annotate(&self, device: &str, annotation: Annotation) -> Result<()> {
+        self.call(|client| async move { client.annotate(device, annotation).await })
+    }
+
+    pub fn set_pip_mode(&self, device: &str, mode: PipMode) -> Result<()> {
+        self.call(|client| async move { client.set_pip_mode(device, mode).await })
+    }
+
+    pub fn assign_source(&self, device: &str, window: u32, input: &Input) -> Result<()> {
+        self.call(|client| async move { client.assign_source(device, window, input).await })
+    }
+
+    pub fn sync(&self, device: &str) -> Result<SyncSettings> {
+        self.call(|client| async move { client.sync(device).await })
+    }
+
+    pub fn set_sync_format(&self, device: &str, format: SyncFormat) -> Result<()> {
+        self.call(|client| async move { client.set_sync_format(device, format).await })
+    }
+
+    pub fn set_genlock(&self, device: &str, genlock: Genlock) -> Result<()> {
+        self.call(|client| async move { client.set_genlock(device, genlock).await })
+    }
+
+    pub fn hold(&self, device: &str, duration: Option<Duration>) -> Result<()> {
+        self.call(|client| async move { client.hold(device, duration).await })
+    }
+
+    pub fn set_maintenance(&self, message: Option<&str>) -> Result<()> {
+        self.call(|client| async move { client.set_maintenance(message).await })
+    }
+
+    pub fn sessions(&self) -> Result<Vec<Session>> {
+        self.call(|client| async move { client.sessions().await })
+    }
+
+    pub fn disconnect(&self, peer: &str) -> Result<()> {
+        self.call(|client| async move { client.disconnect(peer).await })
+    }
+
+    pub fn device_info(&self, device: &str) -> Result<DeviceInfo> {
+        self.call(|client| async move { client.device_info(device).await })
+    }
+
+    pub fn device_health(&self, device: &str) -> Result<DeviceHealth> {
+        self.call(|client| async move { client.device_health(device).await })
+    }
+
+    pub fn create_token(&self, scope: Scope, devices: &[String]) -> Result<(Token, String)> {
+        self.call(|client| async move { client.create_token(scope, devices).await })
+    }
+
+    pub fn revoke_token(&self, id: &str) -> Result<()> {
+        self.call(|client| async move { client.revoke_token(id).await })
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<Token>> {
+        self.call(|client| async move { client.list_tokens().await })
+    }
+
+    pub fn stats(&self) -> Result<Vec<DeviceStats>> {
+        self.call(|client| async move { client.stats().await })
+    }
+
+    pub fn dump_state(&self) -> Result<String> {
+        self.call(|client| async move { client.dump_state().await })
     }
 
     pub fn rescan(&self) -> Result<()> {
-        use tokio::runtime;
-        let rt = runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-        let stream = std::net::TcpStream::connect(self.addr)?;
+        self.call(|client| async move { client.rescan().await })
+    }
 
-        rt.block_on(do_rescan(stream))
+    pub fn start_rescan(&self) -> Result<ScanTicket> {
+        self.call(|client| async move { client.start_rescan().await })
+    }
+
+    pub fn wait_scan(&self, ticket: &ScanTicket) -> Result<()> {
+        self.call(|client| async move { client.wait_scan(ticket).await })
+    }
+
+    pub fn scan_done(&self, ticket: &ScanTicket) -> Result<bool> {
+        self.call(|client| async move { client.scan_done(ticket).await })
     }
 
     pub fn stop(&self) -> Result<()> {
-        use tokio::runtime;
-        let rt = runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-        let stream = std::net::TcpStream::connect(self.addr)?;
-        rt.block_on(do_stop(stream))
+        self.call(|client| async move { client.stop().await })
+    }
+
+    /// Events from the server, see [`AsyncClient::subscribe`]. They are received on a
+    /// connection of their own by a background thread, which ends when the server closes the
+    /// connection or the first event after the receiver was dropped.
+    pub fn events(&self) -> Result<mpsc::Receiver<Event>> {
+        self.subscribe(false)
+    }
+
+    /// Events from the server as with [`Client::events`], for clients that run for as long as
+    /// the server does, such as panels. When the connection drops, e.g. while the server
+    /// restarts, it is made again, waiting twice as long after every failed attempt up to
+    /// [`RECONNECT_DELAY_MAX`], and the events are subscribed to again, starting with
+    /// [`Event::Reconnected`]. The thread only ends once the receiver is dropped.
+    pub fn watch(&self) -> Result<mpsc::Receiver<Event>> {
+        self.subscribe(true)
+    }
+
+    fn subscribe(&self, reconnect: bool) -> Result<mpsc::Receiver<Event>> {
+        #[cfg(unix)]
+        if self.socket.is_some() {
+            return Err(ControlError::Unsupported(
+                "Events on the admin socket".to_string(),
+            ));
+        }
+        let runtime = Self::runtime()?;
+        let servers = self.servers.clone();
+        let connect_timeout = self.connect_timeout;
+        let retries = self.retries;
+        let setup = self.setup.clone();
+        let (tx, rx) = mpsc::channel();
+        let (subscribed_tx, subscribed) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let local = tokio::task::LocalSet::new();
+            local.block_on(&runtime, async move {
+                let subscribed = match setup.connect(&servers, connect_timeout, retries).await {
+                    Ok((client, connection)) => subscribe_on(client, connection, tx.clone()).await,
+                    Err(e) => Err(e),
+                };
+                let mut subscription = match subscribed {
+                    Ok(subscription) => {
+                        let _ = subscribed_tx.send(Ok(()));
+                        subscription
+                    }
+                    Err(e) => {
+                        let _ = subscribed_tx.send(Err(e));
+                        return;
+                    }
+                };
+                loop {
+                    let (connection, done) = subscription;
+                    futures::future::select(connection, Box::pin(done.cancelled())).await;
+                    if done.is_cancelled() || !reconnect {
+                        return;
+                    }
+                    let mut delay = RETRY_DELAY;
+                    subscription = loop {
+                        // Waiting on the runtime, which the connection tasks share, and not
+                        // past the end of the subscription. A dropped receiver is only seen
+                        // when sending, at the latest on reconnecting.
+                        let sleep = Box::pin(tokio::time::sleep(delay));
+                        futures::future::select(sleep, Box::pin(done.cancelled())).await;
+                        if done.is_cancelled() {
+                            return;
+                        }
+                        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+                        let (client, connection) =
+                            match setup.connect(&servers, connect_timeout, 0).await {
+                                Ok(connected) => connected,
+                                Err(e) => {
+                                    debug!("Cannot connect to the server again: {}", e);
+                                    continue;
+                                }
+                            };
+                        // Before any event of the new subscription.
+                        if tx.send(Event::Reconnected).is_err() {
+                            return;
+                        }
+                        match subscribe_on(client, connection, tx.clone()).await {
+                            Ok(subscription) => break subscription,
+                            Err(e) => debug!("Cannot subscribe to events again: {}", e),
+                        }
+                    };
+                }
+            });
+        });
+
+        subscribed.recv().map_err(|_| ControlError::internal())??;
+        Ok(rx)
     }
 }
@@ -0,0 +1,528 @@
+use anyhow::{anyhow, Context, Result};
+use control_dsc::extron::Input;
+use control_dsc::network::NetworkDevice;
+use control_dsc::tokens::Scope;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const REMOTE_ENV: &str = "CONTROL_RS_REMOTE";
+/// API token for servers that require one, when `--token` is not given.
+pub const TOKEN_ENV: &str = "CONTROL_RS_TOKEN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Text,
+    Json,
+    /// A header line and a line per record, for spreadsheets.
+    Csv,
+    Yaml,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Other names the device goes by, e.g. the room it is in.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// Input numbers by label.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, u32>,
+    /// Where the device is, e.g. the rack and room, for `export`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Host name or IP address of a device on the network, which is reached over SIS on
+    /// Telnet rather than found on a USB serial port.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Password of the Telnet port of a device on the network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl DeviceConfig {
+    /// The device called `name` on the network, if it has an address.
+    pub fn network(&self, name: &str) -> Option<NetworkDevice> {
+        let device = NetworkDevice::new(name, self.address.as_deref()?);
+        Some(match &self.password {
+            Some(password) => device.password(password),
+            None => device,
+        })
+    }
+}
+
+/// The devices, scenes and groups of the configuration, which describe the rooms, as `config
+/// show` prints them and `config apply` takes them.
+///
+/// ```yaml
+/// devices:
+///   room3:
+///     aliases: [lobby]
+///     location: Lobby, rack 1
+///     inputs: {laptop: 2, document-camera: 3}
+/// scenes:
+///   presentation: [select 2, volume 60]
+/// groups:
+///   lecture-halls: [room3, room4]
+/// ```
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rooms {
+    pub devices: BTreeMap<String, DeviceConfig>,
+    pub scenes: BTreeMap<String, Vec<String>>,
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+impl Rooms {
+    /// Reads rooms from YAML, or from JSON, which is YAML as well.
+    pub fn parse(text: &str) -> Result<Self> {
+        serde_yaml::from_str(text).context("Invalid rooms")
+    }
+}
+
+/// Command the server runs on an event, with the details in `CONTROL_RS_*` environment
+/// variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// `input_selected`, `device_offline` and so on.
+    pub event: String,
+    /// Run by `/bin/sh -c`, or `cmd /C` on Windows.
+    pub command: String,
+}
+
+/// Input selected from `from` until `until`, as `HH:MM` in local time.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeriodConfig {
+    pub from: String,
+    pub until: String,
+    /// Input number or label, as a string.
+    pub input: String,
+}
+
+/// Inputs the server selects on a device by time of day.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub device: String,
+    pub periods: Vec<PeriodConfig>,
+    /// Input outside the periods.
+    pub otherwise: Option<String>,
+}
+
+/// Key of a hotkey pad on the server host.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HotkeyConfig {
+    /// Position on a Stream Deck, counted from 0, or HID usage code on other pads.
+    pub key: u8,
+    /// Device the commands go to, instead of the default device.
+    pub device: Option<String>,
+    /// Commands in `run` script syntax.
+    pub commands: Vec<String>,
+    pub image: Option<PathBuf>,
+    /// Shown while the inputs the key selects are selected.
+    pub active_image: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotkeysConfig {
+    /// `streamdeck`, the default, or `vendor:product` in hex for another pad.
+    pub pad: Option<String>,
+    pub keys: Vec<HotkeyConfig>,
+}
+
+/// Limits beyond which the server flags a device. Unset limits are not checked.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Degrees Celsius.
+    pub max_temperature: Option<u32>,
+    /// Error codes a device may send on its own within `error_window_minutes`, 10 by default.
+    pub max_errors: Option<usize>,
+    pub error_window_minutes: Option<u64>,
+    pub max_offline_minutes: Option<u64>,
+    /// How long the selected input may be without a signal before it is reported.
+    pub signal_loss_seconds: Option<u64>,
+}
+
+/// How the connection to an SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum SmtpTls {
+    /// Upgraded with STARTTLS, on port 587 unless set otherwise.
+    #[default]
+    #[serde(rename = "starttls")]
+    StartTls,
+    /// TLS from the start, on port 465 unless set otherwise.
+    #[serde(rename = "tls")]
+    Tls,
+    /// Not at all, for a relay on the local network, on port 25 unless set otherwise.
+    #[serde(rename = "none")]
+    Off,
+}
+
+/// Mail the server sends alerts by.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    /// Host name of the SMTP server.
+    pub server: String,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// Login on the SMTP server, if it asks for one.
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Sender, e.g. `AV rack <av@example.org>`.
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Chats and mail boxes the server posts to when a device goes offline, keeps failing or runs
+/// hot.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Incoming webhook of a Slack channel.
+    pub slack_webhook: Option<String>,
+    /// Token of the Telegram bot posting to `telegram_chat`.
+    pub telegram_token: Option<String>,
+    pub telegram_chat: Option<String>,
+    pub email: Option<EmailConfig>,
+    /// Before the same alert of a device is posted again, 30 by default.
+    pub repeat_minutes: Option<u64>,
+    /// Messages posted at most within an hour, 20 by default.
+    pub max_per_hour: Option<usize>,
+}
+
+impl NotifyConfig {
+    /// Whether there is nowhere to post to.
+    pub fn is_empty(&self) -> bool {
+        self.slack_webhook.is_none() && self.telegram_token.is_none() && self.email.is_none()
+    }
+}
+
+/// How clients connect to servers over TLS.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of the CA that signed the certificates of the servers.
+    pub ca: PathBuf,
+    /// PEM files of the certificate of this machine and its key, for servers requiring client
+    /// certificates.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Name the certificates of the servers are for, instead of the host of the server address.
+    pub server_name: Option<String>,
+}
+
+/// What a client may do on a server requiring client certificates, by a name in its
+/// certificate.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// Common name or subject alternative name.
+    pub identity: String,
+    pub scope: Scope,
+    /// Glob patterns of the names of the devices, all devices if there are none.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+/// Client defaults read from `~/.config/control-rs/config.toml`.
+///
+/// ```toml
+/// remote = "av-gateway.example.org:14000"
+/// device = "DSC 301 HD"
+/// format = "json"
+/// probe_timeout_seconds = 5
+/// adaptive_deadlines = true
+///
+/// [scenes]
+/// presentation = ["select 2", "volume 60"]
+///
+/// [groups]
+/// lecture-halls = ["DSC 301 HD", "DSC 301 HD#2"]
+///
+/// [devices."DSC 301 HD"]
+/// aliases = ["lobby"]
+/// location = "Lobby, rack 1"
+///
+/// [devices."DSC 301 HD".inputs]
+/// laptop = 2
+///
+/// [devices."IN1808 hall"]
+/// address = "10.0.20.5"
+///
+/// [[schedules]]
+/// device = "DSC 301 HD"
+/// periods = [{ from = "09:00", until = "18:00", input = "signage" }]
+/// otherwise = "laptop"
+///
+/// [[hooks]]
+/// event = "device_offline"
+/// command = "logger \"$CONTROL_RS_DEVICE went offline\""
+///
+/// [health]
+/// max_temperature = 60
+/// max_offline_minutes = 5
+///
+/// [notify]
+/// slack_webhook = "https://hooks.slack.com/services/T000/B000/XXXX"
+///
+/// [[hotkeys.keys]]
+/// key = 0
+/// commands = ["select laptop"]
+/// image = "/usr/local/share/control-rs/laptop.png"
+///
+/// [tls]
+/// ca = "/etc/control-rs/site-ca.pem"
+/// cert = "/etc/control-rs/panel-hall.pem"
+/// key = "/etc/control-rs/panel-hall.key"
+///
+/// [[certificates]]
+/// identity = "panel-hall.av.example.org"
+/// scope = "control"
+/// devices = ["hall-*"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub remote: Option<String>,
+    pub device: Option<String>,
+    pub format: Option<OutputFormat>,
+    /// Named command sequences, in `run` script syntax.
+    pub scenes: HashMap<String, Vec<String>>,
+    /// Device names by group name, for `--group`.
+    pub groups: HashMap<String, Vec<String>>,
+    pub devices: HashMap<String, DeviceConfig>,
+    /// Inputs the server selects by time of day.
+    pub schedules: Vec<ScheduleConfig>,
+    /// Commands the server runs on events.
+    pub hooks: Vec<HookConfig>,
+    /// Limits the server checks the devices against.
+    pub health: HealthConfig,
+    /// Chats and mail boxes the server posts alerts to.
+    pub notify: NotifyConfig,
+    /// Keys the server handles.
+    pub hotkeys: HotkeysConfig,
+    /// Connecting to servers over TLS.
+    pub tls: Option<TlsConfig>,
+    /// What clients may do by their certificates, on a server requiring them.
+    pub certificates: Vec<CertificateConfig>,
+    /// How long a serial port may take to answer when scanning, 2 by default.
+    pub probe_timeout_seconds: Option<f64>,
+    /// Wait for answers as long as each device was seen to take, rather than a fixed time.
+    pub adaptive_deadlines: bool,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("control-rs").join("config.toml"))
+    }
+
+    /// Reads the configuration file if there is one and applies the environment overrides.
+    pub fn load() -> Result<Self> {
+        let mut config = match Self::path() {
+            Some(path) if path.exists() => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("Invalid configuration in {}", path.display()))?
+            }
+            _ => Config::default(),
+        };
+
+        if let Ok(remote) = std::env::var(REMOTE_ENV) {
+            if !remote.is_empty() {
+                config.remote = Some(remote);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The devices, scenes and groups.
+    pub fn rooms(&self) -> Rooms {
+        Rooms {
+            devices: self.devices.clone().into_iter().collect(),
+            scenes: self.scenes.clone().into_iter().collect(),
+            groups: self.groups.clone().into_iter().collect(),
+        }
+    }
+
+    /// Writes the devices, scenes and groups of `rooms` to the configuration file, in place of
+    /// those of the same names, and returns what changed. Applying the same rooms again
+    /// changes nothing, and the file is only written when something changed; it is then
+    /// written anew, without its comments.
+    pub fn apply(rooms: &Rooms) -> Result<Vec<String>> {
+        let (path, mut document) = Self::document()?;
+
+        let mut changes = Vec::new();
+        for (section, kind, entries) in [
+            ("devices", "device", toml::Value::try_from(&rooms.devices)?),
+            ("scenes", "scene", toml::Value::try_from(&rooms.scenes)?),
+            ("groups", "group", toml::Value::try_from(&rooms.groups)?),
+        ] {
+            let entries = match entries {
+                toml::Value::Table(entries) => entries,
+                _ => unreachable!("maps become tables"),
+            };
+            let table = document
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("{} in {} is not a table", section, path.display()))?;
+            for (name, value) in entries {
+                if table.get(&name) != Some(&value) {
+                    changes.push(format!("{} {}", kind, name));
+                    table.insert(name, value);
+                }
+            }
+        }
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+        Self::write(&path, document, "The rooms do not fit the configuration")?;
+        Ok(changes)
+    }
+
+    /// Writes `remote` as the server to connect to into the configuration file, which is
+    /// then written anew, without its comments.
+    #[cfg(feature = "mdns")]
+    pub fn set_remote(remote: &str) -> Result<()> {
+        let (path, mut document) = Self::document()?;
+        document.insert(
+            "remote".to_string(),
+            toml::Value::String(remote.to_string()),
+        );
+        Self::write(&path, document, "The server does not fit the configuration")
+    }
+
+    /// Writes the device called `name` at `address` on the network, with `password` if given,
+    /// into the configuration file, keeping the rest of what it has for the device. The file
+    /// is then written anew, without its comments.
+    pub fn add_device(name: &str, address: &str, password: Option<&str>) -> Result<()> {
+        let (path, mut document) = Self::document()?;
+        let devices = document
+            .entry("devices")
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("devices in {} is not a table", path.display()))?;
+        let device = devices
+            .entry(name)
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("device {} in {} is not a table", name, path.display()))?;
+        device.insert(
+            "address".to_string(),
+            toml::Value::String(address.to_string()),
+        );
+        if let Some(password) = password {
+            device.insert(
+                "password".to_string(),
+                toml::Value::String(password.to_string()),
+            );
+        }
+        Self::write(&path, document, "The device does not fit the configuration")
+    }
+
+    /// The path of the configuration file and what it holds, nothing if there is none yet.
+    fn document() -> Result<(PathBuf, toml::value::Table)> {
+        let path = Self::path().ok_or_else(|| anyhow!("No configuration directory"))?;
+        let text = match path.exists() {
+            true => std::fs::read_to_string(&path)
+                .with_context(|| format!("Cannot read {}", path.display()))?,
+            false => String::new(),
+        };
+        let document = toml::from_str(&text)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        Ok((path, document))
+    }
+
+    /// Writes `document` to `path` once it is checked to be a configuration, failing with
+    /// `invalid` if it is not. A configuration holding a password is only readable by the
+    /// owner on Unix.
+    fn write(path: &Path, document: toml::value::Table, invalid: &'static str) -> Result<()> {
+        let text = toml::to_string(&toml::Value::Table(document))?;
+        let config = toml::from_str::<Config>(&text).context(invalid)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Cannot create {}", dir.display()))?;
+        }
+        // Renamed into place, so that the file is never half written.
+        let temporary = path.with_extension("toml.new");
+        let written = match config.holds_password() {
+            true => write_private(&temporary, text.as_bytes()),
+            false => std::fs::write(&temporary, text),
+        };
+        written
+            .and_then(|()| std::fs::rename(&temporary, path))
+            .with_context(|| format!("Cannot write {}", path.display()))
+    }
+
+    /// Whether a device or the mail server has a password here.
+    fn holds_password(&self) -> bool {
+        self.devices
+            .values()
+            .any(|device| device.password.is_some())
+            || self
+                .notify
+                .email
+                .as_ref()
+                .is_some_and(|email| email.password.is_some())
+    }
+
+    /// Devices of the group `name`.
+    pub fn group(&self, name: &str) -> Result<&[String]> {
+        self.groups
+            .get(name)
+            .map(Vec::as_slice)
+            .ok_or_else(|| anyhow::anyhow!("Group '{}' not found", name))
+    }
+
+    /// Translates an input label configured for `device` into its number. Anything else is
+    /// passed on unchanged, for the device to resolve against its own input names.
+    pub fn resolve_input(&self, device: &str, input: &Input) -> Input {
+        let label = match input {
+            Input::Name(label) => label,
+            Input::Number(_) => return input.clone(),
+        };
+        self.devices
+            .get(device)
+            .and_then(|d| d.inputs.iter().find(|(l, _)| l.eq_ignore_ascii_case(label)))
+            .map_or(input.clone(), |(_, n)| Input::Number(*n))
+    }
+}
+
+/// Writes `data` to a new file at `path`, readable only by the owner on Unix. A file left
+/// there before is removed first, as it keeps its permissions when opened.
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
@@ -0,0 +1,94 @@
+use anyhow::{bail, Result};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Typed on a line of its own, ends the console.
+pub const ESCAPE: &str = "~.";
+
+/// Turns the escapes `\e`, `\r`, `\n`, `\xHH` and `\\` in `line` into the bytes they stand
+/// for, as SIS commands starting with Escape or ending in a carriage return cannot be typed.
+pub fn unescape(line: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('e') => bytes.push(0x1b),
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => bytes.push(b),
+                    _ => bail!("'\\x{}' is not a byte, expected two hex digits", hex),
+                }
+            }
+            Some(c) => bail!("Unknown escape '\\{}'", c),
+            None => bail!("Escape '\\' at the end of the line"),
+        }
+    }
+    Ok(bytes)
+}
+
+/// `reply` as text, with the bytes that cannot be shown written as escapes.
+pub fn escape(reply: &[u8]) -> String {
+    let mut text = String::new();
+    for &b in reply {
+        match b {
+            b'\r' => {}
+            b'\n' => text.push('\n'),
+            0x1b => text.push_str("\\e"),
+            b'\\' => text.push_str("\\\\"),
+            b if b.is_ascii_graphic() || b == b' ' => text.push(b as char),
+            b => text.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    text
+}
+
+/// Reads commands from standard input and prints what `device` answers, until the escape,
+/// end of input or a failure to read. Failed commands are reported and the console goes on.
+///
+/// A terminal echoes what is typed; otherwise the commands are echoed after the prompt, so
+/// that the output reads the same.
+pub fn run<F>(device: &str, send: F) -> Result<()>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    let stdin = io::stdin();
+    let echo = !stdin.is_terminal();
+    eprintln!(
+        "Connected to {}, type {} on a line of its own to exit",
+        device, ESCAPE
+    );
+    let mut line = String::new();
+    loop {
+        print!("{}> ", device);
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let command = line.trim_end_matches(&['\r', '\n'][..]);
+        if echo {
+            println!("{}", command);
+        }
+        if command == ESCAPE {
+            return Ok(());
+        }
+        if command.is_empty() {
+            continue;
+        }
+        let result = unescape(command).and_then(|command| send(&command));
+        match result {
+            Ok(reply) if reply.is_empty() => println!("(no reply)"),
+            Ok(reply) => println!("{}", escape(&reply).trim_end_matches('\n')),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! D-Bus service `be.psychaos.ControlRs`, for desktop applets and automation. It serves the
+//! devices through the same command loop as the network interfaces and signals their changes.
+
+use crate::error::ControlError;
+use crate::extron::Input;
+use crate::server::{call, Request, ServerEvent, ServerRequest};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use zbus::{interface, SignalContext};
+
+/// Well-known name of the service.
+pub const NAME: &str = "be.psychaos.ControlRs";
+
+const PATH: &str = "/be/psychaos/ControlRs";
+
+/// Who the history names for the changes made through here.
+const CLIENT: &str = "D-Bus";
+
+/// Bus to offer the service on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// The bus of the desktop session, for a scaler on the USB port of a workstation.
+    Session,
+    /// The system bus, for a server started at boot.
+    System,
+}
+
+/// Failures as D-Bus errors, e.g. `be.psychaos.ControlRs1.Error.DeviceNotFound`.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "be.psychaos.ControlRs1.Error")]
+enum Error {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    DeviceNotFound(String),
+    InvalidInput(String),
+    Device(String),
+    Unavailable(String),
+}
+
+impl From<ControlError> for Error {
+    fn from(e: ControlError) -> Self {
+        let message = e.to_string();
+        match e {
+            ControlError::DeviceNotFound(_) => Error::DeviceNotFound(message),
+            ControlError::InvalidInput { .. } | ControlError::MalformedInput(_) => {
+                Error::InvalidInput(message)
+            }
+            ControlError::SerialIo(_)
+            | ControlError::UnexpectedReply(_)
+            | ControlError::Timeout(_)
+            | ControlError::Unsupported(_)
+            | ControlError::Busy(_) => Error::Device(message),
+            ControlError::Cancelled
+            | ControlError::NotAllowed(_)
+            | ControlError::Unauthorized(_)
+            | ControlError::Maintenance(_)
+            | ControlError::Connection(_)
+            | ControlError::OldServer { .. }
+            | ControlError::Rpc(_) => Error::Unavailable(message),
+        }
+    }
+}
+
+struct ControlRs {
+    tx_channel: mpsc::Sender<Request>,
+}
+
+#[interface(name = "be.psychaos.ControlRs1")]
+impl ControlRs {
+    /// Name and path of every device.
+    async fn list_devices(&self) -> Result<Vec<(String, String)>, Error> {
+        let (devices, _) =
+            call(self.tx_channel.clone(), CLIENT, ServerRequest::ListDevices).await?;
+        Ok(devices
+            .into_iter()
+            .map(|device| (device.name, device.device_path))
+            .collect())
+    }
+
+    /// Selects `input`, an input number or a name stored in the device.
+    async fn select_input(&self, device: String, input: String) -> Result<(), Error> {
+        let input: Input = input.parse()?;
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Select {
+                name: device,
+                input,
+                reply,
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_volume(&self, device: String, level: u8) -> Result<(), Error> {
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Volume {
+                name: device,
+                level,
+                reply,
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_mute(&self, device: String, mute: bool) -> Result<(), Error> {
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Mute {
+                name: device,
+                mute,
+                reply,
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Selected input, whether the device has audio output, volume and mute. The last two are
+    /// 0 and false without audio output.
+    async fn get_status(&self, device: String) -> Result<(u32, bool, u8, bool), Error> {
+        let status = call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Status {
+                name: device,
+                reply,
+            }
+        })
+        .await?;
+        Ok((
+            status.input,
+            status.volume.is_some(),
+            status.volume.unwrap_or(0),
+            status.mute.unwrap_or(false),
+        ))
+    }
+
+    /// The devices were scanned again, so `ListDevices` may return something else.
+    #[zbus(signal)]
+    async fn devices_changed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn input_selected(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        input: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn volume_changed(ctxt: &SignalContext<'_>, device: &str, level: u8) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn mute_changed(ctxt: &SignalContext<'_>, device: &str, mute: bool) -> zbus::Result<()>;
+}
+
+/// Offers the service on `bus`, serving its calls through `tx_channel`.
+pub(crate) async fn connect(
+    bus: Bus,
+    tx_channel: mpsc::Sender<Request>,
+) -> zbus::Result<zbus::Connection> {
+    let builder = match bus {
+        Bus::Session => zbus::connection::Builder::session()?,
+        Bus::System => zbus::connection::Builder::system()?,
+    };
+    builder
+        .name(NAME)?
+        .serve_at(PATH, ControlRs { tx_channel })?
+        .build()
+        .await
+}
+
+/// Signals `events` on `connection` until `cancel` fires.
+pub(crate) async fn signal_events(
+    connection: zbus::Connection,
+    mut events: broadcast::Receiver<ServerEvent>,
+    cancel: CancellationToken,
+) -> zbus::Result<()> {
+    let interface = connection
+        .object_server()
+        .interface::<_, ControlRs>(PATH)
+        .await?;
+    let ctxt = interface.signal_context();
+    loop {
+        let event = tokio::select! {
+            _ = cancel.cancelled() => break,
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        let sent = match event {
+            ServerEvent::DevicesScanned(_) => ControlRs::devices_changed(ctxt).await,
+            ServerEvent::InputSelected { device, input } => {
+                ControlRs::input_selected(ctxt, &device, &input.to_string()).await
+            }
+            ServerEvent::VolumeChanged { device, level } => {
+                ControlRs::volume_changed(ctxt, &device, level).await
+            }
+            ServerEvent::MuteChanged { device, mute } => {
+                ControlRs::mute_changed(ctxt, &device, mute).await
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = sent {
+            info!("Cannot send D-Bus signal: {}", e);
+        }
+    }
+    Ok(())
+}
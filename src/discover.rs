@@ -0,0 +1,82 @@
+//! Finding the servers on the local network by multicast DNS, for clients that are not told
+//! where the server is, e.g. a laptop brought into a room.
+//!
+//! Servers are found when they are advertised as [`SERVICE_TYPE`], e.g. by Avahi with the
+//! service file in `contrib/avahi`.
+
+use crate::error::{ControlError, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::Serialize;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The DNS-SD service type servers are advertised as.
+pub const SERVICE_TYPE: &str = "_control-dsc._tcp.local.";
+
+/// A server found on the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Advertised {
+    /// The name it is advertised under, e.g. `AV rack hall`.
+    pub name: String,
+    /// The host it runs on, ending in `.local.`.
+    pub host: String,
+    /// Its addresses, IPv4 first.
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+impl Advertised {
+    /// The address to connect to, as [`crate::client::Client::with_servers`] takes them.
+    pub fn server(&self) -> String {
+        match self.addresses[0] {
+            IpAddr::V4(ip) => format!("{}:{}", ip, self.port),
+            IpAddr::V6(ip) => format!("[{}]:{}", ip, self.port),
+        }
+    }
+}
+
+fn failed(e: mdns_sd::Error) -> ControlError {
+    ControlError::Connection(io::Error::new(io::ErrorKind::Other, format!("mDNS: {}", e)))
+}
+
+/// Listens for the servers on the network for `wait`, or only until the first one is found
+/// with `first`. Returns them by name.
+pub fn discover(wait: Duration, first: bool) -> Result<Vec<Advertised>> {
+    let daemon = ServiceDaemon::new().map_err(failed)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(failed)?;
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<Advertised> = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        let info = match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let mut addresses: Vec<_> = info.get_addresses().iter().copied().collect();
+        if addresses.is_empty() {
+            continue;
+        }
+        addresses.sort();
+        let fullname = info.get_fullname();
+        let name = fullname
+            .strip_suffix(SERVICE_TYPE)
+            .map_or(fullname, |name| name.trim_end_matches('.'));
+        let advertised = Advertised {
+            name: name.to_string(),
+            host: info.get_hostname().to_string(),
+            addresses,
+            port: info.get_port(),
+        };
+        // Announcements are repeated, and may come with more addresses.
+        found.retain(|known| known.name != advertised.name);
+        found.push(advertised);
+        if first {
+            break;
+        }
+    }
+    // Only fails once the daemon is gone, which is what is wanted.
+    let _ = daemon.shutdown();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
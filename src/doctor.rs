@@ -0,0 +1,262 @@
+//! Checks of what talking to the devices needs from this machine, each saying what to do
+//! when it fails.
+
+use anyhow::{bail, Result};
+use control_dsc::error::ControlError;
+use control_dsc::extron;
+use control_dsc::lock;
+
+/// Directories udev reads rules from.
+#[cfg(target_os = "linux")]
+const UDEV_RULE_DIRS: &[&str] = &[
+    "/etc/udev/rules.d",
+    "/lib/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+];
+
+/// What a check found.
+pub struct Finding {
+    check: &'static str,
+    detail: String,
+    /// What to do about it, `None` when all is well.
+    fix: Option<String>,
+    /// Whether it keeps the devices from working, rather than maybe getting in the way.
+    failed: bool,
+}
+
+impl Finding {
+    fn ok(check: &'static str, detail: String) -> Self {
+        Finding {
+            check,
+            detail,
+            fix: None,
+            failed: false,
+        }
+    }
+
+    fn warn(check: &'static str, detail: String, fix: String) -> Self {
+        Finding {
+            check,
+            detail,
+            fix: Some(fix),
+            failed: false,
+        }
+    }
+
+    fn fail(check: &'static str, detail: String, fix: String) -> Self {
+        Finding {
+            check,
+            detail,
+            fix: Some(fix),
+            failed: true,
+        }
+    }
+}
+
+/// Checks the devices on the USB serial ports of this machine.
+pub fn local() -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let ports = match extron::extron_ports() {
+        Ok(ports) => ports,
+        Err(e) => {
+            findings.push(Finding::fail(
+                "devices",
+                format!("Cannot list the serial ports: {}", e),
+                "Use a build with the serial feature, on a system with USB serial ports"
+                    .to_string(),
+            ));
+            return findings;
+        }
+    };
+    if ports.is_empty() {
+        findings.push(Finding::fail(
+            "devices",
+            "No USB serial port with an Extron device (vendor id 1ce2)".to_string(),
+            "Check that the device is powered and its USB cable is plugged in; lsusb should \
+             list it"
+                .to_string(),
+        ));
+    } else {
+        findings.push(Finding::ok(
+            "devices",
+            format!("Extron devices on {}", ports.join(", ")),
+        ));
+    }
+
+    for port in &ports {
+        match lock::lock(port, port, None) {
+            Err(e @ ControlError::Busy(_)) => findings.push(Finding::warn(
+                "contention",
+                e.to_string(),
+                format!(
+                    "Another control-dsc process uses {}; stop it, or reach the device \
+                     through the server with -r",
+                    port
+                ),
+            )),
+            // A port that cannot be opened for its lock is what the access check explains.
+            _lock => findings.push(access(port)),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        findings.extend(modem_manager());
+        findings.push(udev_rule());
+    }
+    findings
+}
+
+/// Whether `port` can be opened for reading and writing.
+#[cfg(unix)]
+fn access(port: &str) -> Finding {
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(port)
+    {
+        Ok(_) => Finding::ok("permissions", format!("{} can be opened", port)),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Finding::fail(
+            "permissions",
+            format!("Cannot open {}: {}", port, e),
+            group_fix(port),
+        ),
+        Err(e) => Finding::fail(
+            "permissions",
+            format!("Cannot open {}: {}", port, e),
+            "Unplug the device and plug it in again".to_string(),
+        ),
+    }
+}
+
+/// Serial ports elsewhere are not files to open.
+#[cfg(not(unix))]
+fn access(port: &str) -> Finding {
+    Finding::ok(
+        "permissions",
+        format!("{} not checked on this system", port),
+    )
+}
+
+/// How to get access to `port` through the group owning it.
+#[cfg(unix)]
+fn group_fix(port: &str) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let gid = match std::fs::metadata(port) {
+        Ok(metadata) => metadata.gid(),
+        Err(e) => return format!("Cannot look at {}: {}", port, e),
+    };
+    let groups = std::fs::read_to_string("/etc/group").unwrap_or_default();
+    // name:password:gid:members
+    let entry = groups
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() == 4 && fields[2] == gid.to_string());
+    let (group, members) = match &entry {
+        Some(fields) => (fields[0].to_string(), fields[3]),
+        None => (gid.to_string(), ""),
+    };
+    let user = std::env::var("USER").unwrap_or_default();
+    let session = std::process::Command::new("id")
+        .arg("-Gn")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        .unwrap_or_default();
+
+    if session.split_whitespace().any(|g| g == group) {
+        format!(
+            "Group {} cannot write to {} either; install the udev rule, see below",
+            group, port
+        )
+    } else if members.split(',').any(|member| member == user) {
+        format!(
+            "You were added to group {} after logging in; log out and in again",
+            group
+        )
+    } else {
+        format!(
+            "sudo usermod -aG {} $USER, then log out and in again",
+            group
+        )
+    }
+}
+
+/// ModemManager probes new serial ports for modems, sending AT commands to the devices and
+/// keeping their ports busy.
+#[cfg(target_os = "linux")]
+fn modem_manager() -> Option<Finding> {
+    let running = std::fs::read_dir("/proc").ok()?.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path().join("comm"))
+            .is_ok_and(|comm| comm.trim() == "ModemManager")
+    });
+    if !running {
+        return None;
+    }
+    Some(Finding::warn(
+        "contention",
+        "ModemManager is running and may probe the devices".to_string(),
+        "Install the udev rule, see below, which tells it to leave them alone".to_string(),
+    ))
+}
+
+/// Whether a udev rule for Extron devices is installed.
+#[cfg(target_os = "linux")]
+fn udev_rule() -> Finding {
+    for dir in UDEV_RULE_DIRS {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "rules")
+                && std::fs::read_to_string(&path).is_ok_and(|rules| rules.contains("1ce2"))
+            {
+                return Finding::ok("udev", format!("Rule in {}", path.display()));
+            }
+        }
+    }
+    Finding::warn(
+        "udev",
+        "No udev rule for Extron devices".to_string(),
+        format!(
+            "control-dsc gen-udev | sudo tee {} && sudo udevadm control --reload && sudo \
+             udevadm trigger --subsystem-match=tty",
+            crate::udev::RULES_PATH
+        ),
+    )
+}
+
+/// Checks the server at `addr`, given the number of devices it listed or why it did not.
+pub fn server(addr: &str, devices: Result<usize>) -> Finding {
+    match devices {
+        Ok(n) => Finding::ok("server", format!("{} answers with {} devices", addr, n)),
+        Err(e) => Finding::fail(
+            "server",
+            format!("{}: {:#}", addr, e),
+            "Check that the server runs there, e.g. with systemctl status control-dsc, and \
+             that no firewall blocks the port"
+                .to_string(),
+        ),
+    }
+}
+
+/// Prints `findings`, failing if any check failed.
+pub fn report(findings: &[Finding]) -> Result<()> {
+    for finding in findings {
+        let status = match (finding.failed, &finding.fix) {
+            (true, _) => "FAIL",
+            (false, Some(_)) => "warn",
+            (false, None) => "ok",
+        };
+        println!("{:<6}{:<13}{}", status, finding.check, finding.detail);
+        if let Some(fix) = &finding.fix {
+            println!("{:<19}fix: {}", "", fix);
+        }
+    }
+    let failed = findings.iter().filter(|finding| finding.failed).count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, findings.len());
+    }
+    Ok(())
+}
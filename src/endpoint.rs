@@ -0,0 +1,68 @@
+//! Server addresses as people write them: a host name or an IP address with an optional port,
+//! IPv6 addresses in brackets when a port follows.
+
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// Port of the server when an address has none.
+pub const DEFAULT_PORT: u16 = 14000;
+
+/// The host and the port of `server`, e.g. `av1`, `av1:14001`, `10.0.0.7`, `::1` or
+/// `[::1]:14001`.
+pub fn split(server: &str) -> std::io::Result<(&str, u16)> {
+    split_or(server, DEFAULT_PORT)
+}
+
+/// The host and the port of `server` like [`split`], with `default_port` when it has none,
+/// e.g. for a device rather than a server.
+pub fn split_or(server: &str, default_port: u16) -> std::io::Result<(&str, u16)> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' is not a host with an optional port", server),
+        )
+    };
+    let port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+
+    let (host, port) = if let Some(rest) = server.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, default_port),
+            _ => (host, port(rest.strip_prefix(':').ok_or_else(invalid)?)?),
+        }
+    } else if server.matches(':').count() > 1 {
+        // An IPv6 address without brackets cannot have a port.
+        (server, default_port)
+    } else {
+        match server.split_once(':') {
+            Some((host, p)) => (host, port(p)?),
+            None => (server, default_port),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+/// Every address `server` stands for, in the order the resolver gives them, so that a name with
+/// several is tried at each in turn.
+pub fn resolve(server: &str) -> std::io::Result<Vec<SocketAddr>> {
+    resolve_or(server, DEFAULT_PORT)
+}
+
+/// Every address `server` stands for like [`resolve`], at `default_port` when it has none.
+pub fn resolve_or(server: &str, default_port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    let (host, port) = split_or(server, default_port)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<_> = (host, port).to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("{} has no address", host),
+        ));
+    }
+    Ok(addrs)
+}
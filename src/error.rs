@@ -0,0 +1,317 @@
+use crate::extron_capnp::control_extron::error::{self, Kind};
+use thiserror::Error;
+
+/// OS error codes for a serial port that went away: `EIO`, `ENXIO` and `ENODEV`.
+#[cfg(unix)]
+const GONE: [i32; 3] = [5, 6, 19];
+
+/// OS error codes for a serial port that went away: `ERROR_BAD_COMMAND`,
+/// `ERROR_GEN_FAILURE` and `ERROR_DEVICE_NOT_CONNECTED`.
+#[cfg(windows)]
+const GONE: [i32; 3] = [22, 31, 1167];
+
+#[cfg(not(any(unix, windows)))]
+const GONE: [i32; 0] = [];
+
+/// Errors from talking to a device, either directly or through a server.
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("Device {0} not found")]
+    DeviceNotFound(String),
+
+    #[error("Serial port error: {0}")]
+    SerialIo(std::io::Error),
+
+    /// `device` is empty when the input was rejected before reaching one. `code` is the SIS
+    /// error code, if the device reported one, `inputs` the number of inputs, if known.
+    #[error("{}", invalid_input(.device, .input, .code, .inputs))]
+    InvalidInput {
+        device: String,
+        input: String,
+        code: Option<u8>,
+        inputs: Option<u32>,
+    },
+
+    #[error("'{0}' is not an input number or name")]
+    MalformedInput(String),
+
+    #[error("Unexpected answer {0}")]
+    UnexpectedReply(String),
+
+    #[error("Timed out waiting for {0}")]
+    Timeout(String),
+
+    #[error("{0} not supported by this device")]
+    Unsupported(String),
+
+    /// Another process on the machine is using the device.
+    #[error("Device {0} is busy")]
+    Busy(String),
+
+    /// An administrative call, e.g. a firmware upload, from a connection the server does not
+    /// serve those to. Carries the method.
+    #[error("{0} is only allowed from the machine the server runs on")]
+    NotAllowed(String),
+
+    /// A call changing a device while the server is in maintenance. Carries the message set
+    /// for it, if any.
+    #[error("{}", maintenance(.0))]
+    Maintenance(String),
+
+    /// A call the API token of the connection does not allow, or any call before
+    /// authenticating on a server that requires tokens. Carries what is missing.
+    #[error("Not authorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Cancelled, the server is stopping")]
+    Cancelled,
+
+    #[error("Cannot reach server: {0}")]
+    Connection(std::io::Error),
+
+    /// The server predates `method`, which came with schema version `since`. `server` is the
+    /// schema version of the server, `None` for one from before version 2, which cannot tell.
+    #[error("{}", old_server(.method, .since, .server))]
+    OldServer {
+        method: String,
+        since: u32,
+        server: Option<u32>,
+    },
+
+    #[error("{}", .0.extra)]
+    Rpc(capnp::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ControlError>;
+
+impl ControlError {
+    pub fn internal() -> Self {
+        ControlError::Rpc(capnp::Error::failed("Internal error".to_string()))
+    }
+
+    /// Whether the port of the device went away, e.g. as it was unplugged in the middle of a
+    /// command.
+    pub fn is_disconnect(&self) -> bool {
+        use std::io::ErrorKind;
+
+        let e = match self {
+            ControlError::SerialIo(e) => e,
+            _ => return false,
+        };
+        match e.kind() {
+            ErrorKind::NotFound | ErrorKind::BrokenPipe | ErrorKind::NotConnected => true,
+            _ => e.raw_os_error().map_or(false, |code| GONE.contains(&code)),
+        }
+    }
+
+    /// A name for the kind of error that stays the same across versions, for tools acting on
+    /// errors without matching their messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ControlError::DeviceNotFound(_) => "device_not_found",
+            ControlError::SerialIo(_) => "serial_io",
+            ControlError::InvalidInput { .. } => "invalid_input",
+            ControlError::MalformedInput(_) => "malformed_input",
+            ControlError::UnexpectedReply(_) => "unexpected_reply",
+            ControlError::Timeout(_) => "timeout",
+            ControlError::Unsupported(_) => "unsupported",
+            ControlError::Busy(_) => "busy",
+            ControlError::NotAllowed(_) => "not_allowed",
+            ControlError::Maintenance(_) => "maintenance",
+            ControlError::Unauthorized(_) => "unauthorized",
+            ControlError::Cancelled => "cancelled",
+            ControlError::Connection(_) => "connection",
+            ControlError::OldServer { .. } => "old_server",
+            ControlError::Rpc(_) => "rpc",
+        }
+    }
+
+    /// Fills in the error struct of a call's results, see `from_wire`.
+    pub(crate) fn to_wire(&self, mut builder: error::Builder) {
+        let kind = match self {
+            ControlError::DeviceNotFound(name) => {
+                builder.set_device(name);
+                Kind::DeviceNotFound
+            }
+            ControlError::SerialIo(e) => {
+                builder.set_detail(&e.to_string());
+                Kind::SerialIo
+            }
+            ControlError::InvalidInput {
+                device,
+                input,
+                code,
+                inputs,
+            } => {
+                builder.set_device(device);
+                builder.set_input(input);
+                builder.set_code(code.unwrap_or(0));
+                builder.set_inputs(inputs.unwrap_or(0));
+                Kind::InvalidInput
+            }
+            ControlError::MalformedInput(input) => {
+                builder.set_input(input);
+                Kind::MalformedInput
+            }
+            ControlError::UnexpectedReply(reply) => {
+                builder.set_detail(reply);
+                Kind::UnexpectedReply
+            }
+            ControlError::Timeout(what) => {
+                builder.set_detail(what);
+                Kind::Timeout
+            }
+            ControlError::Unsupported(what) => {
+                builder.set_detail(what);
+                Kind::Unsupported
+            }
+            ControlError::Busy(name) => {
+                builder.set_device(name);
+                Kind::Busy
+            }
+            ControlError::NotAllowed(method) => {
+                builder.set_detail(method);
+                Kind::NotAllowed
+            }
+            ControlError::Maintenance(message) => {
+                builder.set_detail(message);
+                Kind::Maintenance
+            }
+            ControlError::Unauthorized(what) => {
+                builder.set_detail(what);
+                Kind::Unauthorized
+            }
+            ControlError::Cancelled => Kind::Cancelled,
+            ControlError::Connection(e) => {
+                builder.set_detail(&e.to_string());
+                Kind::Connection
+            }
+            ControlError::OldServer { .. } => {
+                builder.set_detail(&self.to_string());
+                Kind::Other
+            }
+            ControlError::Rpc(e) => {
+                builder.set_detail(&e.extra);
+                Kind::Other
+            }
+        };
+        builder.set_kind(kind);
+    }
+
+    /// Rebuilds the error the server put in a call's results. Kinds added by newer servers
+    /// come back as `Rpc` errors carrying the detail.
+    pub(crate) fn from_wire(reader: error::Reader) -> capnp::Result<Self> {
+        use std::io::{Error, ErrorKind};
+
+        let text = |t: capnp::Result<capnp::text::Reader>| -> capnp::Result<String> {
+            Ok(t?.to_str()?.to_string())
+        };
+        let detail = text(reader.get_detail())?;
+        Ok(match reader.get_kind() {
+            Ok(Kind::DeviceNotFound) => ControlError::DeviceNotFound(text(reader.get_device())?),
+            Ok(Kind::SerialIo) => ControlError::SerialIo(Error::new(ErrorKind::Other, detail)),
+            Ok(Kind::InvalidInput) => ControlError::InvalidInput {
+                device: text(reader.get_device())?,
+                input: text(reader.get_input())?,
+                code: Some(reader.get_code()).filter(|&c| c != 0),
+                inputs: Some(reader.get_inputs()).filter(|&n| n != 0),
+            },
+            Ok(Kind::MalformedInput) => ControlError::MalformedInput(text(reader.get_input())?),
+            Ok(Kind::UnexpectedReply) => ControlError::UnexpectedReply(detail),
+            Ok(Kind::Timeout) => ControlError::Timeout(detail),
+            Ok(Kind::Unsupported) => ControlError::Unsupported(detail),
+            Ok(Kind::Busy) => ControlError::Busy(text(reader.get_device())?),
+            Ok(Kind::NotAllowed) => ControlError::NotAllowed(detail),
+            Ok(Kind::Maintenance) => ControlError::Maintenance(detail),
+            Ok(Kind::Unauthorized) => ControlError::Unauthorized(detail),
+            Ok(Kind::Cancelled) => ControlError::Cancelled,
+            Ok(Kind::Connection) => ControlError::Connection(Error::new(ErrorKind::Other, detail)),
+            Ok(Kind::Other) => ControlError::Rpc(capnp::Error::failed(detail)),
+            // Only a server newer than this client has kinds it does not know.
+            Err(_) => ControlError::Rpc(capnp::Error::failed(format!(
+                "{} (the server is newer than this client, which may tell more once upgraded)",
+                detail
+            ))),
+        })
+    }
+}
+
+/// Message for [`ControlError::InvalidInput`]. Numbers beyond a known input count are
+/// reported as out of range.
+fn invalid_input(device: &str, input: &str, code: &Option<u8>, inputs: &Option<u32>) -> String {
+    if let Some(inputs) = inputs {
+        if input.parse::<u32>().is_ok() {
+            return format!(
+                "Input {} is out of range on {} ({} inputs)",
+                input, device, inputs
+            );
+        }
+    }
+    let mut message = format!("Invalid input {}", input);
+    if !device.is_empty() {
+        message += &format!(" on {}", device);
+    }
+    if let Some(code) = code {
+        message += &format!(" (E{:02})", code);
+    }
+    message
+}
+
+/// Message for [`ControlError::Maintenance`].
+fn maintenance(message: &str) -> String {
+    match message {
+        "" => "Maintenance in progress".to_string(),
+        message => format!("Maintenance in progress: {}", message),
+    }
+}
+
+/// Message for [`ControlError::OldServer`].
+fn old_server(method: &str, since: &u32, server: &Option<u32>) -> String {
+    let server = match server {
+        Some(version) => format!("schema version {}", version),
+        None => "a schema from before version 2".to_string(),
+    };
+    format!(
+        "The server speaks {}, which has no {} (added in version {}); upgrade the server",
+        server, method, since
+    )
+}
+
+impl From<std::io::Error> for ControlError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => ControlError::Timeout("device reply".to_string()),
+            _ => ControlError::SerialIo(e),
+        }
+    }
+}
+
+impl From<std::str::Utf8Error> for ControlError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        ControlError::Rpc(e.into())
+    }
+}
+
+#[cfg(feature = "serial")]
+impl From<serialport::Error> for ControlError {
+    fn from(e: serialport::Error) -> Self {
+        ControlError::SerialIo(e.into())
+    }
+}
+
+impl From<capnp::Error> for ControlError {
+    fn from(e: capnp::Error) -> Self {
+        ControlError::Rpc(e)
+    }
+}
+
+/// Errors for clients that predate the error field in the results, which only get the
+/// message.
+impl From<ControlError> for capnp::Error {
+    fn from(e: ControlError) -> Self {
+        match e {
+            ControlError::Rpc(e) => e,
+            e => capnp::Error::failed(e.to_string()),
+        }
+    }
+}
@@ -1,76 +1,1033 @@
+use crate::error::{ControlError, Result};
+#[cfg(feature = "serial")]
+use crate::lock::{self, DeviceLock};
+use crate::metrics::Metrics;
+use crate::sis::{self, Command, Plane, Reply};
+use crate::trace;
+#[cfg(feature = "serial")]
 use serialport::prelude::*;
-use std::io::{BufRead, BufReader, Result, Write};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem::{discriminant, Discriminant};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Debug, Clone)]
+/// Upper bound when walking the inputs of a device by number.
+const MAX_INPUTS: u32 = 64;
+
+/// Upper bound on the windows of a layout, against a device answering nonsense.
+const MAX_WINDOWS: u32 = 16;
+
+/// Longest input name accepted. Devices store far shorter names, this only keeps out junk.
+const MAX_NAME_LEN: usize = 32;
+
+/// Bytes of a firmware image written between progress reports.
+const UPLOAD_CHUNK: usize = 1024;
+
+/// How long a device may take to write a firmware image once it has all of it.
+const FLASH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a device may take to answer again after restarting with new firmware.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How long a scan gives a port to answer with the name of the device by default.
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Directory of the links to the devices made by the udev rules of `control-dsc gen-udev`,
+/// named after the serial numbers of the devices.
+pub const STABLE_PORT_DIR: &str = "/dev/extron";
+
+/// How long a scan waits at least for a device another process is using, which is normally
+/// done with it within a command, before leaving it out.
+#[cfg(feature = "serial")]
+const SCAN_LOCK_WAIT: Duration = Duration::from_secs(2);
+
+/// Ports a scan probes at once. Probing mostly waits on the devices, so it overlaps well, but
+/// a machine with hundreds of ports should not open all of them and as many threads together.
+#[cfg(feature = "serial")]
+pub(crate) const PARALLEL_PROBES: usize = 16;
+
+/// How long reads wait on a freshly opened port, until a command sets its own deadline.
+#[cfg(feature = "serial")]
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Bytes kept of what a device sent on its own and nobody took yet. A chatty device that is
+/// not listened to loses the oldest lines rather than growing the buffer without end.
+const UNSOLICITED_LIMIT: usize = 16 * 1024;
+
+/// Shortest and longest deadline learned, as a multiple of the deadline of the command.
+const ADAPTIVE_RANGE: (f64, f64) = (0.2, 4.0);
+
+/// Whether devices learn how long they take to answer, see [`set_adaptive_deadlines`].
+static ADAPTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Makes devices wait for answers as long as they were seen to take, four times the typical
+/// time, rather than for the fixed [`Command::deadline`]. A device that fails to answer in
+/// time gets twice as long the next time.
+pub fn set_adaptive_deadlines(adaptive: bool) {
+    ADAPTIVE.store(adaptive, Ordering::Relaxed);
+}
+
+/// An input of a device, by number or by the name stored in the device.
+///
+/// Parsing checks the syntax, so only a valid input number ever ends up in a command sent to
+/// the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Input number, counting from 1.
+    Number(u32),
+    Name(String),
+}
+
+impl FromStr for Input {
+    type Err = ControlError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            return match s.parse::<u32>() {
+                Ok(n) if n >= 1 && n <= MAX_INPUTS => Ok(Input::Number(n)),
+                _ => Err(ControlError::InvalidInput {
+                    device: String::new(),
+                    input: s.to_string(),
+                    code: None,
+                    inputs: None,
+                }),
+            };
+        }
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || " -_.+/()#&'".contains(c);
+        if s.trim().is_empty() || s.len() > MAX_NAME_LEN || !s.chars().all(valid) {
+            return Err(ControlError::MalformedInput(s.to_string()));
+        }
+        Ok(Input::Name(s.to_string()))
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Number(n) => write!(f, "{}", n),
+            Input::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Sync on the output of a scaler, for broadcast equipment downstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncSettings {
+    pub format: SyncFormat,
+    pub genlock: Genlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncFormat {
+    /// Separate horizontal and vertical sync.
+    SeparateHv,
+    Composite,
+    SyncOnGreen,
+    TriLevel,
+}
+
+impl SyncFormat {
+    /// Number in the SIS commands.
+    fn code(&self) -> u32 {
+        match self {
+            SyncFormat::SeparateHv => 0,
+            SyncFormat::Composite => 1,
+            SyncFormat::SyncOnGreen => 2,
+            SyncFormat::TriLevel => 3,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(SyncFormat::SeparateHv),
+            1 => Some(SyncFormat::Composite),
+            2 => Some(SyncFormat::SyncOnGreen),
+            3 => Some(SyncFormat::TriLevel),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SyncFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SyncFormat::SeparateHv => "hv",
+            SyncFormat::Composite => "composite",
+            SyncFormat::SyncOnGreen => "sync-on-green",
+            SyncFormat::TriLevel => "tri-level",
+        })
+    }
+}
+
+/// What the output timing is locked to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Genlock {
+    /// Nothing, the output runs on its own clock.
+    FreeRun,
+    SelectedInput,
+    /// The reference input, e.g. black burst or tri-level sync from the facility.
+    Reference,
+}
+
+impl Genlock {
+    /// Number in the SIS commands.
+    fn code(&self) -> u32 {
+        match self {
+            Genlock::FreeRun => 0,
+            Genlock::SelectedInput => 1,
+            Genlock::Reference => 2,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Genlock::FreeRun),
+            1 => Some(Genlock::SelectedInput),
+            2 => Some(Genlock::Reference),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Genlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Genlock::FreeRun => "free-run",
+            Genlock::SelectedInput => "input",
+            Genlock::Reference => "reference",
+        })
+    }
+}
+
+/// Byte stream to a device, normally its USB serial port.
+pub trait Port: Read + Write + Send {
+    /// Drops anything the device sent that nobody read yet.
+    fn clear_input(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Takes what the device sent that nobody read yet, without waiting for more.
+    fn take_pending(&mut self) -> std::io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Sets how long a read waits for the device before failing with `TimedOut`.
+    fn set_read_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Port for Box<dyn SerialPort> {
+    fn clear_input(&mut self) -> std::io::Result<()> {
+        self.clear(ClearBuffer::All).map_err(|e| e.into())
+    }
+
+    fn take_pending(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut pending = vec![0; self.bytes_to_read()? as usize];
+        self.read_exact(&mut pending)?;
+        Ok(pending)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.set_timeout(timeout).map_err(|e| e.into())
+    }
+}
+
+/// A serial port, with the lock that keeps other processes off it while open.
+#[cfg(feature = "serial")]
+struct LockedPort {
+    port: Box<dyn SerialPort>,
+    _lock: DeviceLock,
+}
+
+#[cfg(feature = "serial")]
+impl Read for LockedPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Write for LockedPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
+}
+
+#[cfg(feature = "serial")]
+impl Port for LockedPort {
+    fn clear_input(&mut self) -> std::io::Result<()> {
+        self.port.clear_input()
+    }
+
+    fn take_pending(&mut self) -> std::io::Result<Vec<u8>> {
+        self.port.take_pending()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.port.set_read_timeout(timeout)
+    }
+}
+
+/// Response times of a device by kind of command, shared by clones of the device.
+#[derive(Debug, Default)]
+struct Latencies(Mutex<HashMap<Discriminant<Command>, Duration>>);
+
+impl Latencies {
+    /// How long to wait for the answer to `command`.
+    fn deadline(&self, command: &Command) -> Duration {
+        let fixed = command.deadline();
+        if !ADAPTIVE.load(Ordering::Relaxed) {
+            return fixed;
+        }
+        match self.0.lock().unwrap().get(&discriminant(command)) {
+            Some(typical) => (*typical * 4).clamp(
+                fixed.mul_f64(ADAPTIVE_RANGE.0),
+                fixed.mul_f64(ADAPTIVE_RANGE.1),
+            ),
+            None => fixed,
+        }
+    }
+
+    /// Learns that the device answered a command of `kind` after `elapsed`.
+    fn answered(&self, kind: Discriminant<Command>, elapsed: Duration) {
+        let mut latencies = self.0.lock().unwrap();
+        let typical = latencies.entry(kind).or_insert(elapsed);
+        *typical = (*typical * 3 + elapsed) / 4;
+    }
+
+    /// Learns that the device did not answer a command of `kind` in time.
+    fn timed_out(&self, kind: Discriminant<Command>) {
+        if let Some(typical) = self.0.lock().unwrap().get_mut(&kind) {
+            *typical *= 2;
+        }
+    }
+}
+
+type OpenPort = Arc<dyn Fn() -> Result<Box<dyn Port>> + Send + Sync>;
+
+#[derive(Clone)]
 pub struct ExtronDevice {
     pub device_path: String,
     pub name: String,
+    /// `None` for the serial port at `device_path`.
+    open_port: Option<OpenPort>,
+    /// How the device is reached, see [`ExtronDevice::transport`].
+    transport: &'static str,
+    /// Number of inputs, 0 until learned from walking the input names. Shared by clones, so
+    /// what one lookup learns is used by the next.
+    input_count: Arc<AtomicU32>,
+    /// Input numbers by the labels configured for the device on the server, see
+    /// [`ExtronDeviceList::label`].
+    labels: Arc<BTreeMap<String, u32>>,
+    /// What the device sent on its own and nobody took yet. Shared by clones, as any of them
+    /// may come across it.
+    unsolicited: Arc<Mutex<Vec<u8>>>,
+    /// How long the device takes to answer, learned with [`set_adaptive_deadlines`].
+    latencies: Arc<Latencies>,
+    /// Where the server keeps how the device fares, `None` outside a server.
+    metrics: Option<Metrics>,
+}
+
+impl std::fmt::Debug for ExtronDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExtronDevice")
+            .field("device_path", &self.device_path)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// What a device is, for keeping an inventory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+    /// Extron part number, e.g. `60-1238-01`, which tells the model. `None` for devices that
+    /// do not say.
+    pub part_number: Option<String>,
+    /// `None` for devices that do not say.
+    pub firmware: Option<String>,
+}
+
+/// What the health checks read of a device, for monitoring.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceHealth {
+    /// Internal temperature in degrees Celsius. `None` for devices that do not say.
+    pub temperature: Option<u32>,
+    /// The selected input and whether it has a signal. `None` for devices that do not say.
+    pub signal: Option<(u32, bool)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceStatus {
+    pub input: u32,
+    /// The label configured for the input on the server, if any.
+    pub label: Option<String>,
+    /// `None` for devices without audio output.
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+    /// `None` for devices without picture-in-picture.
+    pub pip: Option<PipMode>,
+    /// Input shown in each window while picture-in-picture is on, from the main one.
+    pub sources: Vec<u32>,
+}
+
+/// How many sources a unit with picture-in-picture shows at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipMode {
+    Off,
+    /// A secondary source in a window over the main one.
+    Pip,
+    /// Four sources side by side.
+    Quad,
+}
+
+impl PipMode {
+    /// Number in the SIS commands.
+    fn code(&self) -> u32 {
+        match self {
+            PipMode::Off => 0,
+            PipMode::Pip => 1,
+            PipMode::Quad => 2,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(PipMode::Off),
+            1 => Some(PipMode::Pip),
+            2 => Some(PipMode::Quad),
+            _ => None,
+        }
+    }
+
+    /// Windows showing a source, 0 while off.
+    pub fn windows(&self) -> u32 {
+        match self {
+            PipMode::Off => 0,
+            PipMode::Pip => 2,
+            PipMode::Quad => 4,
+        }
+    }
+}
+
+impl fmt::Display for PipMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PipMode::Off => "off",
+            PipMode::Pip => "pip",
+            PipMode::Quad => "quad",
+        })
+    }
+}
+
+/// What a multi-window processor shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallLayout {
+    /// Video wall preset recalled last, 0 while none was.
+    pub preset: u32,
+    pub windows: u32,
+}
+
+/// Inputs in the windows of a multi-window processor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowLayout {
+    /// Window layout preset recalled.
+    pub layout: u32,
+    /// Input shown in each window, from window 1, 0 for an empty window.
+    pub inputs: Vec<u32>,
+}
+
+/// Command for an Annotator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Annotation {
+    /// Clears everything drawn.
+    Clear,
+    /// Selects the pointer tool.
+    Pointer,
+    /// Freezes the annotated picture, or lets it go for `false`.
+    Freeze(bool),
+}
+
+/// Annotation tool that is the pointer.
+const POINTER_TOOL: u32 = 1;
+
+/// Firmware versions a device reported around an upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareUpdate {
+    pub before: String,
+    pub after: String,
+}
+
+/// A line a device sent on its own, e.g. on a front panel change, an error or a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMessage {
+    /// When the server heard it.
+    pub time: SystemTime,
+    pub text: String,
+}
+
+/// An input change on a device, as the server keeps them for its history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputChange {
+    /// When the server made or noticed it.
+    pub time: SystemTime,
+    /// Who made it: a client by the name in its certificate, its token or its address, or
+    /// `schedule`, `front panel` and the like for what the server did not get from a client.
+    pub by: String,
+    /// The input before, `None` when the server did not know it.
+    pub from: Option<Input>,
+    pub to: Input,
 }
 
 #[derive(Debug, Clone)]
 pub struct ExtronDeviceList {
-    map: std::collections::HashMap<String, ExtronDevice>,
+    map: HashMap<String, ExtronDevice>,
+    /// Names of the devices by their other keys: path, serial number and alias.
+    keys: HashMap<String, String>,
+}
+
+/// Settings of every Extron USB serial port. They are not configurable, and a port is opened
+/// for each operation and closed after it, so no open connection ever holds outdated settings.
+#[cfg(feature = "serial")]
+fn port_settings() -> SerialPortSettings {
+    SerialPortSettings {
+        baud_rate: 115200,
+        data_bits: DataBits::Eight,
+        flow_control: FlowControl::None,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        timeout: READ_TIMEOUT,
+    }
+}
+
+/// Reads one line from the device, empty at end of input.
+fn read_line(serial_reader: &mut BufReader<Box<dyn Port>>) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    serial_reader.read_until(b'\n', &mut line)?;
+    Ok(line)
+}
+
+/// Reads one line like `read_line`, but waits through read timeouts until `timeout` has
+/// passed, for replies that take long.
+fn read_line_within(
+    serial_reader: &mut BufReader<Box<dyn Port>>,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut line = Vec::new();
+    loop {
+        match serial_reader.read_until(b'\n', &mut line) {
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut && Instant::now() < deadline => {}
+            result => {
+                result?;
+                return Ok(line);
+            }
+        }
+    }
+}
+
+/// Reads until the device goes quiet, i.e. until a read times out or hits the end.
+fn read_until_quiet(port: &mut Box<dyn Port>) -> Result<Vec<u8>> {
+    let mut heard = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        match port.read(&mut buf) {
+            Ok(0) => return Ok(heard),
+            Ok(n) => heard.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Ok(heard),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// What a device answered to a command: the answer expected, or an error code.
+type Answer<T> = std::result::Result<T, u8>;
+
+/// Error for an error code answering a command that cannot fail.
+fn unexpected_code(code: u8) -> ControlError {
+    ControlError::UnexpectedReply(format!("E{:02}", code))
+}
+
+impl ExtronDevice {
+    /// Sends `command` and waits up to `deadline` for the answer `expected` picks out, or for
+    /// an error code. Echoes of the command are skipped, and anything else the device sends
+    /// meanwhile is kept for [`ExtronDevice::take_unsolicited`]. Fails with the last of those
+    /// if the answer does not come, or with [`ControlError::Timeout`] if nothing does.
+    fn send_and_expect<T, F>(
+        &self,
+        serial_reader: &mut BufReader<Box<dyn Port>>,
+        command: Command,
+        mut expected: F,
+        deadline: Duration,
+    ) -> Result<Answer<T>>
+    where
+        F: FnMut(&Reply) -> Option<T>,
+    {
+        let encoded = command.encode();
+        let echo = sis::text(&encoded);
+        let _span = debug_span!(
+            "command",
+            device = self.name.as_str(),
+            command = echo.as_str()
+        )
+        .entered();
+        let kind = discriminant(&command);
+        let port = serial_reader.get_mut();
+        port.set_read_timeout(deadline)?;
+        port.write_all(&encoded)?;
+
+        let sent = Instant::now();
+        let mut unexpected = None;
+        loop {
+            let line = match read_line(serial_reader) {
+                Err(ControlError::Timeout(_)) => Vec::new(),
+                result => result?,
+            };
+            if line.is_empty() || sent.elapsed() > deadline {
+                debug!("No answer within {:?}", deadline);
+                self.latencies.timed_out(kind);
+                if let Some(metrics) = &self.metrics {
+                    metrics.timed_out(&self.name);
+                }
+                return Err(match unexpected {
+                    Some(text) => ControlError::UnexpectedReply(text),
+                    None => ControlError::Timeout(format!("{} to answer", self.name)),
+                });
+            }
+            let reply = Reply::parse(&line);
+            let answer = match reply {
+                Reply::Error(code) => Some(Err(code)),
+                _ => expected(&reply).map(Ok),
+            };
+            if let Some(answer) = answer {
+                debug!("Answered {:?} after {:?}", sis::text(&line), sent.elapsed());
+                self.latencies.answered(kind, sent.elapsed());
+                if let Some(metrics) = &self.metrics {
+                    metrics.answered(&self.name, sent.elapsed());
+                    if let Err(code) = answer {
+                        metrics.error(&self.name, code);
+                    }
+                }
+                return Ok(answer);
+            }
+            let text = sis::text(&line);
+            if !text.is_empty() && text != echo {
+                self.keep_unsolicited(&line);
+                unexpected = Some(text);
+            }
+        }
+    }
+
+    /// Sends `command` and waits for the answer as long as the device may take, see
+    /// [`set_adaptive_deadlines`].
+    fn query<T, F>(
+        &self,
+        serial_reader: &mut BufReader<Box<dyn Port>>,
+        command: Command,
+        expected: F,
+    ) -> Result<Answer<T>>
+    where
+        F: FnMut(&Reply) -> Option<T>,
+    {
+        let deadline = self.latencies.deadline(&command);
+        self.send_and_expect(serial_reader, command, expected, deadline)
+    }
+
+    /// The input selected.
+    fn query_input(&self, serial_reader: &mut BufReader<Box<dyn Port>>) -> Result<u32> {
+        let input = self.query(serial_reader, Command::QueryInput, Reply::number)?;
+        input.map_err(unexpected_code)
+    }
+
+    fn query_layout(&self, serial_reader: &mut BufReader<Box<dyn Port>>) -> Result<WallLayout> {
+        let layout = self.query(serial_reader, Command::QueryLayout, |reply| match *reply {
+            Reply::Layout { preset, windows } => Some(WallLayout { preset, windows }),
+            _ => None,
+        })?;
+        layout.map_err(|_| ControlError::Unsupported("Multi-window presets".to_string()))
+    }
+
+    /// The output sync settings. Devices without fail with [`ControlError::Unsupported`].
+    fn query_sync(&self, serial_reader: &mut BufReader<Box<dyn Port>>) -> Result<SyncSettings> {
+        let unsupported = |_| ControlError::Unsupported("Output sync settings".to_string());
+
+        let format = self.query(
+            serial_reader,
+            Command::QuerySyncFormat,
+            |reply| match *reply {
+                Reply::SyncFormat(code) => SyncFormat::from_code(code),
+                _ => None,
+            },
+        )?;
+        let format = format.map_err(unsupported)?;
+        let genlock = self.query(serial_reader, Command::QueryGenlock, |reply| match *reply {
+            Reply::Genlock(code) => Genlock::from_code(code),
+            _ => None,
+        })?;
+        let genlock = genlock.map_err(unsupported)?;
+        Ok(SyncSettings { format, genlock })
+    }
+
+    /// The picture-in-picture mode, or `None` for devices without.
+    fn query_pip_mode(
+        &self,
+        serial_reader: &mut BufReader<Box<dyn Port>>,
+    ) -> Result<Option<PipMode>> {
+        let mode = self.query(serial_reader, Command::QueryPipMode, |reply| match *reply {
+            Reply::Pip(code) => PipMode::from_code(code),
+            _ => None,
+        })?;
+        Ok(mode.ok())
+    }
+
+    /// The input shown in each of the first `windows` windows.
+    fn query_windows(
+        &self,
+        serial_reader: &mut BufReader<Box<dyn Port>>,
+        windows: u32,
+    ) -> Result<Vec<u32>> {
+        let mut inputs = Vec::new();
+        for n in 1..=windows.min(MAX_WINDOWS) {
+            let input = self.query(
+                serial_reader,
+                Command::QueryWindow(n),
+                |reply| match *reply {
+                    Reply::Window { window, input } if window == n => Some(input),
+                    _ => None,
+                },
+            )?;
+            inputs.push(input.map_err(unexpected_code)?);
+        }
+        Ok(inputs)
+    }
+}
+
+/// Asks the device on a freshly opened port for its name. Gives up after `timeout` even while
+/// bytes keep coming, as they may from a device that is no Extron.
+#[cfg(feature = "serial")]
+fn probe(port_name: &str, mut serial: Box<dyn SerialPort>, timeout: Duration) -> Result<String> {
+    let deadline = Instant::now() + timeout;
+    serial.clear(ClearBuffer::All)?;
+    serial.write_all(&Command::Name.encode())?;
+    let mut line = Vec::new();
+    let mut buf = [0; 64];
+    while !line.contains(&b'\n') {
+        if Instant::now() >= deadline {
+            return Err(ControlError::Timeout(format!(
+                "the name of the device at {}",
+                port_name
+            )));
+        }
+        match serial.read(&mut buf) {
+            Ok(n) => line.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let end = line.iter().position(|&b| b == b'\n').unwrap_or(line.len());
+    Ok(sis::text(&line[..end]))
+}
+
+/// Probes the device on `port` for its name, `None` when another process keeps the port or
+/// nothing answers within `timeout`.
+#[cfg(feature = "serial")]
+fn probe_port(port: &str, settings: &SerialPortSettings, timeout: Duration) -> Option<String> {
+    let wait = lock::wait().map_or(SCAN_LOCK_WAIT, |w| w.max(SCAN_LOCK_WAIT));
+    let _lock = match lock::lock(port, port, Some(wait)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            warn!("Not scanning {}: {}", port, e);
+            return None;
+        }
+    };
+    let serial = serialport::open_with_settings(port, settings).ok()?;
+    match probe(port, serial, timeout) {
+        Ok(name) => Some(name),
+        Err(e) => {
+            warn!("Not scanning {}: {}", port, e);
+            None
+        }
+    }
+}
+
+/// The device on `port`, as a scan finds it, for scans that probe the ports one by one.
+/// Counting its inputs is left to the scan.
+#[cfg(all(feature = "serial", feature = "server"))]
+pub(crate) fn probe_device(port: &str, timeout: Duration) -> Option<ExtronDevice> {
+    probe_port(port, &port_settings(), timeout).map(|name| ExtronDevice::new(&name, port))
+}
+
+/// The serial number of the device at `path`, when that is its link in [`STABLE_PORT_DIR`].
+pub fn serial_number(path: &str) -> Option<&str> {
+    path.strip_prefix(STABLE_PORT_DIR)?.strip_prefix('/')
+}
+
+/// Error for device access in builds without the `serial` feature.
+#[cfg(not(feature = "serial"))]
+fn no_serial() -> ControlError {
+    ControlError::SerialIo(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "Built without serial port support",
+    ))
+}
+
+/// Windows reports the manufacturer of the serial driver instead of the device, and IOKit
+/// does not always find it for the modem interface, so there the vendor id has to do.
+#[cfg(feature = "serial")]
+fn is_extron(port: &serialport::UsbPortInfo) -> bool {
+    port.vid == 0x1ce2
+        && (cfg!(windows)
+            || match port.manufacturer.as_deref() {
+                Some(manufacturer) => manufacturer.starts_with("Extron"),
+                None => cfg!(target_os = "macos"),
+            })
+}
+
+/// macOS lists every device twice, as `/dev/tty.*` and `/dev/cu.*`. Opening the tty one
+/// waits for carrier detect, which never comes, so only the `cu` one is used.
+#[cfg(feature = "serial")]
+fn is_macos_tty(port_name: &str) -> bool {
+    cfg!(target_os = "macos") && port_name.starts_with("/dev/tty.")
+}
+
+/// The USB serial ports with an Extron device on them, in port order, whether or not the
+/// device can be opened. A port with a link in [`STABLE_PORT_DIR`] goes by the link, which
+/// stays the same when the device is plugged into another port.
+#[cfg(feature = "serial")]
+pub fn extron_ports() -> Result<Vec<String>> {
+    let links = stable_links();
+    let mut ports: Vec<String> = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            serialport::SerialPortType::UsbPort(p)
+                if is_extron(&p) && !is_macos_tty(&port.port_name) =>
+            {
+                Some(stable_path(port.port_name, &links))
+            }
+            _ => None,
+        })
+        .collect();
+    ports.sort();
+    Ok(ports)
+}
+
+/// The links in [`STABLE_PORT_DIR`] with the ports they lead to, none without the udev rules.
+#[cfg(feature = "serial")]
+fn stable_links() -> Vec<(String, std::path::PathBuf)> {
+    let entries = match std::fs::read_dir(STABLE_PORT_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let target = entry.path().canonicalize().ok()?;
+            Some((entry.path().to_string_lossy().to_string(), target))
+        })
+        .collect()
+}
+
+/// `port` by its link among `links`, or as is without one.
+#[cfg(feature = "serial")]
+fn stable_path(port: String, links: &[(String, std::path::PathBuf)]) -> String {
+    let target = match std::fs::canonicalize(&port) {
+        Ok(target) => target,
+        Err(_) => return port,
+    };
+    links
+        .iter()
+        .find(|(_, link_target)| *link_target == target)
+        .map_or(port, |(link, _)| link.clone())
+}
+
+/// Without the `serial` feature no ports can be listed.
+#[cfg(not(feature = "serial"))]
+pub fn extron_ports() -> Result<Vec<String>> {
+    Err(no_serial())
 }
 
 impl ExtronDeviceList {
+    /// Scans the USB serial ports again, giving each [`DEFAULT_PROBE_TIMEOUT`] to answer.
     pub fn rescan(&mut self) -> Result<()> {
-        self.map.clear();
-        let settings = SerialPortSettings {
-            baud_rate: 115200,
-            data_bits: DataBits::Eight,
-            flow_control: FlowControl::None,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-            timeout: Duration::from_millis(100),
-        };
+        self.rescan_within(DEFAULT_PROBE_TIMEOUT)
+    }
 
-        for port in serialport::available_ports()? {
-            match port.port_type {
-                serialport::SerialPortType::UsbPort(p)
-                    if p.vid == 0x1ce2
-                        && p.manufacturer.clone().unwrap_or("".to_string()) == "Extron" =>
-                {
-                    match serialport::open_with_settings(&port.port_name, &settings) {
-                        Ok(mut serial) => {
-                            serial.clear(ClearBuffer::All)?;
-                            serial.write(b"\x1bCN\x0d")?;
-                            let mut serial_reader = BufReader::new(serial);
-                            let mut device_name = String::new();
-                            serial_reader.read_line(&mut device_name)?;
-                            let name = device_name.trim_end().to_string();
-                            self.map.insert(
-                                name.clone(),
-                                ExtronDevice {
-                                    device_path: port.port_name,
-                                    name,
-                                },
-                            );
-                        }
-                        Err(_) => {}
+    /// Scans the USB serial ports again. Ports that do not answer with a name within
+    /// `probe_timeout` are left out with a warning.
+    pub fn rescan_within(&mut self, probe_timeout: Duration) -> Result<()> {
+        self.rescan_ports(probe_timeout, |_| true)
+    }
+
+    /// Scans the USB serial ports for which `wanted` returns true, leaving the others alone
+    /// for whoever else uses them, e.g. another server on this machine. Up to
+    /// [`PARALLEL_PROBES`] ports are probed at once, each device also for its number of
+    /// inputs.
+    #[cfg(feature = "serial")]
+    pub fn rescan_ports<F>(&mut self, probe_timeout: Duration, wanted: F) -> Result<()>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.clear();
+        let settings = port_settings();
+        let ports: Vec<String> = extron_ports()?
+            .into_iter()
+            .filter(|port| wanted(port))
+            .collect();
+
+        let queue = Mutex::new(ports.iter().enumerate());
+        // Each worker probes the next port in the queue until none are left.
+        let work = || {
+            let mut found = Vec::new();
+            loop {
+                let next = queue.lock().unwrap().next();
+                let (i, port) = match next {
+                    Some(next) => next,
+                    None => return found,
+                };
+                if let Some(name) = probe_port(port, &settings, probe_timeout) {
+                    let device = ExtronDevice::new(&name, port);
+                    if let Err(e) = device.count_inputs() {
+                        warn!("Cannot count the inputs of {}: {}", name, e);
                     }
+                    found.push((i, device));
                 }
-                _ => {}
             }
+        };
+        let work = &work;
+        let mut found: Vec<(usize, ExtronDevice)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..PARALLEL_PROBES.min(ports.len()))
+                .map(|_| scope.spawn(work))
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_default())
+                .collect()
+        });
+
+        // In port order, so that identical units keep their numbering across rescans.
+        found.sort_by_key(|(i, _)| *i);
+        for (_, device) in found {
+            self.insert(device);
         }
         Ok(())
     }
 
+    /// Without the `serial` feature there are no local devices.
+    #[cfg(not(feature = "serial"))]
+    pub fn rescan_ports<F>(&mut self, _probe_timeout: Duration, _wanted: F) -> Result<()>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.clear();
+        Ok(())
+    }
+
     pub fn enumerate_extron() -> Result<Self> {
-        let extron = std::collections::HashMap::new();
-        let mut result = Self { map: extron };
-        result.rescan()?;
+        Self::enumerate_extron_within(DEFAULT_PROBE_TIMEOUT)
+    }
+
+    /// The devices on the USB serial ports, giving each port `probe_timeout` to answer.
+    pub fn enumerate_extron_within(probe_timeout: Duration) -> Result<Self> {
+        let mut result = Self::new();
+        result.rescan_within(probe_timeout)?;
 
         Ok(result)
     }
 
     pub fn new() -> Self {
-        let map = std::collections::HashMap::new();
-        Self { map }
+        Self {
+            map: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.keys.clear();
+    }
+
+    /// Adds `device`. When its name is taken, e.g. by a second unit of the same model, it is
+    /// renamed to `name#2`, `name#3` and so on, so that both can be addressed.
+    pub fn insert(&mut self, mut device: ExtronDevice) {
+        if let Some(other) = self.map.get(&device.name) {
+            let name = (2..)
+                .map(|n| format!("{}#{}", device.name, n))
+                .find(|name| !self.map.contains_key(name))
+                .unwrap();
+            warn!(
+                "Devices at {} and {} are both called {}, using {} for the second",
+                other.device_path, device.device_path, device.name, name
+            );
+            device.name = name;
+        }
+        self.keys
+            .insert(device.device_path.clone(), device.name.clone());
+        if let Some(serial) = serial_number(&device.device_path) {
+            self.keys.insert(serial.to_string(), device.name.clone());
+        }
+        self.map.insert(device.name.clone(), device);
+    }
+
+    /// Lets the device known as `key` take the inputs in `labels` by their label, e.g.
+    /// `laptop` for 2, before the names stored in the device. Returns false if there is no
+    /// such device.
+    pub fn label(&mut self, key: &str, labels: BTreeMap<String, u32>) -> bool {
+        let name = match self.resolve(key) {
+            Some(name) => name.to_string(),
+            None => return false,
+        };
+        if let Some(device) = self.map.get_mut(&name) {
+            device.labels = Arc::new(labels);
+        }
+        true
+    }
+
+    /// Makes the device called `name` also go by `alias`, unless a device has that name.
+    /// Returns false if there is no device called `name`.
+    pub fn alias(&mut self, alias: &str, name: &str) -> bool {
+        if !self.map.contains_key(name) {
+            return false;
+        }
+        if !self.map.contains_key(alias) {
+            self.keys.insert(alias.to_string(), name.to_string());
+        }
+        true
+    }
+
+    /// The name of the device known as `key`: its name, its path, the serial number its
+    /// link in [`STABLE_PORT_DIR`] is named after, or an alias. Names win over the others.
+    pub fn resolve(&self, key: &str) -> Option<&str> {
+        match self.map.get_key_value(key) {
+            Some((name, _)) => Some(name.as_str()),
+            None => self.keys.get(key).map(String::as_str),
+        }
     }
 
-    pub fn find(&self, name: &str) -> Option<ExtronDevice> {
-        self.map.get(name).map(|d| d.clone())
+    /// Adds the devices of `other`, in path order, renaming them as `insert` does.
+    pub fn extend(&mut self, other: ExtronDeviceList) {
+        let mut devices: Vec<_> = other.map.into_iter().map(|(_, d)| d).collect();
+        devices.sort_by(|a, b| a.device_path.cmp(&b.device_path));
+        for device in devices {
+            self.insert(device);
+        }
+    }
+
+    /// The device known as `key`, see [`ExtronDeviceList::resolve`].
+    pub fn find(&self, key: &str) -> Option<ExtronDevice> {
+        self.resolve(key)
+            .and_then(|name| self.map.get(name))
+            .cloned()
     }
 
     pub fn len(&self) -> usize {
@@ -80,38 +1037,700 @@ impl ExtronDeviceList {
     pub fn iter(&self) -> impl Iterator<Item = ExtronDevice> + '_ {
         self.map.iter().map(|(_, d)| d.clone())
     }
+
+    /// Keeps only the devices for which `f` returns true.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&ExtronDevice) -> bool,
+    {
+        self.map.retain(|_, device| f(device));
+        let map = &self.map;
+        self.keys.retain(|_, name| map.contains_key(name));
+    }
+
+    /// Makes the devices record how they fare in `metrics`.
+    pub fn set_metrics(&mut self, metrics: &Metrics) {
+        for device in self.map.values_mut() {
+            device.metrics = Some(metrics.clone());
+        }
+    }
+}
+
+/// The devices among `devices` whose name or path matches the glob pattern `filter`, all for
+/// an empty one, sorted by name, skipping the first `offset` and at most `limit` of them, all
+/// for 0, with the number that matched.
+pub fn page_of(
+    mut devices: Vec<ExtronDevice>,
+    filter: &str,
+    offset: u32,
+    limit: u32,
+) -> Result<(Vec<ExtronDevice>, u32)> {
+    if !filter.is_empty() {
+        let pattern = glob::Pattern::new(filter).map_err(|e| {
+            ControlError::Rpc(capnp::Error::failed(format!(
+                "Invalid filter '{}': {}",
+                filter, e
+            )))
+        })?;
+        devices
+            .retain(|device| pattern.matches(&device.name) || pattern.matches(&device.device_path));
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    let total = devices.len() as u32;
+    let limit = match limit {
+        0 => devices.len(),
+        limit => limit as usize,
+    };
+    let page = devices
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit)
+        .collect();
+    Ok((page, total))
 }
 
 impl ExtronDevice {
-    pub fn select(&self, input: &str) -> Result<()> {
-        use std::io::{Error, ErrorKind};
-        let settings = SerialPortSettings {
-            baud_rate: 115200,
-            data_bits: DataBits::Eight,
-            flow_control: FlowControl::None,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-            timeout: Duration::from_millis(100),
+    /// A device on the serial port at `device_path`.
+    pub fn new(name: &str, device_path: &str) -> Self {
+        ExtronDevice {
+            device_path: device_path.to_string(),
+            name: name.to_string(),
+            open_port: None,
+            transport: "serial",
+            input_count: Arc::new(AtomicU32::new(0)),
+            labels: Arc::new(BTreeMap::new()),
+            unsolicited: Arc::new(Mutex::new(Vec::new())),
+            latencies: Arc::new(Latencies::default()),
+            metrics: None,
+        }
+    }
+
+    /// A device reached through the ports returned by `open`, e.g. a simulated one.
+    pub fn with_port<F>(name: &str, device_path: &str, open: F) -> Self
+    where
+        F: Fn() -> Result<Box<dyn Port>> + Send + Sync + 'static,
+    {
+        ExtronDevice {
+            device_path: device_path.to_string(),
+            name: name.to_string(),
+            open_port: Some(Arc::new(open)),
+            transport: "custom",
+            input_count: Arc::new(AtomicU32::new(0)),
+            labels: Arc::new(BTreeMap::new()),
+            unsolicited: Arc::new(Mutex::new(Vec::new())),
+            latencies: Arc::new(Latencies::default()),
+            metrics: None,
+        }
+    }
+
+    /// Names how the device is reached, as [`ExtronDevice::transport`] tells.
+    pub(crate) fn with_transport(mut self, transport: &'static str) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// How the device is reached: `serial` for the serial port at `device_path`, `tcp` for a
+    /// device on the network, see [`crate::network`], `custom` for other ports from
+    /// [`ExtronDevice::with_port`].
+    pub fn transport(&self) -> &'static str {
+        self.transport
+    }
+
+    /// Opens the port, keeping what the device sent since it was last used for
+    /// [`ExtronDevice::take_unsolicited`].
+    fn open(&self) -> Result<Box<dyn Port>> {
+        let port = match &self.open_port {
+            Some(open) => open()?,
+            None => self.open_serial()?,
         };
-        let mut port = serialport::open_with_settings(&self.device_path, &settings)?;
-        let command = format!("{}!", input);
-        port.write(command.as_bytes())?;
-        //    .map(|_| ())
-        //    .map_err(|e| e.into())?;
-
-        let serial_reader = BufReader::new(port);
-        let ok_pattern = format!("In{}All", input);
-        for line in serial_reader.lines() {
-            let l = line?;
-            let result = if l.starts_with("E01") {
-                Err(Error::new(ErrorKind::Other, format!("Invalid input {}", input)))
-            } else if l.starts_with(&ok_pattern) {
+        let mut port = trace::wrap(&self.name, port);
+        let pending = port.take_pending()?;
+        self.keep_unsolicited(&pending);
+        Ok(port)
+    }
+
+    /// Opens the serial port, once no other process is using it, see [`lock::set_wait`].
+    #[cfg(feature = "serial")]
+    fn open_serial(&self) -> Result<Box<dyn Port>> {
+        let lock = lock::lock(&self.name, &self.device_path, lock::wait())?;
+        let port = serialport::open_with_settings(&self.device_path, &port_settings())?;
+        Ok(Box::new(LockedPort { port, _lock: lock }))
+    }
+
+    #[cfg(not(feature = "serial"))]
+    fn open_serial(&self) -> Result<Box<dyn Port>> {
+        Err(no_serial())
+    }
+
+    /// Takes the labels the server has for the device, as listed by it.
+    #[cfg(feature = "client")]
+    pub(crate) fn with_labels(mut self, labels: BTreeMap<String, u32>) -> Self {
+        self.labels = Arc::new(labels);
+        self
+    }
+
+    /// Input numbers by the labels configured for the device, empty outside a server.
+    pub fn labels(&self) -> &BTreeMap<String, u32> {
+        &self.labels
+    }
+
+    /// The label configured for `input`, if any.
+    pub fn label_of(&self, input: u32) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, n)| **n == input)
+            .map(|(label, _)| label.as_str())
+    }
+
+    /// Number of inputs, once known from a scan or from looking up an input by name.
+    pub fn input_count(&self) -> Option<u32> {
+        Some(self.input_count.load(Ordering::Relaxed)).filter(|&n| n > 0)
+    }
+
+    /// Takes the number of inputs as learned before, e.g. by the server before it restarted.
+    #[cfg(feature = "server")]
+    pub(crate) fn set_input_count(&self, count: u32) {
+        self.input_count.store(count, Ordering::Relaxed);
+    }
+
+    /// Asks the device for its number of inputs unless that is known already, so that
+    /// inputs out of range are refused without sending it the command.
+    pub fn count_inputs(&self) -> Result<Option<u32>> {
+        if self.input_count().is_none() {
+            self.walk_inputs(None)?;
+        }
+        Ok(self.input_count())
+    }
+
+    /// Error for an input the device does not have.
+    fn invalid_input(&self, input: &str, code: Option<u8>) -> ControlError {
+        ControlError::InvalidInput {
+            device: self.name.clone(),
+            input: input.to_string(),
+            code,
+            inputs: self.input_count(),
+        }
+    }
+
+    /// Walks the input names until the device reports an error, which tells the number of
+    /// inputs. Stops early at the input called `name`, if given, and returns its number.
+    fn walk_inputs(&self, name: Option<&str>) -> Result<Option<u32>> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        for n in 1..=MAX_INPUTS {
+            let reply = self.query(&mut serial_reader, Command::InputName(n), |reply| {
+                Some(reply.clone())
+            })?;
+            match reply {
+                Err(_) => {
+                    self.input_count.store(n - 1, Ordering::Relaxed);
+                    break;
+                }
+                Ok(Reply::Text(text))
+                    if name.map_or(false, |name| text.eq_ignore_ascii_case(name)) =>
+                {
+                    return Ok(Some(n));
+                }
+                Ok(_) => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Translates an input given by a label configured for the device, or else by the name
+    /// stored in the device, into its number. Input numbers are checked against the number of
+    /// inputs, if known.
+    pub fn resolve_input(&self, input: &Input) -> Result<u32> {
+        let labelled = match input {
+            Input::Name(name) => self
+                .labels
+                .iter()
+                .find(|(label, _)| label.eq_ignore_ascii_case(name))
+                .map(|(_, n)| Input::Number(*n)),
+            Input::Number(_) => None,
+        };
+        match labelled.as_ref().unwrap_or(input) {
+            Input::Number(n) if *n == 0 || *n > self.input_count().unwrap_or(MAX_INPUTS) => {
+                Err(self.invalid_input(&input.to_string(), None))
+            }
+            Input::Number(n) => Ok(*n),
+            Input::Name(name) => match self.walk_inputs(Some(name))? {
+                Some(n) => Ok(n),
+                None => Err(self.invalid_input(name, None)),
+            },
+        }
+    }
+
+    pub fn select(&self, input: &Input) -> Result<()> {
+        self.select_plane(input, Plane::All)
+    }
+
+    /// Selects `input` for the signals of `plane` only, e.g. for audio from another source
+    /// than the video.
+    pub fn select_plane(&self, input: &Input, plane: Plane) -> Result<()> {
+        let input = self.resolve_input(input)?;
+        let mut serial_reader = BufReader::new(self.open()?);
+        let command = Command::Select(input, plane);
+        let acknowledged = self.query(&mut serial_reader, command, |reply| match *reply {
+            Reply::Input(n) if n == input => Some(()),
+            _ => None,
+        })?;
+        match acknowledged {
+            Ok(()) => {
+                // Returns on the acknowledgement rather than waiting for the device to go
+                // quiet, so whatever followed it was sent on its own.
+                self.keep_unsolicited(serial_reader.buffer());
                 Ok(())
-            } else  {
-                Err(Error::new(ErrorKind::Other, format!("Unexpected answer {}", l)))
-            };
-            result?;
+            }
+            Err(code) => {
+                // Learn the number of inputs for the message, on a best effort basis.
+                drop(serial_reader);
+                if self.input_count().is_none() {
+                    let _ = self.walk_inputs(None);
+                }
+                // An input the device has, so it is the plane that it cannot switch.
+                if plane != Plane::All && self.input_count().is_some_and(|n| input <= n) {
+                    return Err(ControlError::Unsupported(
+                        "Switching audio apart from video".to_string(),
+                    ));
+                }
+                Err(self.invalid_input(&input.to_string(), Some(code)))
+            }
         }
+    }
+
+    pub fn set_volume(&self, level: u8) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        let set = self.query(
+            &mut serial_reader,
+            Command::SetVolume(level),
+            |reply| match reply {
+                Reply::Volume(_) => Some(()),
+                _ => None,
+            },
+        )?;
+        set.map_err(|code| ControlError::InvalidInput {
+            device: self.name.clone(),
+            input: format!("volume {}", level),
+            code: Some(code),
+            inputs: None,
+        })
+    }
+
+    pub fn set_mute(&self, mute: bool) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        let set = self.query(
+            &mut serial_reader,
+            Command::SetMute(mute),
+            |reply| match reply {
+                Reply::Mute(_) => Some(()),
+                _ => None,
+            },
+        )?;
+        set.map_err(|_| ControlError::Unsupported("Audio mute".to_string()))
+    }
+
+    /// Powers the display on the output on or off over HDMI CEC. Devices without CEC fail
+    /// with [`ControlError::Unsupported`] before anything reaches the display.
+    pub fn set_display_power(&self, on: bool) -> Result<()> {
+        let unsupported = |_| ControlError::Unsupported("Display power over CEC".to_string());
+        let power = |reply: &Reply| match reply {
+            Reply::DisplayPower(_) => Some(()),
+            _ => None,
+        };
+
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query(&mut serial_reader, Command::QueryDisplayPower, power)?
+            .map_err(unsupported)?;
+        self.query(&mut serial_reader, Command::SetDisplayPower(on), power)?
+            .map_err(unsupported)
+    }
+
+    /// The video wall layout of a multi-window processor. Other devices fail with
+    /// [`ControlError::Unsupported`].
+    pub fn wall_layout(&self) -> Result<WallLayout> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_layout(&mut serial_reader)
+    }
+
+    /// Recalls video wall preset `preset` on a multi-window processor.
+    pub fn recall_preset(&self, preset: u32) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        // Other devices may take the recall for something else, so it is only sent to those
+        // that know about layouts.
+        self.query_layout(&mut serial_reader)?;
+        let recalled =
+            self.query(
+                &mut serial_reader,
+                Command::RecallPreset(preset),
+                |reply| match *reply {
+                    Reply::Preset(n) if n == preset => Some(()),
+                    _ => None,
+                },
+            )?;
+        recalled.map_err(|_| ControlError::Unsupported(format!("Preset {}", preset)))
+    }
+
+    /// Recalls window layout preset `layout` on a multi-window processor and reports which
+    /// inputs it shows in its windows.
+    pub fn recall_layout(&self, layout: u32) -> Result<WindowLayout> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_layout(&mut serial_reader)?;
+        let windows =
+            self.query(
+                &mut serial_reader,
+                Command::RecallLayout(layout),
+                |reply| match *reply {
+                    Reply::Layout { preset, windows } if preset == layout => Some(windows),
+                    _ => None,
+                },
+            )?;
+        let windows =
+            windows.map_err(|_| ControlError::Unsupported(format!("Layout {}", layout)))?;
+        let inputs = self.query_windows(&mut serial_reader, windows)?;
+        Ok(WindowLayout { layout, inputs })
+    }
+
+    /// Switches picture-in-picture to `mode`.
+    pub fn set_pip_mode(&self, mode: PipMode) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        let set = self.query(
+            &mut serial_reader,
+            Command::SetPipMode(mode.code()),
+            |reply| match *reply {
+                Reply::Pip(code) if code == mode.code() => Some(()),
+                _ => None,
+            },
+        )?;
+        set.map_err(|_| ControlError::Unsupported("Picture-in-picture".to_string()))
+    }
+
+    /// Shows `input` in picture-in-picture window `window`, 1 being the main one.
+    pub fn assign_source(&self, window: u32, input: &Input) -> Result<()> {
+        let input = self.resolve_input(input)?;
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_pip_mode(&mut serial_reader)?
+            .ok_or_else(|| ControlError::Unsupported("Picture-in-picture".to_string()))?;
+        let command = Command::AssignWindow { window, input };
+        let assigned = self.query(&mut serial_reader, command, |reply| match *reply {
+            Reply::Window {
+                window: w,
+                input: i,
+            } if (w, i) == (window, input) => Some(()),
+            _ => None,
+        })?;
+        assigned.map_err(|code| match code {
+            1 => self.invalid_input(&input.to_string(), Some(1)),
+            _ => ControlError::InvalidInput {
+                device: self.name.clone(),
+                input: format!("window {}", window),
+                code: Some(code),
+                inputs: None,
+            },
+        })
+    }
+
+    /// Sync format and genlock of the output. Devices without these settings fail with
+    /// [`ControlError::Unsupported`].
+    pub fn sync(&self) -> Result<SyncSettings> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_sync(&mut serial_reader)
+    }
+
+    /// Sets the sync format of the output. Formats the device does not offer fail with
+    /// [`ControlError::Unsupported`], like devices without sync settings.
+    pub fn set_sync_format(&self, format: SyncFormat) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_sync(&mut serial_reader)?;
+        let command = Command::SetSyncFormat(format.code());
+        let set = self.query(&mut serial_reader, command, |reply| match *reply {
+            Reply::SyncFormat(code) if code == format.code() => Some(()),
+            _ => None,
+        })?;
+        set.map_err(|_| ControlError::Unsupported(format!("Sync format {}", format)))
+    }
+
+    /// Locks the output to `genlock`. Without a reference input, locking to it fails with
+    /// [`ControlError::Unsupported`].
+    pub fn set_genlock(&self, genlock: Genlock) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        self.query_sync(&mut serial_reader)?;
+        let command = Command::SetGenlock(genlock.code());
+        let set = self.query(&mut serial_reader, command, |reply| match *reply {
+            Reply::Genlock(code) if code == genlock.code() => Some(()),
+            _ => None,
+        })?;
+        set.map_err(|_| ControlError::Unsupported(format!("Genlock to {}", genlock)))
+    }
+
+    /// Sends `annotation` to an Annotator. Other devices fail with
+    /// [`ControlError::Unsupported`].
+    pub fn annotate(&self, annotation: Annotation) -> Result<()> {
+        let mut serial_reader = BufReader::new(self.open()?);
+        // Only Annotators know about tools, which keeps the commands off other devices.
+        self.query(
+            &mut serial_reader,
+            Command::QueryTool,
+            |reply| match reply {
+                Reply::Tool(_) => Some(()),
+                _ => None,
+            },
+        )?
+        .map_err(|_| ControlError::Unsupported("Annotation".to_string()))?;
+        let command = match annotation {
+            Annotation::Clear => Command::ClearAnnotations,
+            Annotation::Pointer => Command::SelectTool(POINTER_TOOL),
+            Annotation::Freeze(on) => Command::FreezeAnnotation(on),
+        };
+        let done = self.query(&mut serial_reader, command, |reply| {
+            match (annotation, reply) {
+                (Annotation::Clear, Reply::AnnotationsCleared)
+                | (Annotation::Pointer, Reply::Tool(POINTER_TOOL))
+                | (Annotation::Freeze(_), Reply::AnnotationFrozen(_)) => Some(()),
+                _ => None,
+            }
+        })?;
+        done.map_err(unexpected_code)
+    }
+
+    /// Passes `data` on to the display through the display-control port of the device, e.g. a
+    /// projector command, and returns what the display answers until it goes quiet.
+    pub fn send_to_display(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| ControlError::Unsupported("Display data over 4 GiB".to_string()))?;
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        self.query(
+            &mut serial_reader,
+            Command::InsertSerial(size),
+            |reply| match *reply {
+                Reply::Insert(n) if n == size => Some(()),
+                _ => None,
+            },
+        )?
+        .map_err(|_| ControlError::Unsupported("Display-control port".to_string()))?;
+        let mut answer = serial_reader.buffer().to_vec();
+        let mut port = serial_reader.into_inner();
+        port.write_all(data)?;
+        answer.extend(read_until_quiet(&mut port)?);
+        Ok(answer)
+    }
+
+    /// Sends `command` as it is, for a terminal on the device, and returns what the device
+    /// answers until it goes quiet.
+    pub fn send_raw(&self, command: &[u8]) -> Result<Vec<u8>> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        port.write_all(command)?;
+        read_until_quiet(&mut port)
+    }
+
+    /// Waits for the device to go quiet, keeping what it sends for
+    /// [`ExtronDevice::take_unsolicited`].
+    pub fn listen(&self) -> Result<()> {
+        let mut port = self.open()?;
+        let heard = read_until_quiet(&mut port)?;
+        self.keep_unsolicited(&heard);
         Ok(())
     }
+
+    /// Keeps `bytes` the device sent on its own for [`ExtronDevice::take_unsolicited`],
+    /// dropping the oldest lines beyond [`UNSOLICITED_LIMIT`].
+    fn keep_unsolicited(&self, bytes: &[u8]) {
+        let mut unsolicited = self.unsolicited.lock().unwrap();
+        unsolicited.extend_from_slice(bytes);
+        if unsolicited.len() > UNSOLICITED_LIMIT {
+            let excess = unsolicited.len() - UNSOLICITED_LIMIT;
+            // From the start of a line, so that no half line is taken for a whole one.
+            let start = unsolicited[excess..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(unsolicited.len(), |i| excess + i + 1);
+            unsolicited.drain(..start);
+        }
+    }
+
+    /// The complete lines the device sent on its own since the last call, without their line
+    /// endings. Only what came up while the port was open is seen, so call
+    /// [`ExtronDevice::listen`] regularly to catch the rest.
+    pub fn take_unsolicited(&self) -> Vec<String> {
+        let mut unsolicited = self.unsolicited.lock().unwrap();
+        let end = match unsolicited.iter().rposition(|&b| b == b'\n') {
+            Some(i) => i + 1,
+            None => return Vec::new(),
+        };
+        let lines: Vec<u8> = unsolicited.drain(..end).collect();
+        lines
+            .split(|&b| b == b'\n')
+            .map(sis::text)
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    pub fn firmware_version(&self) -> Result<String> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        let version = self.query(
+            &mut serial_reader,
+            Command::FirmwareVersion,
+            |reply| match reply {
+                Reply::Text(version) => Some(version.clone()),
+                Reply::Number(n) => Some(n.to_string()),
+                _ => None,
+            },
+        )?;
+        version.map_err(|_| ControlError::Unsupported("Firmware version".to_string()))
+    }
+
+    /// The part number and firmware version, either left out when the device does not answer
+    /// the query.
+    pub fn info(&self) -> Result<DeviceInfo> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        let text = |reply: &Reply| match reply {
+            Reply::Text(text) => Some(text.clone()),
+            Reply::Number(n) => Some(n.to_string()),
+            _ => None,
+        };
+        let part_number = self
+            .query(&mut serial_reader, Command::PartNumber, text)?
+            .ok();
+        let firmware = self
+            .query(&mut serial_reader, Command::FirmwareVersion, text)?
+            .ok();
+        Ok(DeviceInfo {
+            part_number,
+            firmware,
+        })
+    }
+
+    /// Internal temperature in degrees Celsius.
+    pub fn temperature(&self) -> Result<u32> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        let temperature = self.query(&mut serial_reader, Command::Temperature, Reply::number)?;
+        temperature.map_err(|_| ControlError::Unsupported("Temperature".to_string()))
+    }
+
+    /// The temperature of the device and the signal on its selected input, as far as the
+    /// device tells them.
+    pub fn health(&self) -> Result<DeviceHealth> {
+        fn supported<T>(result: Result<T>) -> Result<Option<T>> {
+            match result {
+                Ok(value) => Ok(Some(value)),
+                Err(ControlError::Unsupported(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        Ok(DeviceHealth {
+            temperature: supported(self.temperature())?,
+            signal: supported(self.selected_signal())?,
+        })
+    }
+
+    /// The input selected and whether it has a signal, which tells a black screen caused by
+    /// the source from one caused by the device.
+    pub fn selected_signal(&self) -> Result<(u32, bool)> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        let input = self.query_input(&mut serial_reader)?;
+        let present = self.query(
+            &mut serial_reader,
+            Command::SignalPresence,
+            |reply| match reply {
+                Reply::Signal(flags) => input
+                    .checked_sub(1)
+                    .and_then(|i| flags.get(i as usize))
+                    .copied(),
+                _ => None,
+            },
+        )?;
+        let present =
+            present.map_err(|_| ControlError::Unsupported("Signal presence".to_string()))?;
+        Ok((input, present))
+    }
+
+    /// Sends `image` to the device as its new firmware, calling `progress` with the bytes
+    /// sent so far and the total, and waits for the device to come back after restarting.
+    /// The device must not lose power or its connection meanwhile.
+    pub fn upload_firmware(
+        &self,
+        image: &[u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<FirmwareUpdate> {
+        let size = u32::try_from(image.len())
+            .map_err(|_| ControlError::Unsupported("Firmware images over 4 GiB".to_string()))?;
+        let before = self.firmware_version()?;
+
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+        self.query(
+            &mut serial_reader,
+            Command::UploadFirmware(size),
+            |reply| match *reply {
+                Reply::Upload(n) if n == size => Some(()),
+                _ => None,
+            },
+        )?
+        .map_err(|_| ControlError::Unsupported("Firmware upload".to_string()))?;
+        let mut sent = 0;
+        for chunk in image.chunks(UPLOAD_CHUNK) {
+            serial_reader.get_mut().write_all(chunk)?;
+            sent += chunk.len();
+            progress(sent, image.len());
+        }
+        let reply = read_line_within(&mut serial_reader, FLASH_TIMEOUT)?;
+        match Reply::parse(&reply) {
+            Reply::Upload(n) if n == size => {}
+            _ => return Err(ControlError::UnexpectedReply(sis::text(&reply))),
+        }
+        drop(serial_reader);
+
+        // The device restarts with the new firmware, which takes its port away for a while.
+        let deadline = Instant::now() + RESTART_TIMEOUT;
+        let after = loop {
+            match self.firmware_version() {
+                Ok(version) => break version,
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => std::thread::sleep(Duration::from_secs(1)),
+            }
+        };
+        Ok(FirmwareUpdate { before, after })
+    }
+
+    pub fn status(&self) -> Result<DeviceStatus> {
+        let mut port = self.open()?;
+        port.clear_input()?;
+        let mut serial_reader = BufReader::new(port);
+
+        let input = self.query_input(&mut serial_reader)?;
+        let volume = self
+            .query(&mut serial_reader, Command::QueryVolume, |reply| {
+                reply.number().and_then(|n| u8::try_from(n).ok())
+            })?
+            .ok();
+        let mute = self
+            .query(&mut serial_reader, Command::QueryMute, Reply::number)?
+            .ok()
+            .map(|mute| mute != 0);
+        let pip = self.query_pip_mode(&mut serial_reader)?;
+        let sources = self.query_windows(&mut serial_reader, pip.map_or(0, |pip| pip.windows()))?;
+
+        Ok(DeviceStatus {
+            input,
+            label: self.label_of(input).map(str::to_string),
+            volume,
+            mute,
+            pip,
+            sources,
+        })
+    }
 }
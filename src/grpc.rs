@@ -0,0 +1,179 @@
+//! gRPC transport, serving the operations of `proto/control_dsc.proto` through the same command
+//! loop as the Cap'n Proto interface.
+
+use crate::error::ControlError;
+use crate::extron::Input;
+use crate::server::{call, is_loopback, scan_over, Request, ServerRequest};
+use futures::Stream;
+use std::convert::TryFrom;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+
+/// Who the history names for the changes made through here.
+const CLIENT: &str = "gRPC";
+
+mod proto {
+    tonic::include_proto!("control_dsc");
+}
+
+use proto::control_extron_server::{ControlExtron, ControlExtronServer};
+
+impl From<ControlError> for Status {
+    fn from(e: ControlError) -> Self {
+        let message = e.to_string();
+        match e {
+            ControlError::DeviceNotFound(_) => Status::not_found(message),
+            ControlError::InvalidInput { .. } | ControlError::MalformedInput(_) => {
+                Status::invalid_argument(message)
+            }
+            ControlError::Unsupported(_) => Status::failed_precondition(message),
+            ControlError::Timeout(_) => Status::deadline_exceeded(message),
+            ControlError::SerialIo(_)
+            | ControlError::UnexpectedReply(_)
+            | ControlError::Busy(_)
+            | ControlError::Connection(_)
+            | ControlError::Maintenance(_)
+            | ControlError::Cancelled => Status::unavailable(message),
+            ControlError::OldServer { .. } => Status::unimplemented(message),
+            ControlError::NotAllowed(_) | ControlError::Unauthorized(_) => {
+                Status::permission_denied(message)
+            }
+            ControlError::Rpc(_) => Status::internal(message),
+        }
+    }
+}
+
+struct Service {
+    tx_channel: mpsc::Sender<Request>,
+    cancel: CancellationToken,
+    /// Whether administrative calls are only served to connections from this machine.
+    local_admin: bool,
+}
+
+impl Service {
+    /// Fails `method` for a `request` from another machine when only local connections may
+    /// make administrative calls.
+    fn check_admin<T>(&self, method: &str, request: &Request<T>) -> Result<(), Status> {
+        if self.local_admin && !request.remote_addr().is_some_and(|peer| is_loopback(&peer)) {
+            info!("Refused {} from another machine", method);
+            return Err(ControlError::NotAllowed(method.to_string()).into());
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl ControlExtron for Service {
+    async fn list_devices(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::DeviceList>, Status> {
+        let (devices, _) =
+            call(self.tx_channel.clone(), CLIENT, ServerRequest::ListDevices).await?;
+        let devices = devices
+            .into_iter()
+            .map(|device| proto::ExtronDevice {
+                name: device.name,
+                path: device.device_path,
+            })
+            .collect();
+        Ok(Response::new(proto::DeviceList { devices }))
+    }
+
+    async fn select_input(
+        &self,
+        request: Request<proto::SelectInputRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let proto::SelectInputRequest { name, input } = request.into_inner();
+        let input: Input = input.parse()?;
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Select { name, input, reply }
+        })
+        .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn rescan(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.check_admin("Rescan", &request)?;
+        let ticket = call(self.tx_channel.clone(), CLIENT, ServerRequest::Rescan).await?;
+        scan_over(ticket).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn set_volume(
+        &self,
+        request: Request<proto::SetVolumeRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let proto::SetVolumeRequest { name, level } = request.into_inner();
+        let level = u8::try_from(level)
+            .map_err(|_| Status::invalid_argument(format!("Volume {} out of range", level)))?;
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Volume { name, level, reply }
+        })
+        .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn set_mute(
+        &self,
+        request: Request<proto::SetMuteRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let proto::SetMuteRequest { name, mute } = request.into_inner();
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Mute { name, mute, reply }
+        })
+        .await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<proto::Device>,
+    ) -> Result<Response<proto::DeviceStatus>, Status> {
+        let name = request.into_inner().name;
+        let status = call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Status { name, reply }
+        })
+        .await?;
+        Ok(Response::new(proto::DeviceStatus {
+            input: status.input,
+            volume: status.volume.map(u32::from),
+            mute: status.mute,
+        }))
+    }
+
+    async fn stop_server(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        self.check_admin("StopServer", &request)?;
+        self.cancel.cancel();
+        Ok(Response::new(proto::Empty {}))
+    }
+}
+
+/// Serves the connections from `incoming` until `cancel` fires.
+pub(crate) async fn serve<I>(
+    incoming: I,
+    tx_channel: mpsc::Sender<Request>,
+    local_admin: bool,
+    cancel: CancellationToken,
+) -> std::io::Result<()>
+where
+    I: Stream<Item = std::io::Result<tokio::net::TcpStream>>,
+{
+    let service = Service {
+        tx_channel,
+        cancel: cancel.clone(),
+        local_admin,
+    };
+    tonic::transport::Server::builder()
+        .add_service(ControlExtronServer::new(service))
+        .serve_with_incoming_shutdown(incoming, cancel.cancelled())
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+}
@@ -0,0 +1,270 @@
+//! Limits on the health of the devices, for the [`crate::server::ServerBuilder::health`] of a
+//! server, so that failing units get flagged before they are needed.
+//!
+//! A device crossing a limit raises a [`HealthAlert`] once. The alert is raised again only
+//! after the device was back within the limit. Loss of the signal on the selected input is
+//! reported with events of its own, as a black screen is usually down to the source rather
+//! than the device.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Limits beyond which a device gets flagged. Unset limits are not checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thresholds {
+    /// Highest internal temperature in degrees Celsius.
+    pub max_temperature: Option<u32>,
+    /// Most error codes a device may send on its own within `error_window`.
+    pub max_errors: Option<usize>,
+    pub error_window: Duration,
+    /// Longest a device may be unreachable.
+    pub max_offline: Option<Duration>,
+    /// Longest the selected input of a device may be without a signal, which keeps a source
+    /// briefly dropping out, e.g. while changing resolution, from being reported.
+    pub signal_loss: Option<Duration>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            max_temperature: None,
+            max_errors: None,
+            error_window: Duration::from_secs(600),
+            max_offline: None,
+            signal_loss: None,
+        }
+    }
+}
+
+/// The limit a device crossed, with the value that crossed it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthAlert {
+    /// Internal temperature in degrees Celsius.
+    Temperature(u32),
+    /// Error codes sent within the window.
+    Errors(usize),
+    /// How long the device has been unreachable.
+    Offline(Duration),
+}
+
+impl HealthAlert {
+    /// Name of the limit, as in the `CONTROL_RS_ALERT` of hooks.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HealthAlert::Temperature(_) => "temperature",
+            HealthAlert::Errors(_) => "errors",
+            HealthAlert::Offline(_) => "offline",
+        }
+    }
+}
+
+impl fmt::Display for HealthAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthAlert::Temperature(celsius) => write!(f, "temperature at {} °C", celsius),
+            HealthAlert::Errors(n) => write!(f, "{} errors reported", n),
+            HealthAlert::Offline(duration) => {
+                write!(f, "unreachable for {} s", duration.as_secs())
+            }
+        }
+    }
+}
+
+/// Change of the signal on the selected input of a device, with the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Signal {
+    Lost(u32),
+    /// The signal came back, or another input was selected.
+    Back(u32),
+}
+
+/// Tracks the devices against the thresholds.
+pub(crate) struct Monitor {
+    thresholds: Thresholds,
+    /// When the errors within the window came in, by device.
+    errors: HashMap<String, VecDeque<Instant>>,
+    offline_since: HashMap<String, Instant>,
+    /// Input selected without a signal and since when, by device.
+    no_signal: HashMap<String, (u32, Instant)>,
+    /// Input whose signal was reported lost, by device.
+    signal_lost: HashMap<String, u32>,
+    /// Alerts raised and not cleared yet, by device and kind.
+    raised: HashSet<(String, &'static str)>,
+}
+
+impl Monitor {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Monitor {
+            thresholds,
+            errors: HashMap::new(),
+            offline_since: HashMap::new(),
+            no_signal: HashMap::new(),
+            signal_lost: HashMap::new(),
+            raised: HashSet::new(),
+        }
+    }
+
+    /// Whether there is anything to check.
+    pub fn is_active(&self) -> bool {
+        let t = &self.thresholds;
+        t.max_temperature.is_some()
+            || t.max_errors.is_some()
+            || t.max_offline.is_some()
+            || t.signal_loss.is_some()
+    }
+
+    pub fn checks_temperature(&self) -> bool {
+        self.thresholds.max_temperature.is_some()
+    }
+
+    pub fn checks_signal(&self) -> bool {
+        self.thresholds.signal_loss.is_some()
+    }
+
+    /// Raises `alert` unless it is already raised for `device`, or clears the alert of that
+    /// `kind` for `None`.
+    fn raise(
+        &mut self,
+        device: &str,
+        kind: &'static str,
+        alert: Option<HealthAlert>,
+    ) -> Option<HealthAlert> {
+        match alert {
+            Some(alert) if self.raised.insert((device.to_string(), kind)) => Some(alert),
+            Some(_) => None,
+            None => {
+                self.raised.remove(&(device.to_string(), kind));
+                None
+            }
+        }
+    }
+
+    pub fn temperature(&mut self, device: &str, celsius: u32) -> Option<HealthAlert> {
+        let alert = self
+            .thresholds
+            .max_temperature
+            .filter(|&max| celsius > max)
+            .map(|_| HealthAlert::Temperature(celsius));
+        self.raise(device, "temperature", alert)
+    }
+
+    /// Counts `new` errors of `device` at `now`, forgetting those that left the window.
+    pub fn errors(&mut self, device: &str, new: usize, now: Instant) -> Option<HealthAlert> {
+        let max = self.thresholds.max_errors?;
+        let window = self.thresholds.error_window;
+        let errors = self.errors.entry(device.to_string()).or_default();
+        errors.resize(errors.len() + new, now);
+        while errors
+            .front()
+            .is_some_and(|&time| now.duration_since(time) > window)
+        {
+            errors.pop_front();
+        }
+        let alert = Some(errors.len())
+            .filter(|&n| n > max)
+            .map(HealthAlert::Errors);
+        self.raise(device, "errors", alert)
+    }
+
+    /// Takes in whether the selected `input` of `device` has a signal at `now`.
+    pub fn signal(
+        &mut self,
+        device: &str,
+        input: u32,
+        present: bool,
+        now: Instant,
+    ) -> Option<Signal> {
+        let max = self.thresholds.signal_loss?;
+        if present {
+            self.no_signal.remove(device);
+        } else {
+            let since = self
+                .no_signal
+                .entry(device.to_string())
+                .or_insert((input, now));
+            if since.0 != input {
+                *since = (input, now);
+            }
+        }
+        match (self.signal_lost.get(device), self.no_signal.get(device)) {
+            (Some(&lost), Some(&(input, _))) if lost == input => None,
+            (Some(&lost), _) => {
+                self.signal_lost.remove(device);
+                Some(Signal::Back(lost))
+            }
+            (None, Some(&(input, since))) if now.duration_since(since) >= max => {
+                self.signal_lost.insert(device.to_string(), input);
+                Some(Signal::Lost(input))
+            }
+            (None, _) => None,
+        }
+    }
+
+    pub fn reachable(&mut self, device: &str) {
+        self.offline_since.remove(device);
+        self.raise(device, "offline", None);
+    }
+
+    pub fn unreachable(&mut self, device: &str, now: Instant) {
+        self.offline_since.entry(device.to_string()).or_insert(now);
+    }
+
+    /// What is tracked of every device at `now`, by name, for state dumps.
+    pub fn dump(&self, now: Instant) -> serde_json::Value {
+        let devices: BTreeSet<&String> = self
+            .errors
+            .keys()
+            .chain(self.offline_since.keys())
+            .chain(self.no_signal.keys())
+            .chain(self.signal_lost.keys())
+            .chain(self.raised.iter().map(|(device, _)| device))
+            .collect();
+        let dump = devices.into_iter().map(|device| {
+            let mut alerts: Vec<_> = self
+                .raised
+                .iter()
+                .filter(|(d, _)| d == device)
+                .map(|(_, kind)| *kind)
+                .collect();
+            alerts.sort_unstable();
+            let health = serde_json::json!({
+                "recent_errors": self.errors.get(device).map_or(0, VecDeque::len),
+                "offline_seconds": self
+                    .offline_since
+                    .get(device)
+                    .map(|since| now.duration_since(*since).as_secs()),
+                "no_signal": self.no_signal.get(device).map(|(input, since)| {
+                    serde_json::json!({
+                        "input": input,
+                        "seconds": now.duration_since(*since).as_secs(),
+                    })
+                }),
+                "signal_lost": self.signal_lost.get(device),
+                "alerts": alerts,
+            });
+            (device.clone(), health)
+        });
+        serde_json::Value::Object(dump.collect())
+    }
+
+    /// Devices unreachable for longer than allowed at `now`, that were not flagged yet.
+    pub fn offline(&mut self, now: Instant) -> Vec<(String, HealthAlert)> {
+        let max = match self.thresholds.max_offline {
+            Some(max) => max,
+            None => return Vec::new(),
+        };
+        let over: Vec<_> = self
+            .offline_since
+            .iter()
+            .map(|(device, since)| (device.clone(), now.duration_since(*since)))
+            .filter(|(_, offline)| *offline > max)
+            .collect();
+        over.into_iter()
+            .filter_map(|(device, offline)| {
+                let alert = self.raise(&device, "offline", Some(HealthAlert::Offline(offline)));
+                alert.map(|alert| (device, alert))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,104 @@
+//! The input changes of every device, kept by the server with who made them, so that a source
+//! switched in the middle of a talk can be traced to a client, a schedule or the front panel.
+//!
+//! With them goes what undoes the last change made through the server on every device, for a
+//! while after it was made.
+
+use crate::extron::{Input, InputChange};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Changes kept per device. Older ones are dropped.
+const HISTORY_LENGTH: usize = 200;
+
+/// Who the front panel changes are by. Devices report them on their own in verbose mode,
+/// which their USB and RS-232 ports are in unless told otherwise.
+pub(crate) const FRONT_PANEL: &str = "front panel";
+
+/// What puts a device back as it was before a change.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Undo {
+    Input(Input),
+    Volume(u8),
+    Mute(bool),
+}
+
+impl fmt::Display for Undo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Undo::Input(input) => write!(f, "input {}", input),
+            Undo::Volume(level) => write!(f, "volume {}", level),
+            Undo::Mute(mute) => write!(f, "mute {}", if *mute { "on" } else { "off" }),
+        }
+    }
+}
+
+/// The latest input changes of every device changed while the server ran.
+pub(crate) struct History {
+    devices: HashMap<String, VecDeque<InputChange>>,
+    /// What undoes the last change made through the server, and when it was made.
+    undo: HashMap<String, (Instant, Undo)>,
+    /// How long a change can be undone.
+    window: Duration,
+}
+
+impl History {
+    /// Keeps changes undoable for `window`.
+    pub fn new(window: Duration) -> Self {
+        History {
+            devices: HashMap::new(),
+            undo: HashMap::new(),
+            window,
+        }
+    }
+
+    /// Adds the change of `device` to `to` by `by`. Selecting the input it is on already is
+    /// kept too, as whoever did it may still have meant something else.
+    pub fn record(&mut self, device: &str, by: &str, to: Input) {
+        let changes = self.devices.entry(device.to_string()).or_default();
+        let from = changes.back().map(|change| change.to.clone());
+        if changes.len() == HISTORY_LENGTH {
+            changes.pop_front();
+        }
+        changes.push_back(InputChange {
+            time: SystemTime::now(),
+            by: by.to_string(),
+            from: from.clone(),
+            to,
+        });
+        // A change at the front panel leaves nothing the server did to undo.
+        let undo = from.filter(|_| by != FRONT_PANEL).map(Undo::Input);
+        self.did(device, undo);
+    }
+
+    /// Notes a change made through the server on `device`, which `undo` undoes, `None` when
+    /// it cannot be undone.
+    pub fn did(&mut self, device: &str, undo: Option<Undo>) {
+        match undo {
+            Some(undo) => self.undo.insert(device.to_string(), (Instant::now(), undo)),
+            None => self.undo.remove(device),
+        };
+    }
+
+    /// What undoes the last change made through the server on `device`, if it was made within
+    /// the undo window.
+    pub fn undo(&self, device: &str) -> Option<Undo> {
+        self.undo
+            .get(device)
+            .filter(|(made, _)| made.elapsed() <= self.window)
+            .map(|(_, undo)| undo.clone())
+    }
+
+    /// How long a change can be undone.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Changes of the device called `name`, oldest first, or `None` if it never changed.
+    pub fn changes(&self, name: &str) -> Option<Vec<InputChange>> {
+        self.devices
+            .get(name)
+            .map(|changes| changes.iter().cloned().collect())
+    }
+}
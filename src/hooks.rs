@@ -0,0 +1,155 @@
+use crate::config::HookConfig;
+use anyhow::{bail, Result};
+use control_dsc::server::ServerEvent;
+use std::process::{Command, Stdio};
+
+/// Names of the events hooks can run on, as given in the configuration.
+const EVENTS: &[&str] = &[
+    "listening",
+    "connected",
+    "rejected",
+    "devices_scanned",
+    "input_selected",
+    "volume_changed",
+    "mute_changed",
+    "device_offline",
+    "health_alert",
+    "signal_lost",
+    "signal_restored",
+    "maintenance_started",
+    "maintenance_ended",
+];
+
+/// Name and environment variables describing `event`.
+fn describe(event: &ServerEvent) -> (&'static str, Vec<(&'static str, String)>) {
+    match event {
+        ServerEvent::Listening(addr) => {
+            ("listening", vec![("CONTROL_RS_ADDRESS", addr.to_string())])
+        }
+        ServerEvent::Connected(addr) => {
+            ("connected", vec![("CONTROL_RS_ADDRESS", addr.to_string())])
+        }
+        ServerEvent::Rejected(addr) => ("rejected", vec![("CONTROL_RS_ADDRESS", addr.to_string())]),
+        ServerEvent::DevicesScanned(devices) => (
+            "devices_scanned",
+            vec![(
+                "CONTROL_RS_DEVICES",
+                devices
+                    .iter()
+                    .map(|device| device.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )],
+        ),
+        ServerEvent::InputSelected { device, input } => (
+            "input_selected",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                ("CONTROL_RS_INPUT", input.to_string()),
+            ],
+        ),
+        ServerEvent::VolumeChanged { device, level } => (
+            "volume_changed",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                ("CONTROL_RS_VOLUME", level.to_string()),
+            ],
+        ),
+        ServerEvent::MuteChanged { device, mute } => (
+            "mute_changed",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                (
+                    "CONTROL_RS_MUTE",
+                    if *mute { "on" } else { "off" }.to_string(),
+                ),
+            ],
+        ),
+        ServerEvent::DeviceOffline(device) => (
+            "device_offline",
+            vec![("CONTROL_RS_DEVICE", device.clone())],
+        ),
+        ServerEvent::SignalLost { device, input } => (
+            "signal_lost",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                ("CONTROL_RS_INPUT", input.to_string()),
+            ],
+        ),
+        ServerEvent::SignalRestored { device, input } => (
+            "signal_restored",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                ("CONTROL_RS_INPUT", input.to_string()),
+            ],
+        ),
+        ServerEvent::HealthAlert { device, alert } => (
+            "health_alert",
+            vec![
+                ("CONTROL_RS_DEVICE", device.clone()),
+                ("CONTROL_RS_ALERT", alert.kind().to_string()),
+                ("CONTROL_RS_MESSAGE", alert.to_string()),
+            ],
+        ),
+        ServerEvent::MaintenanceStarted(message) => (
+            "maintenance_started",
+            vec![("CONTROL_RS_MESSAGE", message.clone())],
+        ),
+        ServerEvent::MaintenanceEnded => ("maintenance_ended", Vec::new()),
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("/bin/sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Starts the commands of `hooks` for `event` without waiting for them, so that a slow hook
+/// does not hold up the server. Their exit status is logged when they finish.
+fn run(hooks: &[HookConfig], event: &ServerEvent) {
+    let (name, env) = describe(event);
+    for hook in hooks.iter().filter(|hook| hook.event == name) {
+        let child = shell(&hook.command)
+            .env("CONTROL_RS_EVENT", name)
+            .envs(env.iter().cloned())
+            .stdin(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                info!("Cannot run hook '{}': {}", hook.command, e);
+                continue;
+            }
+        };
+        let command = hook.command.clone();
+        std::thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => info!("Hook '{}' failed: {}", command, status),
+            Err(e) => info!("Hook '{}' failed: {}", command, e),
+        });
+    }
+}
+
+/// Checks the hooks of the configuration and returns the event hook running them.
+pub fn event_hook(hooks: &[HookConfig]) -> Result<impl Fn(&ServerEvent) + Send + Sync> {
+    for hook in hooks {
+        if !EVENTS.contains(&hook.event.as_str()) {
+            bail!(
+                "Unknown event '{}' for hook, expected one of {}",
+                hook.event,
+                EVENTS.join(", ")
+            );
+        }
+    }
+    let hooks = hooks.to_vec();
+    Ok(move |event: &ServerEvent| run(&hooks, event))
+}
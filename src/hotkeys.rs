@@ -0,0 +1,471 @@
+//! Hotkeys on an Elgato Stream Deck or another HID macro pad attached to the server host. Key
+//! presses run their actions through the command loop, like requests of the network clients,
+//! and the Stream Deck shows on every key whether the inputs it selects are the active ones.
+
+use crate::extron::Input;
+use crate::server::{call, Request, ServerEvent, ServerRequest};
+use hidapi::{HidApi, HidDevice};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+
+const ELGATO_VENDOR_ID: u16 = 0x0fd9;
+
+/// How long a read waits for a key before the pad thread looks for images and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Size of the reports carrying key images, including the report ID.
+const IMAGE_REPORT_LEN: usize = 1024;
+const IMAGE_HEADER_LEN: usize = 8;
+
+/// Who the history names for the changes made through here.
+const CLIENT: &str = "hotkeys";
+
+/// Pad to read keys from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pad {
+    /// The first Stream Deck found. Keys are numbered from 0, left to right and top to bottom.
+    StreamDeck,
+    /// A macro pad sending boot keyboard reports. Keys are numbered by their HID usage code,
+    /// and have no images.
+    Generic { vendor_id: u16, product_id: u16 },
+}
+
+/// Something a key does, in the order given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Select { device: String, input: Input },
+    Volume { device: String, level: u8 },
+    Mute { device: String, mute: bool },
+    Sleep(Duration),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Key {
+    pub actions: Vec<Action>,
+    /// Shown while the key is not active.
+    pub image: Option<PathBuf>,
+    /// Shown while all inputs the key selects are the selected ones. Defaults to `image`.
+    pub active_image: Option<PathBuf>,
+}
+
+/// Keys and what they do, for [`crate::server::ServerBuilder::hotkeys`].
+#[derive(Debug, Clone)]
+pub struct Hotkeys {
+    pub pad: Pad,
+    pub keys: HashMap<u8, Key>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Model {
+    /// Stream Decks with JPEG key images of `image_size` pixels square.
+    StreamDeck {
+        keys: usize,
+        image_size: u32,
+        rotate: bool,
+    },
+    Generic,
+}
+
+impl Model {
+    fn stream_deck(product_id: u16) -> Option<Self> {
+        let (keys, image_size, rotate) = match product_id {
+            // Original V2 and MK.2
+            0x006d | 0x0080 => (15, 72, true),
+            // XL and XL V2
+            0x006c | 0x008f => (32, 96, true),
+            // Plus
+            0x0084 => (8, 120, false),
+            _ => return None,
+        };
+        Some(Model::StreamDeck {
+            keys,
+            image_size,
+            rotate,
+        })
+    }
+}
+
+fn hid_error(e: hidapi::HidError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Opened pad with the key images in its format, made before the server starts so that a
+/// missing pad or a broken image stops it right away.
+pub(crate) struct OpenPad {
+    device: HidDevice,
+    model: Model,
+    keys: HashMap<u8, Key>,
+    images: HashMap<u8, KeyImages>,
+}
+
+struct KeyImages {
+    image: Option<Arc<Vec<u8>>>,
+    active_image: Option<Arc<Vec<u8>>>,
+}
+
+pub(crate) fn open(hotkeys: Hotkeys) -> io::Result<OpenPad> {
+    let api = HidApi::new().map_err(hid_error)?;
+    let (device, model) = match hotkeys.pad {
+        Pad::StreamDeck => {
+            let (info, model) = api
+                .device_list()
+                .filter(|info| info.vendor_id() == ELGATO_VENDOR_ID)
+                .find_map(|info| Model::stream_deck(info.product_id()).map(|m| (info, m)))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "No supported Stream Deck found")
+                })?;
+            (info.open_device(&api).map_err(hid_error)?, model)
+        }
+        Pad::Generic {
+            vendor_id,
+            product_id,
+        } => (
+            api.open(vendor_id, product_id).map_err(hid_error)?,
+            Model::Generic,
+        ),
+    };
+
+    let mut images = HashMap::new();
+    if let Model::StreamDeck {
+        keys,
+        image_size,
+        rotate,
+    } = model
+    {
+        for (key, config) in &hotkeys.keys {
+            if usize::from(*key) >= keys {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("The Stream Deck has no key {}", key),
+                ));
+            }
+            let load = |path: &Option<PathBuf>| -> io::Result<Option<Arc<Vec<u8>>>> {
+                path.as_ref()
+                    .map(|path| key_image(path, image_size, rotate).map(Arc::new))
+                    .transpose()
+            };
+            let image = load(&config.image)?;
+            let active_image = load(&config.active_image)?.or_else(|| image.clone());
+            images.insert(
+                *key,
+                KeyImages {
+                    image,
+                    active_image,
+                },
+            );
+        }
+    }
+    Ok(OpenPad {
+        device,
+        model,
+        keys: hotkeys.keys,
+        images,
+    })
+}
+
+/// Reads the image at `path` and turns it into a JPEG as the keys show it.
+fn key_image(path: &Path, size: u32, rotate: bool) -> io::Result<Vec<u8>> {
+    let invalid = |e: image::ImageError| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let mut image = image::open(path).map_err(invalid)?.resize_exact(
+        size,
+        size,
+        image::imageops::FilterType::Lanczos3,
+    );
+    if rotate {
+        image = image.rotate180();
+    }
+    let image = image::DynamicImage::ImageRgb8(image.to_rgb8());
+    let mut jpeg = Vec::new();
+    image
+        .write_to(
+            &mut io::Cursor::new(&mut jpeg),
+            image::ImageOutputFormat::Jpeg(90),
+        )
+        .map_err(invalid)?;
+    Ok(jpeg)
+}
+
+/// Keys pressed since the previous report, from a report read from the pad.
+fn pressed(model: Model, report: &[u8], previous: &mut Vec<u8>) -> Vec<u8> {
+    let down: Vec<u8> = match model {
+        Model::StreamDeck { keys, .. } => {
+            // Dials and the touch strip of the Plus report with a different second byte.
+            if report.len() < 4 + keys || report[0] != 0x01 || report[1] != 0x00 {
+                return Vec::new();
+            }
+            (0..keys as u8)
+                .filter(|key| report[4 + usize::from(*key)] != 0)
+                .collect()
+        }
+        Model::Generic => {
+            // Modifiers and a reserved byte, then up to six usage codes, after the report ID
+            // on pads that use one.
+            let codes = match report.len() {
+                9 => &report[3..],
+                n if n >= 3 => &report[2..],
+                _ => return Vec::new(),
+            };
+            codes.iter().copied().filter(|code| *code > 3).collect()
+        }
+    };
+    let new = down
+        .iter()
+        .copied()
+        .filter(|key| !previous.contains(key))
+        .collect();
+    *previous = down;
+    new
+}
+
+fn write_image(device: &HidDevice, key: u8, jpeg: &[u8]) -> hidapi::HidResult<()> {
+    let payload = IMAGE_REPORT_LEN - IMAGE_HEADER_LEN;
+    let pages = jpeg.chunks(payload).collect::<Vec<_>>();
+    for (page, chunk) in pages.iter().enumerate() {
+        let mut report = vec![0u8; IMAGE_REPORT_LEN];
+        let last = page + 1 == pages.len();
+        report[..IMAGE_HEADER_LEN].copy_from_slice(&[
+            0x02,
+            0x07,
+            key,
+            last as u8,
+            chunk.len() as u8,
+            (chunk.len() >> 8) as u8,
+            page as u8,
+            (page >> 8) as u8,
+        ]);
+        report[IMAGE_HEADER_LEN..IMAGE_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+        device.write(&report)?;
+    }
+    Ok(())
+}
+
+/// Owns the device: passes key presses on and shows the images it is handed, until `cancel`
+/// fires or the pad goes away.
+fn pad_thread(
+    device: HidDevice,
+    model: Model,
+    presses: mpsc::Sender<u8>,
+    images: std_mpsc::Receiver<(u8, Arc<Vec<u8>>)>,
+    cancel: CancellationToken,
+) {
+    let mut report = [0u8; 512];
+    let mut down = Vec::new();
+    while !cancel.is_cancelled() {
+        while let Ok((key, jpeg)) = images.try_recv() {
+            if let Err(e) = write_image(&device, key, &jpeg) {
+                info!("Cannot set the image of key {}: {}", key, e);
+            }
+        }
+        let n = match device.read_timeout(&mut report, POLL_INTERVAL.as_millis() as i32) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Cannot read the hotkey pad: {}", e);
+                return;
+            }
+        };
+        for key in pressed(model, &report[..n], &mut down) {
+            if presses.blocking_send(key).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+struct Handler {
+    keys: HashMap<u8, Key>,
+    images: HashMap<u8, KeyImages>,
+    tx_channel: mpsc::Sender<Request>,
+    image_tx: std_mpsc::Sender<(u8, Arc<Vec<u8>>)>,
+    /// Selected input by device, as far as known.
+    selected: HashMap<String, u32>,
+}
+
+impl Handler {
+    async fn press(&self, key: u8) {
+        let actions = match self.keys.get(&key) {
+            Some(config) => &config.actions,
+            None => return,
+        };
+        debug!("Hotkey {} pressed", key);
+        for action in actions {
+            let tx_channel = self.tx_channel.clone();
+            let result = match action.clone() {
+                Action::Select { device, input } => {
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Select {
+                        name: device,
+                        input,
+                        reply,
+                    })
+                    .await
+                }
+                Action::Volume { device, level } => {
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Volume {
+                        name: device,
+                        level,
+                        reply,
+                    })
+                    .await
+                }
+                Action::Mute { device, mute } => {
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Mute {
+                        name: device,
+                        mute,
+                        reply,
+                    })
+                    .await
+                }
+                Action::Sleep(duration) => {
+                    tokio::time::sleep(duration).await;
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                info!("Hotkey {}: {}", key, e);
+                return;
+            }
+        }
+    }
+
+    /// Devices the keys select inputs on.
+    fn devices(&self) -> Vec<String> {
+        let mut devices = Vec::new();
+        for action in self.keys.values().flat_map(|key| &key.actions) {
+            if let Action::Select { device, .. } = action {
+                if !devices.contains(device) {
+                    devices.push(device.clone());
+                }
+            }
+        }
+        devices
+    }
+
+    /// Asks the device for its selected input, which also tells the number of an input
+    /// selected by name.
+    async fn refresh(&mut self, device: String) {
+        let status = call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Status {
+                name: device.clone(),
+                reply,
+            }
+        })
+        .await;
+        match status {
+            Ok(status) => {
+                self.selected.insert(device, status.input);
+            }
+            Err(e) => {
+                debug!("No status for hotkeys of {}: {}", device, e);
+                self.selected.remove(&device);
+            }
+        }
+    }
+
+    fn is_active(&self, key: &Key) -> bool {
+        let mut selects = key
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Select { device, input } => Some((device, input)),
+                _ => None,
+            })
+            .peekable();
+        selects.peek().is_some()
+            && selects.all(|(device, input)| match input {
+                Input::Number(n) => self.selected.get(device) == Some(n),
+                Input::Name(_) => false,
+            })
+    }
+
+    /// Shows the image for the current state on the keys that select inputs on `device`, or
+    /// on all keys.
+    fn show(&self, device: Option<&str>) {
+        for (n, key) in &self.keys {
+            let concerned = match device {
+                Some(device) => key.actions.iter().any(
+                    |action| matches!(action, Action::Select { device: d, .. } if d == device),
+                ),
+                None => true,
+            };
+            let images = match self.images.get(n) {
+                Some(images) if concerned => images,
+                _ => continue,
+            };
+            let image = if self.is_active(key) {
+                &images.active_image
+            } else {
+                &images.image
+            };
+            if let Some(image) = image {
+                let _ = self.image_tx.send((*n, image.clone()));
+            }
+        }
+    }
+
+    async fn refresh_all(&mut self) {
+        for device in self.devices() {
+            self.refresh(device).await;
+        }
+        self.show(None);
+    }
+}
+
+/// Handles the keys of `pad` until `cancel` fires or the pad goes away.
+pub(crate) async fn run(
+    pad: OpenPad,
+    tx_channel: mpsc::Sender<Request>,
+    mut events: broadcast::Receiver<ServerEvent>,
+    cancel: CancellationToken,
+) {
+    let (press_tx, mut presses) = mpsc::channel(16);
+    let (image_tx, image_rx) = std_mpsc::channel();
+    let thread_cancel = cancel.child_token();
+    let (device, model) = (pad.device, pad.model);
+    let pad_cancel = thread_cancel.clone();
+    let thread = tokio::task::spawn_blocking(move || {
+        pad_thread(device, model, press_tx, image_rx, pad_cancel)
+    });
+
+    let mut hotkeys = Handler {
+        keys: pad.keys,
+        images: pad.images,
+        tx_channel,
+        image_tx,
+        selected: HashMap::new(),
+    };
+    hotkeys.refresh_all().await;
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            key = presses.recv() => match key {
+                Some(key) => hotkeys.press(key).await,
+                None => break,
+            },
+            event = events.recv() => match event {
+                Ok(ServerEvent::InputSelected { device, .. }) => {
+                    if hotkeys.devices().contains(&device) {
+                        hotkeys.refresh(device.clone()).await;
+                        hotkeys.show(Some(&device));
+                    }
+                }
+                Ok(ServerEvent::DevicesScanned(_)) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    hotkeys.refresh_all().await
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+    thread_cancel.cancel();
+    let _ = thread.await;
+}
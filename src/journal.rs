@@ -0,0 +1,59 @@
+//! Messages the devices sent on their own, kept by the server so that faults that come and
+//! go, like a device restarting or someone at the front panel, can be traced afterwards.
+
+use crate::extron::{DeviceMessage, ExtronDevice};
+use std::collections::{HashMap, VecDeque};
+use std::time::SystemTime;
+
+/// Messages kept per device. Older ones are dropped.
+const JOURNAL_LENGTH: usize = 500;
+
+/// The latest messages of every device heard from, including those that went offline since.
+#[derive(Default)]
+pub(crate) struct Journal {
+    devices: HashMap<String, VecDeque<DeviceMessage>>,
+}
+
+impl Journal {
+    /// Adds what `device` sent since the last call, and returns it.
+    pub fn collect(&mut self, device: &ExtronDevice) -> Vec<String> {
+        let lines = device.take_unsolicited();
+        if lines.is_empty() {
+            return lines;
+        }
+        let messages = self.devices.entry(device.name.clone()).or_default();
+        let time = SystemTime::now();
+        for text in &lines {
+            info!("{} says {}", device.name, text);
+            if messages.len() == JOURNAL_LENGTH {
+                messages.pop_front();
+            }
+            messages.push_back(DeviceMessage {
+                time,
+                text: text.clone(),
+            });
+        }
+        lines
+    }
+
+    /// Puts back `messages` of `device`, oldest first, as they were saved.
+    pub fn restore(&mut self, device: &str, mut messages: Vec<DeviceMessage>) {
+        let excess = messages.len().saturating_sub(JOURNAL_LENGTH);
+        messages.drain(..excess);
+        self.devices.insert(device.to_string(), messages.into());
+    }
+
+    /// The messages of every device heard from.
+    pub fn all(&self) -> impl Iterator<Item = (&str, &VecDeque<DeviceMessage>)> {
+        self.devices
+            .iter()
+            .map(|(device, messages)| (device.as_str(), messages))
+    }
+
+    /// Messages of the device called `name`, oldest first, or `None` if it never sent any.
+    pub fn messages(&self, name: &str) -> Option<Vec<DeviceMessage>> {
+        self.devices
+            .get(name)
+            .map(|messages| messages.iter().cloned().collect())
+    }
+}
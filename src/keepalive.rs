@@ -0,0 +1,58 @@
+//! TCP keepalive on the connections between clients and servers. A peer that went away without
+//! closing the connection, by losing power or its network, is noticed: the connection fails,
+//! and with it the calls waiting on it, instead of hanging on half-open forever.
+
+use socket2::{SockRef, TcpKeepalive};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Idle time after which a connection is probed, unless set otherwise with [`set_idle`].
+pub const DEFAULT_IDLE: Duration = Duration::from_secs(30);
+
+/// Unanswered probes after which a connection fails.
+const PROBES: u32 = 3;
+
+/// Idle time before probing, `None` for no keepalive.
+static IDLE: Mutex<Option<Duration>> = Mutex::new(Some(DEFAULT_IDLE));
+
+/// Makes the connections of this process probe their peer once they were idle for `idle`, or
+/// not at all with `None`.
+pub fn set_idle(idle: Option<Duration>) {
+    *IDLE.lock().unwrap() = idle;
+}
+
+/// Idle time before probing, see [`set_idle`].
+pub fn idle() -> Option<Duration> {
+    *IDLE.lock().unwrap()
+}
+
+/// Turns on keepalive for `socket` as set with [`set_idle`]. The peer is probed every third of
+/// the idle time after that, and the connection fails after [`PROBES`] probes go unanswered, or
+/// on Linux as soon as data sent has gone unacknowledged for as long.
+pub fn enable<'s, S>(socket: &'s S) -> std::io::Result<()>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    let idle = match idle() {
+        Some(idle) => idle,
+        None => return Ok(()),
+    };
+    let interval = (idle / PROBES).max(Duration::from_secs(1));
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        windows
+    ))]
+    let keepalive = keepalive.with_interval(interval);
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    let keepalive = keepalive.with_retries(PROBES);
+    let socket = SockRef::from(socket);
+    socket.set_tcp_keepalive(&keepalive)?;
+    // Probes are only sent once what was sent has been acknowledged, which by a peer that is
+    // gone it never is.
+    #[cfg(target_os = "linux")]
+    socket.set_tcp_user_timeout(Some(idle + interval * PROBES))?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+/*
+ *  Copyright 2020 Peter De Schrijver
+ *
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ */
+
+//! Control Extron scalers and switchers, directly over USB or through a server.
+//!
+//! [`client::AsyncClient`] talks to a server from async code, [`client::Client`] does the same
+//! for blocking code. [`server::ServerBuilder`] runs a server inside another program.
+//! [`sim::SimDevice`] stands in for hardware in tests.
+
+#[macro_use]
+extern crate tracing;
+
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+#[cfg(feature = "mdns")]
+pub mod discover;
+pub mod endpoint;
+pub mod error;
+pub mod extron;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "server")]
+pub mod health;
+#[cfg(feature = "server")]
+mod history;
+#[cfg(feature = "hotkeys")]
+pub mod hotkeys;
+#[cfg(feature = "server")]
+mod journal;
+pub mod keepalive;
+pub mod lock;
+pub mod metrics;
+pub mod network;
+pub mod rpc_trace;
+#[cfg(feature = "server")]
+pub mod schedule;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod sim;
+pub mod sis;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+#[cfg(all(feature = "server", feature = "client"))]
+pub mod standby;
+#[cfg(feature = "server")]
+mod state;
+#[cfg(all(feature = "server", unix))]
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tokens;
+pub mod trace;
+#[cfg(feature = "server")]
+pub mod web;
+
+pub mod extron_capnp {
+    include!(concat!(env!("OUT_DIR"), "/extron_capnp.rs"));
+}
+
+/// Version in the `$version` annotation of the schema.
+pub const SCHEMA_VERSION: u32 = 35;
+
+/// The Cap'n Proto schema spoken by the server, for generating clients in other languages.
+pub fn schema() -> &'static str {
+    include_str!("../extron.capnp")
+}
@@ -0,0 +1,96 @@
+//! Advisory locks on the serial ports of devices, so that two processes on this machine never
+//! interleave their commands to one. A lock is held as long as the port is open, which is for
+//! one operation, and goes away with the process if it dies. It is taken on the device node, so
+//! no other user can hold it without access to the device.
+
+use crate::error::{ControlError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a busy device is tried again while waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait for a device another process is using, `None` to fail right away.
+static WAIT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Makes device access in this process wait up to `wait` for a device another process is
+/// using, instead of failing with [`ControlError::Busy`].
+pub fn set_wait(wait: Option<Duration>) {
+    *WAIT.lock().unwrap() = wait;
+}
+
+/// How long device access waits for a busy device, see [`set_wait`].
+pub fn wait() -> Option<Duration> {
+    *WAIT.lock().unwrap()
+}
+
+/// Held while a device is in use. Dropping it lets other processes have the device.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+/// Opens the device node at `device_path` for locking it. Only its flock is used, so the open
+/// neither waits for a modem nor makes the port a controlling terminal.
+#[cfg(unix)]
+fn open(device_path: &str) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(device_path)
+}
+
+/// Opens the lock file for the device at `device_path`. Serial ports cannot be flocked here,
+/// so it lives in the temporary directory, which is per user as a COM port is.
+#[cfg(not(unix))]
+fn open(device_path: &str) -> std::io::Result<File> {
+    let name: String = device_path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = std::env::temp_dir().join(format!("control-dsc-{}.lock", name));
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Locks the device `name` at `device_path`, trying for up to `wait`. On Unix the lock is a
+/// flock on the device node itself, which every user of the device can take.
+pub fn lock(name: &str, device_path: &str, wait: Option<Duration>) -> Result<DeviceLock> {
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    loop {
+        match open(device_path) {
+            Ok(file) => match file.try_lock() {
+                Ok(()) => return Ok(DeviceLock { _file: file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            },
+            // A port another process opened exclusively cannot be opened at all until it is
+            // done, which is as busy as a held lock.
+            Err(e) if is_busy(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let now = Instant::now();
+        match deadline {
+            Some(deadline) if now < deadline => {
+                std::thread::sleep(std::cmp::min(POLL_INTERVAL, deadline - now));
+            }
+            _ => return Err(ControlError::Busy(name.to_string())),
+        }
+    }
+}
+
+/// Whether opening the device failed because another process opened it exclusively.
+#[cfg(unix)]
+fn is_busy(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EBUSY)
+}
+
+#[cfg(not(unix))]
+fn is_busy(_: &std::io::Error) -> bool {
+    false
+}
@@ -14,248 +14,2577 @@
  *  limitations under the License.
  */
 
-#[macro_use]
-extern crate log;
+#[cfg_attr(feature = "server", macro_use)]
+extern crate tracing;
 
-mod client;
-mod extron;
-mod server;
+mod check;
+mod cli;
+mod config;
+mod console;
+mod doctor;
+#[cfg(feature = "server")]
+mod hooks;
+#[cfg(feature = "notify")]
+mod notify;
+mod script;
+#[cfg(all(feature = "server", unix))]
+mod service;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(target_os = "linux")]
+mod udev;
+mod zabbix;
 
-use anyhow::Result;
-use extron::ExtronDeviceList;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use cli::{Cli, Command, Mode, Targets};
+use config::{Config, OutputFormat};
+#[cfg(feature = "client")]
+use control_dsc::client;
+use control_dsc::extron::{
+    page_of, Annotation, DeviceInfo, DeviceStatus, ExtronDevice, ExtronDeviceList, Genlock,
+    PipMode, SyncFormat, SyncSettings, WallLayout, WindowLayout,
+};
+use control_dsc::metrics::{DeviceStats, LATENCY_BUCKETS_MS};
+#[cfg(feature = "server")]
+use control_dsc::server;
+use control_dsc::sis::Plane;
+use control_dsc::tokens::Token;
 use itertools::Itertools;
-pub mod extron_capnp {
-    include!(concat!(env!("OUT_DIR"), "/extron_capnp.rs"));
-}
-
-fn get_ip_endpoint_arg(value_name: &str) -> clap::Arg {
-    clap::Arg::with_name("address")
-        .takes_value(true)
-        .value_name(value_name)
-
-        .validator(|x| {
-            use std::net::ToSocketAddrs;
-            let mut addrs = x.to_socket_addrs().unwrap_or(Vec::new().into_iter());
-            addrs
-                .next()
-                .map(|_| ())
-                .ok_or(format!("'{}' does not contain a valid address", x))
+
+/// Stands in for `control_dsc::client` in builds without the `client` feature, so that asking
+/// for a server fails with a clear message instead of every caller needing a `cfg`.
+#[cfg(not(feature = "client"))]
+mod client {
+    use anyhow::{bail, Result};
+    use control_dsc::extron::{
+        Annotation, DeviceHealth, DeviceInfo, DeviceMessage, DeviceStatus, ExtronDevice,
+        FirmwareUpdate, Genlock, Input, InputChange, PipMode, SyncFormat, SyncSettings, WallLayout,
+        WindowLayout,
+    };
+    use control_dsc::metrics::DeviceStats;
+    use control_dsc::sis::Plane;
+    use control_dsc::tokens::{Scope, Token};
+
+    pub enum Client {}
+
+    pub struct Session {
+        pub peer: String,
+        pub version: u32,
+        pub connected: std::time::Duration,
+        pub idle: std::time::Duration,
+        pub calls: u64,
+        pub errors: u64,
+        pub caller: bool,
+        pub identity: Option<String>,
+    }
+
+    impl Client {
+        pub fn with_servers(_servers: &str) -> Result<Self> {
+            bail!("Built without client support, only local devices can be used")
+        }
+
+        pub fn connect_timeout(self, _timeout: std::time::Duration) -> Self {
+            self
+        }
+
+        pub fn retries(self, _retries: u32) -> Self {
+            self
+        }
+
+        pub fn token(self, _token: &str) -> Self {
+            self
+        }
+
+        #[cfg(unix)]
+        pub fn with_socket(_path: &std::path::Path) -> Result<Self> {
+            bail!("Built without client support, only local devices can be used")
+        }
+
+        pub fn create_token(&self, _scope: Scope, _devices: &[String]) -> Result<(Token, String)> {
+            match *self {}
+        }
+
+        pub fn revoke_token(&self, _id: &str) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn list_tokens(&self) -> Result<Vec<Token>> {
+            match *self {}
+        }
+
+        pub fn list(&self) -> Result<Vec<ExtronDevice>> {
+            match *self {}
+        }
+
+        pub fn list_scanning(&self) -> Result<(Vec<ExtronDevice>, bool)> {
+            match *self {}
+        }
+
+        pub fn select(&self, _device: &str, _input: &Input) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn toggle_input(&self, _device: &str) -> Result<Input> {
+            match *self {}
+        }
+
+        pub fn select_group_plane(
+            &self,
+            _devices: &[String],
+            _input: &Input,
+            _plane: Plane,
+        ) -> Result<Vec<(String, Result<()>)>> {
+            match *self {}
+        }
+
+        pub fn select_plane(&self, _device: &str, _input: &Input, _plane: Plane) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_volume(&self, _device: &str, _level: u8) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_mute(&self, _device: &str, _mute: bool) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn status(&self, _device: &str) -> Result<DeviceStatus> {
+            match *self {}
+        }
+
+        pub fn device_info(&self, _device: &str) -> Result<DeviceInfo> {
+            match *self {}
+        }
+
+        pub fn device_health(&self, _device: &str) -> Result<DeviceHealth> {
+            match *self {}
+        }
+
+        pub fn send_raw(&self, _device: &str, _command: &[u8]) -> Result<Vec<u8>> {
+            match *self {}
+        }
+
+        pub fn upload_firmware<F>(
+            &self,
+            _device: &str,
+            _image: &[u8],
+            _progress: F,
+        ) -> Result<FirmwareUpdate> {
+            match *self {}
+        }
+
+        pub fn device_log(&self, _device: &str) -> Result<Vec<DeviceMessage>> {
+            match *self {}
+        }
+
+        pub fn history(&self, _device: &str) -> Result<Vec<InputChange>> {
+            match *self {}
+        }
+
+        pub fn undo(&self, _device: &str) -> Result<String> {
+            match *self {}
+        }
+
+        pub fn set_display_power(&self, _device: &str, _on: bool) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn send_to_display(&self, _device: &str, _data: &[u8]) -> Result<Vec<u8>> {
+            match *self {}
+        }
+
+        pub fn recall_preset(&self, _device: &str, _preset: u32) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn wall_layout(&self, _device: &str) -> Result<WallLayout> {
+            match *self {}
+        }
+
+        pub fn recall_layout(&self, _device: &str, _layout: u32) -> Result<WindowLayout> {
+            match *self {}
+        }
+
+        pub fn annotate(&self, _device: &str, _annotation: Annotation) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_pip_mode(&self, _device: &str, _mode: PipMode) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn assign_source(&self, _device: &str, _window: u32, _input: &Input) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn sync(&self, _device: &str) -> Result<SyncSettings> {
+            match *self {}
+        }
+
+        pub fn set_sync_format(&self, _device: &str, _format: SyncFormat) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_genlock(&self, _device: &str, _genlock: Genlock) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn hold(&self, _device: &str, _duration: Option<std::time::Duration>) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_maintenance(&self, _message: Option<&str>) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn sessions(&self) -> Result<Vec<Session>> {
+            match *self {}
+        }
+
+        pub fn disconnect(&self, _peer: &str) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn stats(&self) -> Result<Vec<DeviceStats>> {
+            match *self {}
+        }
+
+        pub fn dump_state(&self) -> Result<String> {
+            match *self {}
+        }
+
+        pub fn rescan(&self) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn stop(&self) -> Result<()> {
+            match *self {}
+        }
+    }
+}
+
+fn remote_client(addr: &str, config: &Config, cli: &Cli) -> Result<client::Client> {
+    let mut remote = client::Client::with_servers(addr)?;
+    if let Some(timeout) = cli.connect_timeout {
+        remote = remote.connect_timeout(timeout);
+    }
+    if let Some(retries) = cli.retries {
+        remote = remote.retries(retries);
+    }
+    let token = cli
+        .token
+        .clone()
+        .or_else(|| std::env::var(config::TOKEN_ENV).ok())
+        .filter(|token| !token.is_empty());
+    if let Some(token) = token {
+        remote = remote.token(&token);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        remote = remote.tls(client_tls(tls)?);
+    }
+    #[cfg(feature = "ssh")]
+    if let Some(ssh) = &cli.ssh {
+        remote = remote.ssh(ssh.clone());
+    }
+    #[cfg(not(feature = "tls"))]
+    if config.tls.is_some() {
+        return Err(anyhow!(
+            "Built without TLS support, cannot connect as [tls] says"
+        ));
+    }
+    Ok(remote)
+}
+
+/// How to connect to servers over TLS, as [tls] of the configuration says.
+#[cfg(feature = "tls")]
+fn client_tls(config: &config::TlsConfig) -> Result<control_dsc::tls::ClientTls> {
+    let identity = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        (None, None) => None,
+        _ => return Err(anyhow!("cert and key in [tls] go together")),
+    };
+    let tls = control_dsc::tls::ClientTls::new(&config.ca, identity)?;
+    Ok(match &config.server_name {
+        Some(name) => tls.server_name(name),
+        None => tls,
+    })
+}
+
+/// Client for managing the tokens of `server`: on its admin socket, or else through the
+/// server it names or the configured one.
+fn token_client(server: &cli::TokenServer, config: &Config, cli: &Cli) -> Result<client::Client> {
+    #[cfg(unix)]
+    if let Some(path) = &server.socket {
+        return Ok(client::Client::with_socket(path)?);
+    }
+    let addr = server
+        .remote
+        .as_deref()
+        .or(config.remote.as_deref())
+        .ok_or(anyhow!("No admin socket or server address given"))?;
+    remote_client(addr, config, cli)
+}
+
+/// Server address from the command line, or else from the configuration, unless `--local`
+/// was requested.
+fn remote_address<'a>(mode: &'a Mode, config: &'a Config) -> Option<&'a str> {
+    if mode.local {
+        None
+    } else {
+        mode.remote.as_deref().or(config.remote.as_deref())
+    }
+}
+
+fn output_format(cli: &Cli, config: &Config) -> OutputFormat {
+    cli.format.or(config.format).unwrap_or_default()
+}
+
+fn print_devices<I: Iterator<Item = ExtronDevice>>(devices: I, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => println!(
+            "{:<32}Device\n{}",
+            "Name",
+            devices.format_with("\n", |e, f| {
+                f(&format_args!("{:<32}{}", e.name, e.device_path))
+            })
+        ),
+        OutputFormat::Csv => print_csv(
+            &["name", "path"],
+            devices.map(|e| vec![e.name, e.device_path]),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let list = devices
+                .map(|e| {
+                    serde_json::json!({
+                        "name": e.name,
+                        "path": e.device_path,
+                        "labels": e.labels(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            print_value(&list, format)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints `records` under `header` as CSV, quoting fields where RFC 4180 asks for it.
+fn print_csv<R>(header: &[&str], records: R)
+where
+    R: IntoIterator<Item = Vec<String>>,
+{
+    let field = |field: &str| {
+        if field.contains(&[',', '"', '\r', '\n'][..]) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    };
+    println!("{}", header.iter().map(|name| field(name)).join(","));
+    for record in records {
+        println!("{}", record.iter().map(|value| field(value)).join(","));
+    }
+}
+
+/// Prints `value` as JSON or as YAML, as `format` asks.
+fn print_value<T: serde::Serialize>(value: &T, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        _ => println!("{}", serde_json::to_string_pretty(value)?),
+    }
+    Ok(())
+}
+
+/// `value` for a CSV field, empty for `None`.
+fn optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or(String::new(), |value| value.to_string())
+}
+
+/// Prints the inventory of `devices` with what they said they are, and where the
+/// configuration says they are, failing when any device did not answer.
+fn print_inventory(
+    config: &Config,
+    mut devices: Vec<(ExtronDevice, Result<DeviceInfo>)>,
+    format: OutputFormat,
+) -> Result<()> {
+    devices.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+    let records: Vec<_> = devices
+        .iter()
+        .map(|(device, info)| {
+            let info = info.as_ref().ok().cloned().unwrap_or_default();
+            let location = config
+                .devices
+                .get(&device.name)
+                .and_then(|device| device.location.clone());
+            [
+                Some(device.name.clone()),
+                info.part_number,
+                info.firmware,
+                control_dsc::extron::serial_number(&device.device_path).map(str::to_string),
+                Some(device.device_path.clone()),
+                location,
+            ]
         })
+        .collect();
+    let header = ["name", "model", "firmware", "serial", "path", "location"];
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{:<32}{:<14}{:<10}{:<16}{:<32}Location",
+                "Name", "Model", "Firmware", "Serial", "Device"
+            );
+            for record in &records {
+                let [name, model, firmware, serial, path, location] = record
+                    .clone()
+                    .map(|field| field.unwrap_or_else(|| "-".to_string()));
+                println!(
+                    "{:<32}{:<14}{:<10}{:<16}{:<32}{}",
+                    name, model, firmware, serial, path, location
+                );
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &header,
+            records.iter().map(|record| {
+                record
+                    .iter()
+                    .map(|field| optional(field.as_ref()))
+                    .collect()
+            }),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let list = records
+                .iter()
+                .zip(&devices)
+                .map(|(record, (_, info))| {
+                    let mut fields: serde_json::Map<_, _> = header
+                        .iter()
+                        .zip(record)
+                        .map(|(name, field)| (name.to_string(), serde_json::json!(field)))
+                        .collect();
+                    if let Err(e) = info {
+                        fields.insert("error".to_string(), error_value(e));
+                    }
+                    serde_json::Value::Object(fields)
+                })
+                .collect::<Vec<_>>();
+            print_value(&list, format)?;
+        }
+    }
+
+    let failed: Vec<_> = devices
+        .iter()
+        .filter_map(|(device, info)| Some((&device.name, info.as_ref().err()?)))
+        .collect();
+    for (name, e) in &failed {
+        eprintln!("Cannot ask {} what it is: {}", name, error_message(e));
+    }
+    match failed.len() {
+        0 => Ok(()),
+        n => Err(anyhow!("{} of {} devices failed", n, devices.len())),
+    }
+}
+
+/// The signals `select` switches, by `--plane` or the flags standing for its values.
+fn select_plane(args: &cli::SelectArgs) -> Plane {
+    if args.video_only {
+        Plane::Video
+    } else if args.audio_only {
+        Plane::Audio
+    } else {
+        plane(args.plane)
+    }
+}
+
+fn plane(plane: cli::Plane) -> Plane {
+    match plane {
+        cli::Plane::Video => Plane::Video,
+        cli::Plane::Audio => Plane::Audio,
+        cli::Plane::All => Plane::All,
+    }
+}
+
+fn print_status(name: &str, status: &DeviceStatus, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!("{:<32}{}", "Device", name);
+            match &status.label {
+                Some(label) => println!("{:<32}{} ({})", "Input", status.input, label),
+                None => println!("{:<32}{}", "Input", status.input),
+            }
+            if let Some(volume) = status.volume {
+                println!("{:<32}{}", "Volume", volume);
+            }
+            if let Some(mute) = status.mute {
+                println!("{:<32}{}", "Mute", if mute { "on" } else { "off" });
+            }
+            if let Some(pip) = status.pip {
+                println!("{:<32}{}", "Picture-in-picture", pip);
+            }
+            for (i, input) in status.sources.iter().enumerate() {
+                match i {
+                    0 => println!("{:<32}{}", "Main source", input),
+                    1 => println!("{:<32}{}", "Secondary source", input),
+                    _ => println!("{:<32}{}", format!("Source {}", i + 1), input),
+                }
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &["name", "input", "volume", "mute", "pip", "sources", "label"],
+            std::iter::once(vec![
+                name.to_string(),
+                status.input.to_string(),
+                optional(status.volume),
+                optional(status.mute),
+                optional(status.pip),
+                status.sources.iter().join(" "),
+                status.label.clone().unwrap_or_default(),
+            ]),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let status = serde_json::json!({
+                "name": name,
+                "input": status.input,
+                "label": status.label,
+                "volume": status.volume,
+                "mute": status.mute,
+                "pip": status.pip.map(|pip| pip.to_string()),
+                "sources": status.sources,
+            });
+            print_value(&status, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_sync(name: &str, sync: &SyncSettings, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!("{:<32}{}", "Device", name);
+            println!("{:<32}{}", "Sync format", sync.format);
+            println!("{:<32}{}", "Genlock", sync.genlock);
+        }
+        OutputFormat::Csv => print_csv(
+            &["name", "format", "genlock"],
+            std::iter::once(vec![
+                name.to_string(),
+                sync.format.to_string(),
+                sync.genlock.to_string(),
+            ]),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let sync = serde_json::json!({
+                "name": name,
+                "format": sync.format.to_string(),
+                "genlock": sync.genlock.to_string(),
+            });
+            print_value(&sync, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_tokens(tokens: &[Token], format: OutputFormat) -> Result<()> {
+    let created = |token: &Token| {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(token.created);
+        chrono::DateTime::<chrono::Local>::from(time)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    };
+    match format {
+        OutputFormat::Text => {
+            println!("{:<12}{:<10}{:<20}Devices", "Id", "Scope", "Created");
+            for token in tokens {
+                let devices = match token.devices.is_empty() {
+                    true => "all".to_string(),
+                    false => token.devices.join(" "),
+                };
+                println!(
+                    "{:<12}{:<10}{:<20}{}",
+                    token.id,
+                    token.scope,
+                    created(token),
+                    devices
+                );
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &["id", "scope", "created", "devices"],
+            tokens.iter().map(|token| {
+                vec![
+                    token.id.clone(),
+                    token.scope.to_string(),
+                    token.created.to_string(),
+                    token.devices.join(" "),
+                ]
+            }),
+        ),
+        _ => print_value(&tokens, format)?,
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mdns")]
+fn print_advertised(
+    servers: &[control_dsc::discover::Advertised],
+    format: OutputFormat,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!("{:<32}{:<48}Host", "Name", "Address");
+            for server in servers {
+                println!("{:<32}{:<48}{}", server.name, server.server(), server.host);
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &["name", "address", "host"],
+            servers
+                .iter()
+                .map(|server| vec![server.name.clone(), server.server(), server.host.clone()]),
+        ),
+        _ => print_value(&servers, format)?,
+    }
+    Ok(())
+}
+
+fn print_sessions(sessions: &[client::Session], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{:<48}{:<9}{:<12}{:<12}{:<8}{:<8}Identity",
+                "Peer", "Version", "Connected", "Idle", "Calls", "Errors"
+            );
+            for session in sessions {
+                let peer = match session.caller {
+                    true => format!("{} (this one)", session.peer),
+                    false => session.peer.clone(),
+                };
+                let line = format!(
+                    "{:<48}{:<9}{:<12}{:<12}{:<8}{:<8}{}",
+                    peer,
+                    session.version,
+                    format!("{} s", session.connected.as_secs()),
+                    format!("{} s", session.idle.as_secs()),
+                    session.calls,
+                    session.errors,
+                    session.identity.as_deref().unwrap_or("")
+                );
+                println!("{}", line.trim_end());
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &[
+                "peer",
+                "version",
+                "connected_seconds",
+                "idle_seconds",
+                "calls",
+                "errors",
+                "caller",
+                "identity",
+            ],
+            sessions.iter().map(|session| {
+                vec![
+                    session.peer.clone(),
+                    session.version.to_string(),
+                    session.connected.as_secs().to_string(),
+                    session.idle.as_secs().to_string(),
+                    session.calls.to_string(),
+                    session.errors.to_string(),
+                    session.caller.to_string(),
+                    session.identity.clone().unwrap_or_default(),
+                ]
+            }),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let list = sessions
+                .iter()
+                .map(|session| {
+                    serde_json::json!({
+                        "peer": session.peer,
+                        "version": session.version,
+                        "connected_seconds": session.connected.as_secs(),
+                        "idle_seconds": session.idle.as_secs(),
+                        "calls": session.calls,
+                        "errors": session.errors,
+                        "caller": session.caller,
+                        "identity": session.identity,
+                    })
+                })
+                .collect::<Vec<_>>();
+            print_value(&list, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_stats(devices: &[DeviceStats], format: OutputFormat) -> Result<()> {
+    let average = |stats: &DeviceStats| match stats.answered() {
+        0 => None,
+        answered => Some(stats.latency_total.as_secs_f64() * 1000.0 / answered as f64),
+    };
+    match format {
+        OutputFormat::Text => {
+            println!(
+                "{:<32}{:<8}{:<10}{:<12}{:<10}{:<12}Errors",
+                "Name", "Online", "Answers", "Average", "Timeouts", "Reconnects"
+            );
+            for stats in devices {
+                let errors = stats
+                    .errors
+                    .iter()
+                    .format_with(" ", |(code, n), f| f(&format_args!("E{:02}:{}", code, n)));
+                println!(
+                    "{:<32}{:<8}{:<10}{:<12}{:<10}{:<12}{}",
+                    stats.name,
+                    if stats.online { "yes" } else { "no" },
+                    stats.answered(),
+                    average(stats).map_or("-".to_string(), |ms| format!("{:.1} ms", ms)),
+                    stats.timeouts,
+                    stats.reconnects,
+                    errors
+                );
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &[
+                "name",
+                "online",
+                "answers",
+                "average_ms",
+                "timeouts",
+                "reconnects",
+                "errors",
+            ],
+            devices.iter().map(|stats| {
+                let errors = stats
+                    .errors
+                    .iter()
+                    .format_with(" ", |(code, n), f| f(&format_args!("E{:02}:{}", code, n)));
+                vec![
+                    stats.name.clone(),
+                    stats.online.to_string(),
+                    stats.answered().to_string(),
+                    average(stats).map_or(String::new(), |ms| format!("{:.1}", ms)),
+                    stats.timeouts.to_string(),
+                    stats.reconnects.to_string(),
+                    errors.to_string(),
+                ]
+            }),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let list = devices
+                .iter()
+                .map(|stats| {
+                    let bounds = LATENCY_BUCKETS_MS.iter().map(|&bound| Some(bound));
+                    let latencies: Vec<_> = bounds
+                        .chain(std::iter::once(None))
+                        .zip(&stats.latencies)
+                        .map(|(bound, n)| serde_json::json!({ "le_ms": bound, "count": n }))
+                        .collect();
+                    let errors: serde_json::Map<_, _> = stats
+                        .errors
+                        .iter()
+                        .map(|(code, n)| (format!("E{:02}", code), serde_json::json!(n)))
+                        .collect();
+                    serde_json::json!({
+                        "name": stats.name,
+                        "online": stats.online,
+                        "answers": stats.answered(),
+                        "average_ms": average(stats),
+                        "latencies": latencies,
+                        "errors": errors,
+                        "timeouts": stats.timeouts,
+                        "reconnects": stats.reconnects,
+                    })
+                })
+                .collect::<Vec<_>>();
+            print_value(&list, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_layout(name: &str, layout: &WallLayout, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!("{:<32}{}", "Device", name);
+            match layout.preset {
+                0 => println!("{:<32}none", "Preset"),
+                preset => println!("{:<32}{}", "Preset", preset),
+            }
+            println!("{:<32}{}", "Windows", layout.windows);
+        }
+        OutputFormat::Csv => print_csv(
+            &["name", "preset", "windows"],
+            std::iter::once(vec![
+                name.to_string(),
+                optional(Some(layout.preset).filter(|&preset| preset != 0)),
+                layout.windows.to_string(),
+            ]),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let layout = serde_json::json!({
+                "name": name,
+                "preset": Some(layout.preset).filter(|&preset| preset != 0),
+                "windows": layout.windows,
+            });
+            print_value(&layout, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_windows(name: &str, windows: &WindowLayout, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            println!("{:<32}{}", "Device", name);
+            println!("{:<32}{}", "Layout", windows.layout);
+            for (n, input) in windows.inputs.iter().enumerate() {
+                let window = format!("Window {}", n + 1);
+                match input {
+                    0 => println!("{:<32}none", window),
+                    input => println!("{:<32}{}", window, input),
+                }
+            }
+        }
+        OutputFormat::Csv => print_csv(
+            &["name", "layout", "window", "input"],
+            windows.inputs.iter().enumerate().map(|(n, &input)| {
+                vec![
+                    name.to_string(),
+                    windows.layout.to_string(),
+                    (n + 1).to_string(),
+                    optional(Some(input).filter(|&input| input != 0)),
+                ]
+            }),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let inputs = windows
+                .inputs
+                .iter()
+                .map(|&input| Some(input).filter(|&input| input != 0))
+                .collect::<Vec<_>>();
+            let windows = serde_json::json!({
+                "name": name,
+                "layout": windows.layout,
+                "windows": inputs,
+            });
+            print_value(&windows, format)?;
+        }
+    }
+    Ok(())
+}
+
+fn find_local_device(devices: &ExtronDeviceList, name: Option<&str>) -> Result<ExtronDevice> {
+    use control_dsc::error::ControlError;
+    use std::io::{Error, ErrorKind};
+    let device = match name {
+        Some(name) => devices
+            .find(name)
+            .ok_or_else(|| ControlError::DeviceNotFound(name.to_string()))?,
+        None if devices.len() > 1 => Err(anyhow!(
+            "Several devices found, pick one with -d or use --all"
+        ))?,
+        None => devices
+            .iter()
+            .next()
+            .ok_or(Error::new(ErrorKind::NotFound, "No Extron device found"))?,
+    };
+    Ok(device)
+}
+
+/// Devices matched by `--all` or a `-d` glob pattern, or `None` when `-d` names one device.
+fn device_pattern(targets: &Targets) -> Result<Option<glob::Pattern>> {
+    if targets.all {
+        return Ok(Some(glob::Pattern::new("*")?));
+    }
+    match targets.device.as_deref() {
+        Some(name) if name.chars().any(|c| "*?[".contains(c)) => {
+            Ok(Some(glob::Pattern::new(name)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Selects `input` for the signals of `plane` on all devices of `group` at once, with a
+/// result per device. Through a server this takes one call per input the label resolves to on
+/// the devices.
+fn select_group(
+    cli: &Cli,
+    config: &Config,
+    devices: &ExtronDeviceList,
+    group: &str,
+    mode: &Mode,
+    input: &control_dsc::extron::Input,
+    plane: Plane,
+) -> Result<()> {
+    let names = config.group(group)?;
+    let mut results: Vec<(String, Result<()>)> = Vec::new();
+    if let Some(addr) = remote_address(mode, config) {
+        let remote = remote_client(addr, config, cli)?;
+        let mut by_input: Vec<(control_dsc::extron::Input, Vec<String>)> = Vec::new();
+        for name in names {
+            let input = config.resolve_input(name, input);
+            match by_input.iter_mut().find(|(i, _)| *i == input) {
+                Some((_, names)) => names.push(name.clone()),
+                None => by_input.push((input, vec![name.clone()])),
+            }
+        }
+        for (input, names) in &by_input {
+            for (name, result) in remote.select_group_plane(names, input, plane)? {
+                results.push((name, result.map_err(|e| e.into())));
+            }
+        }
+        // In the order of the group, as for local devices.
+        results.sort_by_key(|(name, _)| names.iter().position(|n| n == name));
+    } else {
+        results = std::thread::scope(|scope| {
+            let selects = names
+                .iter()
+                .map(|name| {
+                    let device = find_local_device(devices, Some(name));
+                    scope.spawn(move || -> Result<()> {
+                        device?.select_plane(&config.resolve_input(name, input), plane)?;
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            names
+                .iter()
+                .cloned()
+                .zip(selects.into_iter().map(|s| {
+                    s.join()
+                        .unwrap_or_else(|_| Err(anyhow!("Selecting the input panicked")))
+                }))
+                .collect()
+        });
+    }
+    print_results(results, output_format(cli, config))
+}
+
+/// Applies `action` to the device addressed by the command line, or to every matching device
+/// with a per-device result table when `--all` or a pattern was given.
+fn for_each_target(
+    cli: &Cli,
+    config: &Config,
+    devices: &ExtronDeviceList,
+    targets: &Targets,
+    mode: &Mode,
+    action: &dyn Fn(&dyn script::Target) -> Result<()>,
+) -> Result<()> {
+    let pattern = device_pattern(targets)?;
+    let device = targets.device.as_deref().or(config.device.as_deref());
+    let group = targets.group.as_deref().map(|g| config.group(g)).transpose()?;
+    if let Some(addr) = remote_address(mode, config) {
+        let remote = remote_client(addr, config, cli)?;
+        if let Some(names) = group {
+            let targets = names
+                .iter()
+                .map(|name| script::RemoteDevice {
+                    client: &remote,
+                    name,
+                })
+                .collect::<Vec<_>>();
+            return run_on_all(
+                targets.iter().map(|t| t as &dyn script::Target).collect(),
+                output_format(cli, config),
+                action,
+            );
+        }
+        match pattern {
+            Some(pattern) => {
+                // The server picks them out, sparing the whole list on the wire.
+                let names = remote
+                    .list_page(pattern.as_str(), 0, 0)?
+                    .0
+                    .into_iter()
+                    .map(|d| d.name)
+                    .filter(|n| pattern.matches(n))
+                    .collect::<Vec<_>>();
+                let targets = names
+                    .iter()
+                    .map(|name| script::RemoteDevice {
+                        client: &remote,
+                        name,
+                    })
+                    .collect::<Vec<_>>();
+                run_on_all(
+                    targets.iter().map(|t| t as &dyn script::Target).collect(),
+                    output_format(cli, config),
+                    action,
+                )
+            }
+            None => action(&script::RemoteDevice {
+                client: &remote,
+                name: device.ok_or(anyhow!("No device given"))?,
+            }),
+        }
+    } else {
+        if let Some(names) = group {
+            let results = names
+                .iter()
+                .map(|name| {
+                    let result = find_local_device(devices, Some(name)).and_then(|d| action(&d));
+                    (name.clone(), result)
+                })
+                .collect();
+            return print_results(results, output_format(cli, config));
+        }
+        match pattern {
+            Some(pattern) => {
+                let targets = devices
+                    .iter()
+                    .filter(|d| pattern.matches(&d.name))
+                    .collect::<Vec<_>>();
+                run_on_all(
+                    targets.iter().map(|t| t as &dyn script::Target).collect(),
+                    output_format(cli, config),
+                    action,
+                )
+            }
+            None => action(&find_local_device(devices, device)?),
+        }
+    }
+}
+
+fn run_on_all(
+    targets: Vec<&dyn script::Target>,
+    format: OutputFormat,
+    action: &dyn Fn(&dyn script::Target) -> Result<()>,
+) -> Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    if targets.is_empty() {
+        Err(Error::new(ErrorKind::NotFound, "No matching device found"))?;
+    }
+    let results = targets
+        .iter()
+        .map(|t| (t.name().to_string(), action(*t)))
+        .collect::<Vec<_>>();
+    print_results(results, format)
+}
+
+/// Prints a result per device, failing when any device failed.
+fn print_results(results: Vec<(String, Result<()>)>, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => println!(
+            "{:<32}Result\n{}",
+            "Name",
+            results.iter().format_with("\n", |(name, result), f| match result {
+                Ok(()) => f(&format_args!("{:<32}ok", name)),
+                Err(e) => f(&format_args!("{:<32}{}", name, error_message(e))),
+            })
+        ),
+        OutputFormat::Csv => print_csv(
+            &["name", "result"],
+            results.iter().map(|(name, result)| match result {
+                Ok(()) => vec![name.clone(), "ok".to_string()],
+                Err(e) => vec![name.clone(), error_message(e)],
+            }),
+        ),
+        OutputFormat::Json | OutputFormat::Yaml => {
+            let list = results
+                .iter()
+                .map(|(name, result)| match result {
+                    Ok(()) => serde_json::json!({ "name": name, "result": "ok" }),
+                    Err(e) => serde_json::json!({ "name": name, "error": error_value(e) }),
+                })
+                .collect::<Vec<_>>();
+            print_value(&list, format)?;
+        }
+    }
+
+    match results.iter().filter(|(_, r)| r.is_err()).count() {
+        0 => Ok(()),
+        n => Err(anyhow!("{} of {} devices failed", n, results.len())),
+    }
+}
+
+const WAIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long a serial port may take to answer when scanning, as configured.
+fn probe_timeout(config: &Config) -> Result<std::time::Duration> {
+    match config.probe_timeout_seconds {
+        None => Ok(control_dsc::extron::DEFAULT_PROBE_TIMEOUT),
+        Some(seconds) => std::time::Duration::try_from_secs_f64(seconds)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or_else(|| anyhow!("Invalid probe_timeout_seconds {}", seconds)),
+    }
+}
+
+/// Polls until `name` shows up, locally or on the server, or `timeout` expires. An unreachable
+/// server counts as the device not being there yet, so this also works while the server boots;
+/// other errors of the server end the wait. The server is asked to rescan once it answers, which
+/// clients that may not rescan leave to the scans of the server.
+fn wait_for_device(
+    cli: &Cli,
+    config: &Config,
+    mode: &Mode,
+    name: &str,
+    timeout: std::time::Duration,
+) -> Result<()> {
+    use control_dsc::error::ControlError;
+    use std::io::{Error, ErrorKind};
+    use std::time::Instant;
+
+    let deadline = Instant::now() + timeout;
+    let probe = probe_timeout(config)?;
+    let remote = match remote_address(mode, config) {
+        Some(addr) => Some(remote_client(addr, config, cli)?),
+        None => None,
+    };
+    let mut rescanned = false;
+    loop {
+        let found = match &remote {
+            Some(remote) => {
+                if !rescanned {
+                    rescanned = match remote.rescan().map_err(anyhow::Error::from) {
+                        Ok(()) => true,
+                        Err(e) => match e.downcast_ref::<ControlError>() {
+                            Some(ControlError::NotAllowed(_))
+                            | Some(ControlError::Unauthorized(_)) => true,
+                            Some(ControlError::Connection(_)) => false,
+                            _ => return Err(e),
+                        },
+                    };
+                }
+                match remote.list().map_err(anyhow::Error::from) {
+                    Ok(list) => list.iter().any(|d| d.name == name),
+                    Err(e) => match e.downcast_ref::<ControlError>() {
+                        Some(ControlError::Connection(_)) => false,
+                        _ => return Err(e),
+                    },
+                }
+            }
+            None => ExtronDeviceList::enumerate_extron_within(probe)
+                .map(|list| list.find(name).is_some())
+                .unwrap_or(false),
+        };
+        if found {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Timed out waiting for device {}", name),
+            ))?;
+        }
+        std::thread::sleep(std::cmp::min(WAIT_POLL_INTERVAL, deadline - now));
+    }
+}
+
+/// Asks to type the name of `device` before its firmware is replaced with `file`, as a
+/// failed upload can leave the device unusable.
+fn confirm_upload(device: &str, file: &std::path::Path, size: usize) -> Result<()> {
+    eprintln!(
+        "This replaces the firmware of {} with {} ({} bytes).",
+        device,
+        file.display(),
+        size
+    );
+    eprintln!(
+        "The device must stay powered and connected until it is back, or it may not start again."
+    );
+    eprint!("Type the name of the device to go on: ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if answer.trim() != device {
+        return Err(anyhow!("Firmware upload cancelled"));
+    }
+    Ok(())
+}
+
+/// How long `discover` listens for servers unless told otherwise.
+#[cfg(feature = "mdns")]
+const DISCOVER_LISTEN: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Lists the servers on the local network, or picks one as `args` say and prints its address
+/// or saves it to the configuration.
+#[cfg(feature = "mdns")]
+fn discover(args: &cli::DiscoverArgs, format: OutputFormat) -> Result<()> {
+    let listen = args.listen.unwrap_or(DISCOVER_LISTEN);
+    let servers = control_dsc::discover::discover(listen, args.first)?;
+    if !(args.first || args.choose || args.save) {
+        return print_advertised(&servers, format);
+    }
+    let server = match servers.as_slice() {
+        [] => return Err(anyhow!("No server found on the local network")),
+        [server] => server,
+        [server, ..] if args.first => server,
+        servers => choose_server(servers)?,
+    };
+    let address = server.server();
+    if args.save {
+        Config::set_remote(&address)?;
+        eprintln!("Connecting to {} at {} from now on", server.name, address);
+    } else {
+        println!("{}", address);
+    }
+    Ok(())
+}
+
+/// Asks which of `servers` to use.
+#[cfg(feature = "mdns")]
+fn choose_server(
+    servers: &[control_dsc::discover::Advertised],
+) -> Result<&control_dsc::discover::Advertised> {
+    for (i, server) in servers.iter().enumerate() {
+        eprintln!("{:>3}  {:<32}{}", i + 1, server.name, server.server());
+    }
+    eprint!("Server to use [1-{}]: ", servers.len());
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    answer
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| servers.get(number.checked_sub(1)?))
+        .ok_or_else(|| anyhow!("No server chosen"))
+}
+
+fn upload_firmware(
+    cli: &Cli,
+    config: &Config,
+    devices: &ExtronDeviceList,
+    args: &cli::FirmwareUploadArgs,
+) -> Result<()> {
+    use anyhow::Context;
+    use std::io::Write;
+
+    let image = std::fs::read(&args.file)
+        .with_context(|| format!("Cannot read {}", args.file.display()))?;
+    let device = args.device.as_deref().or(config.device.as_deref());
+    let remote = match remote_address(&args.mode, config) {
+        Some(addr) => Some(remote_client(addr, config, cli)?),
+        None => None,
+    };
+    let local = match (&remote, device) {
+        (Some(_), _) => None,
+        (None, device) => Some(find_local_device(devices, device)?),
+    };
+    let name = match &local {
+        Some(local) => local.name.as_str(),
+        None => device.ok_or(anyhow!("No device given"))?,
+    };
+    if !args.yes {
+        confirm_upload(name, &args.file, image.len())?;
+    }
+
+    let mut shown = None;
+    let progress = move |done: usize, total: usize| {
+        let percent = done * 100 / total.max(1);
+        if shown != Some(percent) {
+            shown = Some(percent);
+            eprint!("\rUploading: {:>3}% ({}/{} bytes)", percent, done, total);
+            let _ = std::io::stderr().flush();
+        }
+    };
+    let update = match (&remote, &local) {
+        (Some(remote), _) => remote
+            .upload_firmware(name, &image, progress)
+            .map_err(anyhow::Error::from),
+        (None, Some(local)) => {
+            let mut progress = progress;
+            local
+                .upload_firmware(&image, &mut progress)
+                .map_err(anyhow::Error::from)
+        }
+        (None, None) => unreachable!("either a server or a local device"),
+    };
+    eprintln!();
+    let update = update?;
+    println!(
+        "Firmware of {} went from {} to {}",
+        name, update.before, update.after
+    );
+    if let Some(expected) = &args.expect_version {
+        if &update.after != expected {
+            return Err(anyhow!(
+                "{} reports firmware {} after the upload, expected {}",
+                name,
+                update.after,
+                expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Exit codes reported to the shell, so scripts can tell failure classes apart.
+mod exit_code {
+    pub const FAILURE: i32 = 1;
+    pub const CONNECTION: i32 = 3;
+    pub const DEVICE_NOT_FOUND: i32 = 4;
+    pub const DEVICE: i32 = 5;
+    pub const SERVER: i32 = 6;
+}
+
+fn exit_code_for(e: &anyhow::Error) -> i32 {
+    use control_dsc::error::ControlError;
+
+    if let Some(e) = e.downcast_ref::<ControlError>() {
+        match e {
+            ControlError::DeviceNotFound(_) => exit_code::DEVICE_NOT_FOUND,
+            ControlError::SerialIo(_)
+            | ControlError::InvalidInput { .. }
+            | ControlError::UnexpectedReply(_)
+            | ControlError::Timeout(_)
+            | ControlError::Unsupported(_)
+            | ControlError::Busy(_) => exit_code::DEVICE,
+            ControlError::MalformedInput(_) => exit_code::FAILURE,
+            ControlError::Connection(_) | ControlError::Cancelled => exit_code::CONNECTION,
+            ControlError::Rpc(e) if e.kind == capnp::ErrorKind::Disconnected => {
+                exit_code::CONNECTION
+            }
+            ControlError::OldServer { .. }
+            | ControlError::NotAllowed(_)
+            | ControlError::Unauthorized(_)
+            | ControlError::Maintenance(_)
+            | ControlError::Rpc(_) => exit_code::SERVER,
+        }
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        exit_code_for_io(e.kind())
+    } else if let Some(e) = e.downcast_ref::<capnp::Error>() {
+        match e.kind {
+            capnp::ErrorKind::Disconnected => exit_code::CONNECTION,
+            _ => exit_code::SERVER,
+        }
+    } else {
+        exit_code::FAILURE
+    }
+}
+
+fn exit_code_for_io(kind: std::io::ErrorKind) -> i32 {
+    use std::io::ErrorKind;
+
+    match kind {
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::AddrNotAvailable
+        | ErrorKind::BrokenPipe
+        | ErrorKind::TimedOut => exit_code::CONNECTION,
+        ErrorKind::NotFound => exit_code::DEVICE_NOT_FOUND,
+        _ => exit_code::DEVICE,
+    }
+}
+
+fn error_message(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<capnp::Error>() {
+        Some(e) => e.extra.clone(),
+        None => format!("{:#}", e),
+    }
+}
+
+/// The stable code of `e` for JSON and YAML output, the code of the [`ControlError`] it is,
+/// if it is one.
+///
+/// [`ControlError`]: control_dsc::error::ControlError
+fn error_code(e: &anyhow::Error) -> &'static str {
+    use control_dsc::error::ControlError;
+
+    if let Some(e) = e.downcast_ref::<ControlError>() {
+        match e {
+            ControlError::Rpc(e) if e.kind == capnp::ErrorKind::Disconnected => "connection",
+            e => e.code(),
+        }
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        match exit_code_for_io(e.kind()) {
+            exit_code::CONNECTION => "connection",
+            exit_code::DEVICE_NOT_FOUND => "device_not_found",
+            _ => "io",
+        }
+    } else if let Some(e) = e.downcast_ref::<capnp::Error>() {
+        match e.kind {
+            capnp::ErrorKind::Disconnected => "connection",
+            _ => "rpc",
+        }
+    } else {
+        "failure"
+    }
+}
+
+/// `e` as an object with its code and message.
+fn error_value(e: &anyhow::Error) -> serde_json::Value {
+    serde_json::json!({ "code": error_code(e), "message": error_message(e) })
 }
 
-fn main() -> Result<()> {
-    let devices = ExtronDeviceList::enumerate_extron().unwrap_or(ExtronDeviceList::new());
-    let program_name: String = std::env::current_exe()
+fn program_name() -> String {
+    std::env::current_exe()
         .unwrap_or("control-dsc".into())
         .file_name()
-        .map_or("control-dsc".into(), |v| v.to_string_lossy().to_string());
-
-    let select_arg = clap::Arg::with_name("device")
-        .short("d")
-        .long("device")
-        .takes_value(true)
-        .value_name("NAME")
-        .required(devices.len() != 1)
-        .help("Extron device to control");
-
-    let remote_arg = get_ip_endpoint_arg("SERVER ADDRESS")
-        .short("r")
-        .long("remote");
-
-    let args = clap::App::new(format!("{}", program_name))
-        .author("Peter De Schrijver <p2@psychaos.be>")
-        .version("0.2")
-        .about("Control Extron scalers/switchers")
-        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
-        .subcommand(
-            clap::SubCommand::with_name("list")
-                .about("list available devices")
-                .arg(remote_arg.clone().help("Remote server to connect to")),
-        )
-        .subcommand(
-            clap::SubCommand::with_name("select")
-                .about("select input")
-                .arg(select_arg)
-                .arg(
-                    clap::Arg::with_name("input")
-                        .index(1)
-                        .takes_value(true)
-                        .value_name("INPUT")
-                        .help("input port")
-                        .required(true),
-                )
-                .arg(
-                    remote_arg
-                        .clone()
-                        .requires("device")
-                        .help("Remote server to connect to"),
-                ),
-        )
-        .subcommand(
-            clap::SubCommand::with_name("server")
-                .about("run as server")
-                .arg(
-                    get_ip_endpoint_arg("LISTEN ADDRESS")
-                        .index(1)
-                        .help("Adress:Port to listen to")
-                        .default_value("0.0.0.0:14000")
-                        .required(true),
-                )
-                .arg(
-                    clap::Arg::with_name("debug output")
-                        .takes_value(true)
-                        .value_name("DEBUG LOG DIRECTORY")
-                        .long("debug"),
-                )
-                .arg(clap::Arg::with_name("no-daemonize").long("no-daemonize")),
-        )
-        .subcommand(
-            clap::SubCommand::with_name("rescan")
-                .about("force rescan on server")
-                .arg(
-                    remote_arg
-                        .clone()
-                        .index(1)
-                        .help("Adress:Port to connect to")
-                        .required(true),
-                ),
-        )
-        .subcommand(
-            clap::SubCommand::with_name("stop_server")
-                .about("halt server")
-                .arg(
-                    remote_arg
-                        .clone()
-                        .index(1)
-                        .help("Adress:Port to connect to")
-                        .required(true),
-                ),
-        )
-        .get_matches();
-
-    match args.subcommand() {
-        ("list", Some(sub_c)) => {
-            if let Some(addr) = sub_c.value_of("address") {
-                let remote = client::Client::new(&addr.to_string())?;
-                remote.list()?;
-            } else {
-                println!(
-                    "{:<32}Device\n{}",
-                    "Name",
-                    devices.iter().format_with("\n", |e, f| {
-                        f(&format_args!("{:<32}{}", e.name, e.device_path))
-                    })
-                )
+        .map_or("control-dsc".into(), |v| v.to_string_lossy().to_string())
+}
+
+/// Prints `e` to stderr, as an object with its code in JSON and YAML output.
+fn print_error(e: &anyhow::Error, format: OutputFormat) {
+    let value = serde_json::json!({ "error": error_value(e) });
+    match format {
+        OutputFormat::Text | OutputFormat::Csv => {
+            eprintln!("{}: {}", program_name(), error_message(e))
+        }
+        OutputFormat::Json => eprintln!("{}", value),
+        OutputFormat::Yaml => match serde_yaml::to_string(&value) {
+            Ok(yaml) => eprint!("{}", yaml),
+            Err(_) => eprintln!("{}: {}", program_name(), error_message(e)),
+        },
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        // The configuration may be what failed.
+        let format = cli
+            .format
+            .or_else(|| Config::load().ok()?.format)
+            .unwrap_or_default();
+        print_error(&e, format);
+        std::process::exit(exit_code_for(&e));
+    }
+}
+
+/// Logs the RPC calls of a client to stderr.
+#[cfg(feature = "server")]
+fn start_rpc_trace_logger() -> Result<()> {
+    use flexi_logger::{LogTarget, Logger};
+
+    Box::new(Logger::with_str(log_spec("warn")).log_target(LogTarget::StdErr)).start()?;
+    Ok(())
+}
+
+/// Logger spec for logging at `level`, and the RPC calls as well with `--trace-rpc`.
+#[cfg(feature = "server")]
+fn log_spec(level: &str) -> String {
+    if control_dsc::rpc_trace::enabled() {
+        format!("{}, {}=trace", level, control_dsc::rpc_trace::TARGET)
+    } else {
+        level.to_string()
+    }
+}
+
+/// Logs to stdout, and also to files in the debug log directory if given, named after the
+/// instance if the server has a name.
+#[cfg(feature = "server")]
+fn start_foreground_logger(args: &cli::ServerArgs) -> Result<()> {
+    use flexi_logger::{Duplicate, LogTarget, Logger};
+
+    if let Some(n) = &args.debug_dir {
+        let mut logger = Logger::with_str(log_spec("debug"))
+            .log_to_file()
+            .directory(n)
+            .suppress_timestamp()
+            .append()
+            .duplicate_to_stdout(Duplicate::Debug);
+        if let Some(instance) = &args.instance {
+            logger = logger.discriminant(instance);
+        }
+        Box::new(logger).start()?;
+    } else {
+        Box::new(Logger::with_str(log_spec("debug")).log_target(LogTarget::StdOut)).start()?;
+    }
+    Ok(())
+}
+
+/// Passes what the server traces on to the logger started for it, each line prefixed with the
+/// spans it happened in, e.g. `connection{peer=..}:rpc{method=.. device=..}:`, so that one
+/// request can be followed through the log. Lines of a named server start with its `instance`
+/// name, as servers on one machine share the system log.
+#[cfg(feature = "server")]
+fn forward_tracing_to_log(instance: Option<&str>) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(LogWriter {
+            instance: instance.map(str::to_string),
+        })
+        .with_ansi(false)
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_max_level(if control_dsc::rpc_trace::enabled() {
+            tracing::Level::TRACE
+        } else {
+            tracing::Level::DEBUG
+        })
+        .try_init()
+        .map_err(|e| anyhow!("Cannot set up tracing: {}", e))
+}
+
+/// Hands out a [`LogLine`] for every traced event.
+#[cfg(feature = "server")]
+struct LogWriter {
+    instance: Option<String>,
+}
+
+#[cfg(feature = "server")]
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for LogWriter {
+    type Writer = LogLine;
+
+    fn make_writer(&'a self) -> LogLine {
+        LogLine {
+            level: log::Level::Info,
+            target: String::new(),
+            instance: self.instance.clone(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> LogLine {
+        let level = match *meta.level() {
+            tracing::Level::ERROR => log::Level::Error,
+            tracing::Level::WARN => log::Level::Warn,
+            tracing::Level::INFO => log::Level::Info,
+            tracing::Level::DEBUG => log::Level::Debug,
+            _ => log::Level::Trace,
+        };
+        LogLine {
+            level,
+            target: meta.target().to_string(),
+            instance: self.instance.clone(),
+        }
+    }
+}
+
+/// Logs the formatted event written to it at the level of the event.
+#[cfg(feature = "server")]
+struct LogLine {
+    level: log::Level,
+    target: String,
+    instance: Option<String>,
+}
+
+#[cfg(feature = "server")]
+impl std::io::Write for LogLine {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        let line = match &self.instance {
+            Some(instance) => format!("{}: {}", instance, line.trim_end()),
+            None => line.trim_end().to_string(),
+        };
+        log::logger().log(
+            &log::Record::builder()
+                .level(self.level)
+                .target(&self.target)
+                .args(format_args!("{}", line))
+                .build(),
+        );
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        log::logger().flush();
+        Ok(())
+    }
+}
+
+/// Socket of the system logger. On macOS it feeds the unified log.
+#[cfg(all(feature = "daemon", target_os = "macos"))]
+const SYSLOG_SOCKET: &str = "/var/run/syslog";
+#[cfg(all(feature = "daemon", unix, not(target_os = "macos")))]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Group owning the serial ports, joined when daemonizing. The macOS ports are open to
+/// everyone.
+#[cfg(all(feature = "daemon", target_os = "macos"))]
+const SERIAL_GROUP: Option<&str> = None;
+#[cfg(all(feature = "daemon", unix, not(target_os = "macos")))]
+const SERIAL_GROUP: Option<&str> = Some("dialout");
+
+#[cfg(all(feature = "daemon", unix))]
+fn run_server(args: &cli::ServerArgs, config: &Config) -> Result<()> {
+    use daemonize::{Daemonize, Group, User};
+    use flexi_logger::{LogTarget, Logger};
+    use std::convert::TryFrom;
+
+    if args.no_daemonize {
+        start_foreground_logger(args)?;
+    } else {
+        use flexi_logger::writers::{SyslogConnector, SyslogFacility, SyslogWriter};
+        use flexi_logger::{Duplicate, LevelFilter};
+        let syslog_connector = SyslogConnector::try_datagram(SYSLOG_SOCKET)?;
+        let syslog_write = SyslogWriter::try_new(
+            SyslogFacility::UserLevel,
+            None,
+            LevelFilter::Info,
+            program_name(),
+            syslog_connector,
+        )?;
+        if let Some(n) = &args.debug_dir {
+            let mut logger = Logger::with_str(log_spec("debug"))
+                .directory(n)
+                .suppress_timestamp()
+                .append()
+                .log_target(LogTarget::FileAndWriter(syslog_write))
+                .duplicate_to_stdout(Duplicate::Debug);
+            if let Some(instance) = &args.instance {
+                logger = logger.discriminant(instance);
             }
+            Box::new(logger).start()?;
+        } else {
+            Box::new(Logger::with_str("info").log_target(LogTarget::Writer(syslog_write)))
+                .start()?;
         }
+    }
 
-        ("select", Some(sub_c)) => {
-            let input = sub_c.value_of("input").unwrap();
-            let device = sub_c.value_of("device");
-            if let Some(addr) = sub_c.value_of("address") {
-                let remote = client::Client::new(&addr.to_string())?;
-                remote.select(device.unwrap(), input)?;
-            } else {
-                match device {
-                    Some(name) => match devices.find(name) {
-                        Some(d) => d.select(input)?,
-                        None => println!("Device {} not found.", name),
-                    },
-                    None => devices.iter().next().unwrap().select(input)?,
-                };
+    let pipe = pipefile::pipe()?;
+    if !args.no_daemonize {
+        let mut daemon = Daemonize::new().user(User::try_from("daemon")?);
+        if let Some(group) = SERIAL_GROUP {
+            daemon = daemon.group(Group::try_from(group)?);
+        }
+        daemon.umask(0o000).stderr(pipe.write_end).start()?;
+    } else {
+        use nix::unistd::dup2;
+        use std::os::unix::io::AsRawFd;
+
+        dup2(pipe.write_end.as_raw_fd(), 2)?;
+    }
+
+    let pipe_read = pipe.read_end.try_clone()?;
+    std::thread::spawn(move || {
+        use std::io::BufRead;
+        let mut reader = std::io::BufReader::new(&pipe_read);
+        loop {
+            let mut message = String::new();
+            match reader.read_line(&mut message) {
+                Ok(_) => debug!("{}", message.trim_end()),
+                Err(_) => {}
+            }
+        }
+    });
+
+    serve(args, config)
+}
+
+/// Without the daemon feature, and always outside Unix, the server stays in the foreground.
+/// To run it as a Windows service, use a service wrapper.
+#[cfg(all(feature = "server", not(all(feature = "daemon", unix))))]
+fn run_server(args: &cli::ServerArgs, config: &Config) -> Result<()> {
+    start_foreground_logger(args)?;
+    serve(args, config)
+}
+
+/// The hotkeys of the configuration, with their scenes expanded and input labels resolved.
+#[cfg(feature = "hotkeys")]
+fn hotkeys(config: &Config) -> Result<control_dsc::hotkeys::Hotkeys> {
+    use anyhow::{bail, Context};
+    use control_dsc::hotkeys::{Action, Hotkeys, Key, Pad};
+    use std::collections::HashMap;
+
+    let pad = match config.hotkeys.pad.as_deref() {
+        None | Some("streamdeck") => Pad::StreamDeck,
+        Some(ids) => {
+            let parse = |id: &str| u16::from_str_radix(id, 16).ok();
+            match ids.split_once(':').map(|(v, p)| (parse(v), parse(p))) {
+                Some((Some(vendor_id), Some(product_id))) => Pad::Generic {
+                    vendor_id,
+                    product_id,
+                },
+                _ => bail!("'{}' is not streamdeck or a vendor:product pair", ids),
             }
         }
-        ("server", Some(sub_c)) => {
-            use daemonize::{Daemonize, Group, User};
-            use flexi_logger::{LogTarget, Logger};
-            use std::convert::TryFrom;
-
-            let addrs = sub_c.value_of("address").unwrap();
-            if sub_c.is_present("no-daemonize") {
-                if let Some(n) = sub_c.value_of("debug output") {
-                    use flexi_logger::Duplicate;
-                    Box::new(
-                        Logger::with_str("debug")
-                            .log_to_file()
-                            .directory(n)
-                            .suppress_timestamp()
-                            .append()
-                            .duplicate_to_stdout(Duplicate::Debug),
-                    )
-                    .start()?
-                } else {
-                    Box::new(Logger::with_str("debug").log_target(LogTarget::StdOut)).start()?
+    };
+
+    let mut keys = HashMap::new();
+    for key in &config.hotkeys.keys {
+        let device = key
+            .device
+            .as_deref()
+            .or(config.device.as_deref())
+            .ok_or(anyhow!("No device given for hotkey {}", key.key))?;
+        let lines = script::parse(&key.commands.join("\n"))
+            .with_context(|| format!("hotkey {}", key.key))?;
+        let actions = script::expand(config, &lines)
+            .with_context(|| format!("hotkey {}", key.key))?
+            .into_iter()
+            .map(|command| {
+                let device = device.to_string();
+                match command {
+                    script::Command::Select(input) => Action::Select {
+                        input: config.resolve_input(&device, &input),
+                        device,
+                    },
+                    script::Command::Volume(level) => Action::Volume { device, level },
+                    script::Command::Mute(mute) => Action::Mute { device, mute },
+                    script::Command::Sleep(duration) => Action::Sleep(duration),
+                    script::Command::Scene(_) => unreachable!("scenes are expanded"),
                 }
+            })
+            .collect();
+        let key_config = Key {
+            actions,
+            image: key.image.clone(),
+            active_image: key.active_image.clone(),
+        };
+        if keys.insert(key.key, key_config).is_some() {
+            bail!("Hotkey {} is configured twice", key.key);
+        }
+    }
+    Ok(Hotkeys { pad, keys })
+}
+
+/// The scenes and input labels of the configuration for the dashboard, with the scenes
+/// expanded.
+#[cfg(feature = "server")]
+fn dashboard(config: &Config) -> Result<control_dsc::web::Dashboard> {
+    use anyhow::Context;
+    use control_dsc::web::{Dashboard, Step};
+
+    let mut scenes = std::collections::BTreeMap::new();
+    for name in config.scenes.keys() {
+        let line = script::Line {
+            number: 1,
+            command: script::Command::Scene(name.clone()),
+        };
+        let steps = script::expand(config, &[line])
+            .with_context(|| format!("scene {}", name))?
+            .into_iter()
+            .map(|command| match command {
+                script::Command::Select(input) => Step::Select(input),
+                script::Command::Volume(level) => Step::Volume(level),
+                script::Command::Mute(mute) => Step::Mute(mute),
+                script::Command::Sleep(duration) => Step::Sleep(duration),
+                script::Command::Scene(_) => unreachable!("scenes are expanded"),
+            })
+            .collect();
+        scenes.insert(name.clone(), steps);
+    }
+    let labels = config
+        .devices
+        .iter()
+        .filter(|(_, device)| !device.inputs.is_empty())
+        .map(|(name, device)| {
+            let inputs = device.inputs.iter().map(|(l, n)| (l.clone(), *n));
+            (name.clone(), inputs.collect())
+        })
+        .collect();
+    Ok(Dashboard { scenes, labels })
+}
+
+/// TLS with the certificate `cert` and the options of `args`, letting clients do what the
+/// certificates of the configuration say.
+#[cfg(all(feature = "server", feature = "tls"))]
+fn server_tls(
+    cert: &std::path::Path,
+    args: &cli::ServerArgs,
+    config: &Config,
+) -> Result<control_dsc::tls::ServerTls> {
+    use anyhow::{bail, Context};
+
+    let key = args
+        .tls_key
+        .as_deref()
+        .ok_or(anyhow!("No --tls-key given"))?;
+    if args.client_ca.is_none() && !config.certificates.is_empty() {
+        bail!("The configured certificates need --client-ca");
+    }
+    let mut tls = control_dsc::tls::ServerTls::new(cert, key, args.client_ca.as_deref())?;
+    for certificate in &config.certificates {
+        let devices = certificate
+            .devices
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern))
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("certificate {}", certificate.identity))?;
+        tls = tls.allow(&certificate.identity, certificate.scope, devices);
+    }
+    Ok(tls)
+}
+
+/// The health limits of the configuration.
+#[cfg(feature = "server")]
+fn thresholds(config: &config::HealthConfig) -> control_dsc::health::Thresholds {
+    use control_dsc::health::Thresholds;
+    use std::time::Duration;
+
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    let defaults = Thresholds::default();
+    Thresholds {
+        max_temperature: config.max_temperature,
+        max_errors: config.max_errors,
+        error_window: config
+            .error_window_minutes
+            .map_or(defaults.error_window, minutes),
+        max_offline: config.max_offline_minutes.map(minutes),
+        signal_loss: config.signal_loss_seconds.map(Duration::from_secs),
+    }
+}
+
+/// The schedules of the configuration, with input labels resolved.
+#[cfg(feature = "server")]
+fn schedules(config: &Config) -> Result<Vec<control_dsc::schedule::Schedule>> {
+    use anyhow::Context;
+    use control_dsc::extron::Input;
+    use control_dsc::schedule::{Period, Schedule};
+
+    let time = |s: &str| {
+        chrono::NaiveTime::parse_from_str(s, "%H:%M")
+            .with_context(|| format!("'{}' is not a time of day as HH:MM", s))
+    };
+    let mut schedules = Vec::new();
+    for schedule in &config.schedules {
+        let device = &schedule.device;
+        let input = |s: &str| -> Result<Input> { Ok(config.resolve_input(device, &s.parse()?)) };
+        let convert = || -> Result<Schedule> {
+            let mut periods = Vec::new();
+            for period in &schedule.periods {
+                periods.push(Period {
+                    from: time(&period.from)?,
+                    until: time(&period.until)?,
+                    input: input(&period.input)?,
+                });
+            }
+            Ok(Schedule {
+                device: device.clone(),
+                periods,
+                otherwise: schedule.otherwise.as_deref().map(input).transpose()?,
+            })
+        };
+        schedules.push(convert().with_context(|| format!("Invalid schedule for {}", device))?);
+    }
+    Ok(schedules)
+}
+
+#[cfg(feature = "server")]
+fn serve(args: &cli::ServerArgs, config: &Config) -> Result<()> {
+    use anyhow::Context;
+
+    forward_tracing_to_log(args.instance.as_deref())?;
+    if args.trace_serial {
+        control_dsc::trace::set_dir(args.debug_dir.clone());
+    }
+    let mut builder = server::ServerBuilder::new().probe_timeout(probe_timeout(config)?);
+    // Started by systemd socket activation, the server listens on the sockets it is handed.
+    #[cfg(unix)]
+    let activated = control_dsc::systemd::listeners()?;
+    #[cfg(not(unix))]
+    let activated: Vec<std::net::TcpListener> = Vec::new();
+    if activated.is_empty() {
+        builder = builder.listen(args.address);
+    }
+    for listener in activated {
+        builder = builder.listen_on(listener);
+    }
+    if let Some(path) = &args.state_file {
+        builder = builder.state_file(path);
+    }
+    if args.restore {
+        builder = builder.restore_state();
+    }
+    if let Some(window) = args.undo_window {
+        builder = builder.undo_window(window);
+    }
+    if args.local_admin {
+        builder = builder.local_admin();
+    }
+    if let Some(path) = &args.tokens {
+        builder = builder.tokens(path);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.admin_socket {
+        builder = builder.admin_socket(path);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(cert) = &args.tls_cert {
+        builder = builder.tls(server_tls(cert, args, config)?);
+    }
+    #[cfg(not(feature = "tls"))]
+    if !config.certificates.is_empty() {
+        warn!("Built without TLS support, ignoring the configured certificates");
+    }
+    if let Some(primary) = args.standby_of {
+        builder = builder.standby_of(primary);
+    }
+    for schedule in schedules(config)? {
+        builder = builder.schedule(schedule);
+    }
+    if !config.hooks.is_empty() {
+        builder = builder.on_event(hooks::event_hook(&config.hooks)?);
+    }
+    #[cfg(feature = "notify")]
+    if !config.notify.is_empty() {
+        builder = builder.on_event(notify::event_hook(&config.notify)?);
+    }
+    #[cfg(not(feature = "notify"))]
+    if !config.notify.is_empty() {
+        warn!("Built without notifications, ignoring the configured chats");
+    }
+    builder = builder.health(thresholds(&config.health));
+    for (name, device) in &config.devices {
+        if let Some(network) = device.network(name) {
+            builder = builder.network_device(network);
+        }
+        for alias in &device.aliases {
+            builder = builder.alias(alias, name);
+        }
+        if !device.inputs.is_empty() {
+            builder = builder.input_labels(name, device.inputs.clone());
+        }
+    }
+    builder = args.ports.iter().fold(builder, |builder, pattern| {
+        builder.device_ports(pattern.clone())
+    });
+    if let Some(path) = &args.simulate {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+        let devices = control_dsc::sim::parse_devices(&text)
+            .with_context(|| format!("Invalid simulation file {}", path.display()))?;
+        info!("Simulating {} devices", devices.len());
+        builder = builder.device_source(move || control_dsc::sim::device_list(&devices));
+    }
+    builder = args
+        .metrics
+        .iter()
+        .fold(builder, |builder, addr| builder.listen_metrics(*addr));
+    if args.dashboard {
+        builder = builder.dashboard(dashboard(config)?);
+    }
+    #[cfg(feature = "grpc")]
+    let builder = args
+        .grpc
+        .iter()
+        .fold(builder, |builder, addr| builder.listen_grpc(*addr));
+    #[cfg(feature = "dbus")]
+    let builder = match args.dbus {
+        Some(cli::DbusBus::Session) => builder.dbus(control_dsc::dbus::Bus::Session),
+        Some(cli::DbusBus::System) => builder.dbus(control_dsc::dbus::Bus::System),
+        None => builder,
+    };
+    #[cfg(feature = "hotkeys")]
+    let builder = if config.hotkeys.keys.is_empty() {
+        builder
+    } else {
+        builder.hotkeys(hotkeys(config)?)
+    };
+    #[cfg(not(feature = "hotkeys"))]
+    if !config.hotkeys.keys.is_empty() {
+        warn!("Built without hotkey support, ignoring the configured hotkeys");
+    }
+    match server::run_until_signal(builder) {
+        Ok(()) => {}
+        Err(e) => error!("{}", e.to_string()),
+    }
+    Ok(())
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    let config = Config::load()?;
+    control_dsc::lock::set_wait(cli.wait);
+    if cli.no_keepalive {
+        control_dsc::keepalive::set_idle(None);
+    } else if let Some(idle) = cli.keepalive {
+        control_dsc::keepalive::set_idle(Some(idle));
+    }
+    #[cfg(feature = "server")]
+    if cli.trace_rpc {
+        control_dsc::rpc_trace::set_enabled(true);
+        // The server logs them with everything else.
+        if !matches!(cli.command, Command::Server(_)) {
+            start_rpc_trace_logger()?;
+        }
+    }
+    control_dsc::extron::set_adaptive_deadlines(config.adaptive_deadlines);
+    let mut devices = ExtronDeviceList::enumerate_extron_within(probe_timeout(&config)?)
+        .unwrap_or(ExtronDeviceList::new());
+    let network: Vec<_> = config
+        .devices
+        .iter()
+        .filter_map(|(name, device)| device.network(name))
+        .collect();
+    devices.extend(control_dsc::network::device_list(&network));
+    for (name, device) in &config.devices {
+        for alias in &device.aliases {
+            devices.alias(alias, name);
+        }
+        if !device.inputs.is_empty() {
+            devices.label(name, device.inputs.clone());
+        }
+    }
+
+    match &cli.command {
+        Command::List(args) => {
+            let format = output_format(cli, &config);
+            let filter = args.filter.as_deref().unwrap_or_default();
+            let (listed, scanning) = if let Some(addr) = remote_address(&args.mode, &config) {
+                let (listed, scanning) = remote_client(addr, &config, cli)?.list_scanning()?;
+                (page_of(listed, filter, 0, 0)?.0, scanning)
             } else {
-                use flexi_logger::writers::{SyslogConnector, SyslogFacility, SyslogWriter};
-                use flexi_logger::{Duplicate, LevelFilter};
-                let syslog_connector = SyslogConnector::try_datagram("/dev/log")?;
-                let syslog_write = SyslogWriter::try_new(
-                    SyslogFacility::UserLevel,
-                    None,
-                    LevelFilter::Info,
-                    program_name.clone(),
-                    syslog_connector,
-                )?;
-                if let Some(n) = sub_c.value_of("debug output") {
-                    Box::new(
-                        Logger::with_str("debug")
-                            .directory(n)
-                            .suppress_timestamp()
-                            .append()
-                            .log_target(LogTarget::FileAndWriter(syslog_write))
-                            .duplicate_to_stdout(Duplicate::Debug),
-                    )
-                    .start()?
-                } else {
-                    Box::new(Logger::with_str("info").log_target(LogTarget::Writer(syslog_write)))
-                        .start()?
-                }
+                (page_of(devices.iter().collect(), filter, 0, 0)?.0, false)
             };
-
-            let pipe = pipefile::pipe()?;
-            if !sub_c.is_present("no-daemonize") {
-                Daemonize::new()
-                    .user(User::try_from("daemon")?)
-                    .group(Group::try_from("dialout")?)
-                    .umask(0o000)
-                    .stderr(pipe.write_end)
-                    .start()?;
+            if scanning {
+                eprintln!("The server is still scanning, more devices may follow");
+            }
+            print_devices(listed.into_iter(), format)?;
+        }
+        Command::Export(args) => {
+            // Inventories go to spreadsheets, unless asked otherwise.
+            let format = cli.format.unwrap_or(OutputFormat::Csv);
+            let inventory = if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                remote
+                    .list()?
+                    .into_iter()
+                    .map(|device| {
+                        let info = remote
+                            .device_info(&device.name)
+                            .map_err(anyhow::Error::from);
+                        (device, info)
+                    })
+                    .collect()
             } else {
-                use nix::unistd::dup2;
-                use std::os::unix::io::AsRawFd;
-
-                dup2(pipe.write_end.as_raw_fd(), 2)?;
+                devices
+                    .iter()
+                    .map(|device| {
+                        let info = device.info().map_err(anyhow::Error::from);
+                        (device, info)
+                    })
+                    .collect()
+            };
+            print_inventory(&config, inventory, format)?;
+        }
+        // Only the server knows what was selected before.
+        Command::Select(args) if args.previous => {
+            let addr = remote_address(&args.mode, &config)
+                .ok_or(anyhow!("--previous needs a server address"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            let device = args
+                .targets
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            println!("{}", remote.toggle_input(device)?);
+        }
+        // Groups are selected through a single call.
+        Command::Select(args) if args.targets.group.is_some() => {
+            let group = args.targets.group.as_deref().unwrap_or_default();
+            select_group(
+                cli,
+                &config,
+                &devices,
+                group,
+                &args.mode,
+                args.input.as_ref().expect("clap requires the input"),
+                select_plane(args),
+            )?;
+        }
+        Command::Select(args) => {
+            for_each_target(
+                cli,
+                &config,
+                &devices,
+                &args.targets,
+                &args.mode,
+                &|target| {
+                    let input = args.input.as_ref().expect("clap requires the input");
+                    let input = config.resolve_input(target.name(), input);
+                    target.select_plane(&input, select_plane(args))
+                },
+            )?;
+        }
+        Command::Status(args) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let format = output_format(cli, &config);
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                print_status(device, &remote.status(device)?, format)?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                print_status(&device.name, &device.status()?, format)?;
+            }
+        }
+        Command::Volume(args) => {
+            for_each_target(
+                cli,
+                &config,
+                &devices,
+                &args.targets,
+                &args.mode,
+                &|target| target.set_volume(args.level),
+            )?;
+        }
+        Command::Mute(args) => {
+            let mute = args.state == cli::Switch::On;
+            for_each_target(
+                cli,
+                &config,
+                &devices,
+                &args.targets,
+                &args.mode,
+                &|target| target.set_mute(mute),
+            )?;
+        }
+        Command::Display(args) => {
+            let on = args.state == cli::Switch::On;
+            for_each_target(
+                cli,
+                &config,
+                &devices,
+                &args.targets,
+                &args.mode,
+                &|target| target.set_display_power(on),
+            )?;
+        }
+        Command::DisplayRaw(args) => {
+            let data = console::unescape(&args.data)?;
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let answer = if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.send_to_display(device, &data)?
+            } else {
+                find_local_device(&devices, device)?.send_to_display(&data)?
+            };
+            if !answer.is_empty() {
+                println!("{}", console::escape(&answer).trim_end_matches('\n'));
             }
+        }
+        Command::Run(args) => {
+            use anyhow::Context;
 
-            let pipe_read = pipe.read_end.try_clone()?;
-            std::thread::spawn(move || {
-                use std::io::BufRead;
-                let mut reader = std::io::BufReader::new(&pipe_read);
-                loop {
-                    let mut message = String::new();
-                    match reader.read_line(&mut message) {
-                        Ok(_) => debug!("{}", message.trim_end()),
-                        Err(_) => {}
+            let file = args.file.display().to_string();
+            let text = std::fs::read_to_string(&args.file)
+                .with_context(|| format!("Cannot read {}", file))?;
+            let lines = script::parse(&text).with_context(|| file.clone())?;
+            let runner = script::Runner {
+                config: &config,
+                stop_on_error: args.stop_on_error,
+            };
+            let device = args.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let target = script::RemoteDevice {
+                    client: &remote,
+                    name: device.ok_or(anyhow!("No device given"))?,
+                };
+                runner.run(&target, &file, &lines)?;
+            } else {
+                runner.run(&find_local_device(&devices, device)?, &file, &lines)?;
+            }
+        }
+        Command::Console(args) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                console::run(device, |command| Ok(remote.send_raw(device, command)?))?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                console::run(&device.name, |command| Ok(device.send_raw(command)?))?;
+            }
+        }
+        #[cfg(feature = "server")]
+        Command::Server(args) => run_server(args, &config)?,
+        #[cfg(all(feature = "server", unix))]
+        Command::InstallService(args) => service::install(args)?,
+        Command::Rescan(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            remote.rescan()?;
+            print_devices(remote.list()?.into_iter(), output_format(cli, &config))?;
+        }
+        Command::WaitForDevice(args) => {
+            let name = args
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            wait_for_device(cli, &config, &args.mode, name, args.timeout)?;
+        }
+        Command::Hold(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let device = args
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            remote.hold(device, args.duration.filter(|_| !args.release))?;
+        }
+        Command::Maintenance(command) => {
+            let (server, message) = match command {
+                cli::MaintenanceCommand::On(args) => (&args.server, Some(args.message.as_str())),
+                cli::MaintenanceCommand::Off(args) => (args, None),
+            };
+            let addr = server
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            remote.set_maintenance(message)?;
+        }
+        Command::Log(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let device = args
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            for message in remote.device_log(device)? {
+                let time = chrono::DateTime::<chrono::Local>::from(message.time);
+                println!("{}  {}", time.format("%Y-%m-%d %H:%M:%S"), message.text);
+            }
+        }
+        Command::Undo(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let device = args
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            println!("{}", remote.undo(device)?);
+        }
+        Command::History(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let device = args
+                .device
+                .as_deref()
+                .or(config.device.as_deref())
+                .ok_or(anyhow!("No device given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            for change in remote.history(device)? {
+                let time = chrono::DateTime::<chrono::Local>::from(change.time);
+                let from = change
+                    .from
+                    .map_or_else(|| "?".to_string(), |from| from.to_string());
+                println!(
+                    "{}  {} -> {}  by {}",
+                    time.format("%Y-%m-%d %H:%M:%S"),
+                    from,
+                    change.to,
+                    change.by
+                );
+            }
+        }
+        Command::Stats(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            print_stats(&remote.stats()?, output_format(cli, &config))?;
+        }
+        Command::Connections(args) => {
+            let addr = args
+                .server
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            match &args.disconnect {
+                Some(peer) => remote.disconnect(peer)?,
+                None => print_sessions(&remote.sessions()?, output_format(cli, &config))?,
+            }
+        }
+        Command::Token(command) => {
+            let server = match command {
+                cli::TokenCommand::Create(args) => &args.server,
+                cli::TokenCommand::Revoke(args) => &args.server,
+                cli::TokenCommand::List(server) => server,
+            };
+            let remote = token_client(server, &config, cli)?;
+            let format = output_format(cli, &config);
+            match command {
+                cli::TokenCommand::Create(args) => {
+                    let (token, secret) = remote.create_token(args.scope, &args.devices)?;
+                    match format {
+                        OutputFormat::Text | OutputFormat::Csv => {
+                            eprintln!(
+                                "Created token {} with the {} scope; its secret is not shown again:",
+                                token.id, token.scope
+                            );
+                            println!("{}", secret);
+                        }
+                        _ => print_value(
+                            &serde_json::json!({ "token": token, "secret": secret }),
+                            format,
+                        )?,
                     }
                 }
-            });
-
-            match server::do_daemon(&addrs) {
-                Ok(()) => {}
-                Err(e) => error!("{}", e.to_string()),
+                cli::TokenCommand::Revoke(args) => remote.revoke_token(&args.id)?,
+                cli::TokenCommand::List(_) => print_tokens(&remote.list_tokens()?, format)?,
             }
         }
-        ("rescan", Some(sub_c)) => {
-            let remote = client::Client::new(&sub_c.value_of("address").unwrap().to_string())?;
-            remote.rescan()?;
-            remote.list()?;
+        #[cfg(feature = "tui")]
+        Command::Tui(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            tui::run(&remote_client(addr, &config, cli)?, &config)?;
+        }
+        #[cfg(feature = "mdns")]
+        Command::Discover(args) => discover(args, output_format(cli, &config))?,
+        Command::Doctor(args) => {
+            let mut findings = doctor::local();
+            if let Some(addr) = args.remote.as_deref().or(config.remote.as_deref()) {
+                let devices =
+                    remote_client(addr, &config, cli).and_then(|remote| Ok(remote.list()?.len()));
+                findings.push(doctor::server(addr, devices));
+            }
+            doctor::report(&findings)?;
+        }
+        Command::Check(args) => {
+            let remote = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))
+                .and_then(|addr| remote_client(addr, &config, cli));
+            let limits = check::Limits {
+                warning: args.warning,
+                critical: args.critical.or(config.max_temperature),
+            };
+            // Monitoring goes by the exit status alone.
+            let state = check::run(remote, args.device.as_deref(), &limits);
+            std::process::exit(state.code());
+        }
+        Command::Zabbix(cli::ZabbixCommand::Discovery(args)) => {
+            let discovery = if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let names: Vec<_> = remote.list()?.into_iter().map(|d| d.name).collect();
+                let targets: Vec<_> = names
+                    .iter()
+                    .map(|name| script::RemoteDevice {
+                        client: &remote,
+                        name,
+                    })
+                    .collect();
+                zabbix::discovery(&targets)
+            } else {
+                zabbix::discovery(&devices.iter().collect::<Vec<_>>())
+            };
+            println!("{}", discovery);
+        }
+        Command::Zabbix(cli::ZabbixCommand::Get(args)) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let value = if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let name = device.ok_or(anyhow!("No device given"))?;
+                zabbix::get(
+                    &script::RemoteDevice {
+                        client: &remote,
+                        name,
+                    },
+                    args.metric,
+                )?
+            } else {
+                zabbix::get(&find_local_device(&devices, device)?, args.metric)?
+            };
+            println!("{}", value);
+        }
+        #[cfg(target_os = "linux")]
+        Command::GenUdev(args) => {
+            print!("{}", udev::rules(&args.group));
+        }
+        Command::Debug(cli::DebugCommand::Dump(args)) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
+            println!("{}", remote.dump_state()?);
+        }
+        Command::Firmware(cli::FirmwareCommand::Upload(args)) => {
+            upload_firmware(cli, &config, &devices, args)?;
+        }
+        Command::Wall(cli::WallCommand::Preset(cli::WallPresetCommand::Recall(args))) => {
+            let device = args.wall.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.wall.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.recall_preset(device, args.preset)?;
+            } else {
+                find_local_device(&devices, device)?.recall_preset(args.preset)?;
+            }
+        }
+        Command::Wall(cli::WallCommand::Layout(args)) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let format = output_format(cli, &config);
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                print_layout(device, &remote.wall_layout(device)?, format)?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                print_layout(&device.name, &device.wall_layout()?, format)?;
+            }
+        }
+        Command::Layout(args) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let format = output_format(cli, &config);
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                print_windows(device, &remote.recall_layout(device, args.layout)?, format)?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                print_windows(&device.name, &device.recall_layout(args.layout)?, format)?;
+            }
+        }
+        Command::Annotate(command) => {
+            let (args, annotation) = match command {
+                cli::AnnotateCommand::Clear(args) => (args, Annotation::Clear),
+                cli::AnnotateCommand::Pointer(args) => (args, Annotation::Pointer),
+                cli::AnnotateCommand::Freeze(args) => (
+                    &args.annotator,
+                    Annotation::Freeze(args.state == cli::Switch::On),
+                ),
+            };
+            let device = args.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.annotate(device, annotation)?;
+            } else {
+                find_local_device(&devices, device)?.annotate(annotation)?;
+            }
+        }
+        Command::Pip(cli::PipCommand::Mode(args)) => {
+            let mode = match args.mode {
+                cli::PipMode::Off => PipMode::Off,
+                cli::PipMode::Pip => PipMode::Pip,
+                cli::PipMode::Quad => PipMode::Quad,
+            };
+            let device = args.pip.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.pip.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.set_pip_mode(device, mode)?;
+            } else {
+                find_local_device(&devices, device)?.set_pip_mode(mode)?;
+            }
+        }
+        Command::Pip(cli::PipCommand::Source(args)) => {
+            let device = args.pip.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.pip.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                let input = config.resolve_input(device, &args.input);
+                remote.assign_source(device, args.window, &input)?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                let input = config.resolve_input(&device.name, &args.input);
+                device.assign_source(args.window, &input)?;
+            }
+        }
+        Command::Sync(cli::SyncCommand::Show(args)) => {
+            let device = args.device.as_deref().or(config.device.as_deref());
+            let format = output_format(cli, &config);
+            if let Some(addr) = remote_address(&args.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                print_sync(device, &remote.sync(device)?, format)?;
+            } else {
+                let device = find_local_device(&devices, device)?;
+                print_sync(&device.name, &device.sync()?, format)?;
+            }
         }
-        ("stop_server", Some(sub_c)) => {
-            let remote = client::Client::new(&sub_c.value_of("address").unwrap().to_string())?;
+        Command::Sync(cli::SyncCommand::Format(args)) => {
+            let format = match args.format {
+                cli::SyncFormat::Hv => SyncFormat::SeparateHv,
+                cli::SyncFormat::Composite => SyncFormat::Composite,
+                cli::SyncFormat::SyncOnGreen => SyncFormat::SyncOnGreen,
+                cli::SyncFormat::TriLevel => SyncFormat::TriLevel,
+            };
+            let device = args.sync.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.sync.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.set_sync_format(device, format)?;
+            } else {
+                find_local_device(&devices, device)?.set_sync_format(format)?;
+            }
+        }
+        Command::Sync(cli::SyncCommand::Genlock(args)) => {
+            let genlock = match args.genlock {
+                cli::Genlock::FreeRun => Genlock::FreeRun,
+                cli::Genlock::Input => Genlock::SelectedInput,
+                cli::Genlock::Reference => Genlock::Reference,
+            };
+            let device = args.sync.device.as_deref().or(config.device.as_deref());
+            if let Some(addr) = remote_address(&args.sync.mode, &config) {
+                let remote = remote_client(addr, &config, cli)?;
+                let device = device.ok_or(anyhow!("No device given"))?;
+                remote.set_genlock(device, genlock)?;
+            } else {
+                find_local_device(&devices, device)?.set_genlock(genlock)?;
+            }
+        }
+        Command::StopServer(args) => {
+            let addr = args
+                .remote
+                .as_deref()
+                .or(config.remote.as_deref())
+                .ok_or(anyhow!("No server address given"))?;
+            let remote = remote_client(addr, &config, cli)?;
             remote.stop()?;
         }
-        _ => unreachable!(),
+        Command::Schema => {
+            print!("{}", control_dsc::schema());
+        }
+        Command::AddDevice(args) => {
+            Config::add_device(&args.name, &args.address, args.password.as_deref())?;
+            println!("Added {} at {}", args.name, args.address);
+        }
+        Command::Config(cli::ConfigCommand::Show) => match cli.format {
+            Some(OutputFormat::Json) => print_value(&config.rooms(), OutputFormat::Json)?,
+            Some(OutputFormat::Csv) => return Err(anyhow!("The configuration is not a table")),
+            _ => print_value(&config.rooms(), OutputFormat::Yaml)?,
+        },
+        Command::Config(cli::ConfigCommand::Apply(args)) => {
+            use anyhow::Context;
+            use std::io::Read;
+
+            let mut text = String::new();
+            if args.file.as_os_str() == "-" {
+                std::io::stdin().read_to_string(&mut text)?;
+            } else {
+                text = std::fs::read_to_string(&args.file)
+                    .with_context(|| format!("Cannot read {}", args.file.display()))?;
+            }
+            let rooms = config::Rooms::parse(&text)?;
+            let changes = Config::apply(&rooms)?;
+            if changes.is_empty() {
+                println!("Nothing to change");
+            }
+            for change in changes {
+                println!("Updated {}", change);
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,203 @@
+//! How the devices fared since the server started: how long they take to answer, which error
+//! codes they answer with, how often they do not answer and how often they come back after
+//! dropping off, so that a flaky connection shows before it fails completely.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in milliseconds. Slower answers go to one more bucket.
+pub const LATENCY_BUCKETS_MS: [u32; 8] = [5, 10, 25, 50, 100, 250, 500, 1000];
+
+/// How one device fared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub name: String,
+    /// Whether the last scan found the device.
+    pub online: bool,
+    /// Answers by how long they took: within each of [`LATENCY_BUCKETS_MS`] and slower.
+    pub latencies: Vec<u64>,
+    /// Time all answers took together.
+    pub latency_total: Duration,
+    /// Answers with an error code, by the code.
+    pub errors: BTreeMap<u8, u64>,
+    /// Commands the device did not answer in time.
+    pub timeouts: u64,
+    /// Times a scan found the device again after one missed it.
+    pub reconnects: u64,
+}
+
+impl DeviceStats {
+    fn new(name: &str) -> Self {
+        DeviceStats {
+            name: name.to_string(),
+            online: true,
+            latencies: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+            latency_total: Duration::ZERO,
+            errors: BTreeMap::new(),
+            timeouts: 0,
+            reconnects: 0,
+        }
+    }
+
+    /// Commands the device answered, with an error code or not.
+    pub fn answered(&self) -> u64 {
+        self.latencies.iter().sum()
+    }
+}
+
+/// Stats of every device the server has seen, shared by everything talking to the devices.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<BTreeMap<String, DeviceStats>>>);
+
+impl Metrics {
+    fn update(&self, device: &str, f: impl FnOnce(&mut DeviceStats)) {
+        let mut devices = self.0.lock().unwrap();
+        f(devices
+            .entry(device.to_string())
+            .or_insert_with(|| DeviceStats::new(device)));
+    }
+
+    /// Records that `device` answered after `latency`.
+    pub fn answered(&self, device: &str, latency: Duration) {
+        let ms = latency.as_millis();
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= u128::from(bound))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.update(device, |stats| {
+            stats.latencies[bucket] += 1;
+            stats.latency_total += latency;
+        });
+    }
+
+    /// Records that `device` answered with the error code `code`.
+    pub fn error(&self, device: &str, code: u8) {
+        self.update(device, |stats| *stats.errors.entry(code).or_insert(0) += 1);
+    }
+
+    /// Records that `device` did not answer in time.
+    pub fn timed_out(&self, device: &str) {
+        self.update(device, |stats| stats.timeouts += 1);
+    }
+
+    /// Records that a scan found `device`, a reconnect if the one before missed it.
+    pub fn found(&self, device: &str) {
+        self.update(device, |stats| {
+            if !stats.online {
+                stats.online = true;
+                stats.reconnects += 1;
+            }
+        });
+    }
+
+    /// Records that a scan missed `device`.
+    pub fn lost(&self, device: &str) {
+        self.update(device, |stats| stats.online = false);
+    }
+
+    /// Stats of every device seen, by name.
+    pub fn devices(&self) -> Vec<DeviceStats> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// `devices` in the Prometheus text exposition format.
+pub fn prometheus(devices: &[DeviceStats]) -> String {
+    let mut text = String::new();
+    let label = |name: &str| {
+        name.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    };
+
+    text.push_str("# HELP control_dsc_device_up Whether the last scan found the device.\n");
+    text.push_str("# TYPE control_dsc_device_up gauge\n");
+    for stats in devices {
+        let _ = writeln!(
+            text,
+            "control_dsc_device_up{{device=\"{}\"}} {}",
+            label(&stats.name),
+            stats.online as u8
+        );
+    }
+
+    text.push_str(
+        "# HELP control_dsc_command_duration_seconds Time the device took to answer commands.\n",
+    );
+    text.push_str("# TYPE control_dsc_command_duration_seconds histogram\n");
+    for stats in devices {
+        let device = label(&stats.name);
+        let mut count = 0;
+        for (bound, n) in LATENCY_BUCKETS_MS.iter().zip(&stats.latencies) {
+            count += n;
+            let _ = writeln!(
+                text,
+                "control_dsc_command_duration_seconds_bucket{{device=\"{}\",le=\"{}\"}} {}",
+                device,
+                f64::from(*bound) / 1000.0,
+                count
+            );
+        }
+        let _ = writeln!(
+            text,
+            "control_dsc_command_duration_seconds_bucket{{device=\"{}\",le=\"+Inf\"}} {}",
+            device,
+            stats.answered()
+        );
+        let _ = writeln!(
+            text,
+            "control_dsc_command_duration_seconds_sum{{device=\"{}\"}} {}",
+            device,
+            stats.latency_total.as_secs_f64()
+        );
+        let _ = writeln!(
+            text,
+            "control_dsc_command_duration_seconds_count{{device=\"{}\"}} {}",
+            device,
+            stats.answered()
+        );
+    }
+
+    text.push_str("# HELP control_dsc_device_errors_total Answers with an error code.\n");
+    text.push_str("# TYPE control_dsc_device_errors_total counter\n");
+    for stats in devices {
+        for (code, n) in &stats.errors {
+            let _ = writeln!(
+                text,
+                "control_dsc_device_errors_total{{device=\"{}\",code=\"E{:02}\"}} {}",
+                label(&stats.name),
+                code,
+                n
+            );
+        }
+    }
+
+    let counters: [(&str, &str, fn(&DeviceStats) -> u64); 2] = [
+        (
+            "control_dsc_command_timeouts_total",
+            "Commands the device did not answer in time.",
+            |stats| stats.timeouts,
+        ),
+        (
+            "control_dsc_device_reconnects_total",
+            "Times a scan found the device again after one missed it.",
+            |stats| stats.reconnects,
+        ),
+    ];
+    for (metric, help, value) in counters.iter() {
+        let _ = writeln!(text, "# HELP {} {}", metric, help);
+        let _ = writeln!(text, "# TYPE {} counter", metric);
+        for stats in devices {
+            let _ = writeln!(
+                text,
+                "{}{{device=\"{}\"}} {}",
+                metric,
+                label(&stats.name),
+                value(stats)
+            );
+        }
+    }
+    text
+}
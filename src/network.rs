@@ -0,0 +1,367 @@
+//! Extron devices on the network, which speak SIS over Telnet as they do over USB: scalers,
+//! switchers and matrices with a LAN port, registered by their address since they cannot be
+//! found by scanning.
+//!
+//! A connection to each device is kept from one operation to the next, as devices take few
+//! Telnet sessions at once and greet every one. It is made again once the device closed it
+//! or it failed. Devices with a password on their Telnet port are given it at every login.
+
+use crate::error::{ControlError, Result};
+use crate::extron::{ExtronDevice, Port};
+use crate::{endpoint, keepalive};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Telnet port devices take SIS on when the address has no port. Some take it on 2023 too.
+pub const SIS_PORT: u16 = 23;
+
+/// How long connecting and logging in may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long the device may pause in the middle of a greeting it is not known to have ended.
+const GREETING_PAUSE: Duration = Duration::from_millis(300);
+
+/// Days the date line of the greeting starts with.
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// What `device_path` starts with for devices on the network.
+const PATH_PREFIX: &str = "tcp:";
+
+/// A device on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkDevice {
+    /// What the device is called here, as it is not asked for its name.
+    pub name: String,
+    /// Its host name or IP address, with an optional port.
+    pub address: String,
+    /// Password of its Telnet port, if it has one.
+    pub password: Option<String>,
+}
+
+impl NetworkDevice {
+    pub fn new(name: &str, address: &str) -> Self {
+        NetworkDevice {
+            name: name.to_string(),
+            address: address.to_string(),
+            password: None,
+        }
+    }
+
+    /// Logs in with `password`, for devices whose Telnet port asks for one.
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// The device as seen by the rest of the crate, at the path `tcp:` and its address. Its
+    /// clones share one connection, closed once the last of them is dropped.
+    pub fn device(&self) -> ExtronDevice {
+        let (address, password) = (self.address.clone(), self.password.clone());
+        let path = format!("{}{}", PATH_PREFIX, self.address);
+        let kept = Kept::default();
+        ExtronDevice::with_port(&self.name, &path, move || {
+            Ok(Box::new(PooledPort::take(
+                &kept,
+                &address,
+                password.as_deref(),
+            )?))
+        })
+        .with_transport("tcp")
+    }
+}
+
+/// `devices` as a list, for [`crate::extron::ExtronDeviceList::extend`].
+pub fn device_list(devices: &[NetworkDevice]) -> crate::extron::ExtronDeviceList {
+    let mut list = crate::extron::ExtronDeviceList::new();
+    for device in devices {
+        list.insert(device.device());
+    }
+    list
+}
+
+/// Error for a device at `address` that cannot be reached or talked to.
+fn failed(address: &str, e: impl std::fmt::Display) -> ControlError {
+    ControlError::Connection(io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("{}: {}", address, e),
+    ))
+}
+
+/// Error for a device at `address` that does not let us in.
+fn refused(address: &str, why: &str) -> ControlError {
+    ControlError::Unauthorized(format!("{}: {}", address, why))
+}
+
+/// Connects to the device at `address` and reads its greeting, logging in with `password`
+/// when it asks for one.
+fn connect(address: &str, password: Option<&str>) -> Result<TcpPort> {
+    let addrs = endpoint::resolve_or(address, SIS_PORT).map_err(|e| failed(address, e))?;
+    let mut last_error = None;
+    let mut stream = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return Err(failed(address, last_error.unwrap())),
+    };
+    stream
+        .set_nodelay(true)
+        .and_then(|()| keepalive::enable(&stream))
+        .map_err(|e| failed(address, e))?;
+    let mut port = TcpPort { stream };
+    // Without a password to give, there is no prompt to wait for after the date.
+    let greeting =
+        port.read_greeting(|text| prompted(text) || (password.is_none() && dated(text)))?;
+    if !prompted(&greeting) {
+        return Ok(port);
+    }
+    let password = password.ok_or_else(|| refused(address, "asks for a password"))?;
+    port.write_all(password.as_bytes())
+        .and_then(|()| port.write_all(b"\r"))
+        .map_err(|e| failed(address, e))?;
+    // Answered with the level logged in at, or with the prompt again.
+    let answer = port.read_greeting(|text| prompted(text) || logged_in(text))?;
+    if !logged_in(&answer) {
+        return Err(refused(address, "password refused"));
+    }
+    Ok(port)
+}
+
+/// Complete lines of `text`, without the one still coming in.
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    let complete = text.rfind('\n').map_or("", |end| &text[..end]);
+    complete.lines().map(str::trim)
+}
+
+/// Whether `text` ends with the password prompt.
+fn prompted(text: &str) -> bool {
+    text.trim_end().ends_with("Password:")
+}
+
+/// Whether `text` has the date line that ends the greeting, e.g. `Wed, 05 Jun 2024 14:32:28`.
+fn dated(text: &str) -> bool {
+    lines(text).any(|line| {
+        line.get(..3).is_some_and(|day| DAYS.contains(&day)) && line.get(3..4) == Some(",")
+    })
+}
+
+/// Whether `text` has the line telling the level logged in at, e.g. `Login Administrator`.
+fn logged_in(text: &str) -> bool {
+    lines(text).any(|line| line.starts_with("Login"))
+}
+
+/// Where the connection to a device is kept between operations, empty while none is.
+type Kept = Arc<Mutex<Option<TcpPort>>>;
+
+/// The connection to a device for one operation, kept for the next one after it unless it
+/// failed.
+struct PooledPort {
+    /// `None` once it failed.
+    port: Option<TcpPort>,
+    /// What the device sent while the connection was kept.
+    pending: Vec<u8>,
+    kept: Kept,
+}
+
+impl PooledPort {
+    /// Takes the connection in `kept`, unless the device closed it meanwhile, or else connects
+    /// to the device at `address` anew.
+    fn take(kept: &Kept, address: &str, password: Option<&str>) -> Result<Self> {
+        let taken = kept.lock().unwrap().take();
+        let alive = taken.and_then(|mut port| match port.read_available() {
+            Ok(pending) => Some((port, pending)),
+            Err(e) => {
+                debug!("Connecting to {} again: {}", address, e);
+                None
+            }
+        });
+        let (port, pending) = match alive {
+            Some(alive) => alive,
+            None => (connect(address, password)?, Vec::new()),
+        };
+        Ok(PooledPort {
+            port: Some(port),
+            pending,
+            kept: kept.clone(),
+        })
+    }
+
+    /// Runs `op` on the connection, which is dropped when it fails other than by timing out.
+    fn with<T>(&mut self, op: impl FnOnce(&mut TcpPort) -> io::Result<T>) -> io::Result<T> {
+        let port = self
+            .port
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "the connection failed"))?;
+        let result = op(port);
+        if matches!(&result, Err(e) if e.kind() != io::ErrorKind::TimedOut) {
+            self.port = None;
+        }
+        result
+    }
+}
+
+impl Read for PooledPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n);
+        }
+        let n = self.with(|port| port.read(buf))?;
+        // The device closed the connection.
+        if n == 0 && !buf.is_empty() {
+            self.port = None;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for PooledPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with(|port| port.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with(|port| port.flush())
+    }
+}
+
+impl Port for PooledPort {
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.pending.clear();
+        self.with(|port| port.clear_input())
+    }
+
+    fn take_pending(&mut self) -> io::Result<Vec<u8>> {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend(self.with(|port| port.take_pending())?);
+        Ok(pending)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.with(|port| port.set_read_timeout(timeout))
+    }
+}
+
+impl Drop for PooledPort {
+    fn drop(&mut self) {
+        if let Some(port) = self.port.take() {
+            let mut kept = self.kept.lock().unwrap();
+            // Another operation may have connected meanwhile, one connection is enough.
+            if kept.is_none() {
+                *kept = Some(port);
+            }
+        }
+    }
+}
+
+/// A connection to a device on the network.
+struct TcpPort {
+    stream: TcpStream,
+}
+
+impl TcpPort {
+    /// Reads what the device says on its own after connecting, e.g. its copyright line and
+    /// the date, until `done` says that was all, or else until it pauses for
+    /// [`GREETING_PAUSE`].
+    fn read_greeting<F>(&mut self, done: F) -> Result<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        self.stream
+            .set_read_timeout(Some(GREETING_PAUSE))
+            .map_err(|e| failed(&self.peer(), e))?;
+        let mut greeting = Vec::new();
+        let mut buf = [0; 256];
+        while Instant::now() < deadline {
+            match self.read(&mut buf) {
+                Ok(0) => return Err(failed(&self.peer(), "closed the connection")),
+                Ok(n) => {
+                    greeting.extend_from_slice(&buf[..n]);
+                    if done(&String::from_utf8_lossy(&greeting)) {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(failed(&self.peer(), e)),
+            }
+        }
+        Ok(String::from_utf8_lossy(&greeting).into_owned())
+    }
+
+    fn peer(&self) -> String {
+        self.stream
+            .peer_addr()
+            .map_or_else(|_| "device".to_string(), |addr| addr.to_string())
+    }
+
+    /// Reads what already arrived, without waiting for more. Fails with `ConnectionAborted`
+    /// once the device closed the connection.
+    fn read_available(&mut self) -> io::Result<Vec<u8>> {
+        self.stream.set_nonblocking(true)?;
+        let mut available = Vec::new();
+        let mut buf = [0; 256];
+        let result = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the device closed the connection",
+                    ))
+                }
+                Ok(n) => available.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result.map(|()| available)
+    }
+}
+
+/// Read timeouts fail with `TimedOut` on every platform, as they do on the serial ports.
+impl Read for TcpPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(io::ErrorKind::TimedOut, e),
+            _ => e,
+        })
+    }
+}
+
+impl Write for TcpPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Port for TcpPort {
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.read_available().map(|_| ())
+    }
+
+    fn take_pending(&mut self) -> io::Result<Vec<u8>> {
+        self.read_available()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        // Sockets take no zero timeout.
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+    }
+}
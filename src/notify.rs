@@ -0,0 +1,235 @@
+//! Posts the alerts of the server to Slack or Telegram, or sends them by mail, e.g. to the
+//! ticketing of the facilities team, so that whoever looks after the rooms hears of a device
+//! going offline, failing or running hot without watching a dashboard.
+//!
+//! A unit that keeps dropping off, or a whole rack losing power, must not flood anyone: the
+//! same alert of a device is posted again only after a while, and only so many messages go
+//! out within an hour. The alerts held back are counted in the next message that goes out.
+
+use crate::config::{EmailConfig, NotifyConfig, SmtpTls};
+use anyhow::{bail, Context, Result};
+use control_dsc::server::ServerEvent;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_REPEAT: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_MAX_PER_HOUR: usize = 20;
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// How long posting a message may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where messages are posted.
+#[derive(Clone)]
+enum Channel {
+    Slack {
+        webhook: String,
+    },
+    Telegram {
+        token: String,
+        chat: String,
+    },
+    Email {
+        transport: SmtpTransport,
+        from: Mailbox,
+        to: Vec<Mailbox>,
+    },
+}
+
+impl Channel {
+    fn post(&self, text: &str) -> Result<()> {
+        let agent = || ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        match self {
+            Channel::Slack { webhook } => {
+                agent()
+                    .post(webhook)
+                    .send_json(serde_json::json!({ "text": text }))?;
+            }
+            Channel::Telegram { token, chat } => {
+                let url = format!("https://api.telegram.org/bot{}/sendMessage", token);
+                agent()
+                    .post(&url)
+                    .send_json(serde_json::json!({ "chat_id": chat, "text": text }))?;
+            }
+            Channel::Email {
+                transport,
+                from,
+                to,
+            } => {
+                let mut message = Message::builder()
+                    .from(from.clone())
+                    .subject(format!("control-dsc: {}", text))
+                    .header(ContentType::TEXT_PLAIN);
+                for to in to {
+                    message = message.to(to.clone());
+                }
+                transport.send(&message.body(text.to_string())?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Channel::Slack { .. } => "Slack",
+            Channel::Telegram { .. } => "Telegram",
+            Channel::Email { .. } => "mail",
+        }
+    }
+}
+
+/// The channel sending mail as `config` says.
+fn email(config: &EmailConfig) -> Result<Channel> {
+    let mailbox = |address: &str| -> Result<Mailbox> {
+        address
+            .parse()
+            .with_context(|| format!("'{}' is not a mail address", address))
+    };
+    if config.to.is_empty() {
+        bail!("No addresses to send alerts to");
+    }
+    let builder = match config.tls {
+        SmtpTls::StartTls => SmtpTransport::starttls_relay(&config.server)?,
+        SmtpTls::Tls => SmtpTransport::relay(&config.server)?,
+        SmtpTls::Off => SmtpTransport::builder_dangerous(&config.server),
+    };
+    let builder = match config.port {
+        Some(port) => builder.port(port),
+        None => builder,
+    };
+    let builder = match (&config.user, &config.password) {
+        (Some(user), Some(password)) => {
+            builder.credentials(Credentials::new(user.clone(), password.clone()))
+        }
+        (None, None) => builder,
+        _ => bail!("user and password of the SMTP server go together"),
+    };
+    Ok(Channel::Email {
+        transport: builder.timeout(Some(TIMEOUT)).build(),
+        from: mailbox(&config.from)?,
+        to: config
+            .to
+            .iter()
+            .map(|to| mailbox(to))
+            .collect::<Result<_>>()?,
+    })
+}
+
+/// Decides which alerts get posted.
+struct Limiter {
+    repeat: Duration,
+    max_per_hour: usize,
+    /// When each alert was last posted, by device and kind.
+    posted: HashMap<(String, &'static str), Instant>,
+    /// When the messages of the last hour went out.
+    sent: VecDeque<Instant>,
+    /// Alerts held back since the last message.
+    held: usize,
+}
+
+impl Limiter {
+    /// Whether the alert of `kind` for `device` is posted at `now`, with the number of alerts
+    /// held back before it.
+    fn admit(&mut self, device: &str, kind: &'static str, now: Instant) -> Option<usize> {
+        while self
+            .sent
+            .front()
+            .is_some_and(|&time| now.duration_since(time) >= HOUR)
+        {
+            self.sent.pop_front();
+        }
+        let key = (device.to_string(), kind);
+        let repeated = self
+            .posted
+            .get(&key)
+            .is_some_and(|&time| now.duration_since(time) < self.repeat);
+        if repeated || self.sent.len() >= self.max_per_hour {
+            self.held += 1;
+            return None;
+        }
+        self.posted.insert(key, now);
+        self.sent.push_back(now);
+        Some(std::mem::take(&mut self.held))
+    }
+}
+
+/// The device, kind and text of the alert `event` is, `None` for other events.
+fn alert(event: &ServerEvent) -> Option<(&str, &'static str, String)> {
+    match event {
+        ServerEvent::DeviceOffline(device) => Some((
+            device.as_str(),
+            "device_offline",
+            format!("{} went offline", device),
+        )),
+        ServerEvent::HealthAlert { device, alert } => Some((
+            device.as_str(),
+            alert.kind(),
+            format!("{}: {}", device, alert),
+        )),
+        _ => None,
+    }
+}
+
+/// Checks the channels of the configuration and returns the event hook posting to them.
+pub fn event_hook(config: &NotifyConfig) -> Result<impl Fn(&ServerEvent) + Send + Sync> {
+    let mut channels = Vec::new();
+    if let Some(webhook) = &config.slack_webhook {
+        channels.push(Channel::Slack {
+            webhook: webhook.clone(),
+        });
+    }
+    match (&config.telegram_token, &config.telegram_chat) {
+        (Some(token), Some(chat)) => channels.push(Channel::Telegram {
+            token: token.clone(),
+            chat: chat.clone(),
+        }),
+        (None, None) => {}
+        _ => bail!("telegram_token and telegram_chat go together"),
+    }
+    if let Some(email) = &config.email {
+        channels.push(self::email(email).context("Invalid [notify.email]")?);
+    }
+    if config.max_per_hour == Some(0) {
+        bail!("max_per_hour must be at least 1");
+    }
+    let limiter = Mutex::new(Limiter {
+        repeat: config
+            .repeat_minutes
+            .map_or(DEFAULT_REPEAT, |minutes| Duration::from_secs(minutes * 60)),
+        max_per_hour: config.max_per_hour.unwrap_or(DEFAULT_MAX_PER_HOUR),
+        posted: HashMap::new(),
+        sent: VecDeque::new(),
+        held: 0,
+    });
+
+    Ok(move |event: &ServerEvent| {
+        let (device, kind, mut text) = match alert(event) {
+            Some(alert) => alert,
+            None => return,
+        };
+        let held = match limiter.lock().unwrap().admit(device, kind, Instant::now()) {
+            Some(held) => held,
+            None => {
+                info!("Holding back alert '{}'", text);
+                return;
+            }
+        };
+        if held > 0 {
+            text.push_str(&format!(" ({} more alerts held back)", held));
+        }
+        // Posting takes round trips to other servers, which the server does not wait for.
+        for channel in channels.clone() {
+            let text = text.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = channel.post(&text) {
+                    info!("Cannot post to {}: {:#}", channel.name(), e);
+                }
+            });
+        }
+    })
+}
@@ -0,0 +1,96 @@
+//! Logging of every RPC call with its parameters and results, at trace level, for when a
+//! client or server generated from the schema elsewhere and this crate do not agree. Data is
+//! logged by its size only: it carries firmware images and raw SIS commands, which can set
+//! device passwords.
+
+use capnp::dynamic_value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Target of the log lines, for filtering them in or out.
+pub const TARGET: &str = "control_dsc::rpc";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes clients and servers in this process log the calls they make and serve, or stop
+/// doing so.
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Logs a call of `method` with `params`.
+pub(crate) fn call<'a>(method: &str, params: impl Into<dynamic_value::Reader<'a>>) {
+    if enabled() {
+        trace!(target: TARGET, "call {} {}", method, show(params));
+    }
+}
+
+/// Logs the `results` of a call of `method`.
+pub(crate) fn reply<'a>(method: &str, results: impl Into<dynamic_value::Reader<'a>>) {
+    if enabled() {
+        trace!(target: TARGET, "reply {} {}", method, show(results));
+    }
+}
+
+/// Logs that a call of `method` failed with `error` instead of returning results.
+pub(crate) fn failed(method: &str, error: &capnp::Error) {
+    if enabled() {
+        trace!(target: TARGET, "failed {} {}", method, error);
+    }
+}
+
+/// `value` in the text format of the schema language, with data replaced by its size.
+pub fn show<'a>(value: impl Into<dynamic_value::Reader<'a>>) -> String {
+    let mut text = String::new();
+    if let Err(e) = write_value(&mut text, value.into()) {
+        text.push_str(&format!("<{}>", e));
+    }
+    text
+}
+
+fn write_value(text: &mut String, value: dynamic_value::Reader) -> capnp::Result<()> {
+    match value {
+        dynamic_value::Reader::Data(data) => text.push_str(&format!("<{} bytes>", data.len())),
+        dynamic_value::Reader::Text(t) => text.push_str(&format!("{:?}", t.to_str()?)),
+        dynamic_value::Reader::Enum(e) => match e.get_enumerant()? {
+            Some(enumerant) => text.push_str(enumerant.get_proto().get_name()?.to_str()?),
+            None => text.push_str(&e.get_value().to_string()),
+        },
+        dynamic_value::Reader::Struct(s) => {
+            let mut fields: Vec<_> = s.get_schema().get_non_union_fields()?.iter().collect();
+            fields.extend(s.which()?);
+            text.push('(');
+            let mut first = true;
+            for field in fields {
+                if !s.has(field)? {
+                    continue;
+                }
+                if !first {
+                    text.push_str(", ");
+                }
+                first = false;
+                text.push_str(field.get_proto().get_name()?.to_str()?);
+                text.push_str(" = ");
+                write_value(text, s.get(field)?)?;
+            }
+            text.push(')');
+        }
+        dynamic_value::Reader::List(list) => {
+            text.push('[');
+            for (i, element) in list.iter().enumerate() {
+                if i > 0 {
+                    text.push_str(", ");
+                }
+                write_value(text, element?)?;
+            }
+            text.push(']');
+        }
+        dynamic_value::Reader::Capability(_) => text.push_str("<capability>"),
+        dynamic_value::Reader::AnyPointer(_) => text.push_str("<pointer>"),
+        other => text.push_str(&format!("{:?}", other)),
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Inputs selected on a device by time of day, for the [`crate::server::ServerBuilder::schedule`]
+//! of a server.
+//!
+//! The server switches a device when its schedule changes, so an input selected by hand
+//! stays until the next change. A hold keeps the schedule off a device altogether for a while,
+//! after which the device goes back to the scheduled input.
+
+use crate::error::{ControlError, Result};
+use crate::extron::Input;
+use chrono::NaiveTime;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// From `from` until `until` in local time. Periods ending before they start run past
+/// midnight, and those ending when they start last all day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Period {
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+    pub input: Input,
+}
+
+impl Period {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from < self.until {
+            self.from <= time && time < self.until
+        } else {
+            time >= self.from || time < self.until
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub device: String,
+    /// The first period containing the time wins.
+    pub periods: Vec<Period>,
+    /// Input outside the periods. Without one the device is left alone then.
+    pub otherwise: Option<Input>,
+}
+
+impl Schedule {
+    pub fn input_at(&self, time: NaiveTime) -> Option<&Input> {
+        self.periods
+            .iter()
+            .find(|period| period.contains(time))
+            .map(|period| &period.input)
+            .or(self.otherwise.as_ref())
+    }
+}
+
+/// Tracks what the schedules last selected and which devices are held.
+pub(crate) struct Scheduler {
+    schedules: Vec<Schedule>,
+    /// Scheduled input last applied by device, `None` for a time the device is left alone.
+    applied: HashMap<String, Option<Input>>,
+    holds: HashMap<String, Instant>,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<Schedule>) -> Self {
+        Scheduler {
+            schedules,
+            applied: HashMap::new(),
+            holds: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    /// Keeps the schedule off `device` until `until`, or puts it back on its schedule right
+    /// away for `None`.
+    pub fn hold(&mut self, device: &str, until: Option<Instant>) -> Result<()> {
+        if !self.schedules.iter().any(|s| s.device == device) {
+            return Err(ControlError::Unsupported(format!(
+                "{} has no schedule",
+                device
+            )));
+        }
+        match until {
+            Some(until) => {
+                self.holds.insert(device.to_string(), until);
+            }
+            None => {
+                self.holds.remove(device);
+                self.applied.remove(device);
+            }
+        }
+        Ok(())
+    }
+
+    /// Has the input of `device` selected again on the next check, after selecting it failed.
+    pub fn retry(&mut self, device: &str) {
+        self.applied.remove(device);
+    }
+
+    /// The schedules with the input due at `time`, the one last applied and how long a hold
+    /// lasts from `now`, for state dumps.
+    pub fn dump(&self, time: NaiveTime, now: Instant) -> serde_json::Value {
+        let schedules = self.schedules.iter().map(|schedule| {
+            let device = &schedule.device;
+            let applied = self.applied.get(device).and_then(Option::as_ref);
+            serde_json::json!({
+                "device": device,
+                "scheduled": schedule.input_at(time).map(Input::to_string),
+                "applied": applied.map(Input::to_string),
+                "held_seconds": self
+                    .holds
+                    .get(device)
+                    .map(|until| until.saturating_duration_since(now).as_secs()),
+            })
+        });
+        serde_json::Value::Array(schedules.collect())
+    }
+
+    /// Inputs to select at `time`, for the devices whose schedule changed since the last
+    /// check, or that were not scheduled yet or came off hold.
+    pub fn due(&mut self, time: NaiveTime, now: Instant) -> Vec<(String, Input)> {
+        let mut due = Vec::new();
+        for schedule in &self.schedules {
+            let device = &schedule.device;
+            match self.holds.get(device) {
+                Some(until) if *until > now => continue,
+                Some(_) => {
+                    self.holds.remove(device);
+                    self.applied.remove(device);
+                }
+                None => {}
+            }
+            let input = schedule.input_at(time).cloned();
+            if self.applied.get(device) != Some(&input) {
+                self.applied.insert(device.clone(), input.clone());
+                if let Some(input) = input {
+                    due.push((device.clone(), input));
+                }
+            }
+        }
+        due
+    }
+}
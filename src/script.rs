@@ -0,0 +1,253 @@
+use crate::client::Client;
+use crate::config::Config;
+use anyhow::{anyhow, bail, Context, Result};
+use control_dsc::extron::{ExtronDevice, Input};
+use control_dsc::sis::Plane;
+use std::time::Duration;
+
+/// Scenes may refer to other scenes, but not endlessly.
+const MAX_SCENE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Select(Input),
+    Volume(u8),
+    Mute(bool),
+    Sleep(Duration),
+    Scene(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub number: usize,
+    pub command: Command,
+}
+
+/// Something a script can send commands to.
+pub trait Target {
+    fn name(&self) -> &str;
+    fn select(&self, input: &Input) -> Result<()>;
+    fn select_plane(&self, input: &Input, plane: Plane) -> Result<()>;
+    fn set_volume(&self, level: u8) -> Result<()>;
+    fn set_mute(&self, mute: bool) -> Result<()>;
+    fn set_display_power(&self, on: bool) -> Result<()>;
+}
+
+impl Target for ExtronDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn select(&self, input: &Input) -> Result<()> {
+        ExtronDevice::select(self, input).map_err(|e| e.into())
+    }
+
+    fn select_plane(&self, input: &Input, plane: Plane) -> Result<()> {
+        ExtronDevice::select_plane(self, input, plane).map_err(|e| e.into())
+    }
+
+    fn set_volume(&self, level: u8) -> Result<()> {
+        ExtronDevice::set_volume(self, level).map_err(|e| e.into())
+    }
+
+    fn set_mute(&self, mute: bool) -> Result<()> {
+        ExtronDevice::set_mute(self, mute).map_err(|e| e.into())
+    }
+
+    fn set_display_power(&self, on: bool) -> Result<()> {
+        ExtronDevice::set_display_power(self, on).map_err(|e| e.into())
+    }
+}
+
+/// A device reached through a server.
+pub struct RemoteDevice<'a> {
+    pub client: &'a Client,
+    pub name: &'a str,
+}
+
+impl<'a> Target for RemoteDevice<'a> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn select(&self, input: &Input) -> Result<()> {
+        self.client.select(self.name, input).map_err(|e| e.into())
+    }
+
+    fn select_plane(&self, input: &Input, plane: Plane) -> Result<()> {
+        // Servers before planes were added know selecting for all of them.
+        let result = match plane {
+            Plane::All => self.client.select(self.name, input),
+            plane => self.client.select_plane(self.name, input, plane),
+        };
+        result.map_err(|e| e.into())
+    }
+
+    fn set_volume(&self, level: u8) -> Result<()> {
+        self.client
+            .set_volume(self.name, level)
+            .map_err(|e| e.into())
+    }
+
+    fn set_mute(&self, mute: bool) -> Result<()> {
+        self.client.set_mute(self.name, mute).map_err(|e| e.into())
+    }
+
+    fn set_display_power(&self, on: bool) -> Result<()> {
+        self.client
+            .set_display_power(self.name, on)
+            .map_err(|e| e.into())
+    }
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>> {
+    let line = match line.find('#') {
+        Some(n) => &line[..n],
+        None => line,
+    };
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        None => return Ok(None),
+        Some(c) => c,
+    };
+    let arg = words
+        .next()
+        .ok_or(anyhow!("'{}' needs an argument", command))?;
+    if words.next().is_some() {
+        bail!("Too many arguments for '{}'", command);
+    }
+
+    let command = match command {
+        "select" => Command::Select(arg.parse()?),
+        "volume" => match arg.parse::<u8>() {
+            Ok(level) if level <= 100 => Command::Volume(level),
+            _ => bail!("'{}' is not a volume between 0 and 100", arg),
+        },
+        "mute" => match arg {
+            "on" => Command::Mute(true),
+            "off" => Command::Mute(false),
+            _ => bail!("'{}' is not on or off", arg),
+        },
+        // Numbers too large for a duration, like `inf`, fail rather than panic.
+        "sleep" => match arg.parse::<f64>().map(Duration::try_from_secs_f64) {
+            Ok(Ok(duration)) => Command::Sleep(duration),
+            _ => bail!("'{}' is not a valid number of seconds", arg),
+        },
+        "scene" => Command::Scene(arg.to_string()),
+        _ => bail!("Unknown command '{}'", command),
+    };
+    Ok(Some(command))
+}
+
+/// Parses a script with one command per line. Blank lines and `#` comments are ignored.
+pub fn parse(text: &str) -> Result<Vec<Line>> {
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        if let Some(command) = parse_command(line).with_context(|| format!("line {}", number))? {
+            lines.push(Line { number, command });
+        }
+    }
+    Ok(lines)
+}
+
+/// The commands of `lines` with the scenes replaced by their commands, for running them
+/// elsewhere.
+#[cfg(feature = "server")]
+pub fn expand(config: &Config, lines: &[Line]) -> Result<Vec<Command>> {
+    fn expand_into(
+        config: &Config,
+        lines: &[Line],
+        depth: usize,
+        commands: &mut Vec<Command>,
+    ) -> Result<()> {
+        for line in lines {
+            match &line.command {
+                Command::Scene(name) => {
+                    if depth >= MAX_SCENE_DEPTH {
+                        bail!("Scenes nested too deeply at '{}'", name);
+                    }
+                    let steps = config
+                        .scenes
+                        .get(name)
+                        .ok_or(anyhow!("Scene '{}' not found", name))?;
+                    let script =
+                        parse(&steps.join("\n")).with_context(|| format!("scene {}", name))?;
+                    expand_into(config, &script, depth + 1, commands)?;
+                }
+                command => commands.push(command.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    let mut commands = Vec::new();
+    expand_into(config, lines, 0, &mut commands)?;
+    Ok(commands)
+}
+
+pub struct Runner<'a> {
+    pub config: &'a Config,
+    pub stop_on_error: bool,
+}
+
+impl<'a> Runner<'a> {
+    fn execute(&self, target: &dyn Target, command: &Command, depth: usize) -> Result<usize> {
+        match command {
+            Command::Select(input) => {
+                target.select(&self.config.resolve_input(target.name(), input))?
+            }
+            Command::Volume(level) => target.set_volume(*level)?,
+            Command::Mute(mute) => target.set_mute(*mute)?,
+            Command::Sleep(duration) => std::thread::sleep(*duration),
+            Command::Scene(name) => {
+                if depth >= MAX_SCENE_DEPTH {
+                    bail!("Scenes nested too deeply at '{}'", name);
+                }
+                let steps = self
+                    .config
+                    .scenes
+                    .get(name)
+                    .ok_or(anyhow!("Scene '{}' not found", name))?;
+                let script = parse(&steps.join("\n")).with_context(|| format!("scene {}", name))?;
+                return self.run_lines(target, &format!("scene {}", name), &script, depth + 1);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Runs the lines in order and returns the number of failed commands. Failures are
+    /// reported on stderr as they happen, unless `stop_on_error` is set, in which case the
+    /// first failure is returned.
+    fn run_lines(
+        &self,
+        target: &dyn Target,
+        source: &str,
+        lines: &[Line],
+        depth: usize,
+    ) -> Result<usize> {
+        let mut failures = 0;
+        for line in lines {
+            match self.execute(target, &line.command, depth) {
+                Ok(n) => failures += n,
+                Err(e) if self.stop_on_error => {
+                    return Err(e.context(format!("{}:{}", source, line.number)))
+                }
+                Err(e) => {
+                    eprintln!("{}:{}: {:#}", source, line.number, e);
+                    failures += 1;
+                }
+            }
+        }
+        Ok(failures)
+    }
+
+    /// `source` names the script in error messages.
+    pub fn run(&self, target: &dyn Target, source: &str, lines: &[Line]) -> Result<()> {
+        match self.run_lines(target, source, lines, 0)? {
+            0 => Ok(()),
+            1 => Err(anyhow!("1 command failed")),
+            n => Err(anyhow!("{} commands failed", n)),
+        }
+    }
+}
@@ -1,278 +1,4330 @@
-use crate::extron::{ExtronDevice, ExtronDeviceList};
+use crate::error::{ControlError, Result};
+use crate::extron::{
+    Annotation, DeviceHealth, DeviceInfo, DeviceMessage, DeviceStatus, ExtronDevice,
+    ExtronDeviceList, FirmwareUpdate, Genlock, Input, InputChange, PipMode, SyncFormat,
+    SyncSettings, WallLayout, WindowLayout,
+};
 use crate::extron_capnp::control_extron;
+use crate::health::{HealthAlert, Monitor, Signal, Thresholds};
+use crate::history::{History, Undo, FRONT_PANEL};
+use crate::journal::Journal;
+use crate::keepalive;
+use crate::metrics::{DeviceStats, Metrics, LATENCY_BUCKETS_MS};
+use crate::rpc_trace;
+use crate::schedule::{Schedule, Scheduler};
+use crate::sis::{Plane, Reply};
+use crate::state::{Known, StateFile};
+use crate::tokens::{Grant, Scope, TokenStore};
 use capnp::capability::Promise;
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-use std::io::Result;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
 use std::net;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+/// Serves one connection.
 #[derive(Clone)]
 struct ControlExtronImpl {
-    tx_channel: tokio::sync::mpsc::Sender<ServerRequest>,
-    stop: tokio::sync::mpsc::Sender<bool>,
+    tx_channel: mpsc::Sender<Request>,
+    cancel: CancellationToken,
+    /// The connection and what its client did, replaced for every connection.
+    session: std::rc::Rc<Session>,
+    /// Every event, for subscribers.
+    events: broadcast::Sender<ServerEvent>,
+    /// Every open connection, for listing and closing them.
+    connections: Sessions,
+    /// Device states kept by the server.
+    states: SharedState,
+    /// Whether administrative calls are only served to connections from this machine, see
+    /// [`ServerBuilder::local_admin`].
+    local_admin: bool,
+    /// Whether this connection may make administrative calls.
+    admin_allowed: bool,
+    /// API tokens, if the server requires them, see [`ServerBuilder::tokens`].
+    tokens: Option<std::rc::Rc<std::cell::RefCell<TokenStore>>>,
+    /// How connections are secured, if they are, see [`ServerBuilder::tls`].
+    #[cfg(feature = "tls")]
+    tls: Option<std::rc::Rc<crate::tls::ServerTls>>,
+}
+
+impl ControlExtronImpl {
+    /// Fails `method` unless this connection may make administrative calls.
+    fn check_admin(&self, method: &str) -> Result<()> {
+        self.session.grant.borrow().check(Scope::Admin)?;
+        if !self.admin_allowed {
+            info!("Refused {} from another machine", method);
+            return Err(ControlError::NotAllowed(method.to_string()));
+        }
+        Ok(())
+    }
+
+    /// The API tokens, failing on a server that does not require them.
+    fn tokens(&self) -> Result<std::rc::Rc<std::cell::RefCell<TokenStore>>> {
+        self.tokens.clone().ok_or_else(|| {
+            ControlError::Unsupported("API tokens on a server without a token file".to_string())
+        })
+    }
+}
+
+/// A connection and what its client did on it.
+struct Session {
+    peer: net::SocketAddr,
+    connected: std::time::Instant,
+    last_call: std::cell::Cell<std::time::Instant>,
+    calls: std::cell::Cell<u64>,
+    errors: std::cell::Cell<u64>,
+    /// Schema version the client negotiated, 0 before it did.
+    version: std::cell::Cell<u32>,
+    /// Closes the connection when cancelled.
+    disconnect: CancellationToken,
+    /// What the client may do, see [`ServerBuilder::tokens`].
+    grant: std::cell::RefCell<Grant>,
+    /// Name in the certificate of the client, see [`crate::tls`], or `admin socket` for the
+    /// connections there.
+    identity: Option<String>,
+}
+
+/// The open connections, by peer.
+type Sessions = std::rc::Rc<
+    std::cell::RefCell<std::collections::BTreeMap<net::SocketAddr, std::rc::Rc<Session>>>,
+>;
+
+impl Session {
+    fn new(peer: net::SocketAddr, grant: Grant) -> Self {
+        let now = std::time::Instant::now();
+        Session {
+            peer,
+            connected: now,
+            last_call: std::cell::Cell::new(now),
+            calls: Default::default(),
+            errors: Default::default(),
+            version: Default::default(),
+            disconnect: CancellationToken::new(),
+            grant: std::cell::RefCell::new(grant),
+            identity: None,
+        }
+    }
+
+    /// Hands the request built by `request` to the command loop like [`call`], unless the
+    /// grant of the client does not allow it. The devices are checked by the command loop,
+    /// which knows the names the keys of the request stand for.
+    async fn request<T>(
+        &self,
+        tx_channel: mpsc::Sender<Request>,
+        request: impl FnOnce(oneshot::Sender<Result<T>>) -> ServerRequest,
+    ) -> Result<T> {
+        let (reply, rx) = oneshot::channel();
+        let request = request(reply);
+        let grant = self.grant.borrow().clone();
+        grant.check(request.scope())?;
+        send(tx_channel, self.client(), Some(grant), request, rx).await
+    }
+
+    /// Who the client is, as the history names it: the name in its certificate, else the token
+    /// it authenticated with, else its address.
+    fn client(&self) -> String {
+        if let Some(identity) = &self.identity {
+            return identity.clone();
+        }
+        match self.grant.borrow().token() {
+            Some(token) => format!("token {}", token),
+            None => self.peer.to_string(),
+        }
+    }
+
+    /// Counts a call, returning the session to report its outcome to.
+    fn call(self: &std::rc::Rc<Self>) -> std::rc::Rc<Self> {
+        self.calls.set(self.calls.get() + 1);
+        self.last_call.set(std::time::Instant::now());
+        self.clone()
+    }
+
+    /// Sorts out how to report `result` to the client, counting failures: they are returned
+    /// for the error field of the results, or raised as exceptions for clients that did not
+    /// negotiate version 2 or later and so do not know about that field.
+    fn failure(
+        &self,
+        result: Result<()>,
+    ) -> std::result::Result<Option<ControlError>, capnp::Error> {
+        let e = match result {
+            Ok(()) => return Ok(None),
+            Err(e) => e,
+        };
+        self.errors.set(self.errors.get() + 1);
+        match e {
+            ControlError::Rpc(e) => Err(e),
+            e if self.version.get() >= 2 => Ok(Some(e)),
+            e => Err(e.into()),
+        }
+    }
+}
+
+/// How often the schedules are checked.
+const SCHEDULE_CHECK: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often the devices are listened to for messages they send on their own.
+const LISTEN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often the selected inputs are checked for a signal.
+const SIGNAL_CHECK: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How often the devices are checked against the health thresholds.
+const HEALTH_CHECK: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Devices talked to at once by the checks and group calls. Each holds a thread of the
+/// blocking pool while it waits on its device, so hundreds of them go in batches.
+const DEVICES_AT_ONCE: usize = 32;
+
+/// Events buffered per subscriber. A subscriber that falls further behind misses events.
+const EVENT_BUFFER: usize = 64;
+
+/// How long a change can be undone unless [`ServerBuilder::undo_window`] says otherwise.
+pub const DEFAULT_UNDO_WINDOW: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Writes `event` for subscribers. Returns false for events only of interest inside the
+/// server, which are not passed on.
+fn event_to_wire(event: &ServerEvent, mut builder: control_extron::event::Builder) -> bool {
+    match event {
+        ServerEvent::DevicesScanned(_) => builder.set_devices_scanned(()),
+        ServerEvent::InputSelected { device, input } => {
+            builder.set_device(device);
+            builder.set_input_selected(&input.to_string());
+        }
+        ServerEvent::VolumeChanged { device, level } => {
+            builder.set_device(device);
+            builder.set_volume_changed(*level);
+        }
+        ServerEvent::MuteChanged { device, mute } => {
+            builder.set_device(device);
+            builder.set_mute_changed(*mute);
+        }
+        ServerEvent::SignalLost { device, input } => {
+            builder.set_device(device);
+            builder.set_signal_lost(*input);
+        }
+        ServerEvent::SignalRestored { device, input } => {
+            builder.set_device(device);
+            builder.set_signal_restored(*input);
+        }
+        ServerEvent::MaintenanceStarted(message) => builder.set_maintenance_started(message),
+        ServerEvent::MaintenanceEnded => builder.set_maintenance_ended(()),
+        ServerEvent::Listening(_)
+        | ServerEvent::Connected(_)
+        | ServerEvent::Rejected(_)
+        | ServerEvent::DeviceOffline(_)
+        | ServerEvent::HealthAlert { .. } => return false,
+    }
+    true
+}
+
+/// Hands the request built by `request` to the command loop and waits for its reply. The loop
+/// serves it in the current span, on behalf of `client`, e.g. `web`, as the history names it.
+pub(crate) async fn call<T>(
+    tx_channel: mpsc::Sender<Request>,
+    client: &str,
+    request: impl FnOnce(oneshot::Sender<Result<T>>) -> ServerRequest,
+) -> Result<T> {
+    let (reply, rx) = oneshot::channel();
+    send(tx_channel, client.to_string(), None, request(reply), rx).await
+}
+
+/// Hands `request` of `client` with `grant` to the command loop and waits for its reply on
+/// `rx`.
+async fn send<T>(
+    tx_channel: mpsc::Sender<Request>,
+    client: String,
+    grant: Option<Grant>,
+    request: ServerRequest,
+    rx: oneshot::Receiver<Result<T>>,
+) -> Result<T> {
+    tx_channel
+        .send(Request {
+            span: tracing::Span::current(),
+            client,
+            grant,
+            request,
+        })
+        .await
+        .map_err(|_| ControlError::Cancelled)?;
+    rx.await.map_err(|_| ControlError::Cancelled)?
 }
 
 async fn do_list_devices(
-    tx_request: tokio::sync::mpsc::Sender<ServerRequest>,
+    tx_request: mpsc::Sender<Request>,
+    session: &Session,
+    filter: String,
+    offset: u32,
+    limit: u32,
     results: &mut control_extron::ListDevicesResults,
 ) -> Result<()> {
     use crate::extron_capnp::control_extron::extron_device;
-    use std::io::{Error, ErrorKind};
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel(5);
-    let request = ServerRequest {
-        reply_channel: tx,
-        cmd: ServerCmd::ListDevices,
-    };
-    tx_request
-        .send(request)
-        .await
-        .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
-    match rx.recv().await {
-        None => Ok(()),
-        Some(v) => {
-            if let ServerReply::ListDevices(devices) = v {
-                let reply = results.get().init_reply(devices.len() as u32);
-                for (i, extron_device) in devices.iter().enumerate() {
-                    let mut builder = capnp::message::Builder::new_default();
-                    let mut device = builder.init_root::<extron_device::Builder>();
-                    device.set_name(&extron_device.name);
-                    device.set_path(&extron_device.device_path);
-                    reply
-                        .set_with_caveats(i as u32, device.into_reader())
-                        .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
-                }
-                Ok(())
-            } else {
-                Err(Error::new(std::io::ErrorKind::Other, "Internal error")).into()
-            }
+    let (mut devices, scanning) = session
+        .request(tx_request, ServerRequest::ListDevices)
+        .await?;
+    devices.retain(|device| session.grant.borrow().covers(&device.name));
+    let (devices, total) = crate::extron::page_of(devices, &filter, offset, limit)?;
+    results.get().set_total(total);
+    results.get().set_scanning(scanning);
+    let reply = results.get().init_reply(devices.len() as u32);
+    for (i, extron_device) in devices.iter().enumerate() {
+        let mut builder = capnp::message::Builder::new_default();
+        let mut device = builder.init_root::<extron_device::Builder>();
+        device.set_name(&extron_device.name);
+        device.set_path(&extron_device.device_path);
+        let labels = extron_device.labels();
+        let mut list = device.reborrow().init_labels(labels.len() as u32);
+        for (i, (label, input)) in labels.iter().enumerate() {
+            let mut entry = list.reborrow().get(i as u32);
+            entry.set_label(label);
+            entry.set_input(*input);
         }
+        reply
+            .set_with_caveats(i as u32, device.into_reader())
+            .map_err(|_| ControlError::internal())?;
+    }
+    Ok(())
+}
+
+fn plane(plane: control_extron::Plane) -> Plane {
+    match plane {
+        control_extron::Plane::All => Plane::All,
+        control_extron::Plane::Video => Plane::Video,
+        control_extron::Plane::Audio => Plane::Audio,
     }
 }
 
+/// Serves an RPC in a span naming the method and the device it is for, under the span of the
+/// connection, so that everything done for it can be told apart in the log. The call is logged
+/// with its `params` when [`rpc_trace`] is enabled.
+fn traced<'a, P, F>(
+    method: &'static str,
+    device: Option<&str>,
+    params: P,
+    future: F,
+) -> Promise<(), capnp::Error>
+where
+    P: Into<capnp::dynamic_value::Reader<'a>>,
+    F: std::future::Future<Output = std::result::Result<(), capnp::Error>> + 'static,
+{
+    let span = info_span!("rpc", method, device);
+    span.in_scope(|| rpc_trace::call(method, params));
+    Promise::from_future(future.instrument(span))
+}
+
 impl control_extron::Server for ControlExtronImpl {
     fn list_devices(
         &mut self,
-        _params: control_extron::ListDevicesParams,
+        params: control_extron::ListDevicesParams,
         mut results: control_extron::ListDevicesResults,
     ) -> Promise<(), ::capnp::Error> {
         let tx_channel = self.tx_channel.clone();
-        Promise::from_future(async move {
-            do_list_devices(tx_channel, &mut results)
-                .await
-                .map_err(|e| e.into())
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let filter = pry!(pry!(params.get_filter()).to_str()).to_string();
+        let (offset, limit) = (params.get_offset(), params.get_limit());
+        traced("list_devices", None, params, async move {
+            let result =
+                do_list_devices(tx_channel, &session, filter, offset, limit, &mut results).await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("list_devices", results.get().into_reader());
+            Ok(())
         })
     }
 
     fn rescan(
         &mut self,
-        _params: control_extron::RescanParams,
-        mut _results: control_extron::RescanResults,
+        params: control_extron::RescanParams,
+        mut results: control_extron::RescanResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let allowed = self.check_admin("rescan");
+        let params = pry!(params.get());
+        let background = params.get_background();
+        traced("rescan", None, params, async move {
+            let result = async {
+                allowed?;
+                let ticket = session.request(tx_channel, ServerRequest::Rescan).await?;
+                if !background {
+                    return scan_over(ticket).await;
+                }
+                let ticket = ScanTicketImpl {
+                    ticket,
+                    session: session.clone(),
+                };
+                results.get().set_ticket(capnp_rpc::new_client(ticket));
+                Ok(())
+            }
+            .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("rescan", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn select_input(
+        &mut self,
+        params: control_extron::SelectInputParams,
+        mut results: control_extron::SelectInputResults,
     ) -> Promise<(), ::capnp::Error> {
         let tx_channel = self.tx_channel.clone();
-        Promise::from_future(async move {
-            use std::io::{Error, ErrorKind};
-            let (tx, mut rx) = tokio::sync::mpsc::channel(5);
-            let request = ServerRequest {
-                reply_channel: tx,
-                cmd: ServerCmd::Rescan,
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let input = pry!(pry!(params.get_input()).to_str()).parse::<Input>();
+        traced("select_input", Some(&name), params, async move {
+            let result = match input {
+                Ok(input) => {
+                    session
+                        .request(tx_channel, |reply| ServerRequest::Select {
+                            name,
+                            input,
+                            reply,
+                        })
+                        .await
+                }
+                Err(e) => Err(e),
             };
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("select_input", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-            tx_channel
-                .send(request)
+    fn toggle_input(
+        &mut self,
+        params: control_extron::ToggleInputParams,
+        mut results: control_extron::ToggleInputResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("toggle_input", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::ToggleInput {
+                    name,
+                    reply,
+                })
                 .await
-                .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
+                .map(|input| results.get().set_input(&input.to_string()));
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("toggle_input", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-            rx.recv()
-                .await
-                .ok_or(Error::new(ErrorKind::Other, "Internal error"))?;
+    fn select_plane(
+        &mut self,
+        params: control_extron::SelectPlaneParams,
+        mut results: control_extron::SelectPlaneResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let input = pry!(pry!(params.get_input()).to_str()).parse::<Input>();
+        let plane = plane(pry!(params.get_plane()));
+        traced("select_plane", Some(&name), params, async move {
+            let result = match input {
+                Ok(input) => {
+                    session
+                        .request(tx_channel, |reply| ServerRequest::SelectPlane {
+                            name,
+                            input,
+                            plane,
+                            reply,
+                        })
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("select_plane", results.get().into_reader());
+            Ok(())
+        })
+    }
 
+    fn set_volume(
+        &mut self,
+        params: control_extron::SetVolumeParams,
+        mut results: control_extron::SetVolumeResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let level = params.get_level();
+        traced("set_volume", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Volume {
+                    name,
+                    level,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_volume", results.get().into_reader());
             Ok(())
         })
     }
 
-    fn select_input(
+    fn set_mute(
         &mut self,
-        params: control_extron::SelectInputParams,
-        mut _results: control_extron::SelectInputResults,
+        params: control_extron::SetMuteParams,
+        mut results: control_extron::SetMuteResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let mute = params.get_mute();
+        traced("set_mute", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Mute {
+                    name,
+                    mute,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_mute", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn select_group(
+        &mut self,
+        params: control_extron::SelectGroupParams,
+        mut results: control_extron::SelectGroupResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let mut names = Vec::new();
+        for name in pry!(params.get_names()).iter() {
+            names.push(pry!(pry!(name).to_str()).to_string());
+        }
+        let input = pry!(pry!(params.get_input()).to_str()).parse::<Input>();
+        let plane = plane(pry!(params.get_plane()));
+        traced("select_group", Some(&names.join(",")), params, async move {
+            let result = match input {
+                Ok(input) => {
+                    session
+                        .request(tx_channel, |reply| ServerRequest::SelectGroup {
+                            names,
+                            input,
+                            plane,
+                            reply,
+                        })
+                        .await
+                }
+                Err(e) => Err(e),
+            }
+            .map(|devices| {
+                let mut list = results.get().init_results(devices.len() as u32);
+                for (i, (name, result)) in devices.iter().enumerate() {
+                    let mut builder = list.reborrow().get(i as u32);
+                    builder.set_name(name);
+                    if let Err(e) = result {
+                        e.to_wire(builder.init_error());
+                    }
+                }
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("select_group", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn send_raw(
+        &mut self,
+        params: control_extron::SendRawParams,
+        mut results: control_extron::SendRawResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let command = pry!(params.get_command()).to_vec();
+        let allowed = self.check_admin("sendRaw");
+        traced("send_raw", Some(&name), params, async move {
+            let result = async {
+                allowed?;
+                session
+                    .request(tx_channel, |reply| ServerRequest::Raw {
+                        name,
+                        command,
+                        reply,
+                    })
+                    .await
+            }
+            .await
+            .map(|reply| results.get().set_reply(&reply));
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("send_raw", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn upload_firmware(
+        &mut self,
+        params: control_extron::UploadFirmwareParams,
+        mut results: control_extron::UploadFirmwareResults,
     ) -> Promise<(), ::capnp::Error> {
         let tx_channel = self.tx_channel.clone();
-        let name = params.get().unwrap().get_name().unwrap().to_string();
-        let input = params.get().unwrap().get_input().unwrap().to_string();
-        Promise::from_future(async move {
-            use std::io::{Error, ErrorKind};
-
-            let (tx, mut rx) = tokio::sync::mpsc::channel(5);
-            let request = ServerRequest {
-                reply_channel: tx,
-                cmd: ServerCmd::Select(ServerCmdSelect { name, input }),
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let image = pry!(params.get_image()).to_vec();
+        let listener = if params.has_progress() {
+            Some(pry!(params.get_progress()))
+        } else {
+            None
+        };
+        let allowed = self.check_admin("uploadFirmware");
+        traced("upload_firmware", Some(&name), params, async move {
+            let (progress, mut reports) = mpsc::unbounded_channel();
+            let upload = async {
+                allowed?;
+                session
+                    .request(tx_channel, |reply| ServerRequest::UploadFirmware {
+                        name,
+                        image,
+                        progress,
+                        reply,
+                    })
+                    .await
             };
-            tx_channel
-                .send(request)
-                .await
-                .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
-            let reply = rx
-                .recv()
-                .await
-                .ok_or(Error::new(ErrorKind::Other, "Internal error"))?;
-            let result = if let ServerReply::Select(r) = reply {
-                r
-            } else {
-                Err(Error::new(ErrorKind::Other, "Internal error"))
+            // Ends once the request is done with the sender.
+            let report = async {
+                while let Some((done, total)) = reports.recv().await {
+                    if let Some(listener) = &listener {
+                        let mut request = listener.progress_request();
+                        request.get().set_done(done as u64);
+                        request.get().set_total(total as u64);
+                        // A listener that went away only misses the progress.
+                        let _ = request.send().promise.await;
+                    }
+                }
             };
-            result?;
+            let (result, ()) = futures::join!(upload, report);
+            let result = result.map(|update| {
+                let mut builder = results.get();
+                builder.set_before(&update.before);
+                builder.set_after(&update.after);
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("upload_firmware", results.get().into_reader());
+            Ok(())
+        })
+    }
 
+    fn get_device_log(
+        &mut self,
+        params: control_extron::GetDeviceLogParams,
+        mut results: control_extron::GetDeviceLogResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_device_log", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::DeviceLog { name, reply })
+                .await
+                .map(|messages| {
+                    let mut list = results.get().init_messages(messages.len() as u32);
+                    for (i, message) in messages.iter().enumerate() {
+                        let mut builder = list.reborrow().get(i as u32);
+                        let time = message
+                            .time
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        builder.set_time(time.as_millis() as u64);
+                        builder.set_text(&message.text);
+                    }
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_device_log", results.get().into_reader());
             Ok(())
         })
     }
 
-    fn stop_server(
+    fn undo(
         &mut self,
-        _params: control_extron::StopServerParams,
-        mut _results: control_extron::StopServerResults,
+        params: control_extron::UndoParams,
+        mut results: control_extron::UndoResults,
     ) -> Promise<(), ::capnp::Error> {
-        let stop = self.stop.clone();
-        Promise::from_future(async move {
-            use std::io::{Error, ErrorKind};
-            stop.send(true)
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("undo", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Undo { name, reply })
                 .await
-                .map_err(|_| Error::new(ErrorKind::Other, "Stop failed"))?;
+                .map(|undone| results.get().set_undone(&undone));
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("undo", results.get().into_reader());
             Ok(())
         })
     }
-}
 
-#[derive(Clone, Debug)]
-struct ServerCmdSelect {
-    name: String,
-    input: String,
-}
+    fn get_history(
+        &mut self,
+        params: control_extron::GetHistoryParams,
+        mut results: control_extron::GetHistoryResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_history", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::History { name, reply })
+                .await
+                .map(|changes| {
+                    let mut list = results.get().init_changes(changes.len() as u32);
+                    for (i, change) in changes.iter().enumerate() {
+                        let mut builder = list.reborrow().get(i as u32);
+                        let time = change
+                            .time
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        builder.set_time(time.as_millis() as u64);
+                        builder.set_by(&change.by);
+                        if let Some(from) = &change.from {
+                            builder.set_from(&from.to_string());
+                        }
+                        builder.set_to(&change.to.to_string());
+                    }
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_history", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-#[derive(Clone, Debug)]
-enum ServerCmd {
-    Rescan,
-    ListDevices,
-    Select(ServerCmdSelect),
-}
-#[derive(Clone, Debug)]
-struct ServerRequest {
-    cmd: ServerCmd,
-    reply_channel: tokio::sync::mpsc::Sender<ServerReply>,
-}
-enum ServerReply {
-    RescanReply,
-    ListDevices(Vec<ExtronDevice>),
-    Select(Result<()>),
-}
+    fn set_display_power(
+        &mut self,
+        params: control_extron::SetDisplayPowerParams,
+        mut results: control_extron::SetDisplayPowerResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let on = params.get_on();
+        traced("set_display_power", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::DisplayPower {
+                    name,
+                    on,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_display_power", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-async fn cmd_loop(cmd_rx: &mut tokio::sync::mpsc::Receiver<ServerRequest>) -> Result<()> {
-    use std::io::{Error, ErrorKind};
+    fn send_to_display(
+        &mut self,
+        params: control_extron::SendToDisplayParams,
+        mut results: control_extron::SendToDisplayResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let data = pry!(params.get_data()).to_vec();
+        traced("send_to_display", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::DisplayRaw {
+                    name,
+                    data,
+                    reply,
+                })
+                .await
+                .map(|reply| results.get().set_reply(&reply));
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("send_to_display", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-    let join = tokio::task::spawn_blocking(move || ExtronDeviceList::enumerate_extron());
-    let devices = join.await?;
-    let mut device_list = devices?;
-
-    while let Some(request) = cmd_rx.recv().await {
-        match request.cmd {
-            ServerCmd::Rescan => {
-                let result: Result<ExtronDeviceList> =
-                    tokio::task::spawn_blocking(move || ExtronDeviceList::enumerate_extron())
-                        .await?;
-                match result {
-                    Ok(d) => {
-                        device_list = d;
-                    }
-                    Err(e) => {
-                        device_list = ExtronDeviceList::new();
-                        info!("Rescan failed: {}", e.to_string())
-                    }
-                }
-                request
-                    .reply_channel
-                    .send(ServerReply::RescanReply)
-                    .await
-                    .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
+    fn recall_preset(
+        &mut self,
+        params: control_extron::RecallPresetParams,
+        mut results: control_extron::RecallPresetResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let preset = params.get_preset();
+        traced("recall_preset", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::RecallPreset {
+                    name,
+                    preset,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
             }
-            ServerCmd::ListDevices => {
-                request
-                    .reply_channel
-                    .send(ServerReply::ListDevices(
-                        device_list.iter().collect::<Vec<_>>(),
-                    ))
-                    .await
-                    .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
-            }
-            ServerCmd::Select(s) => {
-                request
-                    .reply_channel
-                    .send(ServerReply::Select(
-                        if let Some(device) = device_list.find(&s.name) {
-                            tokio::task::spawn_blocking(move || device.select(&s.input))
-                                .await?
-                                .into()
-                        } else {
-                            Err(
-                                std::io::Error::new(std::io::ErrorKind::Other, "Device not found")
-                                    .into(),
-                            )
-                        },
-                    ))
-                    .await
-                    .map_err(|_| Error::new(ErrorKind::Other, "Internal error"))?;
+            rpc_trace::reply("recall_preset", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_wall_layout(
+        &mut self,
+        params: control_extron::GetWallLayoutParams,
+        mut results: control_extron::GetWallLayoutResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_wall_layout", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::WallLayout {
+                    name,
+                    reply,
+                })
+                .await
+                .map(|layout| {
+                    let mut builder = results.get().init_layout();
+                    builder.set_preset(layout.preset);
+                    builder.set_windows(layout.windows);
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
             }
-        }
+            rpc_trace::reply("get_wall_layout", results.get().into_reader());
+            Ok(())
+        })
     }
-    Ok(())
-}
 
-async fn run_server<A: net::ToSocketAddrs>(
-    addr: &A,
-    stop_server: tokio::sync::mpsc::Sender<bool>,
-) -> Result<()> {
-    let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    info!("Server listening on {}", addr);
-    let (cmd_tx, mut cmd_rx) = tokio::sync::mpsc::channel::<ServerRequest>(50);
-    tokio::task::spawn(async move { cmd_loop(&mut cmd_rx).await });
-
-    let control_extron = ControlExtronImpl {
-        tx_channel: cmd_tx.clone(),
-        stop: stop_server.clone(),
-    };
-    let extron_client: control_extron::Client = capnp_rpc::new_client(control_extron);
-    loop {
-        use futures::{AsyncReadExt, FutureExt};
-        let (stream, _) = listener.accept().await?;
-        stream.set_nodelay(true)?;
-        let (reader, writer) =
-            tokio_util::compat::Tokio02AsyncReadCompatExt::compat(stream).split();
-        let network = twoparty::VatNetwork::new(
-            reader,
-            writer,
-            rpc_twoparty_capnp::Side::Server,
-            Default::default(),
-        );
-        let rpc_system = RpcSystem::new(Box::new(network), Some(extron_client.clone().client));
-        tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
+    fn recall_layout(
+        &mut self,
+        params: control_extron::RecallLayoutParams,
+        mut results: control_extron::RecallLayoutResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let layout = params.get_layout();
+        traced("recall_layout", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::RecallLayout {
+                    name,
+                    layout,
+                    reply,
+                })
+                .await
+                .map(|windows| {
+                    let mut builder = results.get().init_windows();
+                    builder.set_layout(windows.layout);
+                    let mut inputs = builder.init_inputs(windows.inputs.len() as u32);
+                    for (i, input) in windows.inputs.iter().enumerate() {
+                        inputs.set(i as u32, *input);
+                    }
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("recall_layout", results.get().into_reader());
+            Ok(())
+        })
     }
-}
 
-async fn server_app<A: net::ToSocketAddrs>(addr: &A) -> Result<()> {
-    use tokio::sync::mpsc;
+    fn annotate(
+        &mut self,
+        params: control_extron::AnnotateParams,
+        mut results: control_extron::AnnotateResults,
+    ) -> Promise<(), ::capnp::Error> {
+        use control_extron::annotation::Which;
 
-    let (stop_tx, mut stop_rx) = mpsc::channel::<bool>(1);
-    let local = tokio::task::LocalSet::new();
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let annotation = match pry!(pry!(params.get_annotation()).which()) {
+            Which::Clear(()) => Annotation::Clear,
+            Which::Pointer(()) => Annotation::Pointer,
+            Which::Freeze(on) => Annotation::Freeze(on),
+        };
+        traced("annotate", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Annotate {
+                    name,
+                    annotation,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("annotate", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-    let r = tokio::select! {
-        r = local.run_until(run_server(addr, stop_tx)) => r,
-        _ = stop_rx.recv() => Ok(()),
-    };
-    r
-}
+    fn set_pip_mode(
+        &mut self,
+        params: control_extron::SetPipModeParams,
+        mut results: control_extron::SetPipModeResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let mode = match pry!(params.get_mode()) {
+            control_extron::PipMode::Off => PipMode::Off,
+            control_extron::PipMode::Pip => PipMode::Pip,
+            control_extron::PipMode::Quad => PipMode::Quad,
+        };
+        traced("set_pip_mode", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::PipMode {
+                    name,
+                    mode,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_pip_mode", results.get().into_reader());
+            Ok(())
+        })
+    }
 
-pub fn do_daemon<A: net::ToSocketAddrs>(addr: &A) -> Result<()> {
-    use tokio::runtime;
-    let rt = runtime::Runtime::new()?;
-    rt.block_on(server_app(addr))?;
-    info!("Server halted");
-    Ok(())
+    fn assign_source(
+        &mut self,
+        params: control_extron::AssignSourceParams,
+        mut results: control_extron::AssignSourceResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let window = params.get_window();
+        let input = pry!(pry!(params.get_input()).to_str()).parse::<Input>();
+        traced("assign_source", Some(&name), params, async move {
+            let result = match input {
+                Ok(input) => {
+                    session
+                        .request(tx_channel, |reply| ServerRequest::AssignSource {
+                            name,
+                            window,
+                            input,
+                            reply,
+                        })
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("assign_source", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_sync(
+        &mut self,
+        params: control_extron::GetSyncParams,
+        mut results: control_extron::GetSyncResults,
+    ) -> Promise<(), ::capnp::Error> {
+        use control_extron::sync_settings;
+
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_sync", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Sync { name, reply })
+                .await
+                .map(|sync| {
+                    let mut builder = results.get().init_sync();
+                    builder.set_format(match sync.format {
+                        SyncFormat::SeparateHv => sync_settings::Format::SeparateHv,
+                        SyncFormat::Composite => sync_settings::Format::Composite,
+                        SyncFormat::SyncOnGreen => sync_settings::Format::SyncOnGreen,
+                        SyncFormat::TriLevel => sync_settings::Format::TriLevel,
+                    });
+                    builder.set_genlock(match sync.genlock {
+                        Genlock::FreeRun => sync_settings::Genlock::FreeRun,
+                        Genlock::SelectedInput => sync_settings::Genlock::SelectedInput,
+                        Genlock::Reference => sync_settings::Genlock::Reference,
+                    });
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_sync", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn set_sync_format(
+        &mut self,
+        params: control_extron::SetSyncFormatParams,
+        mut results: control_extron::SetSyncFormatResults,
+    ) -> Promise<(), ::capnp::Error> {
+        use control_extron::sync_settings;
+
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let format = match pry!(params.get_format()) {
+            sync_settings::Format::SeparateHv => SyncFormat::SeparateHv,
+            sync_settings::Format::Composite => SyncFormat::Composite,
+            sync_settings::Format::SyncOnGreen => SyncFormat::SyncOnGreen,
+            sync_settings::Format::TriLevel => SyncFormat::TriLevel,
+        };
+        traced("set_sync_format", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::SyncFormat {
+                    name,
+                    format,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_sync_format", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn set_genlock(
+        &mut self,
+        params: control_extron::SetGenlockParams,
+        mut results: control_extron::SetGenlockResults,
+    ) -> Promise<(), ::capnp::Error> {
+        use control_extron::sync_settings;
+
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let genlock = match pry!(params.get_genlock()) {
+            sync_settings::Genlock::FreeRun => Genlock::FreeRun,
+            sync_settings::Genlock::SelectedInput => Genlock::SelectedInput,
+            sync_settings::Genlock::Reference => Genlock::Reference,
+        };
+        traced("set_genlock", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Genlock {
+                    name,
+                    genlock,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_genlock", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_stats(
+        &mut self,
+        params: control_extron::GetStatsParams,
+        mut results: control_extron::GetStatsResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        traced("get_stats", None, pry!(params.get()), async move {
+            let result =
+                session
+                    .request(tx_channel, ServerRequest::Stats)
+                    .await
+                    .map(|mut devices| {
+                        devices.retain(|stats| session.grant.borrow().covers(&stats.name));
+                        let mut bounds = results
+                            .get()
+                            .init_latency_bounds(LATENCY_BUCKETS_MS.len() as u32);
+                        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+                            bounds.set(i as u32, *bound);
+                        }
+                        let mut list = results.get().init_stats(devices.len() as u32);
+                        for (i, stats) in devices.iter().enumerate() {
+                            let mut builder = list.reborrow().get(i as u32);
+                            builder.set_name(&stats.name);
+                            builder.set_online(stats.online);
+                            let mut latencies = builder
+                                .reborrow()
+                                .init_latencies(stats.latencies.len() as u32);
+                            for (j, n) in stats.latencies.iter().enumerate() {
+                                latencies.set(j as u32, *n);
+                            }
+                            builder.set_latency_total(stats.latency_total.as_micros() as u64);
+                            let mut errors =
+                                builder.reborrow().init_errors(stats.errors.len() as u32);
+                            for (j, (code, count)) in stats.errors.iter().enumerate() {
+                                let mut error = errors.reborrow().get(j as u32);
+                                error.set_code(*code);
+                                error.set_count(*count);
+                            }
+                            builder.set_timeouts(stats.timeouts);
+                            builder.set_reconnects(stats.reconnects);
+                        }
+                    });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_stats", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn dump_state(
+        &mut self,
+        params: control_extron::DumpStateParams,
+        mut results: control_extron::DumpStateResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        // Covers every device and connection, whatever the token is for.
+        let allowed = self.session.grant.borrow().check(Scope::Admin);
+        let connections: Vec<_> = self
+            .connections
+            .borrow()
+            .keys()
+            .map(|peer| peer.to_string())
+            .collect();
+        let states: serde_json::Map<_, _> = self
+            .states
+            .lock()
+            .unwrap()
+            .devices()
+            .into_iter()
+            .map(|(name, state)| (name, serde_json::to_value(state).unwrap_or_default()))
+            .collect();
+        let server = serde_json::json!({
+            "requests_queued": self.tx_channel.max_capacity() - self.tx_channel.capacity(),
+            "connections": connections,
+            "subscribers": self.events.receiver_count(),
+            "states": states,
+        });
+        traced("dump_state", None, pry!(params.get()), async move {
+            let result = async {
+                allowed?;
+                session.request(tx_channel, ServerRequest::Dump).await
+            }
+            .await
+            .map(|mut dump| {
+                if let (Some(dump), serde_json::Value::Object(server)) =
+                    (dump.as_object_mut(), server)
+                {
+                    dump.extend(server);
+                }
+                let text = serde_json::to_string_pretty(&dump).unwrap_or_default();
+                results.get().set_state(&text);
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("dump_state", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn set_maintenance(
+        &mut self,
+        params: control_extron::SetMaintenanceParams,
+        mut results: control_extron::SetMaintenanceResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let allowed = self.check_admin("setMaintenance");
+        let params = pry!(params.get());
+        let message = match params.get_on() {
+            true => Some(pry!(pry!(params.get_message()).to_str()).to_string()),
+            false => None,
+        };
+        traced("set_maintenance", None, params, async move {
+            let result = async {
+                allowed?;
+                session
+                    .request(tx_channel, |reply| ServerRequest::Maintenance {
+                        message,
+                        reply,
+                    })
+                    .await
+            }
+            .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("set_maintenance", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn list_sessions(
+        &mut self,
+        params: control_extron::ListSessionsParams,
+        mut results: control_extron::ListSessionsResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let allowed = self.session.grant.borrow().check(Scope::Admin);
+        let sessions: Vec<_> = self.connections.borrow().values().cloned().collect();
+        traced("list_sessions", None, pry!(params.get()), async move {
+            let result = allowed.map(|()| {
+                let now = std::time::Instant::now();
+                let mut list = results.get().init_sessions(sessions.len() as u32);
+                for (i, other) in sessions.iter().enumerate() {
+                    let mut builder = list.reborrow().get(i as u32);
+                    builder.set_peer(&other.peer.to_string());
+                    builder.set_version(other.version.get());
+                    builder.set_connected_seconds((now - other.connected).as_secs());
+                    builder.set_idle_seconds((now - other.last_call.get()).as_secs());
+                    builder.set_calls(other.calls.get());
+                    builder.set_errors(other.errors.get());
+                    builder.set_caller(std::rc::Rc::ptr_eq(other, &session));
+                    if let Some(identity) = &other.identity {
+                        builder.set_identity(identity);
+                    }
+                }
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("list_sessions", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn disconnect(
+        &mut self,
+        params: control_extron::DisconnectParams,
+        mut results: control_extron::DisconnectResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let allowed = self.check_admin("disconnect");
+        let params = pry!(params.get());
+        let peer = pry!(pry!(params.get_peer()).to_str()).to_string();
+        let connections = self.connections.clone();
+        traced("disconnect", None, params, async move {
+            let result = allowed.and_then(|()| {
+                let other = peer
+                    .parse()
+                    .ok()
+                    .and_then(|peer| connections.borrow().get(&peer).cloned())
+                    .ok_or_else(|| {
+                        ControlError::Rpc(capnp::Error::failed(format!(
+                            "No connection from {}",
+                            peer
+                        )))
+                    })?;
+                info!("Disconnecting {}", other.peer);
+                other.disconnect.cancel();
+                Ok(())
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("disconnect", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_device_info(
+        &mut self,
+        params: control_extron::GetDeviceInfoParams,
+        mut results: control_extron::GetDeviceInfoResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_device_info", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Info { name, reply })
+                .await
+                .map(|info| {
+                    let mut builder = results.get().init_info();
+                    builder.set_part_number(info.part_number.as_deref().unwrap_or_default());
+                    builder.set_firmware(info.firmware.as_deref().unwrap_or_default());
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_device_info", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_device_health(
+        &mut self,
+        params: control_extron::GetDeviceHealthParams,
+        mut results: control_extron::GetDeviceHealthResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_device_health", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Health { name, reply })
+                .await
+                .map(|health| {
+                    let mut builder = results.get().init_health();
+                    if let Some(celsius) = health.temperature {
+                        builder.set_temperature(celsius);
+                        builder.set_has_temperature(true);
+                    }
+                    if let Some((input, present)) = health.signal {
+                        builder.set_input(input);
+                        builder.set_signal(present);
+                        builder.set_has_signal(true);
+                    }
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_device_health", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn hold(
+        &mut self,
+        params: control_extron::HoldParams,
+        mut results: control_extron::HoldResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        let duration = match params.get_seconds() {
+            0 => None,
+            seconds => Some(std::time::Duration::from_secs(seconds.into())),
+        };
+        traced("hold", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Hold {
+                    name,
+                    duration,
+                    reply,
+                })
+                .await;
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("hold", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn get_status(
+        &mut self,
+        params: control_extron::GetStatusParams,
+        mut results: control_extron::GetStatusResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let tx_channel = self.tx_channel.clone();
+        let session = self.session.call();
+        let params = pry!(params.get());
+        let name = pry!(pry!(params.get_name()).to_str()).to_string();
+        traced("get_status", Some(&name), params, async move {
+            let result = session
+                .request(tx_channel, |reply| ServerRequest::Status { name, reply })
+                .await
+                .map(|status| {
+                    let mut builder = results.get().init_status();
+                    builder.set_input(status.input);
+                    builder.set_label(status.label.as_deref().unwrap_or_default());
+                    builder.set_has_audio(status.volume.is_some());
+                    builder.set_volume(status.volume.unwrap_or(0));
+                    builder.set_mute(status.mute.unwrap_or(false));
+                    builder.set_has_pip(status.pip.is_some());
+                    builder.set_pip_mode(match status.pip {
+                        None | Some(PipMode::Off) => control_extron::PipMode::Off,
+                        Some(PipMode::Pip) => control_extron::PipMode::Pip,
+                        Some(PipMode::Quad) => control_extron::PipMode::Quad,
+                    });
+                    let mut sources = builder.init_sources(status.sources.len() as u32);
+                    for (i, input) in status.sources.iter().enumerate() {
+                        sources.set(i as u32, *input);
+                    }
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("get_status", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn stop_server(
+        &mut self,
+        params: control_extron::StopServerParams,
+        mut _results: control_extron::StopServerResults,
+    ) -> Promise<(), ::capnp::Error> {
+        rpc_trace::call("stop_server", pry!(params.get()));
+        self.session.call();
+        if let Err(e) = self.check_admin("stopServer") {
+            return Promise::err(e.into());
+        }
+        self.cancel.cancel();
+        Promise::ok(())
+    }
+
+    fn negotiate(
+        &mut self,
+        params: control_extron::NegotiateParams,
+        mut results: control_extron::NegotiateResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let params = pry!(params.get());
+        rpc_trace::call("negotiate", params);
+        self.session.call();
+        self.session.version.set(params.get_version());
+        results.get().set_version(crate::SCHEMA_VERSION);
+        rpc_trace::reply("negotiate", results.get().into_reader());
+        Promise::ok(())
+    }
+
+    fn subscribe(
+        &mut self,
+        params: control_extron::SubscribeParams,
+        mut _results: control_extron::SubscribeResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let params = pry!(params.get());
+        rpc_trace::call("subscribe", params);
+        self.session.call();
+        let grant = self.session.grant.borrow().clone();
+        if let Err(e) = grant.check(Scope::Read) {
+            return Promise::err(e.into());
+        }
+        let listener = pry!(params.get_listener());
+        let mut events = self.events.subscribe();
+        tokio::task::spawn_local(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        info!("Subscriber missed {} events", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let device = match &event {
+                    ServerEvent::InputSelected { device, .. }
+                    | ServerEvent::VolumeChanged { device, .. }
+                    | ServerEvent::MuteChanged { device, .. }
+                    | ServerEvent::SignalLost { device, .. }
+                    | ServerEvent::SignalRestored { device, .. } => Some(device),
+                    _ => None,
+                };
+                if device.is_some_and(|device| !grant.covers(device)) {
+                    continue;
+                }
+                let mut request = listener.event_request();
+                if event_to_wire(&event, request.get().init_event())
+                    && request.send().promise.await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Promise::ok(())
+    }
+
+    fn authenticate(
+        &mut self,
+        params: control_extron::AuthenticateParams,
+        mut results: control_extron::AuthenticateResults,
+    ) -> Promise<(), ::capnp::Error> {
+        // Not traced with its params, which hold the secret.
+        self.session.call();
+        let secret = pry!(pry!(pry!(params.get()).get_token()).to_str()).to_string();
+        let grant = match &self.tokens {
+            Some(tokens) => tokens.borrow().grant(&secret).ok_or_else(|| {
+                info!("Refused unknown token from {}", self.session.peer);
+                ControlError::Unauthorized("unknown token".to_string())
+            }),
+            // Without tokens, a connection keeps what it may do.
+            None => Ok(self.session.grant.borrow().clone()),
+        };
+        let result = grant.map(|grant| {
+            if let Some(scope) = grant.scope() {
+                results.get().set_scope(scope.into());
+            }
+            *self.session.grant.borrow_mut() = grant;
+        });
+        if let Some(e) = pry!(self.session.failure(result)) {
+            e.to_wire(results.get().init_error());
+        }
+        rpc_trace::reply("authenticate", results.get().into_reader());
+        Promise::ok(())
+    }
+
+    fn create_token(
+        &mut self,
+        params: control_extron::CreateTokenParams,
+        mut results: control_extron::CreateTokenResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let allowed = self.check_admin("createToken");
+        let tokens = self.tokens();
+        let params = pry!(params.get());
+        let scope = pry!(params.get_scope()).into();
+        let mut devices = Vec::new();
+        for pattern in pry!(params.get_devices()).iter() {
+            devices.push(pry!(pry!(pattern).to_str()).to_string());
+        }
+        traced("create_token", None, params, async move {
+            let result = allowed
+                .and(tokens)
+                .and_then(|tokens| tokens.borrow_mut().create(scope, devices))
+                .map(|(token, secret)| {
+                    info!("Created token {} with the {} scope", token.id, token.scope);
+                    token.to_wire(results.get().init_token());
+                    results.get().set_secret(&secret);
+                });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            // The reply is not traced, it holds the secret.
+            Ok(())
+        })
+    }
+
+    fn revoke_token(
+        &mut self,
+        params: control_extron::RevokeTokenParams,
+        mut results: control_extron::RevokeTokenResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let allowed = self.check_admin("revokeToken");
+        let tokens = self.tokens();
+        let connections = self.connections.clone();
+        let params = pry!(params.get());
+        let id = pry!(pry!(params.get_id()).to_str()).to_string();
+        traced("revoke_token", None, params, async move {
+            let result = allowed
+                .and(tokens)
+                .and_then(|tokens| tokens.borrow_mut().revoke(&id));
+            if result.is_ok() {
+                info!("Revoked token {}", id);
+                // Whoever holds it loses what it allowed right away.
+                for other in connections.borrow().values() {
+                    if other.grant.borrow().token() == Some(id.as_str()) {
+                        info!("Disconnecting {}", other.peer);
+                        other.disconnect.cancel();
+                    }
+                }
+            }
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("revoke_token", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn list_tokens(
+        &mut self,
+        params: control_extron::ListTokensParams,
+        mut results: control_extron::ListTokensResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let allowed = self.check_admin("listTokens");
+        let tokens = self.tokens();
+        traced("list_tokens", None, pry!(params.get()), async move {
+            let result = allowed.and(tokens).map(|tokens| {
+                let tokens = tokens.borrow().list();
+                let mut list = results.get().init_tokens(tokens.len() as u32);
+                for (i, token) in tokens.iter().enumerate() {
+                    token.to_wire(list.reborrow().get(i as u32));
+                }
+            });
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("list_tokens", results.get().into_reader());
+            Ok(())
+        })
+    }
+}
+
+/// A scan that `rescan` started in the background, for the client to wait for or poll.
+struct ScanTicketImpl {
+    ticket: ScanTicket,
+    session: std::rc::Rc<Session>,
+}
+
+impl control_extron::scan_ticket::Server for ScanTicketImpl {
+    fn wait(
+        &mut self,
+        params: control_extron::scan_ticket::WaitParams,
+        mut results: control_extron::scan_ticket::WaitResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let ticket = self.ticket.clone();
+        let session = self.session.call();
+        traced("wait_scan", None, pry!(params.get()), async move {
+            if let Some(e) = session.failure(scan_over(ticket).await)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("wait_scan", results.get().into_reader());
+            Ok(())
+        })
+    }
+
+    fn poll(
+        &mut self,
+        params: control_extron::scan_ticket::PollParams,
+        mut results: control_extron::scan_ticket::PollResults,
+    ) -> Promise<(), ::capnp::Error> {
+        let session = self.session.call();
+        let done = *self.ticket.borrow();
+        // The scan cannot end any more once the server stopped.
+        let result = if done || self.ticket.has_changed().is_ok() {
+            results.get().set_done(done);
+            Ok(())
+        } else {
+            Err(ControlError::Cancelled)
+        };
+        traced("poll_scan", None, pry!(params.get()), async move {
+            if let Some(e) = session.failure(result)? {
+                e.to_wire(results.get().init_error());
+            }
+            rpc_trace::reply("poll_scan", results.get().into_reader());
+            Ok(())
+        })
+    }
+}
+
+/// Waits for the scan of `ticket` to be over.
+pub(crate) async fn scan_over(mut ticket: ScanTicket) -> Result<()> {
+    match ticket.wait_for(|done| *done).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(ControlError::Cancelled),
+    }
+}
+
+/// A [`ServerRequest`] with the span of the call that made it, so that serving it, down to the
+/// commands sent to the device, is logged under that call.
+pub(crate) struct Request {
+    pub(crate) span: tracing::Span,
+    /// Who made the call, for the history.
+    pub(crate) client: String,
+    /// What the client may do on which devices, `None` for everything.
+    pub(crate) grant: Option<Grant>,
+    pub(crate) request: ServerRequest,
+}
+
+/// Work for the command loop, each with the channel its result goes back on.
+#[derive(Debug)]
+pub(crate) enum ServerRequest {
+    /// Starts a scan, or joins the one under way, replying with its ticket right away.
+    Rescan(oneshot::Sender<Result<ScanTicket>>),
+    /// The devices, and whether a scan is still looking for more.
+    ListDevices(oneshot::Sender<Result<(Vec<ExtronDevice>, bool)>>),
+    Select {
+        name: String,
+        input: Input,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Selects `input` for the signals of `plane` only.
+    SelectPlane {
+        name: String,
+        input: Input,
+        plane: Plane,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Selects the input selected before the current one, replying with it.
+    ToggleInput {
+        name: String,
+        reply: oneshot::Sender<Result<Input>>,
+    },
+    Volume {
+        name: String,
+        level: u8,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Mute {
+        name: String,
+        mute: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Status {
+        name: String,
+        reply: oneshot::Sender<Result<DeviceStatus>>,
+    },
+    /// Selects `input` for the signals of `plane` on all `names` concurrently, replying with
+    /// the result of each.
+    SelectGroup {
+        names: Vec<String>,
+        input: Input,
+        plane: Plane,
+        reply: oneshot::Sender<Result<Vec<(String, Result<()>)>>>,
+    },
+    /// Sends the bytes of `command` to the device as they are, replying with its answer.
+    Raw {
+        name: String,
+        command: Vec<u8>,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    /// Uploads `image` as the firmware of the device, sending the bytes done and the total to
+    /// `progress` as it goes.
+    UploadFirmware {
+        name: String,
+        image: Vec<u8>,
+        progress: mpsc::UnboundedSender<(usize, usize)>,
+        reply: oneshot::Sender<Result<FirmwareUpdate>>,
+    },
+    /// Replies with the messages the device sent on its own, oldest first.
+    DeviceLog {
+        name: String,
+        reply: oneshot::Sender<Result<Vec<DeviceMessage>>>,
+    },
+    /// Replies with the input changes of the device, oldest first.
+    History {
+        name: String,
+        reply: oneshot::Sender<Result<Vec<InputChange>>>,
+    },
+    /// Undoes the last input, volume or mute change made through the server on the device,
+    /// replying with what it set.
+    Undo {
+        name: String,
+        reply: oneshot::Sender<Result<String>>,
+    },
+    /// Powers the display on the output of the device on or off over HDMI CEC.
+    DisplayPower {
+        name: String,
+        on: bool,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Passes `data` on to the display behind the device, replying with its answer.
+    DisplayRaw {
+        name: String,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    RecallPreset {
+        name: String,
+        preset: u32,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    WallLayout {
+        name: String,
+        reply: oneshot::Sender<Result<WallLayout>>,
+    },
+    /// Recalls a window layout preset, replying with the inputs in its windows.
+    RecallLayout {
+        name: String,
+        layout: u32,
+        reply: oneshot::Sender<Result<WindowLayout>>,
+    },
+    Annotate {
+        name: String,
+        annotation: Annotation,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    PipMode {
+        name: String,
+        mode: PipMode,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Shows `input` in picture-in-picture window `window`, 1 being the main one.
+    AssignSource {
+        name: String,
+        window: u32,
+        input: Input,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Sync {
+        name: String,
+        reply: oneshot::Sender<Result<SyncSettings>>,
+    },
+    Info {
+        name: String,
+        reply: oneshot::Sender<Result<DeviceInfo>>,
+    },
+    Health {
+        name: String,
+        reply: oneshot::Sender<Result<DeviceHealth>>,
+    },
+    SyncFormat {
+        name: String,
+        format: SyncFormat,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Genlock {
+        name: String,
+        genlock: Genlock,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Stats(oneshot::Sender<Result<Vec<DeviceStats>>>),
+    /// What the command loop knows, as JSON, see `dumpState` in the schema.
+    Dump(oneshot::Sender<Result<serde_json::Value>>),
+    /// Keeps the schedule off the device for `duration`, or puts it back on for `None`.
+    Hold {
+        name: String,
+        duration: Option<std::time::Duration>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Puts the server in maintenance with `message`, or takes it out again for `None`.
+    Maintenance {
+        message: Option<String>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+impl ServerRequest {
+    /// The device the request is for, if it is for one.
+    fn device_name_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Self::Select { name, .. }
+            | Self::SelectPlane { name, .. }
+            | Self::ToggleInput { name, .. }
+            | Self::Volume { name, .. }
+            | Self::Mute { name, .. }
+            | Self::Status { name, .. }
+            | Self::Raw { name, .. }
+            | Self::UploadFirmware { name, .. }
+            | Self::DeviceLog { name, .. }
+            | Self::History { name, .. }
+            | Self::Undo { name, .. }
+            | Self::DisplayPower { name, .. }
+            | Self::DisplayRaw { name, .. }
+            | Self::RecallPreset { name, .. }
+            | Self::WallLayout { name, .. }
+            | Self::RecallLayout { name, .. }
+            | Self::Annotate { name, .. }
+            | Self::PipMode { name, .. }
+            | Self::AssignSource { name, .. }
+            | Self::Sync { name, .. }
+            | Self::Info { name, .. }
+            | Self::Health { name, .. }
+            | Self::SyncFormat { name, .. }
+            | Self::Genlock { name, .. }
+            | Self::Hold { name, .. } => Some(name),
+            Self::Rescan(_)
+            | Self::ListDevices(_)
+            | Self::SelectGroup { .. }
+            | Self::Stats(_)
+            | Self::Dump(_)
+            | Self::Maintenance { .. } => None,
+        }
+    }
+
+    /// Whether the request changes a device, which needs the control scope of a token.
+    fn changes_device(&self) -> bool {
+        matches!(
+            self,
+            Self::Select { .. }
+                | Self::SelectPlane { .. }
+                | Self::ToggleInput { .. }
+                | Self::Undo { .. }
+                | Self::Volume { .. }
+                | Self::Mute { .. }
+                | Self::DisplayPower { .. }
+                | Self::RecallPreset { .. }
+                | Self::Annotate { .. }
+                | Self::PipMode { .. }
+                | Self::AssignSource { .. }
+                | Self::SyncFormat { .. }
+                | Self::Genlock { .. }
+                | Self::Raw { .. }
+                | Self::DisplayRaw { .. }
+                | Self::SelectGroup { .. }
+                | Self::UploadFirmware { .. }
+                | Self::RecallLayout { .. }
+                | Self::Hold { .. }
+        )
+    }
+
+    /// The scope a token needs for the request.
+    fn scope(&self) -> Scope {
+        match self.changes_device() {
+            true => Scope::Control,
+            false => Scope::Read,
+        }
+    }
+
+    /// Fails unless `grant` allows the request for all the devices it is for, by the names
+    /// of `devices` for their keys.
+    fn permit(&mut self, grant: &Grant, devices: &ExtronDeviceList) -> Result<()> {
+        let scope = self.scope();
+        grant.check(scope)?;
+        let check = |name: &str| grant.check_device(scope, devices.resolve(name).unwrap_or(name));
+        match self {
+            Self::SelectGroup { names, .. } => names.iter().try_for_each(|name| check(name)),
+            request => match request.device_name_mut() {
+                Some(name) => check(name),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Fails the request with `error`.
+    fn fail(self, error: ControlError) {
+        match self {
+            Self::Rescan(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Self::ListDevices(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Stats(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Dump(reply) => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Select { reply, .. }
+            | Self::SelectPlane { reply, .. }
+            | Self::Volume { reply, .. }
+            | Self::Mute { reply, .. }
+            | Self::DisplayPower { reply, .. }
+            | Self::RecallPreset { reply, .. }
+            | Self::Annotate { reply, .. }
+            | Self::PipMode { reply, .. }
+            | Self::AssignSource { reply, .. }
+            | Self::SyncFormat { reply, .. }
+            | Self::Genlock { reply, .. }
+            | Self::Hold { reply, .. }
+            | Self::Maintenance { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::ToggleInput { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Status { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::SelectGroup { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Raw { reply, .. } | Self::DisplayRaw { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::UploadFirmware { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::DeviceLog { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::History { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Undo { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::WallLayout { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::RecallLayout { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Sync { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Info { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Health { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+        }
+    }
+
+    /// Fails the request with `error` if it changes a device, handing it back otherwise.
+    fn refuse_control(self, error: ControlError) -> Option<Self> {
+        match self {
+            Self::Select { reply, .. }
+            | Self::SelectPlane { reply, .. }
+            | Self::Volume { reply, .. }
+            | Self::Mute { reply, .. }
+            | Self::DisplayPower { reply, .. }
+            | Self::RecallPreset { reply, .. }
+            | Self::Annotate { reply, .. }
+            | Self::PipMode { reply, .. }
+            | Self::AssignSource { reply, .. }
+            | Self::SyncFormat { reply, .. }
+            | Self::Genlock { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Raw { reply, .. } | Self::DisplayRaw { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::ToggleInput { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::Undo { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::SelectGroup { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::UploadFirmware { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            Self::RecallLayout { reply, .. } => {
+                let _ = reply.send(Err(error));
+            }
+            request @ (Self::Rescan(_)
+            | Self::ListDevices(_)
+            | Self::Status { .. }
+            | Self::DeviceLog { .. }
+            | Self::History { .. }
+            | Self::WallLayout { .. }
+            | Self::Sync { .. }
+            | Self::Info { .. }
+            | Self::Health { .. }
+            | Self::Stats(_)
+            | Self::Dump(_)
+            | Self::Hold { .. }
+            | Self::Maintenance { .. }) => return Some(request),
+        }
+        None
+    }
+}
+
+/// Something that happened on the server, passed to the hooks registered with
+/// [`ServerBuilder::on_event`].
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// The address actually bound, which tells the port picked for port 0.
+    Listening(net::SocketAddr),
+    Connected(net::SocketAddr),
+    /// A connection turned away by the [`ServerBuilder::authorize`] check.
+    Rejected(net::SocketAddr),
+    /// The devices found, shared as every event buffered for subscribers holds them.
+    DevicesScanned(std::sync::Arc<Vec<ExtronDevice>>),
+    InputSelected {
+        device: String,
+        input: Input,
+    },
+    VolumeChanged {
+        device: String,
+        level: u8,
+    },
+    MuteChanged {
+        device: String,
+        mute: bool,
+    },
+    /// A device found before was missing on a rescan.
+    DeviceOffline(String),
+    /// The selected input of a device went without a signal for longer than the
+    /// [`ServerBuilder::health`] thresholds allow.
+    SignalLost {
+        device: String,
+        input: u32,
+    },
+    /// The signal on the input of a [`ServerEvent::SignalLost`] came back, or another input
+    /// was selected.
+    SignalRestored {
+        device: String,
+        input: u32,
+    },
+    /// A device crossed one of the [`ServerBuilder::health`] thresholds.
+    HealthAlert {
+        device: String,
+        alert: HealthAlert,
+    },
+    /// The server went into maintenance with the message, which may be empty.
+    MaintenanceStarted(String),
+    MaintenanceEnded,
+}
+
+type DeviceSource = Box<dyn Fn() -> Result<ExtronDeviceList> + Send + Sync>;
+/// The devices a scan finds, as it finds them.
+type Found = mpsc::UnboundedReceiver<ExtronDeviceList>;
+/// The ports a scan has yet to probe, `None` until it listed them.
+type Pending = std::sync::Arc<std::sync::Mutex<Option<Vec<String>>>>;
+/// A scan as [`ServerRequest::Rescan`] started or joined it, holding `true` once it is over.
+pub(crate) type ScanTicket = tokio::sync::watch::Receiver<bool>;
+type EventHook = Box<dyn Fn(&ServerEvent) + Send + Sync>;
+type AuthCheck = Box<dyn Fn(&net::SocketAddr) -> bool>;
+/// Device states, recorded by an event hook and read back on restore.
+pub(crate) type SharedState = std::sync::Arc<std::sync::Mutex<StateFile>>;
+
+struct DeviceSources {
+    sources: Vec<DeviceSource>,
+    /// How long the USB serial ports get to answer, when the devices are on them rather than
+    /// from [`ServerBuilder::device_source`].
+    serial: Option<std::time::Duration>,
+    /// Patterns of the device paths to use, all paths if empty.
+    ports: Vec<glob::Pattern>,
+    /// Other names of the devices, as alias and name.
+    aliases: Vec<(String, String)>,
+    /// Input numbers by label, by the name or alias of the device.
+    labels: Vec<(String, std::collections::BTreeMap<String, u32>)>,
+    /// Where the devices found record how they fare.
+    metrics: Metrics,
+}
+
+/// Whether the device at `path` is on one of `ports`, or `ports` is empty. A pattern also
+/// matches through links to the device, such as those in `/dev/serial/by-path`, which tell
+/// the USB controllers apart where the port names do not.
+fn on_ports(ports: &[glob::Pattern], path: &str) -> bool {
+    if ports.is_empty() {
+        return true;
+    }
+    let target = std::fs::canonicalize(path).ok();
+    ports.iter().any(|pattern| {
+        pattern.matches(path) || target.as_ref().is_some_and(|t| links_to(pattern, t))
+    })
+}
+
+/// Whether a path matching `pattern` leads to `target`.
+fn links_to(pattern: &glob::Pattern, target: &std::path::Path) -> bool {
+    let links = match glob::glob(pattern.as_str()) {
+        Ok(links) => links,
+        Err(_) => return false,
+    };
+    links
+        .flatten()
+        .any(|link| link.canonicalize().is_ok_and(|link| link == target))
+}
+
+impl DeviceSources {
+    /// Starts scanning all sources, sending the devices found as they are, until the scan is
+    /// over. A failing source is logged and skipped, so one broken bus does not take the
+    /// devices on the others with it. The devices in `known` whose port is still there are
+    /// taken to be on it, without probing the port. Every device found is counted its inputs,
+    /// unless `known` has the count already. The ports still to probe are kept in `pending`.
+    fn scan(
+        self: &std::sync::Arc<Self>,
+        known: &[Known],
+        pending: Pending,
+        cancel: &CancellationToken,
+    ) -> Found {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let find = self
+            .clone()
+            .find(known.to_vec(), tx, pending, cancel.clone());
+        tokio::spawn(find.in_current_span());
+        rx
+    }
+
+    async fn find(
+        self: std::sync::Arc<Self>,
+        known: Vec<Known>,
+        found: mpsc::UnboundedSender<ExtronDeviceList>,
+        pending: Pending,
+        cancel: CancellationToken,
+    ) {
+        use futures::StreamExt;
+
+        let send = |mut devices: ExtronDeviceList| {
+            devices.retain(|device| on_ports(&self.ports, &device.device_path));
+            // Only fails once nobody waits for the scan any more.
+            let _ = found.send(devices);
+        };
+        if let Some(probe_timeout) = self.serial {
+            let known: Vec<_> = known
+                .iter()
+                .filter(|known| std::path::Path::new(&known.path).exists())
+                .collect();
+            if !known.is_empty() {
+                info!(
+                    "Taking {} devices from the state file without probing them",
+                    known.len()
+                );
+            }
+            let mut devices = ExtronDeviceList::new();
+            for known in &known {
+                let device = ExtronDevice::new(&known.name, &known.path);
+                if let Some(inputs) = known.inputs {
+                    device.set_input_count(inputs);
+                }
+                devices.insert(device);
+            }
+            send(devices);
+            let ports = device_work(&cancel, crate::extron::extron_ports).await;
+            let ports: Vec<_> = ports
+                .unwrap_or_else(|e| {
+                    info!("Rescan failed: {}", e.to_string());
+                    Vec::new()
+                })
+                .into_iter()
+                .filter(|port| {
+                    on_ports(&self.ports, port) && known.iter().all(|known| known.path != *port)
+                })
+                .collect();
+            *pending.lock().unwrap() = Some(ports.clone());
+            let (pending, cancel) = (&pending, &cancel);
+            let probes = ports.into_iter().map(|port| async move {
+                let probed = device_work(cancel, {
+                    let port = port.clone();
+                    move || {
+                        let device = crate::extron::probe_device(&port, probe_timeout);
+                        if let Some(device) = &device {
+                            count_inputs(device, &[]);
+                        }
+                        Ok(device)
+                    }
+                })
+                .await;
+                if let Some(ports) = pending.lock().unwrap().as_mut() {
+                    ports.retain(|pending| *pending != port);
+                }
+                probed
+            });
+            // In port order, so that identical units keep their numbering across rescans.
+            let mut probes = futures::stream::iter(probes).buffered(crate::extron::PARALLEL_PROBES);
+            while let Some(probed) = probes.next().await {
+                // Also sent without a device, for the requests waiting on the port.
+                let mut devices = ExtronDeviceList::new();
+                if let Ok(Some(device)) = probed {
+                    devices.insert(device);
+                }
+                send(devices);
+            }
+        }
+        for i in 0..self.sources.len() {
+            let (sources, known) = (self.clone(), known.clone());
+            let devices = device_work(&cancel, move || {
+                let devices = (sources.sources[i])()?;
+                for device in devices.iter() {
+                    count_inputs(&device, &known);
+                }
+                Ok(devices)
+            });
+            match devices.await {
+                Ok(devices) => send(devices),
+                Err(e) => info!("Rescan failed: {}", e.to_string()),
+            }
+        }
+    }
+
+    /// Makes the devices a scan found go by their aliases, take their input labels and record
+    /// in the metrics.
+    fn settle(&self, devices: &mut ExtronDeviceList) {
+        for (alias, name) in &self.aliases {
+            if !devices.alias(alias, name) {
+                debug!("Not aliasing {} to {}, which was not found", alias, name);
+            }
+        }
+        for (name, labels) in &self.labels {
+            if !devices.label(name, labels.clone()) {
+                debug!("Not labelling the inputs of {}, which was not found", name);
+            }
+        }
+        devices.set_metrics(&self.metrics);
+        for device in devices.iter() {
+            self.metrics.found(&device.name);
+        }
+    }
+}
+
+/// Takes the number of inputs of `device` from what `known` has for its port, or else asks
+/// the device, so that a failing count does not lose the device.
+fn count_inputs(device: &ExtronDevice, known: &[Known]) {
+    let learned = known.iter().find(|known| known.path == device.device_path);
+    if let Some(inputs) = learned.and_then(|known| known.inputs) {
+        device.set_input_count(inputs);
+    } else if let Err(e) = device.count_inputs() {
+        info!("Cannot count the inputs of {}: {}", device.name, e);
+    }
+}
+
+pub(crate) struct EventHooks(Vec<EventHook>);
+
+impl EventHooks {
+    pub(crate) fn emit(&self, event: ServerEvent) {
+        for hook in &self.0 {
+            hook(&event);
+        }
+    }
+}
+
+/// Runs `work` on the blocking pool, in the current span. Work that has not started when the server stops is
+/// skipped; work already talking to a device runs to the end, so no device is left halfway
+/// through a command.
+async fn device_work<T, F>(cancel: &CancellationToken, work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let cancel = cancel.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        if cancel.is_cancelled() {
+            Err(ControlError::Cancelled)
+        } else {
+            work()
+        }
+    })
+    .await
+    .map_err(|_| ControlError::internal())?
+}
+
+/// A scan under way, see [`DeviceSources::scan`].
+struct Scan {
+    /// The devices found so far.
+    devices: ExtronDeviceList,
+    found: Found,
+    pending: Pending,
+    /// Set once the scan is over, for the tickets of [`ServerRequest::Rescan`].
+    done: tokio::sync::watch::Sender<bool>,
+}
+
+impl Scan {
+    fn start(
+        sources: &std::sync::Arc<DeviceSources>,
+        known: Vec<Known>,
+        cancel: &CancellationToken,
+    ) -> Self {
+        // Without the serial ports, the scan probes none.
+        let ports = match sources.serial {
+            Some(_) => None,
+            None => Some(Vec::new()),
+        };
+        let pending = std::sync::Arc::new(std::sync::Mutex::new(ports));
+        Scan {
+            devices: ExtronDeviceList::new(),
+            found: sources.scan(&known, pending.clone(), cancel),
+            pending,
+            done: tokio::sync::watch::channel(false).0,
+        }
+    }
+
+    fn ticket(&self) -> ScanTicket {
+        self.done.subscribe()
+    }
+
+    /// Whether `request` has to wait for the scan to end, see [`held`].
+    fn holds(&self, request: &mut ServerRequest, device_list: &ExtronDeviceList) -> bool {
+        held(
+            self.pending.lock().unwrap().as_deref(),
+            request,
+            device_list,
+        )
+    }
+
+    /// Takes the first request of `deferred` that the scan no longer holds.
+    fn release(
+        &self,
+        deferred: &mut std::collections::VecDeque<Request>,
+        device_list: &ExtronDeviceList,
+    ) -> Option<Request> {
+        let pending = self.pending.lock().unwrap();
+        let i = deferred
+            .iter_mut()
+            .position(|deferred| !held(pending.as_deref(), &mut deferred.request, device_list))?;
+        deferred.remove(i)
+    }
+
+    /// Adds the next devices the scan finds, returning false once it is over.
+    async fn next(&mut self) -> bool {
+        match self.found.recv().await {
+            Some(devices) => {
+                self.devices.extend(devices);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Whether `request` has to wait for a scan with `pending` ports to end, being for a device
+/// that was not in `device_list` before the scan or whose port the scan has yet to probe.
+fn held(
+    pending: Option<&[String]>,
+    request: &mut ServerRequest,
+    device_list: &ExtronDeviceList,
+) -> bool {
+    let waits = |name: &str| match (device_list.find(name), pending) {
+        (Some(device), Some(ports)) => ports.contains(&device.device_path),
+        _ => true,
+    };
+    match request {
+        ServerRequest::SelectGroup { names, .. } => names.iter().any(|name| waits(name)),
+        request => request.device_name_mut().is_some_and(|name| waits(name)),
+    }
+}
+
+/// The next step of `scan`, which never comes without one.
+async fn scanned(scan: &mut Option<Scan>) -> bool {
+    match scan {
+        Some(scan) => scan.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Puts the devices `scan` found in place of `device_list` and lets those waiting for it
+/// know. Devices no longer found are reported offline, and those that showed up are put back
+/// in their state in `restore`, if given.
+async fn finish(
+    scan: Scan,
+    device_list: &mut ExtronDeviceList,
+    sources: &DeviceSources,
+    health: &mut Monitor,
+    events: &EventHooks,
+    restore: Option<&SharedState>,
+    cancel: &CancellationToken,
+) {
+    let Scan {
+        devices: mut list,
+        done,
+        ..
+    } = scan;
+    sources.settle(&mut list);
+    let appeared: Vec<_> = list
+        .iter()
+        .map(|device| device.name)
+        .filter(|name| device_list.find(name).is_none())
+        .collect();
+    let gone: Vec<_> = device_list
+        .iter()
+        .map(|device| device.name)
+        .filter(|name| list.find(name).is_none())
+        .collect();
+    *device_list = list;
+    events.emit(ServerEvent::DevicesScanned(std::sync::Arc::new(
+        device_list.iter().collect(),
+    )));
+    for name in gone {
+        info!("{} went offline", name);
+        health.unreachable(&name, std::time::Instant::now());
+        sources.metrics.lost(&name);
+        events.emit(ServerEvent::DeviceOffline(name));
+    }
+    if let Some(state) = restore {
+        restore_state(device_list, &appeared, state, events, cancel).await;
+    }
+    // Fails when no ticket is left, which is fine.
+    let _ = done.send(true);
+}
+
+/// Waits for `scan` to end and finishes it, see [`finish`].
+async fn complete(
+    mut scan: Scan,
+    device_list: &mut ExtronDeviceList,
+    sources: &DeviceSources,
+    health: &mut Monitor,
+    events: &EventHooks,
+    restore: Option<&SharedState>,
+    cancel: &CancellationToken,
+) {
+    while scan.next().await {}
+    finish(scan, device_list, sources, health, events, restore, cancel).await;
+}
+
+async fn select(
+    device_list: &ExtronDeviceList,
+    name: &str,
+    input: &Input,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    match device_list.find(name) {
+        Some(device) => {
+            let input = input.clone();
+            device_work(cancel, move || device.select(&input)).await
+        }
+        None => Err(ControlError::DeviceNotFound(name.to_string())),
+    }
+}
+
+/// Puts the devices named in `names` back on the input, volume and mute saved for them.
+/// Failures are logged, as there is nobody to report them to.
+async fn restore_state(
+    device_list: &ExtronDeviceList,
+    names: &[String],
+    state: &SharedState,
+    events: &EventHooks,
+    cancel: &CancellationToken,
+) {
+    for name in names {
+        let saved = state.lock().unwrap().get(name).cloned();
+        let (saved, device) = match (saved, device_list.find(name)) {
+            (Some(saved), Some(device)) => (saved, device),
+            _ => continue,
+        };
+        info!("Restoring the state of {}", name);
+        if let Some(input) = saved.input {
+            let input: Input = match input.parse() {
+                Ok(input) => input,
+                Err(e) => {
+                    info!("Cannot restore input of {}: {}", name, e);
+                    continue;
+                }
+            };
+            let (d, i) = (device.clone(), input.clone());
+            match device_work(cancel, move || d.select(&i)).await {
+                Ok(()) => events.emit(ServerEvent::InputSelected {
+                    device: name.clone(),
+                    input,
+                }),
+                Err(e) => info!("Cannot restore input of {}: {}", name, e),
+            }
+        }
+        if let Some(level) = saved.volume {
+            let d = device.clone();
+            match device_work(cancel, move || d.set_volume(level)).await {
+                Ok(()) => events.emit(ServerEvent::VolumeChanged {
+                    device: name.clone(),
+                    level,
+                }),
+                Err(e) => info!("Cannot restore volume of {}: {}", name, e),
+            }
+        }
+        if let Some(mute) = saved.mute {
+            match device_work(cancel, move || device.set_mute(mute)).await {
+                Ok(()) => events.emit(ServerEvent::MuteChanged {
+                    device: name.clone(),
+                    mute,
+                }),
+                Err(e) => info!("Cannot restore mute of {}: {}", name, e),
+            }
+        }
+    }
+}
+
+/// Selects the inputs the schedules call for now. A device that cannot be switched is tried
+/// again on the next check.
+async fn apply_schedule(
+    device_list: &ExtronDeviceList,
+    scheduler: &mut Scheduler,
+    history: &mut History,
+    events: &EventHooks,
+    cancel: &CancellationToken,
+) {
+    let time = chrono::Local::now().time();
+    for (name, input) in scheduler.due(time, std::time::Instant::now()) {
+        let result = match device_list.find(&name) {
+            Some(device) => {
+                let i = input.clone();
+                device_work(cancel, move || device.select(&i)).await
+            }
+            None => Err(ControlError::DeviceNotFound(name.clone())),
+        };
+        match result {
+            Ok(()) => {
+                info!("Selected scheduled input {} on {}", input, name);
+                history.record(&name, "schedule", input.clone());
+                events.emit(ServerEvent::InputSelected {
+                    device: name,
+                    input,
+                });
+            }
+            Err(e) => {
+                info!("Cannot select scheduled input {} on {}: {}", input, name, e);
+                scheduler.retry(&name);
+            }
+        }
+    }
+}
+
+fn raise(events: &EventHooks, device: String, alert: HealthAlert) {
+    warn!("{} needs attention: {}", device, alert);
+    events.emit(ServerEvent::HealthAlert { device, alert });
+}
+
+/// What `value` takes from the state the server keeps of the device called `name`, if any.
+fn saved<T>(
+    state: &SharedState,
+    name: &str,
+    value: impl FnOnce(&crate::state::DeviceState) -> Option<T>,
+) -> Option<T> {
+    let state = state.lock().unwrap();
+    state.get(name).and_then(value)
+}
+
+/// Keeps what `device` said on its own in `journal`, with the front panel changes among it in
+/// `history` and `state`, and counts the error codes among it.
+fn heard(
+    device: &ExtronDevice,
+    journal: &mut Journal,
+    history: &mut History,
+    state: &SharedState,
+    health: &mut Monitor,
+    events: &EventHooks,
+) {
+    let mut errors = 0;
+    for text in journal.collect(device) {
+        match Reply::parse(text.as_bytes()) {
+            Reply::Error(_) => errors += 1,
+            // Audio switched apart leaves the input of the device, which is that of its video.
+            Reply::Input(input) if !text.ends_with("Aud") => {
+                let input = Input::Number(input);
+                state.lock().unwrap().front_panel(&device.name, &input);
+                history.record(&device.name, FRONT_PANEL, input)
+            }
+            _ => {}
+        }
+    }
+    if let Some(alert) = health.errors(&device.name, errors, std::time::Instant::now()) {
+        raise(events, device.name.clone(), alert);
+    }
+}
+
+/// Awaits `futures`, [`DEVICES_AT_ONCE`] at a time, with their outputs in the same order.
+async fn join_bounded<I>(futures: I) -> Vec<<I::Item as std::future::Future>::Output>
+where
+    I: IntoIterator,
+    I::Item: std::future::Future,
+{
+    use futures::StreamExt;
+
+    futures::stream::iter(futures)
+        .buffered(DEVICES_AT_ONCE)
+        .collect()
+        .await
+}
+
+/// Gives every device a moment to say something and keeps what they said in `journal`.
+/// Devices that cannot be listened to count as unreachable.
+async fn listen(
+    device_list: &ExtronDeviceList,
+    journal: &mut Journal,
+    history: &mut History,
+    state: &SharedState,
+    health: &mut Monitor,
+    events: &EventHooks,
+    cancel: &CancellationToken,
+) {
+    let listens = device_list.iter().map(|device| async move {
+        let d = device.clone();
+        let result = device_work(cancel, move || d.listen()).await;
+        (device, result)
+    });
+    for (device, result) in join_bounded(listens).await {
+        match result {
+            Ok(()) => health.reachable(&device.name),
+            Err(_) => health.unreachable(&device.name, std::time::Instant::now()),
+        }
+        heard(&device, journal, history, state, health, events);
+    }
+}
+
+/// Checks the temperature of the devices and how long devices have been unreachable.
+async fn check_health(
+    device_list: &ExtronDeviceList,
+    health: &mut Monitor,
+    events: &EventHooks,
+    cancel: &CancellationToken,
+) {
+    if health.checks_temperature() {
+        let reads = device_list.iter().map(|device| async move {
+            let d = device.clone();
+            (
+                device.name,
+                device_work(cancel, move || d.temperature()).await,
+            )
+        });
+        // Devices that cannot tell are left to the other checks.
+        for (name, result) in join_bounded(reads).await {
+            if let Some(alert) = result.ok().and_then(|t| health.temperature(&name, t)) {
+                raise(events, name, alert);
+            }
+        }
+    }
+    for (name, alert) in health.offline(std::time::Instant::now()) {
+        raise(events, name, alert);
+    }
+}
+
+/// Checks the selected input of every device for a signal.
+async fn check_signal(
+    device_list: &ExtronDeviceList,
+    health: &mut Monitor,
+    events: &EventHooks,
+    cancel: &CancellationToken,
+) {
+    let reads = device_list.iter().map(|device| async move {
+        let d = device.clone();
+        (
+            device.name,
+            device_work(cancel, move || d.selected_signal()).await,
+        )
+    });
+    let now = std::time::Instant::now();
+    // Devices that cannot tell are left to the other checks.
+    for (name, result) in join_bounded(reads).await {
+        let (input, present) = match result {
+            Ok(signal) => signal,
+            Err(_) => continue,
+        };
+        match health.signal(&name, input, present, now) {
+            Some(Signal::Lost(input)) => {
+                warn!("Signal lost on {} input {}", name, input);
+                events.emit(ServerEvent::SignalLost {
+                    device: name,
+                    input,
+                });
+            }
+            Some(Signal::Back(input)) => {
+                info!("Signal restored on {} input {}", name, input);
+                events.emit(ServerEvent::SignalRestored {
+                    device: name,
+                    input,
+                });
+            }
+            None => {}
+        }
+    }
+}
+
+/// Serves requests one at a time until `cancel` fires. Requests still queued then are
+/// dropped, which their callers see as [`ControlError::Cancelled`]. Starts from the devices
+/// and the journal in `state` and keeps them there when it ends. Devices showing
+/// up are put back in their state with `restore`. While a scan runs, the devices it found so
+/// far are listed, and requests for devices it may find or has yet to probe wait for it to
+/// end.
+#[allow(clippy::too_many_arguments)]
+async fn cmd_loop(
+    mut cmd_rx: mpsc::Receiver<Request>,
+    sources: std::sync::Arc<DeviceSources>,
+    events: std::sync::Arc<EventHooks>,
+    state: SharedState,
+    restore: bool,
+    mut scheduler: Scheduler,
+    thresholds: Thresholds,
+    undo_window: std::time::Duration,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let known = state.lock().unwrap().found().to_vec();
+    // Starting from no devices, every device the first scan finds shows up.
+    let mut device_list = ExtronDeviceList::new();
+    let mut scan = Some(Scan::start(&sources, known, &cancel));
+    // Requests that came in during a scan, served once it is over.
+    let mut deferred = std::collections::VecDeque::new();
+    let restore = restore.then(|| state.clone());
+
+    let mut journal = state.lock().unwrap().journal();
+    let mut history = History::new(undo_window);
+    let mut health = Monitor::new(thresholds);
+    let mut health_check = tokio::time::interval(HEALTH_CHECK);
+    let mut signal_check = tokio::time::interval(SIGNAL_CHECK);
+    signal_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut schedule_check = tokio::time::interval(SCHEDULE_CHECK);
+    let mut listen_check = tokio::time::interval(LISTEN_INTERVAL);
+    // A long firmware upload should not be followed by a burst of catching up.
+    listen_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The message while in maintenance, during which the schedules leave the devices alone.
+    let mut maintenance: Option<String> = None;
+    // A reply can only fail to send when the caller went away, so those errors are ignored.
+    loop {
+        // Keeps what the last request came across.
+        for device in device_list.iter() {
+            heard(
+                &device,
+                &mut journal,
+                &mut history,
+                &state,
+                &mut health,
+                &events,
+            );
+        }
+        let next = match &scan {
+            Some(scan) => scan.release(&mut deferred, &device_list),
+            None => deferred.pop_front(),
+        };
+        // Whether the request just came in, rather than after waiting for the scan.
+        let (
+            fresh,
+            Request {
+                span,
+                client,
+                grant,
+                mut request,
+            },
+        ) = match next {
+            Some(request) => (false, request),
+            // The periodic checks wait for a scan to end, they would find the ports busy.
+            None => tokio::select! {
+                biased;
+                _ = cancel.cancelled() => break,
+                more = scanned(&mut scan), if scan.is_some() => {
+                    if !more {
+                        finish(
+                            scan.take().unwrap(),
+                            &mut device_list,
+                            &sources,
+                            &mut health,
+                            &events,
+                            restore.as_ref(),
+                            &cancel,
+                        )
+                        .await;
+                    }
+                    continue;
+                }
+                _ = schedule_check.tick(),
+                    if !scheduler.is_empty() && maintenance.is_none() && scan.is_none() =>
+                {
+                    apply_schedule(&device_list, &mut scheduler, &mut history, &events, &cancel).await;
+                    continue;
+                }
+                _ = listen_check.tick(), if scan.is_none() => {
+                    listen(
+                        &device_list,
+                        &mut journal,
+                        &mut history,
+                        &state,
+                        &mut health,
+                        &events,
+                        &cancel,
+                    )
+                    .await;
+                    continue;
+                }
+                _ = health_check.tick(), if health.is_active() && scan.is_none() => {
+                    check_health(&device_list, &mut health, &events, &cancel).await;
+                    continue;
+                }
+                _ = signal_check.tick(), if health.checks_signal() && scan.is_none() => {
+                    check_signal(&device_list, &mut health, &events, &cancel).await;
+                    continue;
+                }
+                request = cmd_rx.recv() => match request {
+                    Some(request) => (true, request),
+                    None => break,
+                },
+            },
+        };
+        if let (true, Some(running)) = (fresh, &scan) {
+            // The devices found so far can be listed, and requests for the devices the scan
+            // leaves alone go ahead, after those held before.
+            match request {
+                ServerRequest::ListDevices(reply) => {
+                    let _ = reply.send(Ok((running.devices.iter().collect(), true)));
+                    continue;
+                }
+                ServerRequest::Rescan(reply) => {
+                    let _ = reply.send(Ok(running.ticket()));
+                    continue;
+                }
+                _ => {}
+            }
+            if !deferred.is_empty() || running.holds(&mut request, &device_list) {
+                deferred.push_back(Request {
+                    span,
+                    client,
+                    grant,
+                    request,
+                });
+                continue;
+            }
+        }
+        if let Some(name) = request.device_name_mut() {
+            // Requests go by the name of the device, whichever key they came with, so that
+            // events, the schedule and the journal do too.
+            if let Some(device) = device_list.resolve(name) {
+                *name = device.to_string();
+            }
+        }
+        // The grant names devices by name, not by the key the client used.
+        if let Some(grant) = &grant {
+            if let Err(e) = request.permit(grant, &device_list) {
+                request.fail(e);
+                continue;
+            }
+        }
+        let request = match &maintenance {
+            Some(message) => {
+                match request.refuse_control(ControlError::Maintenance(message.clone())) {
+                    Some(request) => request,
+                    None => continue,
+                }
+            }
+            None => request,
+        };
+        async {
+            match request {
+                ServerRequest::Rescan(reply) => {
+                    let started = Scan::start(&sources, Vec::new(), &cancel);
+                    let _ = reply.send(Ok(started.ticket()));
+                    scan = Some(started);
+                }
+                ServerRequest::ListDevices(reply) => {
+                    let _ = reply.send(Ok((device_list.iter().collect(), false)));
+                }
+                ServerRequest::Select { name, input, reply } => {
+                    let mut result = select(&device_list, &name, &input, &cancel).await;
+                    // An unplugged device would stay listed, failing every call. The rescan takes
+                    // it off, or finds it again, e.g. on another port, to try once more on.
+                    if matches!(&result, Err(e) if e.is_disconnect()) {
+                        warn!("{} disconnected, scanning again", name);
+                        // Joins the scan under way, if there is one.
+                        let running = scan
+                            .take()
+                            .unwrap_or_else(|| Scan::start(&sources, Vec::new(), &cancel));
+                        complete(
+                            running,
+                            &mut device_list,
+                            &sources,
+                            &mut health,
+                            &events,
+                            restore.as_ref(),
+                            &cancel,
+                        )
+                        .await;
+                        result = select(&device_list, &name, &input, &cancel).await;
+                    }
+                    if result.is_ok() {
+                        history.record(&name, &client, input.clone());
+                        events.emit(ServerEvent::InputSelected {
+                            device: name,
+                            input,
+                        });
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::ToggleInput { name, reply } => {
+                    let previous = saved(&state, &name, |saved| {
+                        saved.previous.as_deref()?.parse::<Input>().ok()
+                    });
+                    let result = match previous {
+                        Some(input) => select(&device_list, &name, &input, &cancel)
+                            .await
+                            .map(|()| input),
+                        None if device_list.find(&name).is_none() => {
+                            Err(ControlError::DeviceNotFound(name.clone()))
+                        }
+                        None => Err(ControlError::Rpc(capnp::Error::failed(format!(
+                            "No input was selected on {} before the one it is on",
+                            name
+                        )))),
+                    };
+                    if let Ok(input) = &result {
+                        history.record(&name, &client, input.clone());
+                        events.emit(ServerEvent::InputSelected {
+                            device: name,
+                            input: input.clone(),
+                        });
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::SelectPlane {
+                    name,
+                    input,
+                    plane,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        let input = input.clone();
+                        device_work(&cancel, move || device.select_plane(&input, plane)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name.clone()))
+                    };
+                    // The input of a device is that of its video, which an audio tie leaves.
+                    if result.is_ok() && plane != Plane::Audio {
+                        history.record(&name, &client, input.clone());
+                        events.emit(ServerEvent::InputSelected {
+                            device: name,
+                            input,
+                        });
+                    } else if result.is_ok() {
+                        // Nothing keeps the audio input it was on before.
+                        history.did(&name, None);
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Volume { name, level, reply } => {
+                    let before = saved(&state, &name, |saved| saved.volume);
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_volume(level)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name.clone()))
+                    };
+                    if result.is_ok() {
+                        history.did(&name, before.map(Undo::Volume));
+                        events.emit(ServerEvent::VolumeChanged {
+                            device: name,
+                            level,
+                        });
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Mute { name, mute, reply } => {
+                    let before = saved(&state, &name, |saved| saved.mute);
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_mute(mute)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name.clone()))
+                    };
+                    if result.is_ok() {
+                        history.did(&name, before.map(Undo::Mute));
+                        events.emit(ServerEvent::MuteChanged { device: name, mute });
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Status { name, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.status()).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::SelectGroup {
+                    mut names,
+                    input,
+                    plane,
+                    reply,
+                } => {
+                    let mut seen = std::collections::HashSet::new();
+                    names.retain(|name| seen.insert(name.clone()));
+                    let selects = names.into_iter().map(|name| {
+                        let device = device_list.find(&name);
+                        let input = input.clone();
+                        let cancel = &cancel;
+                        async move {
+                            let result = match device {
+                                Some(device) => {
+                                    device_work(cancel, move || device.select_plane(&input, plane))
+                                        .await
+                                }
+                                None => Err(ControlError::DeviceNotFound(name.clone())),
+                            };
+                            (name, result)
+                        }
+                    });
+                    let results = join_bounded(selects).await;
+                    for (name, result) in &results {
+                        if result.is_ok() && plane != Plane::Audio {
+                            let device = device_list.resolve(name).unwrap_or(name).to_string();
+                            history.record(&device, &client, input.clone());
+                            events.emit(ServerEvent::InputSelected {
+                                device,
+                                input: input.clone(),
+                            });
+                        }
+                    }
+                    let _ = reply.send(Ok(results));
+                }
+                ServerRequest::Raw {
+                    name,
+                    command,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.send_raw(&command)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::UploadFirmware {
+                    name,
+                    image,
+                    progress,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        info!("Uploading {} bytes of firmware to {}", image.len(), name);
+                        device_work(&cancel, move || {
+                            device.upload_firmware(&image, &mut |done, total| {
+                                let _ = progress.send((done, total));
+                            })
+                        })
+                        .await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name.clone()))
+                    };
+                    match &result {
+                        Ok(update) => info!(
+                            "Firmware of {} went from {} to {}",
+                            name, update.before, update.after
+                        ),
+                        Err(e) => info!("Firmware upload to {} failed: {}", name, e),
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::DeviceLog { name, reply } => {
+                    if let Some(device) = device_list.find(&name) {
+                        let d = device.clone();
+                        let _ = device_work(&cancel, move || d.listen()).await;
+                        heard(
+                            &device,
+                            &mut journal,
+                            &mut history,
+                            &state,
+                            &mut health,
+                            &events,
+                        );
+                    }
+                    let result = match journal.messages(&name) {
+                        Some(messages) => Ok(messages),
+                        None if device_list.find(&name).is_some() => Ok(Vec::new()),
+                        None => Err(ControlError::DeviceNotFound(name)),
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Undo { name, reply } => {
+                    let result = match (history.undo(&name), device_list.find(&name)) {
+                        (_, None) => Err(ControlError::DeviceNotFound(name.clone())),
+                        (None, Some(_)) => Err(ControlError::Rpc(capnp::Error::failed(format!(
+                            "Nothing changed on {} through the server in the last {} seconds",
+                            name,
+                            history.window().as_secs()
+                        )))),
+                        (Some(undo), Some(device)) => {
+                            let u = undo.clone();
+                            device_work(&cancel, move || match u {
+                                Undo::Input(input) => device.select(&input),
+                                Undo::Volume(level) => device.set_volume(level),
+                                Undo::Mute(mute) => device.set_mute(mute),
+                            })
+                            .await
+                            .map(|()| undo)
+                        }
+                    };
+                    if let Ok(undo) = &result {
+                        info!("Undid the last change on {}, back to {}", name, undo);
+                        let device = name.clone();
+                        match undo.clone() {
+                            Undo::Input(input) => {
+                                history.record(&name, &client, input.clone());
+                                events.emit(ServerEvent::InputSelected { device, input });
+                            }
+                            Undo::Volume(level) => {
+                                events.emit(ServerEvent::VolumeChanged { device, level })
+                            }
+                            Undo::Mute(mute) => {
+                                events.emit(ServerEvent::MuteChanged { device, mute })
+                            }
+                        }
+                        // Undone once; the change before it is left to select, volume and mute.
+                        history.did(&name, None);
+                    }
+                    let _ = reply.send(result.map(|undo| undo.to_string()));
+                }
+                ServerRequest::History { name, reply } => {
+                    // Catches the front panel changes made since the devices were listened to.
+                    if let Some(device) = device_list.find(&name) {
+                        let d = device.clone();
+                        let _ = device_work(&cancel, move || d.listen()).await;
+                        heard(
+                            &device,
+                            &mut journal,
+                            &mut history,
+                            &state,
+                            &mut health,
+                            &events,
+                        );
+                    }
+                    let result = match history.changes(&name) {
+                        Some(changes) => Ok(changes),
+                        None if device_list.find(&name).is_some() => Ok(Vec::new()),
+                        None => Err(ControlError::DeviceNotFound(name)),
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::DisplayPower { name, on, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_display_power(on)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::DisplayRaw { name, data, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.send_to_display(&data)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::RecallPreset {
+                    name,
+                    preset,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.recall_preset(preset)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::WallLayout { name, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.wall_layout()).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::RecallLayout {
+                    name,
+                    layout,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.recall_layout(layout)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Annotate {
+                    name,
+                    annotation,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.annotate(annotation)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::PipMode { name, mode, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_pip_mode(mode)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::AssignSource {
+                    name,
+                    window,
+                    input,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.assign_source(window, &input)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Sync { name, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.sync()).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Info { name, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.info()).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Health { name, reply } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.health()).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::SyncFormat {
+                    name,
+                    format,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_sync_format(format)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Genlock {
+                    name,
+                    genlock,
+                    reply,
+                } => {
+                    let result = if let Some(device) = device_list.find(&name) {
+                        device_work(&cancel, move || device.set_genlock(genlock)).await
+                    } else {
+                        Err(ControlError::DeviceNotFound(name))
+                    };
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Stats(reply) => {
+                    let _ = reply.send(Ok(sources.metrics.devices()));
+                }
+                ServerRequest::Dump(reply) => {
+                    let now = std::time::Instant::now();
+                    let devices: Vec<_> = device_list
+                        .iter()
+                        .map(|device| {
+                            let messages = journal.messages(&device.name).map_or(0, |m| m.len());
+                            serde_json::json!({
+                                "name": device.name,
+                                "path": device.device_path,
+                                "transport": device.transport(),
+                                "messages": messages,
+                            })
+                        })
+                        .collect();
+                    let time = chrono::Local::now().time();
+                    let _ = reply.send(Ok(serde_json::json!({
+                        "devices": devices,
+                        "health": health.dump(now),
+                        "schedules": scheduler.dump(time, now),
+                        "maintenance": maintenance,
+                    })));
+                }
+                ServerRequest::Hold {
+                    name,
+                    duration,
+                    reply,
+                } => {
+                    let until = duration.map(|duration| std::time::Instant::now() + duration);
+                    let result = scheduler.hold(&name, until);
+                    if result.is_ok() && until.is_none() && maintenance.is_none() {
+                        apply_schedule(
+                            &device_list,
+                            &mut scheduler,
+                            &mut history,
+                            &events,
+                            &cancel,
+                        )
+                        .await;
+                    }
+                    let _ = reply.send(result);
+                }
+                ServerRequest::Maintenance { message, reply } => {
+                    if message != maintenance {
+                        match &message {
+                            Some(message) => {
+                                info!("Maintenance started: {}", message);
+                                events.emit(ServerEvent::MaintenanceStarted(message.clone()));
+                            }
+                            None => {
+                                info!("Maintenance ended");
+                                events.emit(ServerEvent::MaintenanceEnded);
+                            }
+                        }
+                    }
+                    let ended = maintenance.is_some() && message.is_none();
+                    maintenance = message;
+                    // Catches up on what the schedules missed.
+                    if ended && !scheduler.is_empty() {
+                        apply_schedule(
+                            &device_list,
+                            &mut scheduler,
+                            &mut history,
+                            &events,
+                            &cancel,
+                        )
+                        .await;
+                    }
+                    let _ = reply.send(Ok(()));
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+    if let Some(state) = &state {
+        let devices = Some(&device_list).filter(|_| scan.is_none());
+        state.lock().unwrap().keep(devices, &journal);
+    }
+    Ok(())
+}
+
+/// Whether `peer` is on this machine, also when it came as an IPv4 address mapped to IPv6.
+pub(crate) fn is_loopback(peer: &net::SocketAddr) -> bool {
+    match peer.ip() {
+        net::IpAddr::V4(ip) => ip.is_loopback(),
+        net::IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback())
+        }
+    }
+}
+
+/// Checks a new connection with the [`ServerBuilder::authorize`] check and reports it.
+fn admit(peer: &net::SocketAddr, authorize: &Option<AuthCheck>, events: &EventHooks) -> bool {
+    if let Some(authorize) = authorize {
+        if !authorize(peer) {
+            info!("Rejected connection from {}", peer);
+            events.emit(ServerEvent::Rejected(*peer));
+            return false;
+        }
+    }
+    events.emit(ServerEvent::Connected(*peer));
+    true
+}
+
+/// The RPC system serving `connection` on `stream`. Dropping it closes the connection.
+fn rpc_system<S>(stream: S, connection: ControlExtronImpl) -> RpcSystem<rpc_twoparty_capnp::Side>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    use futures::AsyncReadExt;
+
+    let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+    let network = twoparty::VatNetwork::new(
+        reader,
+        writer,
+        rpc_twoparty_capnp::Side::Server,
+        Default::default(),
+    );
+    let extron_client: control_extron::Client = capnp_rpc::new_client(connection);
+    RpcSystem::new(Box::new(network), Some(extron_client.client))
+}
+
+async fn accept_loop(
+    listener: tokio::net::TcpListener,
+    control_extron: ControlExtronImpl,
+    authorize: std::rc::Rc<Option<AuthCheck>>,
+    events: std::sync::Arc<EventHooks>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        if !admit(&peer, &authorize, &events) {
+            continue;
+        }
+        stream.set_nodelay(true)?;
+        if let Err(e) = keepalive::enable(&stream) {
+            warn!("No keepalive on the connection from {}: {}", peer, e);
+        }
+        // A TLS handshake takes round trips, which the other connections do not wait for.
+        tokio::task::spawn_local(Box::pin(serve_connection(
+            stream,
+            peer,
+            control_extron.clone(),
+        )));
+    }
+}
+
+/// A connection, in the clear or over TLS.
+#[cfg(feature = "tls")]
+trait Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin {}
+
+#[cfg(feature = "tls")]
+impl<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> Stream for S {}
+
+/// How long a client has for the TLS handshake.
+#[cfg(feature = "tls")]
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Serves the connection from `peer` on `stream` until either end closes it, after the TLS
+/// handshake if the server has TLS.
+async fn serve_connection(
+    stream: tokio::net::TcpStream,
+    peer: net::SocketAddr,
+    mut connection: ControlExtronImpl,
+) {
+    // With tokens, a connection may do nothing until it authenticated.
+    let grant = match connection.tokens {
+        Some(_) => Grant::none(),
+        None => Grant::all(),
+    };
+    #[cfg(feature = "tls")]
+    let (stream, identity, grant): (Box<dyn Stream>, _, _) = match connection.tls.clone() {
+        Some(tls) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+            Ok(Ok((stream, identity))) => {
+                let grant = tls.grant(identity.as_deref()).unwrap_or(grant);
+                (Box::new(stream), identity, grant)
+            }
+            Ok(Err(e)) => return info!("TLS handshake with {} failed: {}", peer, e),
+            Err(_) => return info!("TLS handshake with {} timed out", peer),
+        },
+        None => (Box::new(stream), None, grant),
+    };
+    #[cfg(not(feature = "tls"))]
+    let identity = None;
+
+    let mut session = Session::new(peer, grant);
+    session.identity = identity;
+    let session = std::rc::Rc::new(session);
+    connection
+        .connections
+        .borrow_mut()
+        .insert(peer, session.clone());
+    // Every connection gets its own capability, which keeps what its client negotiated.
+    connection.admin_allowed = !connection.local_admin || is_loopback(&peer);
+    connection.session = session.clone();
+    let connections = connection.connections.clone();
+    let rpc_system = rpc_system(stream, connection);
+    let span = match &session.identity {
+        Some(identity) => info_span!("connection", %peer, %identity),
+        None => info_span!("connection", %peer),
+    };
+    // Dropping the RPC system closes the connection.
+    async move {
+        tokio::select! {
+            biased;
+            _ = session.disconnect.cancelled() => info!("Disconnected {}", peer),
+            _ = rpc_system => {}
+        }
+        connections.borrow_mut().remove(&peer);
+    }
+    .instrument(span)
+    .await
+}
+
+/// Serves the admin socket until `cancel` fires. Only those who may open the socket file get
+/// there, so its connections may do everything, whatever the tokens say. They are not listed
+/// with the sessions, which are by peer address.
+#[cfg(unix)]
+async fn admin_loop(
+    listener: tokio::net::UnixListener,
+    control_extron: ControlExtronImpl,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let peer = net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut session = Session::new(peer, Grant::all());
+        session.identity = Some("admin socket".to_string());
+        let session = std::rc::Rc::new(session);
+        let mut connection = control_extron.clone();
+        connection.admin_allowed = true;
+        connection.session = session;
+        let rpc_system = rpc_system(stream, connection);
+        let span = info_span!("connection", peer = "admin socket");
+        let rpc_system = async move {
+            let _ = rpc_system.await;
+        };
+        tokio::task::spawn_local(Box::pin(rpc_system.instrument(span)));
+    }
+}
+
+/// Serves the metrics on `listener` until `cancel` fires, to HTTP GETs of `/metrics`, and
+/// everything else to `web` if there is a dashboard. Peers failing the
+/// [`ServerBuilder::authorize`] check are turned away without events, as scrapers come back
+/// every few seconds.
+async fn http_loop(
+    listener: tokio::net::TcpListener,
+    metrics: Metrics,
+    web: Option<std::rc::Rc<crate::web::Web>>,
+    authorize: std::rc::Rc<Option<AuthCheck>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        if let Some(authorize) = authorize.as_ref() {
+            if !authorize(&peer) {
+                debug!("Rejected HTTP request from {}", peer);
+                continue;
+            }
+        }
+        let metrics = metrics.clone();
+        let web = web.clone();
+        tokio::task::spawn_local(async move {
+            let mut stream = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream);
+            // The request line and the headers matter, a body is not read.
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(n) if n > 0 && request.len() < 16384 => request.extend(&buf[..n]),
+                    _ => return,
+                }
+            }
+            let head = String::from_utf8_lossy(&request);
+            let mut lines = head.split("\r\n");
+            let mut words = lines.next().unwrap_or_default().split_whitespace();
+            let method = words.next().unwrap_or_default();
+            let path = words.next().unwrap_or_default();
+            let headers: Vec<(&str, &str)> = lines
+                .take_while(|line| !line.is_empty())
+                .filter_map(|line| line.split_once(':'))
+                .collect();
+            let result = if method == "GET" && path.split('?').next() == Some("/metrics") {
+                let body = crate::metrics::prometheus(&metrics.devices());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await
+            } else if let Some(web) = web {
+                web.serve(&mut stream, method, path, &headers).await
+            } else {
+                stream
+                    .write_all(
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+            };
+            if let Err(e) = result {
+                debug!("Cannot answer HTTP request from {}: {}", peer, e);
+            }
+            let _ = stream.close().await;
+        });
+    }
+}
+
+/// Serves gRPC on `listener` until `cancel` fires, with the same connection check and events
+/// as `accept_loop`.
+#[cfg(feature = "grpc")]
+async fn grpc_loop(
+    listener: tokio::net::TcpListener,
+    tx_channel: mpsc::Sender<Request>,
+    local_admin: bool,
+    authorize: std::rc::Rc<Option<AuthCheck>>,
+    events: std::sync::Arc<EventHooks>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    let incoming = futures::stream::unfold(listener, move |listener| {
+        let authorize = authorize.clone();
+        let events = events.clone();
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) if admit(&peer, &authorize, &events) => {
+                        if let Err(e) = keepalive::enable(&stream) {
+                            warn!("No keepalive on the connection from {}: {}", peer, e);
+                        }
+                        let stream = stream.set_nodelay(true).map(|()| stream);
+                        return Some((stream, listener));
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Some((Err(e), listener)),
+                }
+            }
+        }
+    });
+    crate::grpc::serve(incoming, tx_channel, local_admin, cancel).await
+}
+
+/// Sets up a server, for running it inside another program.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use control_dsc::server::{ServerBuilder, ServerEvent};
+///
+/// ServerBuilder::new()
+///     .listen("127.0.0.1:14000".parse().unwrap())
+///     .authorize(|peer| peer.ip().is_loopback())
+///     .on_event(|event| {
+///         if let ServerEvent::InputSelected { device, input } = event {
+///             println!("{} switched to {}", device, input);
+///         }
+///     })
+///     .serve()
+///     .await
+/// # }
+/// ```
+pub struct ServerBuilder {
+    addrs: Vec<net::SocketAddr>,
+    bound: Vec<net::TcpListener>,
+    #[cfg(feature = "grpc")]
+    grpc_addrs: Vec<net::SocketAddr>,
+    metrics_addrs: Vec<net::SocketAddr>,
+    dashboard: Option<crate::web::Dashboard>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<crate::dbus::Bus>,
+    #[cfg(feature = "hotkeys")]
+    hotkeys: Option<crate::hotkeys::Hotkeys>,
+    sources: Vec<DeviceSource>,
+    network: Vec<crate::network::NetworkDevice>,
+    ports: Vec<glob::Pattern>,
+    aliases: Vec<(String, String)>,
+    labels: Vec<(String, std::collections::BTreeMap<String, u32>)>,
+    probe_timeout: std::time::Duration,
+    authorize: Option<AuthCheck>,
+    local_admin: bool,
+    tokens: Option<std::path::PathBuf>,
+    #[cfg(unix)]
+    admin_socket: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    tls: Option<crate::tls::ServerTls>,
+    hooks: Vec<EventHook>,
+    state_file: Option<std::path::PathBuf>,
+    restore_state: bool,
+    schedules: Vec<Schedule>,
+    thresholds: Thresholds,
+    undo_window: std::time::Duration,
+    #[cfg(feature = "client")]
+    standby_of: Option<net::SocketAddr>,
+    cancel: CancellationToken,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder {
+            addrs: Vec::new(),
+            bound: Vec::new(),
+            #[cfg(feature = "grpc")]
+            grpc_addrs: Vec::new(),
+            metrics_addrs: Vec::new(),
+            dashboard: None,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            #[cfg(feature = "hotkeys")]
+            hotkeys: None,
+            sources: Vec::new(),
+            network: Vec::new(),
+            ports: Vec::new(),
+            aliases: Vec::new(),
+            labels: Vec::new(),
+            probe_timeout: crate::extron::DEFAULT_PROBE_TIMEOUT,
+            authorize: None,
+            local_admin: false,
+            tokens: None,
+            #[cfg(unix)]
+            admin_socket: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            hooks: Vec::new(),
+            state_file: None,
+            restore_state: false,
+            schedules: Vec::new(),
+            thresholds: Thresholds::default(),
+            undo_window: DEFAULT_UNDO_WINDOW,
+            #[cfg(feature = "client")]
+            standby_of: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Adds an address to accept connections on.
+    pub fn listen(mut self, addr: net::SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Accepts connections on `listener`, bound already, e.g. one passed by systemd.
+    pub fn listen_on(mut self, listener: net::TcpListener) -> Self {
+        self.bound.push(listener);
+        self
+    }
+
+    /// Adds an address to accept gRPC connections on, see `proto/control_dsc.proto`.
+    #[cfg(feature = "grpc")]
+    pub fn listen_grpc(mut self, addr: net::SocketAddr) -> Self {
+        self.grpc_addrs.push(addr);
+        self
+    }
+
+    /// Adds an address to serve the stats of the devices on over HTTP, in the Prometheus text
+    /// format, see [`crate::metrics`].
+    pub fn listen_metrics(mut self, addr: net::SocketAddr) -> Self {
+        self.metrics_addrs.push(addr);
+        self
+    }
+
+    /// Also serves a dashboard of the devices with `dashboard` on the addresses of
+    /// [`Self::listen_metrics`], see [`crate::web`].
+    pub fn dashboard(mut self, dashboard: crate::web::Dashboard) -> Self {
+        self.dashboard = Some(dashboard);
+        self
+    }
+
+    /// Also offers the devices as the D-Bus service [`crate::dbus::NAME`] on `bus`.
+    #[cfg(feature = "dbus")]
+    pub fn dbus(mut self, bus: crate::dbus::Bus) -> Self {
+        self.dbus = Some(bus);
+        self
+    }
+
+    /// Also runs the actions of `hotkeys` when their keys are pressed on the pad.
+    #[cfg(feature = "hotkeys")]
+    pub fn hotkeys(mut self, hotkeys: crate::hotkeys::Hotkeys) -> Self {
+        self.hotkeys = Some(hotkeys);
+        self
+    }
+
+    /// Adds a function to find devices with, called at start and on every rescan, after the
+    /// ones added before. The devices it returns are listed while the scan goes on. Without
+    /// one, the USB serial ports of this machine are scanned.
+    pub fn device_source<F>(mut self, source: F) -> Self
+    where
+        F: Fn() -> Result<ExtronDeviceList> + Send + Sync + 'static,
+    {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Also serves `device` on the network, next to the devices on the USB serial ports or from
+    /// the [`ServerBuilder::device_source`]. It is not connected to until it is used, except
+    /// for counting its inputs. With [`ServerBuilder::device_ports`], a pattern has to match
+    /// its path, `tcp:` and its address.
+    pub fn network_device(mut self, device: crate::network::NetworkDevice) -> Self {
+        self.network.push(device);
+        self
+    }
+
+    /// Only uses the devices whose path matches `pattern` or another pattern given this way,
+    /// e.g. `/dev/serial/by-path/pci-0000:00:14.0-usb-0:1*`, so that servers on one machine
+    /// can split its devices between them. Ports that do not match are not even scanned.
+    pub fn device_ports(mut self, pattern: glob::Pattern) -> Self {
+        self.ports.push(pattern);
+        self
+    }
+
+    /// Makes the device called `name` also go by `alias` in calls, e.g. by the room it is in.
+    /// A device actually called `alias` goes first.
+    pub fn alias(mut self, alias: &str, name: &str) -> Self {
+        self.aliases.push((alias.to_string(), name.to_string()));
+        self
+    }
+
+    /// Lets `selectInput` and its siblings take the inputs of the device called `name`, or
+    /// known by it as an alias, by the labels in `labels`, e.g. `laptop` for 2, before the
+    /// names stored in the device. The labels are listed with the device.
+    pub fn input_labels(
+        mut self,
+        name: &str,
+        labels: std::collections::BTreeMap<String, u32>,
+    ) -> Self {
+        self.labels.push((name.to_string(), labels));
+        self
+    }
+
+    /// Gives each USB serial port `timeout` to answer when scanning them, instead of
+    /// [`crate::extron::DEFAULT_PROBE_TIMEOUT`]. Has no effect with a
+    /// [`ServerBuilder::device_source`].
+    pub fn probe_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.probe_timeout = timeout;
+        self
+    }
+
+    /// Only serves connections from peers for which `check` returns true.
+    pub fn authorize<F>(mut self, check: F) -> Self
+    where
+        F: Fn(&net::SocketAddr) -> bool + 'static,
+    {
+        self.authorize = Some(Box::new(check));
+        self
+    }
+
+    /// Calls `hook` for every [`ServerEvent`]. Hooks run on the server's tasks, so they
+    /// should return quickly.
+    pub fn on_event<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ServerEvent) + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Saves the input, volume and mute last set on every device to `path`, with the devices
+    /// found and the journal. On the next start, the devices are taken from the file where
+    /// their ports are still there, rather than probed again; a rescan probes them.
+    pub fn state_file<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Sets the devices back to their saved state when the server starts and when they show
+    /// up again on a rescan, e.g. after a power cycle. Needs a [`ServerBuilder::state_file`].
+    pub fn restore_state(mut self) -> Self {
+        self.restore_state = true;
+        self
+    }
+
+    /// Switches a device by time of day, see [`crate::schedule`].
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedules.push(schedule);
+        self
+    }
+
+    /// Raises [`ServerEvent::HealthAlert`] for devices crossing `thresholds`, see
+    /// [`crate::health`].
+    pub fn health(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+
+    /// Lets clients undo the last input, volume or mute change made through the server on a
+    /// device for `window` after it was made, rather than for [`DEFAULT_UNDO_WINDOW`].
+    pub fn undo_window(mut self, window: std::time::Duration) -> Self {
+        self.undo_window = window;
+        self
+    }
+
+    /// Runs as the standby of the server at `primary`, see [`crate::standby`]. The state of the
+    /// devices is kept in memory when there is no [`ServerBuilder::state_file`].
+    #[cfg(feature = "client")]
+    pub fn standby_of(mut self, primary: net::SocketAddr) -> Self {
+        self.standby_of = Some(primary);
+        self
+    }
+
+    /// Serves administrative calls, stopping the server, rescans, raw commands and firmware
+    /// uploads, only to connections from this machine, i.e. on a loopback address. Other
+    /// machines may still list, select and read. Without this, any connection passing the
+    /// [`ServerBuilder::authorize`] check may make them.
+    pub fn local_admin(mut self) -> Self {
+        self.local_admin = true;
+        self
+    }
+
+    /// Requires clients to authenticate with one of the API tokens kept in `path` before
+    /// anything else, see [`crate::tokens`]. A connection can then do what the scope of its
+    /// token allows, on the devices it is for. Tokens are managed on the
+    /// [`ServerBuilder::admin_socket`], or by clients with an admin token.
+    ///
+    /// Tokens only guard the Cap'n Proto connections, so [`ServerBuilder::serve`] refuses to
+    /// start with them and a dashboard or gRPC listener but no [`ServerBuilder::authorize`]
+    /// check, which would leave those open to anyone. D-Bus relies on the policy of the bus.
+    pub fn tokens<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.tokens = Some(path.into());
+        self
+    }
+
+    /// Also serves on the Unix socket at `path`, which only the user running the server may
+    /// open. Connections on it may do everything without a token, e.g. create the first one.
+    #[cfg(unix)]
+    pub fn admin_socket<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.admin_socket = Some(path.into());
+        self
+    }
+
+    /// Serves the Cap'n Proto connections over TLS as `tls` says, requiring client
+    /// certificates if it does, see [`crate::tls`]. The other listeners stay in the clear.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: crate::tls::ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Stops the server when `cancel` is cancelled, as `stop_server` does. Without one, only
+    /// clients can stop the server.
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Runs the server until a client asks it to stop or the [`ServerBuilder::cancel_token`]
+    /// is cancelled. It returns once the accept loops have ended and the device operation in
+    /// progress, if any, has finished.
+    ///
+    /// The RPC system is not `Send`, so the future has to be driven by `Runtime::block_on`
+    /// or from within a `tokio::task::LocalSet`, not by `tokio::spawn`.
+    pub async fn serve(mut self) -> std::io::Result<()> {
+        use std::rc::Rc;
+        use std::sync::Arc;
+
+        if self.tokens.is_some() && self.authorize.is_none() {
+            let dashboard = self.dashboard.is_some() && !self.metrics_addrs.is_empty();
+            #[cfg(feature = "grpc")]
+            let grpc = !self.grpc_addrs.is_empty();
+            #[cfg(not(feature = "grpc"))]
+            let grpc = false;
+            if dashboard || grpc {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "API tokens do not guard the dashboard or gRPC, which need an authorize \
+                     check to be served with them",
+                ));
+            }
+        }
+        let serial = Some(self.probe_timeout).filter(|_| self.sources.is_empty());
+        // A source of their own, which still leaves the serial ports to scan.
+        if !self.network.is_empty() {
+            let network = std::mem::take(&mut self.network);
+            self.sources
+                .push(Box::new(move || Ok(crate::network::device_list(&network))));
+        }
+        let local_admin = self.local_admin;
+        #[cfg(feature = "client")]
+        let standby_of = self.standby_of;
+        #[cfg(not(feature = "client"))]
+        let standby_of: Option<net::SocketAddr> = None;
+        // Kept in memory without a file, for toggling inputs.
+        let state = match self.state_file {
+            Some(path) => StateFile::load(path)?,
+            None => StateFile::in_memory(),
+        };
+        let state = Arc::new(std::sync::Mutex::new(state));
+        let recorded = state.clone();
+        self.hooks.push(Box::new(move |event| {
+            recorded.lock().unwrap().record(event)
+        }));
+        let (event_tx, _) = broadcast::channel(EVENT_BUFFER);
+        let subscribers = event_tx.clone();
+        self.hooks.push(Box::new(move |event| {
+            // Fails only while nobody is subscribed.
+            let _ = subscribers.send(event.clone());
+        }));
+        let metrics = Metrics::default();
+        let sources = Arc::new(DeviceSources {
+            sources: self.sources,
+            serial,
+            ports: self.ports,
+            aliases: self.aliases,
+            labels: self.labels,
+            metrics: metrics.clone(),
+        });
+        let events = Arc::new(EventHooks(self.hooks));
+        let authorize = Rc::new(self.authorize);
+
+        let tokens = match self.tokens {
+            Some(path) => {
+                info!("Requiring API tokens from {}", path.display());
+                Some(Rc::new(std::cell::RefCell::new(TokenStore::load(path)?)))
+            }
+            None => None,
+        };
+
+        #[cfg(unix)]
+        let admin_listener = match &self.admin_socket {
+            Some(path) => {
+                let listener = bind_admin_socket(path)?;
+                info!("Admin socket at {}", path.display());
+                Some(listener)
+            }
+            None => None,
+        };
+        let mut listeners = Vec::new();
+        for addr in &self.addrs {
+            listeners.push(tokio::net::TcpListener::bind(*addr).await?);
+        }
+        for listener in self.bound {
+            listener.set_nonblocking(true)?;
+            listeners.push(tokio::net::TcpListener::from_std(listener)?);
+        }
+        for listener in &listeners {
+            let addr = listener.local_addr()?;
+            info!("Server listening on {}", addr);
+            events.emit(ServerEvent::Listening(addr));
+        }
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            info!("Serving over TLS");
+        }
+        #[cfg(feature = "grpc")]
+        let mut grpc_listeners = Vec::new();
+        #[cfg(feature = "grpc")]
+        for addr in &self.grpc_addrs {
+            let listener = tokio::net::TcpListener::bind(*addr).await?;
+            let addr = listener.local_addr()?;
+            info!("Server listening for gRPC on {}", addr);
+            events.emit(ServerEvent::Listening(addr));
+            grpc_listeners.push(listener);
+        }
+        let mut metrics_listeners = Vec::new();
+        for addr in &self.metrics_addrs {
+            let listener = tokio::net::TcpListener::bind(*addr).await?;
+            let addr = listener.local_addr()?;
+            info!("Server serving metrics on {}", addr);
+            if self.dashboard.is_some() {
+                info!("Dashboard on http://{}/", addr);
+            }
+            events.emit(ServerEvent::Listening(addr));
+            metrics_listeners.push(listener);
+        }
+
+        #[cfg(feature = "hotkeys")]
+        let pad = match self.hotkeys {
+            Some(hotkeys) => Some(crate::hotkeys::open(hotkeys)?),
+            None => None,
+        };
+
+        let cancel = self.cancel;
+        let (cmd_tx, cmd_rx) = mpsc::channel::<Request>(50);
+        #[cfg(feature = "dbus")]
+        let dbus = match self.dbus {
+            Some(bus) => {
+                let connection = crate::dbus::connect(bus, cmd_tx.clone())
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                info!("Server offered on D-Bus as {}", crate::dbus::NAME);
+                Some(tokio::task::spawn(crate::dbus::signal_events(
+                    connection,
+                    event_tx.subscribe(),
+                    cancel.clone(),
+                )))
+            }
+            None => None,
+        };
+
+        let states = state.clone();
+        let cmd_loop = match standby_of {
+            #[cfg(feature = "client")]
+            Some(primary) => {
+                info!("Standing by for {}", primary);
+                let shared = state.clone();
+                let (local_events, schedules) = (events.clone(), self.schedules);
+                let (thresholds, undo_window) = (self.thresholds, self.undo_window);
+                let start_local = move |cmd_rx: mpsc::Receiver<Request>, cancel| {
+                    tokio::task::spawn(cmd_loop(
+                        cmd_rx,
+                        sources.clone(),
+                        local_events.clone(),
+                        state.clone(),
+                        true,
+                        Scheduler::new(schedules.clone()),
+                        thresholds.clone(),
+                        undo_window,
+                        cancel,
+                    ))
+                };
+                tokio::task::spawn(crate::standby::run(
+                    primary,
+                    cmd_rx,
+                    shared,
+                    events.clone(),
+                    start_local,
+                    cancel.clone(),
+                ))
+            }
+            _ => tokio::task::spawn(cmd_loop(
+                cmd_rx,
+                sources,
+                events.clone(),
+                state,
+                self.restore_state,
+                Scheduler::new(self.schedules),
+                self.thresholds,
+                self.undo_window,
+                cancel.clone(),
+            )),
+        };
+
+        #[cfg(feature = "hotkeys")]
+        let hotkeys = pad.map(|pad| {
+            info!("Handling hotkeys");
+            tokio::task::spawn(crate::hotkeys::run(
+                pad,
+                cmd_tx.clone(),
+                event_tx.subscribe(),
+                cancel.clone(),
+            ))
+        });
+
+        let web = self.dashboard.map(|dashboard| {
+            Rc::new(crate::web::Web::new(
+                dashboard,
+                cmd_tx.clone(),
+                event_tx.clone(),
+            ))
+        });
+        let control_extron = ControlExtronImpl {
+            tx_channel: cmd_tx.clone(),
+            cancel: cancel.clone(),
+            session: std::rc::Rc::new(Session::new(
+                net::SocketAddr::from(([0, 0, 0, 0], 0)),
+                Grant::all(),
+            )),
+            events: event_tx,
+            connections: Default::default(),
+            states,
+            local_admin,
+            admin_allowed: true,
+            tokens,
+            #[cfg(feature = "tls")]
+            tls: self.tls.map(Rc::new),
+        };
+
+        let local = tokio::task::LocalSet::new();
+        // The watchdog runs next to the connections, so that systemd restarts a server that
+        // stopped serving them.
+        #[cfg(unix)]
+        {
+            notify_systemd("READY=1");
+            if let Some(interval) = crate::systemd::watchdog_interval() {
+                local.spawn_local(crate::systemd::watchdog(interval, cancel.clone()));
+            }
+        }
+        let accept = futures::future::try_join_all(listeners.into_iter().map(|listener| {
+            accept_loop(
+                listener,
+                control_extron.clone(),
+                authorize.clone(),
+                events.clone(),
+                cancel.clone(),
+            )
+        }));
+        #[cfg(unix)]
+        let accept = futures::future::try_join(
+            accept,
+            futures::future::try_join_all(
+                admin_listener
+                    .into_iter()
+                    .map(|listener| admin_loop(listener, control_extron.clone(), cancel.clone())),
+            ),
+        );
+        let accept = futures::future::try_join(
+            accept,
+            futures::future::try_join_all(metrics_listeners.into_iter().map(|listener| {
+                http_loop(
+                    listener,
+                    metrics.clone(),
+                    web.clone(),
+                    authorize.clone(),
+                    cancel.clone(),
+                )
+            })),
+        );
+        #[cfg(feature = "grpc")]
+        let accept = futures::future::try_join(
+            accept,
+            futures::future::try_join_all(grpc_listeners.into_iter().map(|listener| {
+                grpc_loop(
+                    listener,
+                    cmd_tx.clone(),
+                    local_admin,
+                    authorize.clone(),
+                    events.clone(),
+                    cancel.clone(),
+                )
+            })),
+        );
+        let result = local.run_until(accept).await;
+
+        // Also stop the other loops when one of the listeners failed.
+        cancel.cancel();
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        #[cfg(unix)]
+        notify_systemd("STOPPING=1");
+        match cmd_loop.await {
+            Ok(Err(e)) => info!("Command loop failed: {}", e),
+            Err(e) => info!("Command loop failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = dbus {
+            match dbus.await {
+                Ok(Err(e)) => info!("D-Bus service failed: {}", e),
+                Err(e) => info!("D-Bus service failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+        #[cfg(feature = "hotkeys")]
+        if let Some(hotkeys) = hotkeys {
+            if let Err(e) = hotkeys.await {
+                info!("Hotkeys failed: {}", e);
+            }
+        }
+        result.map(|_| ())
+    }
+}
+
+/// Binds the admin socket at `path`, replacing one a server left behind, and makes it
+/// accessible to the user running the server only.
+#[cfg(unix)]
+fn bind_admin_socket(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Tells systemd `state`, if it started the server. Failing costs only systemd's view of it.
+#[cfg(unix)]
+fn notify_systemd(state: &str) {
+    if let Err(e) = crate::systemd::notify(state) {
+        warn!("Cannot notify systemd: {}", e);
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn do_daemon<A: net::ToSocketAddrs>(addr: &A) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+
+    let addr = addr.to_socket_addrs()?.next().ok_or(Error::new(
+        ErrorKind::AddrNotAvailable,
+        "No address to listen on",
+    ))?;
+    run_until_signal(ServerBuilder::new().listen(addr))
+}
+
+/// Runs the server set up by `builder` on a runtime of its own, until a client stops it or the
+/// process gets a termination signal.
+pub fn run_until_signal(builder: ServerBuilder) -> std::io::Result<()> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let cancel = CancellationToken::new();
+        let signal_cancel = cancel.clone();
+        tokio::spawn(async move {
+            match shutdown_signal().await {
+                Ok(()) => info!("Stopping on signal"),
+                Err(e) => info!("Cannot wait for signals: {}", e),
+            }
+            signal_cancel.cancel();
+        });
+        builder.cancel_token(cancel).serve().await
+    })?;
+    info!("Server halted");
+    Ok(())
+}
+
+/// Waits for SIGINT or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Waits for Ctrl-C, or Ctrl-Break on Windows.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
 }
@@ -0,0 +1,121 @@
+//! systemd units running the server. The server stays in the foreground under systemd, which
+//! takes care of the user, the group and the log that daemonizing would otherwise set up.
+
+use crate::cli::InstallServiceArgs;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+
+/// A unit file, by its name.
+struct Unit {
+    name: String,
+    text: String,
+}
+
+/// `arg` as one word of a command line in a unit, where `%` starts a specifier and `$` a
+/// variable.
+fn quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/-_.,:=@+*?[]".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg
+    } else {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// The service unit running the server as set up by `args`, and with
+/// [`InstallServiceArgs::socket`] the socket unit starting it.
+fn units(args: &InstallServiceArgs) -> Result<Vec<Unit>> {
+    let exe = std::env::current_exe().context("Cannot find the path of this program")?;
+    let base = match &args.instance {
+        Some(instance) => format!("control-dsc-{}", instance),
+        None => "control-dsc".to_string(),
+    };
+    let description = match &args.instance {
+        Some(instance) => format!("Extron device control server {}", instance),
+        None => "Extron device control server".to_string(),
+    };
+
+    let mut command = vec![exe.to_string_lossy().to_string(), "server".to_string()];
+    // A socket activated server listens on the socket systemd hands it instead.
+    if !args.socket {
+        command.push(args.address.to_string());
+    }
+    command.push("--no-daemonize".to_string());
+    if let Some(instance) = &args.instance {
+        command.push("--instance".to_string());
+        command.push(instance.clone());
+    }
+    command.extend(args.server_args.iter().cloned());
+    let command: Vec<String> = command.iter().map(|arg| quote(arg)).collect();
+
+    let mut service = String::new();
+    writeln!(service, "[Unit]")?;
+    writeln!(service, "Description={}", description)?;
+    if args.socket {
+        writeln!(service, "Requires={}.socket", base)?;
+        writeln!(service, "After=network.target {}.socket", base)?;
+    } else {
+        writeln!(service, "After=network.target")?;
+    }
+    writeln!(service)?;
+    writeln!(service, "[Service]")?;
+    writeln!(service, "Type=notify")?;
+    writeln!(service, "ExecStart={}", command.join(" "))?;
+    writeln!(service, "User={}", args.user)?;
+    writeln!(service, "Group={}", args.group)?;
+    if let Some(watchdog) = args.watchdog {
+        writeln!(service, "WatchdogSec={}ms", watchdog.as_millis())?;
+    }
+    writeln!(service, "Restart=on-failure")?;
+    writeln!(service)?;
+    writeln!(service, "[Install]")?;
+    writeln!(service, "WantedBy=multi-user.target")?;
+
+    let mut units = vec![Unit {
+        name: format!("{}.service", base),
+        text: service,
+    }];
+    if args.socket {
+        let mut socket = String::new();
+        writeln!(socket, "[Unit]")?;
+        writeln!(socket, "Description={} socket", description)?;
+        writeln!(socket)?;
+        writeln!(socket, "[Socket]")?;
+        writeln!(socket, "ListenStream={}", args.address)?;
+        writeln!(socket)?;
+        writeln!(socket, "[Install]")?;
+        writeln!(socket, "WantedBy=sockets.target")?;
+        units.push(Unit {
+            name: format!("{}.socket", base),
+            text: socket,
+        });
+    }
+    Ok(units)
+}
+
+/// Writes the units for `args`, or prints them with [`InstallServiceArgs::print`].
+pub fn install(args: &InstallServiceArgs) -> Result<()> {
+    let units = units(args)?;
+    if args.print {
+        for (i, unit) in units.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("# {}", args.dir.join(&unit.name).display());
+            print!("{}", unit.text);
+        }
+        return Ok(());
+    }
+    for unit in &units {
+        let path = args.dir.join(&unit.name);
+        std::fs::write(&path, &unit.text)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    println!(
+        "Start it with: systemctl daemon-reload && systemctl enable --now {}",
+        units.last().map_or("", |unit| unit.name.as_str())
+    );
+    Ok(())
+}
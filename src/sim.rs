@@ -0,0 +1,752 @@
+//! Simulated devices speaking SIS, for tests and for trying the server without hardware.
+//!
+//! [`parse_devices`] reads a set of them from a TOML file, for `server --simulate`.
+
+use crate::error::Result;
+use crate::extron::{ExtronDevice, Port};
+use crate::sis::{Command, Plane};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Part number simulated devices answer with.
+pub const PART_NUMBER: &str = "60-1238-01";
+
+/// Failure a [`SimDevice`] can be told to show.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fault {
+    /// The device ignores commands, so every read times out.
+    Timeout,
+    /// The device is unplugged, so its port cannot be opened or used.
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct State {
+    name: String,
+    inputs: Vec<String>,
+    /// Input selected for video.
+    input: u32,
+    audio_input: u32,
+    /// Whether each input has a signal.
+    signal: Vec<bool>,
+    /// `None` for devices without audio output.
+    volume: Option<u8>,
+    mute: Option<bool>,
+    /// `None` for devices without HDMI CEC, otherwise whether the display is on.
+    display: Option<bool>,
+    /// `None` for devices without a display-control port, otherwise what the display received
+    /// through it.
+    display_port: Option<Vec<u8>>,
+    /// What the display answers to each insertion.
+    display_answer: Vec<u8>,
+    /// Windows of each video wall preset, numbered from 1, or `None` for devices without.
+    presets: Option<Vec<u32>>,
+    /// Preset recalled last, 0 for none. Video wall and window layout presets are the same
+    /// here.
+    preset: u32,
+    /// Input shown in each window.
+    windows: Vec<u32>,
+    /// `None` for devices without picture-in-picture, otherwise the mode, 0 being off.
+    pip: Option<u32>,
+    /// `None` for devices without output sync settings, otherwise the sync format and what
+    /// the output is locked to.
+    sync: Option<(u32, u32)>,
+    /// `None` for devices other than Annotators.
+    annotator: Option<Annotator>,
+    firmware: String,
+    part_number: String,
+    /// Internal temperature in degrees Celsius.
+    temperature: u32,
+    fault: Option<Fault>,
+    /// How long the device takes to answer.
+    latency: Duration,
+    /// Ignores every command whose count is a multiple of this.
+    fail_every: Option<u32>,
+    /// Commands received so far.
+    commands: u32,
+    /// Lines sent on the device's own account that nobody read yet.
+    unsolicited: Vec<u8>,
+}
+
+/// What an Annotator shows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotator {
+    /// Annotation tool selected, 1 being the pointer.
+    pub tool: u32,
+    pub frozen: bool,
+    /// How often the annotations were cleared.
+    pub clears: u32,
+}
+
+impl Annotator {
+    /// Reply to one of the annotation commands.
+    fn execute(&mut self, command: Command) -> String {
+        match command {
+            Command::SelectTool(n) => {
+                self.tool = n;
+                format!("Atol{}", n)
+            }
+            Command::ClearAnnotations => {
+                self.clears += 1;
+                "Aclr".to_string()
+            }
+            Command::FreezeAnnotation(on) => {
+                self.frozen = on;
+                format!("Afrz{}", on as u8)
+            }
+            _ => format!("Atol{}", self.tool),
+        }
+    }
+}
+
+impl State {
+    /// Recalls preset `n`, filling its windows with the inputs in turn, and returns the number
+    /// of windows, or the error code for the device.
+    fn recall(&mut self, n: u32) -> std::result::Result<usize, &'static str> {
+        let presets = self.presets.as_ref().ok_or("E10")?;
+        let windows = *n
+            .checked_sub(1)
+            .and_then(|i| presets.get(i as usize))
+            .ok_or("E11")?;
+        let inputs = self.inputs.len().max(1) as u32;
+        self.preset = n;
+        self.windows = (0..windows).map(|w| w % inputs + 1).collect();
+        Ok(self.windows.len())
+    }
+
+    /// Reply to one command, without the line ending.
+    fn execute(&mut self, command: Option<Command>) -> String {
+        let command = match command {
+            Some(command) => command,
+            None => return "E10".to_string(),
+        };
+        match (command, self.volume, self.mute) {
+            (Command::Name, _, _) => self.name.clone(),
+            (Command::InputName(n), _, _) => {
+                match n.checked_sub(1).and_then(|i| self.inputs.get(i as usize)) {
+                    Some(name) => name.clone(),
+                    None => "E01".to_string(),
+                }
+            }
+            (Command::QueryInput, _, _) => self.input.to_string(),
+            (Command::Select(n, plane), _, _) if n >= 1 && n as usize <= self.inputs.len() => {
+                if plane != Plane::Audio {
+                    self.input = n;
+                }
+                if plane != Plane::Video {
+                    self.audio_input = n;
+                }
+                let tail = match plane {
+                    Plane::All => "All",
+                    Plane::Video => "Vid",
+                    Plane::Audio => "Aud",
+                };
+                format!("In{}{}", n, tail)
+            }
+            (Command::Select(..), _, _) => "E01".to_string(),
+            (Command::QueryVolume, Some(volume), _) => volume.to_string(),
+            (Command::SetVolume(level), Some(_), _) if level <= 100 => {
+                self.volume = Some(level);
+                format!("Vol{}", level)
+            }
+            (Command::SetVolume(_), Some(_), _) => "E13".to_string(),
+            (Command::QueryMute, _, Some(mute)) => (mute as u8).to_string(),
+            (Command::SetMute(mute), _, Some(_)) => {
+                self.mute = Some(mute);
+                format!("Amt{}", mute as u8)
+            }
+            (Command::FirmwareVersion, _, _) => self.firmware.clone(),
+            (Command::PartNumber, _, _) => self.part_number.clone(),
+            (Command::UploadFirmware(size), _, _) => format!("Upl{}", size),
+            (Command::QueryDisplayPower, _, _) => match self.display {
+                Some(on) => format!("Dcec{}", on as u8),
+                None => "E10".to_string(),
+            },
+            (Command::SetDisplayPower(on), _, _) if self.display.is_some() => {
+                self.display = Some(on);
+                format!("Dcec{}", on as u8)
+            }
+            (Command::QueryLayout, _, _) if self.presets.is_some() => {
+                format!("Lyt{}*{}", self.preset, self.windows.len())
+            }
+            (Command::RecallPreset(n), _, _) => match self.recall(n) {
+                Ok(_) => format!("Rpr{}", n),
+                Err(code) => code.to_string(),
+            },
+            (Command::RecallLayout(n), _, _) => match self.recall(n) {
+                Ok(windows) => format!("Lyt{}*{}", n, windows),
+                Err(code) => code.to_string(),
+            },
+            (Command::QueryWindow(n), _, _) if self.presets.is_some() || self.pip.is_some() => {
+                match n.checked_sub(1).and_then(|i| self.windows.get(i as usize)) {
+                    Some(input) => format!("Win{}*{}", n, input),
+                    None => "E11".to_string(),
+                }
+            }
+            (Command::AssignWindow { window, input }, _, _)
+                if self.presets.is_some() || self.pip.is_some() =>
+            {
+                if input < 1 || input as usize > self.inputs.len() {
+                    return "E01".to_string();
+                }
+                match window
+                    .checked_sub(1)
+                    .and_then(|i| self.windows.get_mut(i as usize))
+                {
+                    Some(shown) => {
+                        *shown = input;
+                        format!("Win{}*{}", window, input)
+                    }
+                    None => "E11".to_string(),
+                }
+            }
+            (Command::QueryPipMode, _, _) => match self.pip {
+                Some(mode) => format!("Pip{}", mode),
+                None => "E10".to_string(),
+            },
+            (Command::SetPipMode(mode), _, _) if self.pip.is_some() && mode <= 2 => {
+                self.pip = Some(mode);
+                self.windows.resize([1, 2, 4][mode as usize], 1);
+                format!("Pip{}", mode)
+            }
+            (Command::QuerySyncFormat, _, _)
+            | (Command::QueryGenlock, _, _)
+            | (Command::SetSyncFormat(_), _, _)
+            | (Command::SetGenlock(_), _, _) => match &mut self.sync {
+                Some(sync) => execute_sync(sync, command),
+                None => "E10".to_string(),
+            },
+            (Command::QueryTool, _, _)
+            | (Command::SelectTool(_), _, _)
+            | (Command::ClearAnnotations, _, _)
+            | (Command::FreezeAnnotation(_), _, _) => match &mut self.annotator {
+                Some(annotator) => annotator.execute(command),
+                None => "E10".to_string(),
+            },
+            (Command::InsertSerial(size), _, _) if self.display_port.is_some() => {
+                format!("Rs{}", size)
+            }
+            (Command::Temperature, _, _) => format!("{:05}", self.temperature),
+            (Command::SignalPresence, _, _) => {
+                let flags: String = self
+                    .signal
+                    .iter()
+                    .map(|&s| if s { '1' } else { '0' })
+                    .collect();
+                format!("Frq00 {}", flags)
+            }
+            _ => "E10".to_string(),
+        }
+    }
+}
+
+/// Reply to a sync command on a device with output sync settings. It has no reference input,
+/// so it only locks to the selected input.
+fn execute_sync(sync: &mut (u32, u32), command: Command) -> String {
+    match command {
+        Command::QuerySyncFormat => format!("Osyn{}", sync.0),
+        Command::QueryGenlock => format!("Glck{}", sync.1),
+        Command::SetSyncFormat(format) if format <= 3 => {
+            sync.0 = format;
+            format!("Osyn{}", format)
+        }
+        Command::SetGenlock(reference) if reference <= 1 => {
+            sync.1 = reference;
+            format!("Glck{}", reference)
+        }
+        _ => "E13".to_string(),
+    }
+}
+
+/// A device that keeps its state in memory. Clones share the state, so a test can keep one
+/// to inspect the device or inject faults while the server uses [`SimDevice::device`].
+#[derive(Debug, Clone)]
+pub struct SimDevice {
+    state: Arc<Mutex<State>>,
+}
+
+impl SimDevice {
+    /// A device without audio output, with the given input names numbered from 1 and input 1
+    /// selected.
+    pub fn new(name: &str, inputs: &[&str]) -> Self {
+        SimDevice {
+            state: Arc::new(Mutex::new(State {
+                name: name.to_string(),
+                inputs: inputs.iter().map(|i| i.to_string()).collect(),
+                input: 1,
+                audio_input: 1,
+                signal: vec![true; inputs.len()],
+                volume: None,
+                mute: None,
+                display: None,
+                display_port: None,
+                display_answer: Vec::new(),
+                presets: None,
+                preset: 0,
+                windows: vec![1],
+                pip: None,
+                sync: None,
+                annotator: None,
+                firmware: "1.00".to_string(),
+                part_number: PART_NUMBER.to_string(),
+                temperature: 40,
+                fault: None,
+                latency: Duration::from_millis(0),
+                fail_every: None,
+                commands: 0,
+                unsolicited: Vec::new(),
+            })),
+        }
+    }
+
+    /// Adds an audio output at volume 50, not muted.
+    pub fn with_audio(self) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.volume = Some(50);
+            state.mute = Some(false);
+        }
+        self
+    }
+
+    /// Adds HDMI CEC, with the display off.
+    pub fn with_cec(self) -> Self {
+        self.state.lock().unwrap().display = Some(false);
+        self
+    }
+
+    /// Adds a display-control port, with a display behind it that answers everything it
+    /// receives with `answer`.
+    pub fn with_display_port(self, answer: &str) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.display_port = Some(Vec::new());
+            state.display_answer = answer.as_bytes().to_vec();
+        }
+        self
+    }
+
+    /// Makes the device a multi-window processor with a preset for each entry of `windows`,
+    /// showing that many windows, and none recalled. Recalling a preset fills its windows
+    /// with the inputs in turn.
+    pub fn with_presets(self, windows: &[u32]) -> Self {
+        self.state.lock().unwrap().presets = Some(windows.to_vec());
+        self
+    }
+
+    /// Adds picture-in-picture and quad view, switched off.
+    pub fn with_pip(self) -> Self {
+        self.state.lock().unwrap().pip = Some(0);
+        self
+    }
+
+    /// Adds output sync settings, with separate H and V sync running free. There is no
+    /// reference input to lock to.
+    pub fn with_sync(self) -> Self {
+        self.state.lock().unwrap().sync = Some((0, 0));
+        self
+    }
+
+    /// Makes the device an Annotator, with the pen selected and nothing frozen.
+    pub fn with_annotator(self) -> Self {
+        self.state.lock().unwrap().annotator = Some(Annotator {
+            tool: 2,
+            ..Annotator::default()
+        });
+        self
+    }
+
+    /// Makes the device answer with `part_number`, to stand in for another model than
+    /// [`PART_NUMBER`].
+    pub fn with_part_number(self, part_number: &str) -> Self {
+        self.state.lock().unwrap().part_number = part_number.to_string();
+        self
+    }
+
+    /// Makes the device take `latency` to answer every command. Reads waiting less than that
+    /// time out, as they would on a slow device.
+    pub fn with_latency(self, latency: Duration) -> Self {
+        self.state.lock().unwrap().latency = latency;
+        self
+    }
+
+    /// Makes the device ignore every `every`th command it receives, so that its answer times
+    /// out, as on a flaky cable. Ignores nothing with 0.
+    pub fn with_failures(self, every: u32) -> Self {
+        self.state.lock().unwrap().fail_every = Some(every).filter(|&every| every > 0);
+        self
+    }
+
+    pub fn set_fault(&self, fault: Option<Fault>) {
+        self.state.lock().unwrap().fault = fault;
+    }
+
+    pub fn input(&self) -> u32 {
+        self.state.lock().unwrap().input
+    }
+
+    /// Input selected for audio, which differs from [`SimDevice::input`] after switching the
+    /// planes apart.
+    pub fn audio_input(&self) -> u32 {
+        self.state.lock().unwrap().audio_input
+    }
+
+    pub fn volume(&self) -> Option<u8> {
+        self.state.lock().unwrap().volume
+    }
+
+    pub fn mute(&self) -> Option<bool> {
+        self.state.lock().unwrap().mute
+    }
+
+    /// Whether the display is on, or `None` for devices without HDMI CEC.
+    pub fn display(&self) -> Option<bool> {
+        self.state.lock().unwrap().display
+    }
+
+    /// Video wall preset recalled last, 0 for none.
+    pub fn preset(&self) -> u32 {
+        self.state.lock().unwrap().preset
+    }
+
+    /// Input shown in each window, from window 1.
+    pub fn windows(&self) -> Vec<u32> {
+        self.state.lock().unwrap().windows.clone()
+    }
+
+    /// Picture-in-picture mode, 0 being off, or `None` for devices without.
+    pub fn pip(&self) -> Option<u32> {
+        self.state.lock().unwrap().pip
+    }
+
+    /// Sync format and what the output is locked to, as numbered in SIS, or `None` for
+    /// devices without output sync settings.
+    pub fn sync(&self) -> Option<(u32, u32)> {
+        self.state.lock().unwrap().sync
+    }
+
+    /// What the Annotator shows, or `None` for other devices.
+    pub fn annotator(&self) -> Option<Annotator> {
+        self.state.lock().unwrap().annotator.clone()
+    }
+
+    /// What the display received through the display-control port, or `None` for devices
+    /// without one.
+    pub fn display_received(&self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().display_port.clone()
+    }
+
+    /// Firmware version, 1.00 until an image is uploaded. The device takes the first line of
+    /// an uploaded image as its new version.
+    pub fn firmware(&self) -> String {
+        self.state.lock().unwrap().firmware.clone()
+    }
+
+    /// Connects or disconnects the source on `input`, counting from 1. All inputs have a
+    /// signal to begin with.
+    pub fn set_signal(&self, input: u32, present: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(signal) = input
+            .checked_sub(1)
+            .and_then(|i| state.signal.get_mut(i as usize))
+        {
+            *signal = present;
+        }
+    }
+
+    /// Internal temperature in degrees Celsius, 40 unless set otherwise.
+    pub fn set_temperature(&self, celsius: u32) {
+        self.state.lock().unwrap().temperature = celsius;
+    }
+
+    /// Has the device send `line` on its own, like a real one does on a front panel change.
+    pub fn announce(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.unsolicited.extend(line.bytes());
+        state.unsolicited.extend(b"\r\n");
+    }
+
+    /// The device as seen by the rest of the crate.
+    pub fn device(&self) -> ExtronDevice {
+        let state = self.state.clone();
+        let name = state.lock().unwrap().name.clone();
+        ExtronDevice::with_port(&name, &format!("sim:{}", name), move || {
+            if state.lock().unwrap().fault == Some(Fault::Disconnect) {
+                return Err(disconnected().into());
+            }
+            Ok(Box::new(SimPort {
+                state: state.clone(),
+                command: Vec::new(),
+                replies: VecDeque::new(),
+                ready: Instant::now(),
+                timeout: None,
+                upload: None,
+                insertion: None,
+            }))
+        })
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "Simulated device unplugged")
+}
+
+struct SimPort {
+    state: Arc<Mutex<State>>,
+    /// Start of a command that has not been terminated yet.
+    command: Vec<u8>,
+    replies: VecDeque<u8>,
+    /// When the replies can be read, after the latency of the device.
+    ready: Instant,
+    timeout: Option<Duration>,
+    /// Size of the firmware image being uploaded and the part of it received so far.
+    upload: Option<(usize, Vec<u8>)>,
+    /// Number of bytes still to pass on to the display.
+    insertion: Option<usize>,
+}
+
+impl SimPort {
+    fn reply(&mut self, reply: &str) {
+        self.replies.extend(reply.bytes());
+        self.replies.extend(b"\r\n");
+        self.ready = Instant::now() + self.state.lock().unwrap().latency;
+    }
+
+    fn execute_complete(&mut self) {
+        loop {
+            if let Some((size, image)) = &mut self.upload {
+                let n = std::cmp::min(*size - image.len(), self.command.len());
+                image.extend(self.command.drain(..n));
+                if image.len() < *size {
+                    return;
+                }
+                let version = image.split(|&b| b == b'\n').next().unwrap_or_default();
+                let version = crate::sis::text(version);
+                let size = *size;
+                self.upload = None;
+                self.state.lock().unwrap().firmware = version;
+                self.reply(&format!("Upl{}", size));
+                continue;
+            }
+            if let Some(left) = &mut self.insertion {
+                let n = std::cmp::min(*left, self.command.len());
+                let mut state = self.state.lock().unwrap();
+                if let Some(received) = &mut state.display_port {
+                    received.extend(self.command.drain(..n));
+                }
+                *left -= n;
+                if *left > 0 {
+                    return;
+                }
+                self.insertion = None;
+                self.replies.extend(state.display_answer.iter());
+                continue;
+            }
+            let (command, len) = match Command::parse(&self.command) {
+                Some(parsed) => parsed,
+                None => return,
+            };
+            self.command.drain(..len);
+            {
+                let mut state = self.state.lock().unwrap();
+                state.commands += 1;
+                if let Some(every) = state.fail_every {
+                    if state.commands % every == 0 {
+                        continue;
+                    }
+                }
+            }
+            let reply = self.state.lock().unwrap().execute(command);
+            self.reply(&reply);
+            match command {
+                Some(Command::UploadFirmware(size)) => {
+                    self.upload = Some((size as usize, Vec::new()))
+                }
+                Some(Command::InsertSerial(size)) if reply.starts_with("Rs") => {
+                    self.insertion = Some(size as usize)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Write for SimPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.state.lock().unwrap().fault {
+            Some(Fault::Disconnect) => return Err(disconnected()),
+            Some(Fault::Timeout) => return Ok(buf.len()),
+            None => {}
+        }
+        self.command.extend_from_slice(buf);
+        self.execute_complete();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads return end of file once all replies have been read.
+impl Read for SimPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.state.lock().unwrap().fault {
+            Some(Fault::Disconnect) => return Err(disconnected()),
+            Some(Fault::Timeout) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Operation timed out",
+                ))
+            }
+            None => {}
+        }
+        if !self.replies.is_empty() {
+            let wait = self.ready.saturating_duration_since(Instant::now());
+            match self.timeout {
+                Some(timeout) if wait > timeout => {
+                    std::thread::sleep(timeout);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Operation timed out",
+                    ));
+                }
+                _ => std::thread::sleep(wait),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.replies.len());
+        for (to, from) in buf.iter_mut().zip(self.replies.drain(..n)) {
+            *to = from;
+        }
+        Ok(n)
+    }
+}
+
+impl Port for SimPort {
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.replies.clear();
+        Ok(())
+    }
+
+    fn take_pending(&mut self) -> io::Result<Vec<u8>> {
+        Ok(std::mem::take(&mut self.state.lock().unwrap().unsolicited))
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+/// `devices` as a list, for [`crate::server::ServerBuilder::device_source`]. Devices showing
+/// [`Fault::Disconnect`] are left out, as a scan would not find them.
+pub fn device_list(devices: &[SimDevice]) -> Result<crate::extron::ExtronDeviceList> {
+    let mut list = crate::extron::ExtronDeviceList::new();
+    for device in devices {
+        if device.state.lock().unwrap().fault != Some(Fault::Disconnect) {
+            list.insert(device.device());
+        }
+    }
+    Ok(list)
+}
+
+/// A device in a simulation file, see [`parse_devices`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceSpec {
+    name: String,
+    inputs: Vec<String>,
+    part_number: Option<String>,
+    #[serde(default)]
+    audio: bool,
+    #[serde(default)]
+    cec: bool,
+    /// What the display behind the display-control port answers.
+    display_port: Option<String>,
+    presets: Option<Vec<u32>>,
+    #[serde(default)]
+    pip: bool,
+    #[serde(default)]
+    sync: bool,
+    #[serde(default)]
+    annotator: bool,
+    /// In milliseconds.
+    #[serde(default)]
+    latency: u64,
+    #[serde(default)]
+    fail_every: u32,
+    fault: Option<Fault>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Simulation {
+    #[serde(default, rename = "device")]
+    devices: Vec<DeviceSpec>,
+}
+
+/// The devices of a simulation file, e.g.
+///
+/// ```toml
+/// [[device]]
+/// name = "DSC hall"
+/// inputs = ["HDMI", "VGA", "DisplayPort"]
+/// part_number = "60-1238-01"
+/// audio = true
+/// cec = true
+/// # Milliseconds to answer each command.
+/// latency = 40
+/// # Ignore every tenth command.
+/// fail_every = 10
+///
+/// [[device]]
+/// name = "Quantum wall"
+/// inputs = ["PC 1", "PC 2", "Camera"]
+/// presets = [1, 2, 4]
+/// pip = true
+/// # Unplugged until the server is restarted, or "timeout" for one that never answers.
+/// fault = "disconnect"
+/// ```
+///
+/// Besides those, devices can have `display_port` with what the display behind it answers,
+/// `sync` and `annotator`, see the `with_` methods of [`SimDevice`].
+pub fn parse_devices(text: &str) -> std::result::Result<Vec<SimDevice>, toml::de::Error> {
+    let simulation: Simulation = toml::from_str(text)?;
+    let devices = simulation.devices.into_iter().map(|spec| {
+        let inputs: Vec<&str> = spec.inputs.iter().map(String::as_str).collect();
+        let mut device = SimDevice::new(&spec.name, &inputs)
+            .with_latency(Duration::from_millis(spec.latency))
+            .with_failures(spec.fail_every);
+        if let Some(part_number) = &spec.part_number {
+            device = device.with_part_number(part_number);
+        }
+        if spec.audio {
+            device = device.with_audio();
+        }
+        if spec.cec {
+            device = device.with_cec();
+        }
+        if let Some(answer) = &spec.display_port {
+            device = device.with_display_port(answer);
+        }
+        if let Some(presets) = &spec.presets {
+            device = device.with_presets(presets);
+        }
+        if spec.pip {
+            device = device.with_pip();
+        }
+        if spec.sync {
+            device = device.with_sync();
+        }
+        if spec.annotator {
+            device = device.with_annotator();
+        }
+        device.set_fault(spec.fault);
+        device
+    });
+    Ok(devices.collect())
+}
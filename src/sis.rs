@@ -0,0 +1,421 @@
+//! SIS commands and replies. Parsing works on raw bytes and never panics, whatever the other
+//! side sends.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// Signals an input is switched for, on devices that can switch audio apart from video, like
+/// the larger DTP and XTP matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plane {
+    /// Video and audio together.
+    All,
+    Video,
+    Audio,
+}
+
+impl Plane {
+    /// The command character selecting an input on this plane.
+    fn suffix(&self) -> char {
+        match self {
+            Plane::All => '!',
+            Plane::Video => '%',
+            Plane::Audio => '$',
+        }
+    }
+}
+
+/// A command sent to a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// `Esc CN CR`, the name of the device.
+    Name,
+    /// `Esc <n>NI CR`, the name of an input.
+    InputName(u32),
+    /// `<n>!`, `<n>%` or `<n>$`, switching input `n` for all signals, video only or audio
+    /// only. Older devices take `<n>&` for video only.
+    Select(u32, Plane),
+    /// `!`
+    QueryInput,
+    /// `<n>V`
+    SetVolume(u8),
+    /// `V`
+    QueryVolume,
+    /// `1Z` or `0Z`
+    SetMute(bool),
+    /// `Z`
+    QueryMute,
+    /// `Q`, the firmware version.
+    FirmwareVersion,
+    /// `N`, the part number, which tells the model.
+    PartNumber,
+    /// `Esc <n>UF CR`, announcing a firmware image of `n` bytes. The device answers `Upl<n>`,
+    /// reads the image raw, answers `Upl<n>` again once it is written and restarts.
+    UploadFirmware(u32),
+    /// `Esc 20STAT CR`, the internal temperature in degrees Celsius.
+    Temperature,
+    /// `0LS`, whether the inputs have a signal.
+    SignalPresence,
+    /// `Esc 1DCEC CR` or `Esc 0DCEC CR`, the display on the output powered on or off over
+    /// HDMI CEC.
+    SetDisplayPower(bool),
+    /// `Esc DCEC CR`, the display power last set over CEC. Devices without CEC answer with
+    /// an error code.
+    QueryDisplayPower,
+    /// `Esc <n>RS CR`, announcing `n` bytes for the display-control port. The device answers
+    /// `Rs<n>`, passes the bytes that follow on to the display as they are and relays what
+    /// the display answers.
+    InsertSerial(u32),
+    /// `<n>.`, recalling a video wall preset on multi-window processors.
+    RecallPreset(u32),
+    /// `Esc LYT CR`, the preset recalled last and the number of windows it shows. Devices
+    /// without video wall presets answer with an error code.
+    QueryLayout,
+    /// `Esc <n>LYT CR`, recalling window layout preset `n` on multi-window processors. The
+    /// device answers like [`Command::QueryLayout`].
+    RecallLayout(u32),
+    /// `Esc <n>WIN CR`, the input shown in window `n`.
+    QueryWindow(u32),
+    /// `Esc <n>*<input>WIN CR`, showing `input` in window `n`. The device answers like
+    /// [`Command::QueryWindow`].
+    AssignWindow { window: u32, input: u32 },
+    /// `Esc <m>PIP CR`, showing one source (0), a second one in a window (1) or four (2).
+    SetPipMode(u32),
+    /// `Esc PIP CR`, the picture-in-picture mode. Devices without answer with an error code.
+    QueryPipMode,
+    /// `Esc <f>OSYN CR`, setting the sync format of the output: separate H and V (0),
+    /// composite (1), sync on green (2) or tri-level (3).
+    SetSyncFormat(u32),
+    /// `Esc OSYN CR`, the output sync format. Devices without answer with an error code.
+    QuerySyncFormat,
+    /// `Esc <r>GLCK CR`, locking the output to nothing (0), the selected input (1) or the
+    /// reference input (2).
+    SetGenlock(u32),
+    /// `Esc GLCK CR`, what the output is locked to.
+    QueryGenlock,
+    /// `Esc ACLR CR`, clearing the annotations drawn on an Annotator.
+    ClearAnnotations,
+    /// `Esc <n>ATOL CR`, selecting annotation tool `n`, 1 being the pointer.
+    SelectTool(u32),
+    /// `Esc ATOL CR`, the annotation tool selected. Devices other than Annotators answer with
+    /// an error code.
+    QueryTool,
+    /// `Esc 1AFRZ CR` or `Esc 0AFRZ CR`, freezing the annotated picture or letting it go.
+    FreezeAnnotation(bool),
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let text = match self {
+            Command::Name => "\x1bCN\r".to_string(),
+            Command::InputName(n) => format!("\x1b{}NI\r", n),
+            Command::Select(n, plane) => format!("{}{}", n, plane.suffix()),
+            Command::QueryInput => "!".to_string(),
+            Command::SetVolume(level) => format!("{}V", level),
+            Command::QueryVolume => "V".to_string(),
+            Command::SetMute(mute) => format!("{}Z", *mute as u8),
+            Command::QueryMute => "Z".to_string(),
+            Command::FirmwareVersion => "Q".to_string(),
+            Command::PartNumber => "N".to_string(),
+            Command::UploadFirmware(size) => format!("\x1b{}UF\r", size),
+            Command::Temperature => "\x1b20STAT\r".to_string(),
+            Command::SignalPresence => "0LS".to_string(),
+            Command::SetDisplayPower(on) => format!("\x1b{}DCEC\r", *on as u8),
+            Command::QueryDisplayPower => "\x1bDCEC\r".to_string(),
+            Command::InsertSerial(size) => format!("\x1b{}RS\r", size),
+            Command::RecallPreset(n) => format!("{}.", n),
+            Command::QueryLayout => "\x1bLYT\r".to_string(),
+            Command::RecallLayout(n) => format!("\x1b{}LYT\r", n),
+            Command::QueryWindow(n) => format!("\x1b{}WIN\r", n),
+            Command::AssignWindow { window, input } => format!("\x1b{}*{}WIN\r", window, input),
+            Command::SetPipMode(mode) => format!("\x1b{}PIP\r", mode),
+            Command::QueryPipMode => "\x1bPIP\r".to_string(),
+            Command::SetSyncFormat(format) => format!("\x1b{}OSYN\r", format),
+            Command::QuerySyncFormat => "\x1bOSYN\r".to_string(),
+            Command::SetGenlock(reference) => format!("\x1b{}GLCK\r", reference),
+            Command::QueryGenlock => "\x1bGLCK\r".to_string(),
+            Command::ClearAnnotations => "\x1bACLR\r".to_string(),
+            Command::SelectTool(n) => format!("\x1b{}ATOL\r", n),
+            Command::QueryTool => "\x1bATOL\r".to_string(),
+            Command::FreezeAnnotation(on) => format!("\x1b{}AFRZ\r", *on as u8),
+        };
+        text.into_bytes()
+    }
+
+    /// How long a device may take to answer the command. Switching and recalling presets
+    /// reconfigure the output before the device answers, so they take much longer than
+    /// queries.
+    pub fn deadline(&self) -> Duration {
+        let millis = match self {
+            Command::Select(..) => 500,
+            Command::RecallPreset(_) | Command::RecallLayout(_) => 2000,
+            Command::SetPipMode(_)
+            | Command::AssignWindow { .. }
+            | Command::SetSyncFormat(_)
+            | Command::SetGenlock(_) => 1000,
+            // Answered once the display acknowledged, over the slow CEC bus.
+            Command::SetDisplayPower(_) | Command::QueryDisplayPower => 1000,
+            Command::Name | Command::InputName(_) => 200,
+            _ => 100,
+        };
+        Duration::from_millis(millis)
+    }
+
+    /// Splits the first command off `buf`, the way a device reads it. Returns the command, or
+    /// `None` if it is not one, with the number of bytes it took. Returns `None` altogether
+    /// while the command is incomplete.
+    pub fn parse(buf: &[u8]) -> Option<(Option<Command>, usize)> {
+        if buf.first() == Some(&0x1b) {
+            let end = buf.iter().position(|&b| b == b'\r')?;
+            let body = &buf[1..end];
+            let command = if body == b"CN" {
+                Some(Command::Name)
+            } else if body == b"20STAT" {
+                Some(Command::Temperature)
+            } else if body == b"LYT" {
+                Some(Command::QueryLayout)
+            } else if let Some(layout) = body.strip_suffix(b"LYT") {
+                number(layout).map(Command::RecallLayout)
+            } else if let Some(window) = body.strip_suffix(b"WIN") {
+                match pair(window) {
+                    Some((window, input)) => Some(Command::AssignWindow { window, input }),
+                    None => number(window).map(Command::QueryWindow),
+                }
+            } else if body == b"PIP" {
+                Some(Command::QueryPipMode)
+            } else if let Some(mode) = body.strip_suffix(b"PIP") {
+                number(mode).map(Command::SetPipMode)
+            } else if body == b"OSYN" {
+                Some(Command::QuerySyncFormat)
+            } else if let Some(format) = body.strip_suffix(b"OSYN") {
+                number(format).map(Command::SetSyncFormat)
+            } else if body == b"GLCK" {
+                Some(Command::QueryGenlock)
+            } else if let Some(reference) = body.strip_suffix(b"GLCK") {
+                number(reference).map(Command::SetGenlock)
+            } else if body == b"ACLR" {
+                Some(Command::ClearAnnotations)
+            } else if body == b"ATOL" {
+                Some(Command::QueryTool)
+            } else if let Some(tool) = body.strip_suffix(b"ATOL") {
+                number(tool).map(Command::SelectTool)
+            } else if let Some(on) = body.strip_suffix(b"AFRZ") {
+                match on {
+                    b"0" => Some(Command::FreezeAnnotation(false)),
+                    b"1" => Some(Command::FreezeAnnotation(true)),
+                    _ => None,
+                }
+            } else if body == b"DCEC" {
+                Some(Command::QueryDisplayPower)
+            } else if let Some(on) = body.strip_suffix(b"DCEC") {
+                match on {
+                    b"0" => Some(Command::SetDisplayPower(false)),
+                    b"1" => Some(Command::SetDisplayPower(true)),
+                    _ => None,
+                }
+            } else if let Some(size) = body.strip_suffix(b"UF") {
+                number(size).map(Command::UploadFirmware)
+            } else if let Some(size) = body.strip_suffix(b"RS") {
+                number(size).map(Command::InsertSerial)
+            } else {
+                body.strip_suffix(b"NI")
+                    .and_then(number)
+                    .map(Command::InputName)
+            };
+            return Some((command, end + 1));
+        }
+
+        let end = buf.iter().position(|b| b"!%$&VZQNS.".contains(b))?;
+        let arg = &buf[..end];
+        let command = match (buf[end], arg.is_empty()) {
+            (b'!', true) => Some(Command::QueryInput),
+            (b'!', false) => number(arg).map(|n| Command::Select(n, Plane::All)),
+            (b'%', false) | (b'&', false) => number(arg).map(|n| Command::Select(n, Plane::Video)),
+            (b'$', false) => number(arg).map(|n| Command::Select(n, Plane::Audio)),
+            (b'%', true) | (b'&', true) | (b'$', true) => None,
+            (b'V', true) => Some(Command::QueryVolume),
+            (b'V', false) => number(arg)
+                .and_then(|n| u8::try_from(n).ok())
+                .map(Command::SetVolume),
+            (b'Z', true) => Some(Command::QueryMute),
+            (b'Q', true) => Some(Command::FirmwareVersion),
+            (b'Q', false) => None,
+            (b'N', true) => Some(Command::PartNumber),
+            (b'N', false) => None,
+            (b'S', _) if arg == b"0L" => Some(Command::SignalPresence),
+            (b'S', _) => None,
+            (b'.', _) => number(arg).map(Command::RecallPreset),
+            _ => match arg {
+                b"0" => Some(Command::SetMute(false)),
+                b"1" => Some(Command::SetMute(true)),
+                _ => None,
+            },
+        };
+        Some((command, end + 1))
+    }
+}
+
+/// One line sent by a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// `Enn`, an error code.
+    Error(u8),
+    /// `In<n>All`, `In<n>Vid`, `In<n>Aud` or `In<n>RGB`, confirming the input selected on a
+    /// plane.
+    Input(u32),
+    /// `Vol<n>`, confirming the volume set.
+    Volume(u32),
+    /// `Amt<n>`, confirming the audio mute set.
+    Mute(bool),
+    /// `Dcec<n>`, confirming the display power set over CEC.
+    DisplayPower(bool),
+    /// `Upl<n>`, ready for or done with a firmware image of `n` bytes.
+    Upload(u32),
+    /// `Frq00 <flags>`, a 1 or 0 per input for whether it has a signal.
+    Signal(Vec<bool>),
+    /// `Rpr<n>`, confirming the preset recalled.
+    Preset(u32),
+    /// `Lyt<preset>*<windows>`, the preset recalled last, 0 for none, and its windows.
+    Layout { preset: u32, windows: u32 },
+    /// `Win<n>*<input>`, the input shown in window `n`, 0 for none.
+    Window { window: u32, input: u32 },
+    /// `Pip<m>`, the picture-in-picture mode.
+    Pip(u32),
+    /// `Osyn<f>`, the output sync format.
+    SyncFormat(u32),
+    /// `Glck<r>`, what the output is locked to.
+    Genlock(u32),
+    /// `Aclr`, confirming the annotations were cleared.
+    AnnotationsCleared,
+    /// `Atol<n>`, the annotation tool selected.
+    Tool(u32),
+    /// `Afrz<n>`, confirming the annotated picture was frozen or let go.
+    AnnotationFrozen(bool),
+    /// `Rs<n>`, ready for `n` bytes for the display-control port.
+    Insert(u32),
+    /// A bare number, answering a query.
+    Number(u32),
+    /// Anything else, e.g. a device or input name.
+    Text(String),
+}
+
+/// Digits only, and small enough for a `u32`.
+fn number(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Two numbers separated by `*`.
+fn pair(digits: &[u8]) -> Option<(u32, u32)> {
+    let fields: Vec<_> = digits.split(|&b| b == b'*').map(number).collect();
+    match fields[..] {
+        [Some(first), Some(second)] => Some((first, second)),
+        _ => None,
+    }
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |n| n + 1);
+    &line[..end]
+}
+
+/// A line as text, without its line ending. Bytes that are not UTF-8 are replaced.
+pub fn text(line: &[u8]) -> String {
+    String::from_utf8_lossy(trim_end(line)).into_owned()
+}
+
+impl Reply {
+    /// Parses one line, with or without its line ending.
+    pub fn parse(line: &[u8]) -> Reply {
+        let line = trim_end(line);
+        if let [b'E', tens, ones] = line {
+            if tens.is_ascii_digit() && ones.is_ascii_digit() {
+                return Reply::Error((tens - b'0') * 10 + (ones - b'0'));
+            }
+        }
+        if let Some(n) = number(line) {
+            return Reply::Number(n);
+        }
+
+        let reply = if let Some(rest) = line.strip_prefix(b"In") {
+            // Some firmware puts a space before the plane.
+            let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+            let tail = &rest[digits..];
+            let tail = &tail[tail.iter().take_while(|&&b| b == b' ').count()..];
+            number(&rest[..digits])
+                .filter(|_| [&b"All"[..], b"Vid", b"Aud", b"RGB"].contains(&tail))
+                .map(Reply::Input)
+        } else if let Some(rest) = line.strip_prefix(b"Vol") {
+            number(rest).map(Reply::Volume)
+        } else if let Some(rest) = line.strip_prefix(b"Amt") {
+            number(rest).map(|n| Reply::Mute(n != 0))
+        } else if let Some(rest) = line.strip_prefix(b"Dcec") {
+            number(rest).map(|n| Reply::DisplayPower(n != 0))
+        } else if let Some(rest) = line.strip_prefix(b"Upl") {
+            number(rest).map(Reply::Upload)
+        } else if let Some(rest) = line.strip_prefix(b"Rpr") {
+            number(rest).map(Reply::Preset)
+        } else if let Some(rest) = line.strip_prefix(b"Lyt") {
+            pair(rest).map(|(preset, windows)| Reply::Layout { preset, windows })
+        } else if let Some(rest) = line.strip_prefix(b"Pip") {
+            number(rest).map(Reply::Pip)
+        } else if let Some(rest) = line.strip_prefix(b"Osyn") {
+            number(rest).map(Reply::SyncFormat)
+        } else if let Some(rest) = line.strip_prefix(b"Glck") {
+            number(rest).map(Reply::Genlock)
+        } else if let Some(rest) = line.strip_prefix(b"Win") {
+            pair(rest).map(|(window, input)| Reply::Window { window, input })
+        } else if line == b"Aclr" {
+            Some(Reply::AnnotationsCleared)
+        } else if let Some(rest) = line.strip_prefix(b"Atol") {
+            number(rest).map(Reply::Tool)
+        } else if let Some(rest) = line.strip_prefix(b"Afrz") {
+            number(rest).map(|n| Reply::AnnotationFrozen(n != 0))
+        } else if let Some(rest) = line.strip_prefix(b"Rs") {
+            number(rest).map(Reply::Insert)
+        } else if let Some(rest) = line.strip_prefix(b"Frq00") {
+            let flags = &rest[rest.iter().take_while(|&&b| b == b' ').count()..];
+            Some(flags)
+                .filter(|flags| !flags.is_empty() && flags.iter().all(|b| b"01".contains(b)))
+                .map(|flags| Reply::Signal(flags.iter().map(|&b| b == b'1').collect()))
+        } else {
+            None
+        };
+        reply.unwrap_or_else(|| Reply::Text(text(line)))
+    }
+
+    /// First number in the reply, e.g. 60 for `Vol60`, for answers to queries.
+    pub fn number(&self) -> Option<u32> {
+        match self {
+            Reply::Error(_) | Reply::Signal(_) | Reply::AnnotationsCleared => None,
+            Reply::Input(n)
+            | Reply::Volume(n)
+            | Reply::Upload(n)
+            | Reply::Insert(n)
+            | Reply::Preset(n)
+            | Reply::Tool(n)
+            | Reply::Pip(n)
+            | Reply::SyncFormat(n)
+            | Reply::Genlock(n)
+            | Reply::Layout { preset: n, .. }
+            | Reply::Window { window: n, .. }
+            | Reply::Number(n) => Some(*n),
+            Reply::Mute(on) | Reply::DisplayPower(on) | Reply::AnnotationFrozen(on) => {
+                Some(*on as u32)
+            }
+            Reply::Text(text) => {
+                let digits = text
+                    .chars()
+                    .skip_while(|c| !c.is_ascii_digit())
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect::<String>();
+                digits.parse().ok()
+            }
+        }
+    }
+}
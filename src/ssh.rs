@@ -0,0 +1,214 @@
+//! SSH tunnels to servers on networks the client cannot reach, such as an AV VLAN behind a
+//! jump host: the client logs in to the jump host and has it connect to the server, as
+//! `ssh -J` would, without a port forward to set up first.
+//!
+//! The jump host has to be in `~/.ssh/known_hosts` already. The client logs in with the keys
+//! of the SSH agent, or else with an unencrypted `~/.ssh/id_ed25519`, `id_ecdsa` or `id_rsa`.
+
+use crate::endpoint;
+use crate::error::{ControlError, Result};
+use russh::client;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Port of the jump host when none is given.
+const SSH_PORT: u16 = 22;
+
+/// Keys in `~/.ssh` tried when the agent has none that the jump host takes.
+const KEY_FILES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// A jump host to reach the servers through, `user@host` with an optional port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshTunnel {
+    user: String,
+    host: String,
+    port: u16,
+}
+
+impl FromStr for SshTunnel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not user@host with an optional port", s);
+        let (user, address) = s.split_once('@').ok_or_else(invalid)?;
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (address, SSH_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SshTunnel {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for SshTunnel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.port {
+            SSH_PORT => write!(f, "{}@{}", self.user, self.host),
+            port if self.host.contains(':') => write!(f, "{}@[{}]:{}", self.user, self.host, port),
+            port => write!(f, "{}@{}:{}", self.user, self.host, port),
+        }
+    }
+}
+
+/// Accepts the jump host only with the key `~/.ssh/known_hosts` has for it.
+struct KnownHosts {
+    host: String,
+    port: u16,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for KnownHosts {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        key: &russh_keys::key::PublicKey,
+    ) -> std::result::Result<bool, Self::Error> {
+        Ok(russh_keys::check_known_hosts(&self.host, self.port, key)?)
+    }
+}
+
+/// A connection through the tunnel, which keeps the SSH session to the jump host open for as
+/// long as it is.
+pub(crate) struct Tunneled {
+    stream: Pin<Box<russh::ChannelStream<client::Msg>>>,
+    _session: client::Handle<KnownHosts>,
+}
+
+impl AsyncRead for Tunneled {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.stream.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Tunneled {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.stream.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.as_mut().poll_shutdown(cx)
+    }
+}
+
+impl SshTunnel {
+    fn failed(&self, e: impl fmt::Display) -> ControlError {
+        ControlError::Connection(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SSH to {}: {}", self.host, e),
+        ))
+    }
+
+    /// Logs in to the jump host and has it connect to the first of `servers` that accepts,
+    /// giving each `timeout` and trying them all `retries` more times. Names are looked up by
+    /// the jump host. Returns the connection with the server it is to.
+    pub(crate) async fn connect(
+        &self,
+        servers: &[String],
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<(Tunneled, String)> {
+        let session = tokio::time::timeout(timeout, self.log_in())
+            .await
+            .map_err(|_| self.failed("timed out"))??;
+        let mut attempt = 0;
+        loop {
+            let mut last_error = None;
+            for server in servers {
+                let (host, port) = endpoint::split(server).map_err(ControlError::Connection)?;
+                let channel = session.channel_open_direct_tcpip(host, port.into(), "127.0.0.1", 0);
+                match tokio::time::timeout(timeout, channel).await {
+                    Ok(Ok(channel)) => {
+                        let tunneled = Tunneled {
+                            stream: Box::pin(channel.into_stream()),
+                            _session: session,
+                        };
+                        return Ok((tunneled, server.clone()));
+                    }
+                    Ok(Err(e)) => last_error = Some(format!("{}: {}", server, e)),
+                    Err(_) => last_error = Some(format!("{}: timed out", server)),
+                }
+            }
+            if attempt >= retries {
+                return Err(self.failed(last_error.unwrap()));
+            }
+            attempt += 1;
+            tokio::time::sleep(crate::client::RETRY_DELAY).await;
+        }
+    }
+
+    async fn log_in(&self) -> Result<client::Handle<KnownHosts>> {
+        let known_hosts = KnownHosts {
+            host: self.host.clone(),
+            port: self.port,
+        };
+        let config = Arc::new(client::Config::default());
+        let mut session = client::connect(config, (self.host.as_str(), self.port), known_hosts)
+            .await
+            .map_err(|e| match e {
+                russh::Error::UnknownKey => {
+                    self.failed("not in ~/.ssh/known_hosts, log in with ssh once to check its key")
+                }
+                e => self.failed(e),
+            })?;
+
+        #[cfg(unix)]
+        if let Ok(mut agent) = russh_keys::agent::client::AgentClient::connect_env().await {
+            for key in agent.request_identities().await.unwrap_or_default() {
+                let (returned, authenticated) =
+                    session.authenticate_future(&self.user, key, agent).await;
+                agent = returned;
+                if let Ok(true) = authenticated {
+                    return Ok(session);
+                }
+            }
+        }
+        let dir = dirs::home_dir().unwrap_or_default().join(".ssh");
+        for name in &KEY_FILES {
+            // Encrypted keys are left to the agent.
+            let key = match russh_keys::load_secret_key(dir.join(name), None) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            let authenticated = session
+                .authenticate_publickey(&self.user, Arc::new(key))
+                .await
+                .map_err(|e| self.failed(e))?;
+            if authenticated {
+                return Ok(session);
+            }
+        }
+        Err(self.failed(format!(
+            "no key of the agent or in {} lets {} in",
+            dir.display(),
+            self.user
+        )))
+    }
+}
@@ -0,0 +1,401 @@
+//! A standby server, for the [`crate::server::ServerBuilder::standby_of`] of another one, so
+//! that the room stays under control while the host of the primary reboots.
+//!
+//! While the primary answers, the standby passes every request on to it and takes over its
+//! events, which keeps the state of the devices on both. When the primary misses a few
+//! heartbeats, the standby scans for devices itself, e.g. through a USB switch, puts them back
+//! in the last state it saw and serves them. Once the primary is back, the standby sets that
+//! state on the primary and passes requests on again.
+
+use crate::client::{Client, Event};
+use crate::error::Result;
+use crate::extron::Input;
+use crate::server::{EventHooks, Request, ServerEvent, ServerRequest, SharedState};
+use crate::state::DeviceState;
+use std::net;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// How often the primary is checked.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Heartbeats the primary can miss before the standby takes over.
+const FAILOVER_AFTER: u32 = 3;
+
+/// Work for the thread talking to the primary.
+enum Message {
+    Request(Request),
+    /// Sets the state of the devices on the primary as it returns.
+    Hand(Vec<(String, DeviceState)>),
+    Event(Event),
+    /// The primary ended the event subscription.
+    Unsubscribed,
+}
+
+/// Carries out `request` on the primary, in the span of the call that made it.
+fn forward(primary: &Client, request: Request) {
+    let _span = request.span.enter();
+    // A reply can only fail to send when the caller went away, so those errors are ignored.
+    match request.request {
+        ServerRequest::Rescan(reply) => {
+            // The primary scans in the meantime, so the ticket is for a scan that is over.
+            let ticket = primary
+                .rescan()
+                .map(|()| tokio::sync::watch::channel(true).1);
+            let _ = reply.send(ticket);
+        }
+        ServerRequest::ListDevices(reply) => {
+            let _ = reply.send(primary.list_scanning());
+        }
+        ServerRequest::Select { name, input, reply } => {
+            let _ = reply.send(primary.select(&name, &input));
+        }
+        ServerRequest::ToggleInput { name, reply } => {
+            let _ = reply.send(primary.toggle_input(&name));
+        }
+        ServerRequest::SelectPlane {
+            name,
+            input,
+            plane,
+            reply,
+        } => {
+            let _ = reply.send(primary.select_plane(&name, &input, plane));
+        }
+        ServerRequest::Volume { name, level, reply } => {
+            let _ = reply.send(primary.set_volume(&name, level));
+        }
+        ServerRequest::Mute { name, mute, reply } => {
+            let _ = reply.send(primary.set_mute(&name, mute));
+        }
+        ServerRequest::Status { name, reply } => {
+            let _ = reply.send(primary.status(&name));
+        }
+        ServerRequest::SelectGroup {
+            names,
+            input,
+            plane,
+            reply,
+        } => {
+            let _ = reply.send(primary.select_group_plane(&names, &input, plane));
+        }
+        ServerRequest::Raw {
+            name,
+            command,
+            reply,
+        } => {
+            let _ = reply.send(primary.send_raw(&name, &command));
+        }
+        ServerRequest::UploadFirmware {
+            name,
+            image,
+            progress,
+            reply,
+        } => {
+            let report = move |done, total| {
+                let _ = progress.send((done, total));
+            };
+            let _ = reply.send(primary.upload_firmware(&name, &image, report));
+        }
+        ServerRequest::DeviceLog { name, reply } => {
+            let _ = reply.send(primary.device_log(&name));
+        }
+        ServerRequest::History { name, reply } => {
+            let _ = reply.send(primary.history(&name));
+        }
+        ServerRequest::Undo { name, reply } => {
+            let _ = reply.send(primary.undo(&name));
+        }
+        ServerRequest::DisplayPower { name, on, reply } => {
+            let _ = reply.send(primary.set_display_power(&name, on));
+        }
+        ServerRequest::DisplayRaw { name, data, reply } => {
+            let _ = reply.send(primary.send_to_display(&name, &data));
+        }
+        ServerRequest::RecallPreset {
+            name,
+            preset,
+            reply,
+        } => {
+            let _ = reply.send(primary.recall_preset(&name, preset));
+        }
+        ServerRequest::WallLayout { name, reply } => {
+            let _ = reply.send(primary.wall_layout(&name));
+        }
+        ServerRequest::RecallLayout {
+            name,
+            layout,
+            reply,
+        } => {
+            let _ = reply.send(primary.recall_layout(&name, layout));
+        }
+        ServerRequest::Annotate {
+            name,
+            annotation,
+            reply,
+        } => {
+            let _ = reply.send(primary.annotate(&name, annotation));
+        }
+        ServerRequest::PipMode { name, mode, reply } => {
+            let _ = reply.send(primary.set_pip_mode(&name, mode));
+        }
+        ServerRequest::AssignSource {
+            name,
+            window,
+            input,
+            reply,
+        } => {
+            let _ = reply.send(primary.assign_source(&name, window, &input));
+        }
+        ServerRequest::Sync { name, reply } => {
+            let _ = reply.send(primary.sync(&name));
+        }
+        ServerRequest::Info { name, reply } => {
+            let _ = reply.send(primary.device_info(&name));
+        }
+        ServerRequest::Health { name, reply } => {
+            let _ = reply.send(primary.device_health(&name));
+        }
+        ServerRequest::SyncFormat {
+            name,
+            format,
+            reply,
+        } => {
+            let _ = reply.send(primary.set_sync_format(&name, format));
+        }
+        ServerRequest::Genlock {
+            name,
+            genlock,
+            reply,
+        } => {
+            let _ = reply.send(primary.set_genlock(&name, genlock));
+        }
+        ServerRequest::Stats(reply) => {
+            let _ = reply.send(primary.stats());
+        }
+        ServerRequest::Dump(reply) => {
+            let dump = primary.dump_state().map(|text| {
+                let primary =
+                    serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text));
+                serde_json::json!({ "primary": primary })
+            });
+            let _ = reply.send(dump);
+        }
+        ServerRequest::Hold {
+            name,
+            duration,
+            reply,
+        } => {
+            let _ = reply.send(primary.hold(&name, duration));
+        }
+        ServerRequest::Maintenance { message, reply } => {
+            let _ = reply.send(primary.set_maintenance(message.as_deref()));
+        }
+    }
+}
+
+/// Sets `states` on the primary. Failures are logged, as there is nobody to report them to.
+fn hand_over(primary: &Client, states: Vec<(String, DeviceState)>) {
+    for (name, state) in states {
+        if let Some(input) = state.input {
+            let result = input
+                .parse::<Input>()
+                .and_then(|input| primary.select(&name, &input));
+            if let Err(e) = result {
+                info!("Cannot hand over input of {}: {}", name, e);
+            }
+        }
+        if let Some(level) = state.volume {
+            if let Err(e) = primary.set_volume(&name, level) {
+                info!("Cannot hand over volume of {}: {}", name, e);
+            }
+        }
+        if let Some(mute) = state.mute {
+            if let Err(e) = primary.set_mute(&name, mute) {
+                info!("Cannot hand over mute of {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Subscribes to the events of the primary, which a thread of its own passes on to `tx`.
+fn subscribe(primary: &Client, tx: &std_mpsc::Sender<Message>) -> bool {
+    let events = match primary.events() {
+        Ok(events) => events,
+        Err(_) => return false,
+    };
+    let tx = tx.clone();
+    std::thread::spawn(move || {
+        for event in events {
+            if tx.send(Message::Event(event)).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Message::Unsubscribed);
+    });
+    true
+}
+
+fn server_event(primary: &Client, event: Event) -> ServerEvent {
+    match event {
+        Event::DevicesScanned | Event::Reconnected => {
+            ServerEvent::DevicesScanned(Arc::new(primary.list().unwrap_or_default()))
+        }
+        Event::InputSelected { device, input } => ServerEvent::InputSelected { device, input },
+        Event::VolumeChanged { device, level } => ServerEvent::VolumeChanged { device, level },
+        Event::MuteChanged { device, mute } => ServerEvent::MuteChanged { device, mute },
+        Event::SignalLost { device, input } => ServerEvent::SignalLost { device, input },
+        Event::SignalRestored { device, input } => ServerEvent::SignalRestored { device, input },
+        Event::MaintenanceStarted { message } => ServerEvent::MaintenanceStarted(message),
+        Event::MaintenanceEnded => ServerEvent::MaintenanceEnded,
+    }
+}
+
+/// Talks to the primary: forwards requests, passes on its events while it is in charge, and
+/// reports on `status` when it goes away (false) or comes back (true).
+fn primary_thread(
+    addr: net::SocketAddr,
+    tx: std_mpsc::Sender<Message>,
+    rx: std_mpsc::Receiver<Message>,
+    status: mpsc::Sender<bool>,
+    events: Arc<EventHooks>,
+    cancel: CancellationToken,
+) {
+    let primary = match Client::with_servers(&addr.to_string()) {
+        Ok(client) => client.connect_timeout(HEARTBEAT).retries(0),
+        Err(e) => {
+            info!("Cannot stand by for {}: {}", addr, e);
+            return;
+        }
+    };
+    let mut subscribed = subscribe(&primary, &tx);
+    let mut in_charge = true;
+    // Between the primary coming back and the state being handed over to it.
+    let mut returning = false;
+    let mut missed = 0;
+    let mut next_check = Instant::now() + HEARTBEAT;
+    while !cancel.is_cancelled() {
+        match rx.recv_timeout(next_check.saturating_duration_since(Instant::now())) {
+            Ok(Message::Request(request)) => forward(&primary, request),
+            Ok(Message::Hand(states)) => {
+                hand_over(&primary, states);
+                in_charge = true;
+                returning = false;
+            }
+            // Events of a returning primary are those of its own restore, which the handover
+            // overrides.
+            Ok(Message::Event(event)) if in_charge && !returning => {
+                events.emit(server_event(&primary, event))
+            }
+            Ok(Message::Event(_)) => {}
+            Ok(Message::Unsubscribed) => {
+                subscribed = false;
+                next_check = Instant::now();
+            }
+            Err(std_mpsc::RecvTimeoutError::Timeout) => {
+                next_check = Instant::now() + HEARTBEAT;
+                if !subscribed {
+                    subscribed = subscribe(&primary, &tx);
+                }
+                if subscribed && primary.list().is_ok() {
+                    missed = 0;
+                    if !in_charge && !returning {
+                        info!("Primary {} is back, handing over", addr);
+                        returning = true;
+                        if status.blocking_send(true).is_err() {
+                            break;
+                        }
+                    }
+                } else if in_charge {
+                    missed += 1;
+                    if missed >= FAILOVER_AFTER {
+                        info!("Primary {} is gone, taking over", addr);
+                        in_charge = false;
+                        if status.blocking_send(false).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(std_mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Queue, stop token and task of the command loop serving the devices of the standby.
+type Local = (
+    mpsc::Sender<Request>,
+    CancellationToken,
+    JoinHandle<Result<()>>,
+);
+
+async fn finish(local: JoinHandle<Result<()>>) {
+    match local.await {
+        Ok(Err(e)) => info!("Command loop failed: {}", e),
+        Err(e) => info!("Command loop failed: {}", e),
+        Ok(Ok(())) => {}
+    }
+}
+
+/// Passes the requests of `cmd_rx` on to the primary at `addr`, or to a command loop started
+/// with `start_local` while the primary is gone, until `cancel` fires.
+pub(crate) async fn run<F>(
+    addr: net::SocketAddr,
+    mut cmd_rx: mpsc::Receiver<Request>,
+    state: SharedState,
+    events: Arc<EventHooks>,
+    start_local: F,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    F: Fn(mpsc::Receiver<Request>, CancellationToken) -> JoinHandle<Result<()>>,
+{
+    let (tx, rx) = std_mpsc::channel();
+    let (status_tx, mut status) = mpsc::channel(1);
+    {
+        let (tx, cancel) = (tx.clone(), cancel.clone());
+        std::thread::spawn(move || primary_thread(addr, tx, rx, status_tx, events, cancel));
+    }
+
+    let mut local: Option<Local> = None;
+    loop {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => break,
+            in_charge = status.recv() => match in_charge {
+                Some(false) if local.is_none() => {
+                    let (local_tx, local_rx) = mpsc::channel(50);
+                    let stop = cancel.child_token();
+                    local = Some((local_tx, stop.clone(), start_local(local_rx, stop)));
+                }
+                Some(false) => {}
+                Some(true) => {
+                    if let Some((_, stop, handle)) = local.take() {
+                        stop.cancel();
+                        finish(handle).await;
+                    }
+                    let states = state.lock().unwrap().devices();
+                    let _ = tx.send(Message::Hand(states));
+                }
+                None => break,
+            },
+            request = cmd_rx.recv() => match (request, &local) {
+                (Some(request), Some((local_tx, _, _))) => {
+                    // Dropped when the local loop failed, which the caller sees as cancelled.
+                    let _ = local_tx.send(request).await;
+                }
+                (Some(request), None) => {
+                    let _ = tx.send(Message::Request(request));
+                }
+                (None, _) => break,
+            },
+        }
+    }
+    if let Some((_, stop, handle)) = local {
+        stop.cancel();
+        finish(handle).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Last-known state of the devices, kept on disk so that the server can put a device that
+//! was power cycled or replaced back on the input it had. The input before it is kept as
+//! well, for toggling between the two; servers without a file keep the states in memory.
+//!
+//! The file also keeps the devices the last scan found, with what was learned about them,
+//! and the journal, so that a restarted server need not probe every port again before it
+//! serves and still has what the devices said before.
+
+use crate::extron::{DeviceMessage, ExtronDevice, ExtronDeviceList, Input};
+use crate::journal::Journal;
+use crate::server::ServerEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What was last set on a device through the server, and the inputs switched to on its front
+/// panel. Unset values are left alone on restore.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DeviceState {
+    /// Input as it was selected, a number or a name stored in the device.
+    pub input: Option<String>,
+    /// The input selected before it, for toggling back.
+    pub previous: Option<String>,
+    pub volume: Option<u8>,
+    pub mute: Option<bool>,
+}
+
+impl DeviceState {
+    /// Makes `input` the input of the device, and the one it replaces the previous input.
+    fn select(&mut self, input: &Input) {
+        let input = Some(input.to_string());
+        if self.input != input {
+            self.previous = std::mem::replace(&mut self.input, input);
+        }
+    }
+}
+
+/// A device a scan found.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Known {
+    pub name: String,
+    pub path: String,
+    /// Number of inputs, once learned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<u32>,
+}
+
+/// A message of the journal, with its time in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Message {
+    time: u64,
+    text: String,
+}
+
+impl From<&DeviceMessage> for Message {
+    fn from(message: &DeviceMessage) -> Self {
+        let since = message.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Message {
+            time: since.as_millis() as u64,
+            text: message.text.clone(),
+        }
+    }
+}
+
+impl From<Message> for DeviceMessage {
+    fn from(message: Message) -> Self {
+        DeviceMessage {
+            time: UNIX_EPOCH + Duration::from_millis(message.time),
+            text: message.text,
+        }
+    }
+}
+
+/// What the file holds. Files of older servers hold the device states only.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Saved {
+    devices: BTreeMap<String, DeviceState>,
+    /// The devices of the last scan.
+    found: Vec<Known>,
+    journal: BTreeMap<String, Vec<Message>>,
+}
+
+/// Device states by name, written to `path` as JSON after every change.
+pub(crate) struct StateFile {
+    /// `None` for states only kept in memory.
+    path: Option<PathBuf>,
+    saved: Saved,
+}
+
+impl StateFile {
+    /// Reads the states saved in `path`. A missing file holds no states yet.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let saved = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .or_else(|e| {
+                    serde_json::from_slice(&data)
+                        .map(|devices| Saved {
+                            devices,
+                            ..Saved::default()
+                        })
+                        .map_err(|_| e)
+                })
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), e),
+                    )
+                })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(StateFile {
+            path: Some(path),
+            saved,
+        })
+    }
+
+    /// States that are not saved, for a server without a state file.
+    pub fn in_memory() -> Self {
+        StateFile {
+            path: None,
+            saved: Saved::default(),
+        }
+    }
+
+    pub fn get(&self, device: &str) -> Option<&DeviceState> {
+        self.saved.devices.get(device)
+    }
+
+    pub fn devices(&self) -> Vec<(String, DeviceState)> {
+        self.saved
+            .devices
+            .iter()
+            .map(|(name, state)| (name.clone(), state.clone()))
+            .collect()
+    }
+
+    /// The devices the last scan found.
+    pub fn found(&self) -> &[Known] {
+        &self.saved.found
+    }
+
+    /// The journal as it was saved.
+    pub fn journal(&self) -> Journal {
+        let mut journal = Journal::default();
+        for (device, messages) in &self.saved.journal {
+            let messages = messages.iter().cloned().map(DeviceMessage::from);
+            journal.restore(device, messages.collect());
+        }
+        journal
+    }
+
+    /// Keeps what the server learned about `devices`, unless a scan was cut short before it
+    /// knew them, and the messages of `journal`, and saves them, e.g. as the server stops.
+    pub fn keep(&mut self, devices: Option<&ExtronDeviceList>, journal: &Journal) {
+        if let Some(devices) = devices {
+            self.find(devices.iter().collect());
+        }
+        self.saved.journal = journal
+            .all()
+            .map(|(device, messages)| {
+                (
+                    device.to_string(),
+                    messages.iter().map(Message::from).collect(),
+                )
+            })
+            .collect();
+        self.write();
+    }
+
+    /// Replaces the devices found with `devices`, keeping what was learned about those that
+    /// are still on the same port.
+    fn find(&mut self, devices: Vec<ExtronDevice>) {
+        let before = std::mem::take(&mut self.saved.found);
+        self.saved.found = devices
+            .into_iter()
+            .map(|device| {
+                let learned = before
+                    .iter()
+                    .find(|known| known.name == device.name && known.path == device.device_path)
+                    .and_then(|known| known.inputs);
+                Known {
+                    inputs: device.input_count().or(learned),
+                    name: device.name,
+                    path: device.device_path,
+                }
+            })
+            .collect();
+        self.saved.found.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Records the change or the scan `event` reports, if any, and saves the states.
+    pub fn record(&mut self, event: &ServerEvent) {
+        let device = match event {
+            ServerEvent::InputSelected { device, .. }
+            | ServerEvent::VolumeChanged { device, .. }
+            | ServerEvent::MuteChanged { device, .. } => device,
+            ServerEvent::DevicesScanned(devices) => {
+                self.find(devices.to_vec());
+                self.write();
+                return;
+            }
+            _ => return,
+        };
+        let state = self.saved.devices.entry(device.clone()).or_default();
+        match event {
+            ServerEvent::InputSelected { input, .. } => state.select(input),
+            ServerEvent::VolumeChanged { level, .. } => state.volume = Some(*level),
+            ServerEvent::MuteChanged { mute, .. } => state.mute = Some(*mute),
+            _ => {}
+        }
+        self.write();
+    }
+
+    /// Records that `input` was selected on the front panel of `device`, and saves the states.
+    pub fn front_panel(&mut self, device: &str, input: &Input) {
+        let state = self.saved.devices.entry(device.to_string()).or_default();
+        state.select(input);
+        self.write();
+    }
+
+    /// Saves everything, unless it is only kept in memory.
+    fn write(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                info!("Cannot save device state to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Writes a new file and moves it in place, so a crash never leaves half a file behind.
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.saved)?;
+        let new = path.with_extension("new");
+        std::fs::write(&new, data)?;
+        std::fs::rename(&new, path)
+    }
+}
@@ -0,0 +1,85 @@
+//! The parts of the systemd service protocol the server speaks: taking the sockets systemd
+//! listens on for it, telling systemd when it is ready and that it is still alive. Outside
+//! systemd none of this does anything.
+
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// First file descriptor of the sockets passed by socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+/// The sockets systemd listens on for this process, none if it was not socket activated.
+/// They are taken, so that the commands of hooks do not take them as theirs.
+pub fn listeners() -> std::io::Result<Vec<TcpListener>> {
+    let pid: Option<u32> = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse().ok());
+    let count: Option<i32> = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok());
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count,
+        _ => return Ok(Vec::new()),
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes the sockets open at these descriptors, and nothing else
+            // in the process owns them.
+            let passed = unsafe { TcpListener::from_raw_fd(fd) };
+            // They come without close-on-exec, which a copy has.
+            passed.try_clone()
+        })
+        .collect()
+}
+
+/// Sends `state`, e.g. `READY=1`, to systemd if it started the server as a notify service.
+pub fn notify(state: &str) -> std::io::Result<()> {
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often to tell systemd that the server is alive: half its watchdog timeout, `None`
+/// without a watchdog.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Tells systemd every `interval` that the server is alive, until `cancel` is cancelled.
+pub(crate) async fn watchdog(interval: Duration, cancel: CancellationToken) {
+    loop {
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("Cannot notify the systemd watchdog: {}", e);
+        }
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
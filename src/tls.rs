@@ -0,0 +1,255 @@
+//! TLS for the Cap'n Proto connections, for venues whose networks may not carry control
+//! traffic in the clear.
+//!
+//! A server may also require client certificates signed by a site CA. The identity of a client
+//! is then a name in its certificate, the common name or a subject alternative name, which
+//! the server logs with everything the client does and can grant a scope and devices as it
+//! does API tokens, see [`crate::tokens`].
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+fn invalid(path: &Path, e: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+fn open(path: &Path) -> io::Result<io::BufReader<std::fs::File>> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(io::BufReader::new(file))
+}
+
+/// The certificates in the PEM file at `path`.
+fn certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| invalid(path, e))?;
+    if certificates.is_empty() {
+        return Err(invalid(path, "no certificates"));
+    }
+    Ok(certificates)
+}
+
+/// The private key in the PEM file at `path`.
+fn private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| invalid(path, e))?
+        .ok_or_else(|| invalid(path, "no private key"))
+}
+
+/// The CA certificates in the PEM file at `path`, to check the other end against.
+fn roots(path: &Path) -> io::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in certificates(path)? {
+        roots.add(certificate).map_err(|e| invalid(path, e))?;
+    }
+    Ok(roots)
+}
+
+/// Named rather than left to the process default, which other crates may set differently.
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn unsupported(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, e.to_string())
+}
+
+/// How a client connects over TLS.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl ClientTls {
+    /// Trusts the servers whose certificates the CA in the PEM file `ca` signed. With
+    /// `identity`, the PEM files of a certificate and its key, presents that certificate to
+    /// servers requiring one.
+    pub fn new(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<Self> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(unsupported)?
+            .with_root_certificates(roots(ca)?);
+        let config = match identity {
+            Some((certificate, key)) => builder
+                .with_client_auth_cert(certificates(certificate)?, private_key(key)?)
+                .map_err(|e| invalid(certificate, e))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Expects the certificates of servers to be for `name`, instead of the host they are
+    /// connected to by, e.g. when that is an IP address.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    /// Handshakes with `server`, as given to [`crate::endpoint::split`], on `stream`.
+    pub(crate) async fn connect<S>(
+        &self,
+        server: &str,
+        stream: S,
+    ) -> io::Result<tokio_rustls::client::TlsStream<S>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let host = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => crate::endpoint::split(server)?.0,
+        };
+        let name = ServerName::try_from(host)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' is not a server name: {}", host, e),
+                )
+            })?
+            .to_owned();
+        tokio_rustls::TlsConnector::from(self.config.clone())
+            .connect(name, stream)
+            .await
+    }
+}
+
+#[cfg(feature = "server")]
+pub use self::server::ServerTls;
+
+#[cfg(feature = "server")]
+mod server {
+    use super::{certificates, invalid, private_key, provider, roots, unsupported};
+    use crate::tokens::{Grant, Scope};
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+
+    /// How a server serves over TLS, see [`crate::server::ServerBuilder::tls`].
+    pub struct ServerTls {
+        acceptor: tokio_rustls::TlsAcceptor,
+        /// What clients may do by the identity of their certificate.
+        identities: Vec<(String, Grant)>,
+    }
+
+    impl ServerTls {
+        /// Serves the certificate chain in the PEM file `certificate` with the key in `key`.
+        /// With `client_ca`, only serves clients presenting a certificate that the CA in that
+        /// PEM file signed.
+        pub fn new(certificate: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Self> {
+            let builder = rustls::ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(unsupported)?;
+            let builder = match client_ca {
+                Some(ca) => {
+                    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+                        Arc::new(roots(ca)?),
+                        provider(),
+                    )
+                    .build()
+                    .map_err(|e| invalid(ca, e))?;
+                    builder.with_client_cert_verifier(verifier)
+                }
+                None => builder.with_no_client_auth(),
+            };
+            let config = builder
+                .with_single_cert(certificates(certificate)?, private_key(key)?)
+                .map_err(|e| invalid(certificate, e))?;
+            Ok(ServerTls {
+                acceptor: Arc::new(config).into(),
+                identities: Vec::new(),
+            })
+        }
+
+        /// Lets clients with `identity` in their certificate do what `scope` allows on the
+        /// devices whose names match `devices`, all if there are none. Once there is an
+        /// identity, clients with none of them may do nothing until they authenticate with a
+        /// token. Takes client certificates, see [`ServerTls::new`].
+        pub fn allow(mut self, identity: &str, scope: Scope, devices: Vec<glob::Pattern>) -> Self {
+            self.identities
+                .push((identity.to_string(), Grant::new(scope, devices)));
+            self
+        }
+
+        /// Handshakes with the client on `stream`, returning the stream with the identity of
+        /// the client: the first name in its certificate that is allowed something, or else
+        /// the first name in it. Without a certificate, there is none.
+        pub(crate) async fn accept(
+            &self,
+            stream: TcpStream,
+        ) -> io::Result<(tokio_rustls::server::TlsStream<TcpStream>, Option<String>)> {
+            let stream = self.acceptor.accept(stream).await?;
+            let names = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .map_or_else(Vec::new, names);
+            let identity = names
+                .iter()
+                .find(|name| {
+                    self.identities
+                        .iter()
+                        .any(|(identity, _)| identity == *name)
+                })
+                .or_else(|| names.first())
+                .cloned();
+            Ok((stream, identity))
+        }
+
+        /// What the client with `identity` may do, `None` if the server grants nothing by
+        /// identity.
+        pub(crate) fn grant(&self, identity: Option<&str>) -> Option<Grant> {
+            if self.identities.is_empty() {
+                return None;
+            }
+            let allowed = self
+                .identities
+                .iter()
+                .find(|(name, _)| Some(name.as_str()) == identity);
+            Some(allowed.map_or_else(Grant::none, |(_, grant)| grant.clone()))
+        }
+    }
+
+    /// The common name and then the DNS, mail and URI subject alternative names of
+    /// `certificate`.
+    fn names(certificate: &CertificateDer) -> Vec<String> {
+        use x509_parser::extensions::GeneralName;
+
+        let certificate = match x509_parser::parse_x509_certificate(certificate.as_ref()) {
+            Ok((_, certificate)) => certificate,
+            Err(_) => return Vec::new(),
+        };
+        let mut names: Vec<_> = certificate
+            .subject()
+            .iter_common_name()
+            .filter_map(|name| name.as_str().ok())
+            .map(str::to_string)
+            .collect();
+        if let Ok(Some(alternatives)) = certificate.subject_alternative_name() {
+            for name in &alternatives.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        names
+    }
+}
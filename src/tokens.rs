@@ -0,0 +1,369 @@
+//! API tokens, for servers reachable from more than the machines allowed to change every
+//! device. Each token has a scope and may be limited to devices by glob patterns on their
+//! names, e.g. a room panel that may only switch `hall-*`.
+//!
+//! The server keeps its tokens in a JSON file, with a hash of each secret rather than the
+//! secret itself, and manages them through [`crate::client::AsyncClient::create_token`] and
+//! its siblings, usually on the admin socket of [`crate::server::ServerBuilder::admin_socket`].
+
+use crate::extron_capnp::control_extron;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// What a token allows, each scope also allowing the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Listing and reading devices.
+    Read,
+    /// Also changing them.
+    Control,
+    /// Also administrative calls, including managing the tokens.
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Read => "read",
+            Scope::Control => "control",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "control" => Ok(Scope::Control),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "'{}' is not a scope, use read, control or admin",
+                s
+            )),
+        }
+    }
+}
+
+/// A token as listed, without its secret.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub id: String,
+    pub scope: Scope,
+    /// Glob patterns of the devices the token may be used for, all if empty.
+    pub devices: Vec<String>,
+    /// When the token was created, in seconds since the Unix epoch.
+    pub created: u64,
+}
+
+impl From<control_extron::Scope> for Scope {
+    fn from(scope: control_extron::Scope) -> Self {
+        match scope {
+            control_extron::Scope::Read => Scope::Read,
+            control_extron::Scope::Control => Scope::Control,
+            control_extron::Scope::Admin => Scope::Admin,
+        }
+    }
+}
+
+impl From<Scope> for control_extron::Scope {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Read => control_extron::Scope::Read,
+            Scope::Control => control_extron::Scope::Control,
+            Scope::Admin => control_extron::Scope::Admin,
+        }
+    }
+}
+
+impl Token {
+    pub(crate) fn to_wire(&self, mut builder: control_extron::token::Builder) {
+        builder.set_id(&self.id);
+        builder.set_scope(self.scope.into());
+        let mut devices = builder.reborrow().init_devices(self.devices.len() as u32);
+        for (i, pattern) in self.devices.iter().enumerate() {
+            devices.set(i as u32, pattern);
+        }
+        builder.set_created(self.created);
+    }
+
+    pub(crate) fn from_wire(reader: control_extron::token::Reader) -> capnp::Result<Self> {
+        let mut devices = Vec::new();
+        for pattern in reader.get_devices()?.iter() {
+            devices.push(pattern?.to_str()?.to_string());
+        }
+        Ok(Token {
+            id: reader.get_id()?.to_str()?.to_string(),
+            scope: reader.get_scope()?.into(),
+            devices,
+            created: reader.get_created(),
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+pub(crate) use self::store::Grant;
+#[cfg(feature = "server")]
+pub use self::store::TokenStore;
+
+#[cfg(feature = "server")]
+mod store {
+    use super::{Scope, Token};
+    use crate::error::{ControlError, Result};
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    /// What a connection may do: nothing before it authenticated on a server with tokens,
+    /// everything on one without.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Grant {
+        /// The token it comes from, if any.
+        id: Option<String>,
+        scope: Option<Scope>,
+        devices: Vec<glob::Pattern>,
+    }
+
+    impl Grant {
+        pub fn all() -> Self {
+            Grant {
+                id: None,
+                scope: Some(Scope::Admin),
+                devices: Vec::new(),
+            }
+        }
+
+        pub fn none() -> Self {
+            Grant {
+                id: None,
+                scope: None,
+                devices: Vec::new(),
+            }
+        }
+
+        /// `scope` on the devices matching `devices`, all if there are none, for a client
+        /// known otherwise than by a token.
+        pub fn new(scope: Scope, devices: Vec<glob::Pattern>) -> Self {
+            Grant {
+                id: None,
+                scope: Some(scope),
+                devices,
+            }
+        }
+
+        pub fn scope(&self) -> Option<Scope> {
+            self.scope
+        }
+
+        pub fn token(&self) -> Option<&str> {
+            self.id.as_deref()
+        }
+
+        /// Fails unless the grant has `scope` or one above it.
+        pub fn check(&self, scope: Scope) -> Result<()> {
+            match self.scope {
+                None => Err(ControlError::Unauthorized(
+                    "authenticate with a token first".to_string(),
+                )),
+                Some(have) if have < scope => Err(ControlError::Unauthorized(format!(
+                    "the token does not have the {} scope",
+                    scope
+                ))),
+                Some(_) => Ok(()),
+            }
+        }
+
+        /// Whether the grant is for the device called `name`.
+        pub fn covers(&self, name: &str) -> bool {
+            self.devices.is_empty() || self.devices.iter().any(|p| p.matches(name))
+        }
+
+        /// Fails unless the grant has `scope` for the device called `name`.
+        pub fn check_device(&self, scope: Scope, name: &str) -> Result<()> {
+            self.check(scope)?;
+            if !self.covers(name) {
+                return Err(ControlError::Unauthorized(format!(
+                    "the token is not for {}",
+                    name
+                )));
+            }
+            Ok(())
+        }
+    }
+
+    /// A token as kept in the file.
+    #[derive(Serialize, Deserialize)]
+    struct Stored {
+        #[serde(flatten)]
+        token: Token,
+        /// SHA-256 of the secret, in hex.
+        hash: String,
+    }
+
+    /// The tokens of a server, written to their file after every change.
+    pub struct TokenStore {
+        path: PathBuf,
+        tokens: Vec<Stored>,
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn random_hex(len: usize) -> io::Result<String> {
+        let mut bytes = vec![0; len];
+        getrandom::getrandom(&mut bytes).map_err(io::Error::from)?;
+        Ok(hex(&bytes))
+    }
+
+    /// Failing to draw a secret or to write the file, which the client can do nothing about.
+    fn failed(e: io::Error) -> ControlError {
+        ControlError::Rpc(capnp::Error::failed(format!(
+            "Cannot update the tokens: {}",
+            e
+        )))
+    }
+
+    fn hash(secret: &str) -> String {
+        hex(&Sha256::digest(secret.as_bytes()))
+    }
+
+    impl TokenStore {
+        /// Reads the tokens in `path`. A missing file holds no tokens yet. A device pattern
+        /// that is not a glob fails, rather than leaving its token for all devices.
+        pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+            let path = path.into();
+            let tokens: Vec<Stored> = match std::fs::read(&path) {
+                Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), e),
+                    )
+                })?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            for token in tokens.iter().map(|stored| &stored.token) {
+                for pattern in &token.devices {
+                    glob::Pattern::new(pattern).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{}: invalid device pattern '{}' of token {}: {}",
+                                path.display(),
+                                pattern,
+                                token.id,
+                                e
+                            ),
+                        )
+                    })?;
+                }
+            }
+            Ok(TokenStore { path, tokens })
+        }
+
+        pub fn list(&self) -> Vec<Token> {
+            self.tokens
+                .iter()
+                .map(|stored| stored.token.clone())
+                .collect()
+        }
+
+        /// Adds a token with `scope` for the devices matching `devices`, all if there are
+        /// none. Returns it with its secret, which is not kept.
+        pub fn create(&mut self, scope: Scope, devices: Vec<String>) -> Result<(Token, String)> {
+            for pattern in &devices {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    ControlError::Rpc(capnp::Error::failed(format!(
+                        "Invalid device pattern '{}': {}",
+                        pattern, e
+                    )))
+                })?;
+            }
+            let id = random_hex(4).map_err(failed)?;
+            let secret = format!("{}.{}", id, random_hex(16).map_err(failed)?);
+            let created = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let token = Token {
+                id,
+                scope,
+                devices,
+                created,
+            };
+            self.tokens.push(Stored {
+                token: token.clone(),
+                hash: hash(&secret),
+            });
+            if let Err(e) = self.save() {
+                self.tokens.pop();
+                return Err(failed(e));
+            }
+            Ok((token, secret))
+        }
+
+        pub fn revoke(&mut self, id: &str) -> Result<()> {
+            let before = self.tokens.len();
+            self.tokens.retain(|stored| stored.token.id != id);
+            if self.tokens.len() == before {
+                return Err(ControlError::Rpc(capnp::Error::failed(format!(
+                    "No token {}",
+                    id
+                ))));
+            }
+            self.save().map_err(failed)
+        }
+
+        /// What the token with `secret` allows, `None` for one that is not in the store, or
+        /// with a device pattern that is not a glob, which [`TokenStore::load`] keeps out.
+        pub(crate) fn grant(&self, secret: &str) -> Option<Grant> {
+            let hash = hash(secret);
+            let stored = self.tokens.iter().find(|stored| stored.hash == hash)?;
+            let devices = stored
+                .token
+                .devices
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<std::result::Result<_, _>>()
+                .ok()?;
+            Some(Grant {
+                id: Some(stored.token.id.clone()),
+                scope: Some(stored.token.scope),
+                devices,
+            })
+        }
+
+        /// Writes a new file, readable only by the owner on Unix, and moves it in place, so a
+        /// crash never leaves half a file behind.
+        fn save(&self) -> io::Result<()> {
+            let data = serde_json::to_vec_pretty(&self.tokens)?;
+            let new = self.path.with_extension("new");
+            write_private(&new, &data)?;
+            std::fs::rename(&new, &self.path)
+        }
+    }
+
+    #[cfg(unix)]
+    fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(data)
+    }
+
+    #[cfg(not(unix))]
+    fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+}
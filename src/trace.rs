@@ -0,0 +1,123 @@
+//! Hex dumps of every byte exchanged with the devices, for when a device answers something
+//! the parser does not expect. Each device gets its own file, which only grows.
+
+use crate::extron::Port;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bytes shown per line of a dump.
+const BYTES_PER_LINE: usize = 16;
+
+/// Directory the dumps go to, `None` when not tracing.
+static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Makes device access in this process dump the traffic of every device to a file in `dir`,
+/// or stop doing so with `None`.
+pub fn set_dir(dir: Option<PathBuf>) {
+    *DIR.lock().unwrap() = dir;
+}
+
+/// The file the traffic of the device `name` is dumped to, in `dir`.
+pub fn path(dir: &Path, name: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    dir.join(format!("serial-{}.trace", name))
+}
+
+/// `port` of the device `name`, dumping its traffic if tracing. A dump that cannot be written
+/// costs the trace, not the device.
+pub(crate) fn wrap(name: &str, port: Box<dyn Port>) -> Box<dyn Port> {
+    let dir = match DIR.lock().unwrap().clone() {
+        Some(dir) => dir,
+        None => return port,
+    };
+    let path = path(&dir, name);
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => Box::new(TracedPort { port, file }),
+        Err(e) => {
+            warn!("Not tracing {} to {}: {}", name, path.display(), e);
+            port
+        }
+    }
+}
+
+/// A port writing what goes through it to a dump.
+struct TracedPort {
+    port: Box<dyn Port>,
+    file: File,
+}
+
+impl TracedPort {
+    /// Dumps `bytes`, sent to the device if `sent` and received from it otherwise, as lines of
+    /// a timestamp, the direction, the offset, the bytes in hex and the printable ones.
+    fn dump(&mut self, sent: bool, bytes: &[u8]) {
+        let time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.6f");
+        let direction = if sent { '>' } else { '<' };
+        let mut text = String::new();
+        for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let printable: String = line
+                .iter()
+                .map(|&b| {
+                    if (b' '..=b'~').contains(&b) {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            text.push_str(&format!(
+                "{} {} {:04x}  {:<47}  |{}|\n",
+                time,
+                direction,
+                i * BYTES_PER_LINE,
+                hex.join(" "),
+                printable
+            ));
+        }
+        if let Err(e) = self.file.write_all(text.as_bytes()) {
+            debug!("Cannot write serial trace: {}", e);
+        }
+    }
+}
+
+impl Read for TracedPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.port.read(buf)?;
+        self.dump(false, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl Write for TracedPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.port.write(buf)?;
+        self.dump(true, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Port for TracedPort {
+    fn clear_input(&mut self) -> std::io::Result<()> {
+        self.port.clear_input()
+    }
+
+    fn take_pending(&mut self) -> std::io::Result<Vec<u8>> {
+        let pending = self.port.take_pending()?;
+        self.dump(false, &pending);
+        Ok(pending)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.port.set_read_timeout(timeout)
+    }
+}
@@ -0,0 +1,338 @@
+//! A live view of the devices on a server for an operator console: a table of the devices
+//! with their selected input, audio and signal, kept up to date by the events of the server,
+//! which are also listed below it. Keys switch the input of the device under the cursor.
+
+use crate::config::Config;
+use anyhow::Result;
+use control_dsc::client::{Client, Event};
+use control_dsc::extron::Input;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Events kept for the bottom of the screen.
+const EVENT_LINES: usize = 8;
+
+/// How long to wait for a key before looking for events again.
+const TICK: Duration = Duration::from_millis(100);
+
+const HELP: &str = "up/down: device  1-9: select input  r: reload  q: quit";
+
+/// A device as last seen.
+struct Row {
+    name: String,
+    input_count: Option<u32>,
+    input: Option<u32>,
+    volume: Option<u8>,
+    mute: Option<bool>,
+    /// Input whose signal was lost, while it is.
+    signal_lost: Option<u32>,
+    /// Why the status could not be read.
+    error: Option<String>,
+}
+
+struct View<'a> {
+    client: &'a Client,
+    config: &'a Config,
+    rows: Vec<Row>,
+    selected: usize,
+    events: VecDeque<String>,
+    /// What the last key did, or why it failed.
+    message: String,
+}
+
+/// Puts the terminal back as it was, also when the view fails.
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Shows the devices of the server `client` talks to until `q` is pressed.
+pub fn run(client: &Client, config: &Config) -> Result<()> {
+    let events = client.watch()?;
+    let mut view = View {
+        client,
+        config,
+        rows: Vec::new(),
+        selected: 0,
+        events: VecDeque::new(),
+        message: String::new(),
+    };
+    view.reload()?;
+    let _screen = Screen::enter()?;
+    loop {
+        view.draw()?;
+        if event::poll(TICK)? {
+            if let event::Event::Key(key) = event::read()? {
+                if !view.key(key) {
+                    return Ok(());
+                }
+            }
+        }
+        loop {
+            match events.try_recv() {
+                Ok(event) => view.apply(event),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    anyhow::bail!("Lost the events of the server")
+                }
+            }
+        }
+    }
+}
+
+impl View<'_> {
+    /// Reads the devices and their status from the server again.
+    fn reload(&mut self) -> Result<()> {
+        let mut devices = self.client.list()?;
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        // Lost signals are only known to the health checks of the server.
+        let dump: serde_json::Value = self
+            .client
+            .dump_state()
+            .ok()
+            .and_then(|dump| serde_json::from_str(&dump).ok())
+            .unwrap_or_default();
+        self.rows = devices
+            .iter()
+            .map(|device| {
+                let status = self.client.status(&device.name);
+                let signal_lost = dump["health"][&device.name]["signal_lost"].as_u64();
+                Row {
+                    name: device.name.clone(),
+                    input_count: device.input_count(),
+                    input: status.as_ref().ok().map(|status| status.input),
+                    volume: status.as_ref().ok().and_then(|status| status.volume),
+                    mute: status.as_ref().ok().and_then(|status| status.mute),
+                    signal_lost: signal_lost.map(|input| input as u32),
+                    error: status.err().map(|e| e.to_string()),
+                }
+            })
+            .collect();
+        self.selected = self.selected.min(self.rows.len().saturating_sub(1));
+        Ok(())
+    }
+
+    /// Handles `key`, returning whether to go on.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if key.kind != KeyEventKind::Press {
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.rows.len().saturating_sub(1))
+            }
+            KeyCode::Char('r') => {
+                self.message = match self.reload() {
+                    Ok(()) => "Reloaded".to_string(),
+                    Err(e) => format!("Cannot reload: {:#}", e),
+                }
+            }
+            KeyCode::Char(c @ '1'..='9') => {
+                if let Some(row) = self.rows.get(self.selected) {
+                    let input = Input::Number(c.to_digit(10).unwrap());
+                    // The row changes with the event of the server.
+                    self.message = match self.client.select(&row.name, &input) {
+                        Ok(()) => format!("Selected {} on {}", self.label(&row.name, c), row.name),
+                        Err(e) => format!("Cannot select {} on {}: {}", input, row.name, e),
+                    };
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// `input` of `device`, with its label in the configuration if it has one.
+    fn label(&self, device: &str, input: impl ToString) -> String {
+        let input = input.to_string();
+        let label = self.config.devices.get(device).and_then(|device| {
+            device
+                .inputs
+                .iter()
+                .find(|(_, n)| n.to_string() == input)
+                .map(|(label, _)| label)
+        });
+        match label {
+            Some(label) => format!("{} ({})", input, label),
+            None => input,
+        }
+    }
+
+    fn row(&mut self, device: &str) -> Option<&mut Row> {
+        self.rows.iter_mut().find(|row| row.name == device)
+    }
+
+    /// Shows `event` and updates the rows for it.
+    fn apply(&mut self, event: Event) {
+        let line = match &event {
+            Event::DevicesScanned => "Devices scanned".to_string(),
+            Event::InputSelected { device, input } => {
+                format!("{}: input {}", device, self.label(device, input))
+            }
+            Event::VolumeChanged { device, level } => format!("{}: volume {}", device, level),
+            Event::MuteChanged { device, mute } => {
+                format!("{}: {}", device, if *mute { "muted" } else { "unmuted" })
+            }
+            Event::SignalLost { device, input } => {
+                format!(
+                    "{}: no signal on input {}",
+                    device,
+                    self.label(device, input)
+                )
+            }
+            Event::SignalRestored { device, input } => {
+                format!(
+                    "{}: signal back on input {}",
+                    device,
+                    self.label(device, input)
+                )
+            }
+            Event::MaintenanceStarted { message } if message.is_empty() => {
+                "Maintenance started".to_string()
+            }
+            Event::MaintenanceStarted { message } => format!("Maintenance started: {}", message),
+            Event::MaintenanceEnded => "Maintenance ended".to_string(),
+            Event::Reconnected => "Connected to the server again".to_string(),
+        };
+        let time = chrono::Local::now().format("%H:%M:%S");
+        self.events.push_front(format!("{} {}", time, line));
+        self.events.truncate(EVENT_LINES);
+
+        match event {
+            Event::DevicesScanned | Event::Reconnected => {
+                if let Err(e) = self.reload() {
+                    self.message = format!("Cannot reload: {:#}", e);
+                }
+            }
+            Event::InputSelected { device, input } => {
+                if let Input::Number(n) = input {
+                    if let Some(row) = self.row(&device) {
+                        row.input = Some(n);
+                    }
+                }
+            }
+            Event::VolumeChanged { device, level } => {
+                if let Some(row) = self.row(&device) {
+                    row.volume = Some(level);
+                }
+            }
+            Event::MuteChanged { device, mute } => {
+                if let Some(row) = self.row(&device) {
+                    row.mute = Some(mute);
+                }
+            }
+            Event::SignalLost { device, input } => {
+                if let Some(row) = self.row(&device) {
+                    row.signal_lost = Some(input);
+                }
+            }
+            Event::SignalRestored { device, .. } => {
+                if let Some(row) = self.row(&device) {
+                    row.signal_lost = None;
+                }
+            }
+            Event::MaintenanceStarted { .. } | Event::MaintenanceEnded => {}
+        }
+    }
+
+    fn draw(&self) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+        let mut out = io::stdout();
+        let mut y = 0;
+        let mut line = |out: &mut io::Stdout, text: &str, reverse: bool| -> io::Result<()> {
+            if y >= height {
+                return Ok(());
+            }
+            let text: String = text.chars().take(width).collect();
+            queue!(out, MoveTo(0, y), Clear(ClearType::CurrentLine))?;
+            if reverse {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(out, Print(text), SetAttribute(Attribute::Reset))?;
+            y += 1;
+            Ok(())
+        };
+
+        let name_width = self
+            .rows
+            .iter()
+            .map(|row| row.name.len())
+            .max()
+            .unwrap_or(0);
+        let name_width = name_width.max("Device".len());
+        line(
+            &mut out,
+            &format!(
+                "{:<w$}  {:<16}{:<8}{:<7}Signal",
+                "Device",
+                "Input",
+                "Volume",
+                "Mute",
+                w = name_width
+            ),
+            false,
+        )?;
+        for (i, row) in self.rows.iter().enumerate() {
+            let text = match &row.error {
+                Some(e) => format!("{:<w$}  {}", row.name, e, w = name_width),
+                None => {
+                    let input = match (row.input, row.input_count) {
+                        (Some(input), Some(count)) => {
+                            format!("{}/{}", self.label(&row.name, input), count)
+                        }
+                        (Some(input), None) => self.label(&row.name, input),
+                        (None, _) => "-".to_string(),
+                    };
+                    let show = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                    let signal = match row.signal_lost {
+                        Some(input) => format!("LOST on {}", input),
+                        None => "ok".to_string(),
+                    };
+                    format!(
+                        "{:<w$}  {:<16}{:<8}{:<7}{}",
+                        row.name,
+                        input,
+                        show(row.volume.map(|v| v.to_string())),
+                        show(row.mute.map(|m| if m { "yes" } else { "no" }.to_string())),
+                        signal,
+                        w = name_width
+                    )
+                }
+            };
+            line(&mut out, &text, i == self.selected)?;
+        }
+        line(&mut out, "", false)?;
+        line(&mut out, "Events", false)?;
+        for event in &self.events {
+            line(&mut out, event, false)?;
+        }
+        line(&mut out, "", false)?;
+        line(&mut out, &self.message, false)?;
+        line(&mut out, HELP, false)?;
+        queue!(out, Clear(ClearType::FromCursorDown))?;
+        out.flush()
+    }
+}
@@ -0,0 +1,23 @@
+//! udev rules for Extron devices, printed by `gen-udev` for `/etc/udev/rules.d`.
+
+use control_dsc::extron::STABLE_PORT_DIR;
+
+/// Where the rules are meant to go. The number puts them after the rules naming serial ports.
+pub const RULES_PATH: &str = "/etc/udev/rules.d/70-extron.rules";
+
+/// Rules letting `group` use the Extron devices, keeping ModemManager from probing them and
+/// linking each device with a serial number into [`STABLE_PORT_DIR`] under that number.
+pub fn rules(group: &str) -> String {
+    let dir = STABLE_PORT_DIR.trim_start_matches("/dev/");
+    format!(
+        "# Extron devices, from control-dsc gen-udev. Install as {path} and run\n\
+         # udevadm control --reload && udevadm trigger --subsystem-match=tty\n\
+         SUBSYSTEM==\"tty\", ATTRS{{idVendor}}==\"1ce2\", GROUP=\"{group}\", MODE=\"0660\", \
+         ENV{{ID_MM_DEVICE_IGNORE}}=\"1\"\n\
+         SUBSYSTEM==\"tty\", ATTRS{{idVendor}}==\"1ce2\", ATTRS{{serial}}==\"?*\", \
+         SYMLINK+=\"{dir}/$attr{{serial}}\"\n",
+        path = RULES_PATH,
+        group = group,
+        dir = dir,
+    )
+}
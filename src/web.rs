@@ -0,0 +1,393 @@
+//! A dashboard for small sites, served next to the metrics on the HTTP listeners: the devices
+//! with their selected input and its signal, with buttons switching inputs and recalling
+//! scenes. The page uses a few JSON calls and a stream of server-sent events, which other
+//! tools can use as well:
+//!
+//! - `GET /api/devices`: the devices with their status and input buttons, the scenes and the
+//!   maintenance message, if any.
+//! - `POST /api/devices/NAME/input/INPUT`: selects an input, by number or label.
+//! - `POST /api/devices/NAME/scenes/SCENE`: runs the steps of a scene on the device.
+//! - `GET /api/events`: the events of the server as they happen, one JSON object each.
+//!
+//! Names in paths are percent-encoded. Failures answer with an HTTP error status and a JSON
+//! object with the message in `error`. POSTs from browsers must come from the dashboard
+//! itself: one whose `Origin`, or `Referer` without it, names another host is refused with
+//! 403, so that other sites cannot switch inputs through the browsers of the staff.
+
+use crate::error::{ControlError, Result};
+use crate::extron::Input;
+use crate::server::{call, Request, ServerEvent, ServerRequest};
+use futures::{AsyncWrite, AsyncWriteExt};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+/// The page, which loads nothing from elsewhere.
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Who the history names for the changes made through here.
+const CLIENT: &str = "web";
+
+/// A step of a scene, run on the device the scene is recalled on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Select(Input),
+    Volume(u8),
+    Mute(bool),
+    Sleep(Duration),
+}
+
+/// What the dashboard offers besides the devices, for
+/// [`crate::server::ServerBuilder::dashboard`].
+#[derive(Debug, Clone, Default)]
+pub struct Dashboard {
+    /// Steps by scene name.
+    pub scenes: BTreeMap<String, Vec<Step>>,
+    /// Input numbers by label, by device. Devices with labels get a button for each; the
+    /// others one for each input, once the number of inputs is known.
+    pub labels: HashMap<String, BTreeMap<String, u32>>,
+}
+
+/// Serves the dashboard of a server.
+pub(crate) struct Web {
+    dashboard: Dashboard,
+    tx_channel: mpsc::Sender<Request>,
+    events: broadcast::Sender<ServerEvent>,
+}
+
+impl Web {
+    pub(crate) fn new(
+        dashboard: Dashboard,
+        tx_channel: mpsc::Sender<Request>,
+        events: broadcast::Sender<ServerEvent>,
+    ) -> Self {
+        Web {
+            dashboard,
+            tx_channel,
+            events,
+        }
+    }
+
+    /// Answers a request for `path` with `method` and `headers`, by name and value, on
+    /// `stream`.
+    pub(crate) async fn serve<S>(
+        &self,
+        stream: &mut S,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> std::io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if method == "POST" && !same_origin(headers) {
+            let body = serde_json::json!({ "error": "Cross-site request refused" }).to_string();
+            return respond(stream, "403 Forbidden", "application/json", &body).await;
+        }
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<String> = path.trim_matches('/').split('/').map(decode).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let result = match (method, segments.as_slice()) {
+            ("GET", [""]) => {
+                return respond(stream, "200 OK", "text/html; charset=utf-8", PAGE).await;
+            }
+            ("GET", ["api", "events"]) => return self.stream_events(stream).await,
+            ("GET", ["api", "devices"]) => self.devices().await,
+            ("POST", ["api", "devices", name, "input", input]) => self
+                .select(name, input)
+                .await
+                .map(|()| serde_json::json!({})),
+            ("POST", ["api", "devices", name, "scenes", scene]) => self
+                .recall(name, scene)
+                .await
+                .map(|()| serde_json::json!({})),
+            _ => {
+                let body = serde_json::json!({ "error": "Not found" }).to_string();
+                return respond(stream, "404 Not Found", "application/json", &body).await;
+            }
+        };
+        match result {
+            Ok(body) => respond(stream, "200 OK", "application/json", &body.to_string()).await,
+            Err(e) => {
+                let body = serde_json::json!({ "error": e.to_string() }).to_string();
+                respond(stream, status_of(&e), "application/json", &body).await
+            }
+        }
+    }
+
+    /// The devices with their status, by name, with the scenes and the maintenance message.
+    async fn devices(&self) -> Result<serde_json::Value> {
+        let (mut devices, _) =
+            call(self.tx_channel.clone(), CLIENT, ServerRequest::ListDevices).await?;
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        // Lost signals are only known to the health checks.
+        let dump = call(self.tx_channel.clone(), CLIENT, ServerRequest::Dump).await?;
+
+        let mut list = Vec::new();
+        for device in devices {
+            let name = device.name.clone();
+            let status = call(self.tx_channel.clone(), CLIENT, |reply| {
+                ServerRequest::Status {
+                    name: name.clone(),
+                    reply,
+                }
+            })
+            .await;
+            let inputs: Vec<_> = match self.dashboard.labels.get(&name) {
+                Some(labels) => {
+                    let mut inputs: Vec<_> = labels.iter().collect();
+                    inputs.sort_by_key(|(_, n)| **n);
+                    inputs
+                        .into_iter()
+                        .map(|(label, n)| serde_json::json!({ "input": n, "label": label }))
+                        .collect()
+                }
+                None => (1..=device.input_count().unwrap_or(0))
+                    .map(|n| serde_json::json!({ "input": n, "label": n.to_string() }))
+                    .collect(),
+            };
+            let mut entry = serde_json::json!({
+                "name": name,
+                "inputs": inputs,
+                "signal_lost": dump["health"][&name]["signal_lost"],
+            });
+            match status {
+                Ok(status) => {
+                    entry["input"] = status.input.into();
+                    entry["volume"] = status.volume.into();
+                    entry["mute"] = status.mute.into();
+                }
+                Err(e) => entry["error"] = e.to_string().into(),
+            }
+            list.push(entry);
+        }
+        Ok(serde_json::json!({
+            "devices": list,
+            "scenes": self.dashboard.scenes.keys().collect::<Vec<_>>(),
+            "maintenance": dump["maintenance"],
+        }))
+    }
+
+    async fn select(&self, device: &str, input: &str) -> Result<()> {
+        let input = self.resolve(device, input.parse()?);
+        call(self.tx_channel.clone(), CLIENT, |reply| {
+            ServerRequest::Select {
+                name: device.to_string(),
+                input,
+                reply,
+            }
+        })
+        .await
+    }
+
+    /// Runs the steps of `scene` on `device`, stopping at the first that fails.
+    async fn recall(&self, device: &str, scene: &str) -> Result<()> {
+        let steps = self.dashboard.scenes.get(scene).ok_or_else(|| {
+            ControlError::Rpc(capnp::Error::failed(format!("Scene '{}' not found", scene)))
+        })?;
+        info!("Recalling scene {} on {}", scene, device);
+        for step in steps {
+            let name = device.to_string();
+            let tx_channel = self.tx_channel.clone();
+            match step.clone() {
+                Step::Select(input) => {
+                    let input = self.resolve(device, input);
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Select {
+                        name,
+                        input,
+                        reply,
+                    })
+                    .await?
+                }
+                Step::Volume(level) => {
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Volume {
+                        name,
+                        level,
+                        reply,
+                    })
+                    .await?
+                }
+                Step::Mute(mute) => {
+                    call(tx_channel, CLIENT, |reply| ServerRequest::Mute {
+                        name,
+                        mute,
+                        reply,
+                    })
+                    .await?
+                }
+                Step::Sleep(duration) => tokio::time::sleep(duration).await,
+            }
+        }
+        Ok(())
+    }
+
+    /// `input` with a label of `device` replaced by its number.
+    fn resolve(&self, device: &str, input: Input) -> Input {
+        let number = match &input {
+            Input::Name(label) => self.dashboard.labels.get(device).and_then(|labels| {
+                labels
+                    .iter()
+                    .find(|(l, _)| l.eq_ignore_ascii_case(label))
+                    .map(|(_, n)| *n)
+            }),
+            Input::Number(_) => None,
+        };
+        number.map_or(input, Input::Number)
+    }
+
+    /// Writes the events of the server to `stream` as server-sent events, until the client
+    /// goes away.
+    async fn stream_events<S>(&self, stream: &mut S) -> std::io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut events = self.events.subscribe();
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .await?;
+        stream.flush().await?;
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+            if let Some(json) = event_json(&event) {
+                stream
+                    .write_all(format!("data: {}\n\n", json).as_bytes())
+                    .await?;
+                stream.flush().await?;
+            }
+        }
+    }
+}
+
+/// `event` for the dashboard, named as for hooks. `None` for events only of interest inside
+/// the server.
+fn event_json(event: &ServerEvent) -> Option<serde_json::Value> {
+    Some(match event {
+        ServerEvent::DevicesScanned(_) => serde_json::json!({ "event": "devices_scanned" }),
+        ServerEvent::InputSelected { device, input } => serde_json::json!({
+            "event": "input_selected",
+            "device": device,
+            "input": input.to_string(),
+        }),
+        ServerEvent::VolumeChanged { device, level } => serde_json::json!({
+            "event": "volume_changed",
+            "device": device,
+            "volume": level,
+        }),
+        ServerEvent::MuteChanged { device, mute } => serde_json::json!({
+            "event": "mute_changed",
+            "device": device,
+            "mute": mute,
+        }),
+        ServerEvent::DeviceOffline(device) => serde_json::json!({
+            "event": "device_offline",
+            "device": device,
+        }),
+        ServerEvent::SignalLost { device, input } => serde_json::json!({
+            "event": "signal_lost",
+            "device": device,
+            "input": input,
+        }),
+        ServerEvent::SignalRestored { device, input } => serde_json::json!({
+            "event": "signal_restored",
+            "device": device,
+            "input": input,
+        }),
+        ServerEvent::MaintenanceStarted(message) => serde_json::json!({
+            "event": "maintenance_started",
+            "message": message,
+        }),
+        ServerEvent::MaintenanceEnded => serde_json::json!({ "event": "maintenance_ended" }),
+        ServerEvent::Listening(_)
+        | ServerEvent::Connected(_)
+        | ServerEvent::Rejected(_)
+        | ServerEvent::HealthAlert { .. } => return None,
+    })
+}
+
+/// Whether a request with `headers` comes from a page of this server, or from no page at all,
+/// as with tools other than browsers. Browsers name the page in `Origin`, or else in `Referer`,
+/// and the server in `Host`.
+fn same_origin(headers: &[(&str, &str)]) -> bool {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+    let page = match header("Origin").or_else(|| header("Referer")) {
+        Some(page) => page,
+        None => return true,
+    };
+    // The host and port of `scheme://host:port/path`; "null" for sandboxed pages is no host.
+    let host = page
+        .split_once("://")
+        .and_then(|(_, rest)| rest.split('/').next())
+        .filter(|host| !host.is_empty());
+    match (host, header("Host")) {
+        (Some(host), Some(server)) => host.eq_ignore_ascii_case(server),
+        _ => false,
+    }
+}
+
+/// HTTP status for a call failing with `e`.
+fn status_of(e: &ControlError) -> &'static str {
+    match e {
+        ControlError::DeviceNotFound(_) => "404 Not Found",
+        ControlError::Unauthorized(_) => "403 Forbidden",
+        ControlError::InvalidInput { .. } | ControlError::MalformedInput(_) => "400 Bad Request",
+        ControlError::Busy(_) | ControlError::Maintenance(_) | ControlError::Cancelled => {
+            "503 Service Unavailable"
+        }
+        _ => "500 Internal Server Error",
+    }
+}
+
+async fn respond<S>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// `segment` of a URL path with its `%XX` escapes decoded.
+fn decode(segment: &str) -> String {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut i = 0;
+    while i < segment.len() {
+        let escaped = segment
+            .get(i..i + 3)
+            .and_then(|escape| escape.strip_prefix('%'))
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            None => {
+                bytes.push(segment.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
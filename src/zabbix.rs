@@ -0,0 +1,140 @@
+//! Devices and their readings for Zabbix: the low-level discovery of the devices with the
+//! metrics each of them has, and single readings for the items created from it, as a Zabbix
+//! agent runs them through `UserParameter`s.
+
+use crate::script::RemoteDevice;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use control_dsc::extron::{DeviceHealth, DeviceStatus, ExtronDevice};
+
+/// What can be read of a device, named as in the discovery and the item keys.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Metric {
+    /// 1 when the device answers, 0 when it does not
+    Online,
+    /// Selected input
+    Input,
+    /// Audio output volume
+    Volume,
+    /// 1 when the audio output is muted
+    Mute,
+    /// Internal temperature in degrees Celsius
+    Temperature,
+    /// 1 when the selected input has a signal
+    Signal,
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Online => "online",
+            Metric::Input => "input",
+            Metric::Volume => "volume",
+            Metric::Mute => "mute",
+            Metric::Temperature => "temperature",
+            Metric::Signal => "signal",
+        }
+    }
+
+    /// The reading of `status`, `None` for metrics the status does not have.
+    fn of_status(self, status: &DeviceStatus) -> Option<String> {
+        match self {
+            Metric::Input => Some(status.input.to_string()),
+            Metric::Volume => status.volume.map(|level| level.to_string()),
+            Metric::Mute => status.mute.map(|mute| u8::from(mute).to_string()),
+            _ => None,
+        }
+    }
+
+    /// The reading of `health`, `None` for metrics the health does not have.
+    fn of_health(self, health: &DeviceHealth) -> Option<String> {
+        match self {
+            Metric::Temperature => health.temperature.map(|celsius| celsius.to_string()),
+            Metric::Signal => health
+                .signal
+                .map(|(_, present)| u8::from(present).to_string()),
+            _ => None,
+        }
+    }
+
+    fn needs_health(self) -> bool {
+        matches!(self, Metric::Temperature | Metric::Signal)
+    }
+}
+
+/// A device to read, on this machine or through a server.
+pub trait Device {
+    fn name(&self) -> &str;
+    fn status(&self) -> Result<DeviceStatus>;
+    fn health(&self) -> Result<DeviceHealth>;
+}
+
+impl Device for ExtronDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn status(&self) -> Result<DeviceStatus> {
+        ExtronDevice::status(self).map_err(|e| e.into())
+    }
+
+    fn health(&self) -> Result<DeviceHealth> {
+        ExtronDevice::health(self).map_err(|e| e.into())
+    }
+}
+
+impl<'a> Device for RemoteDevice<'a> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn status(&self) -> Result<DeviceStatus> {
+        self.client.status(self.name).map_err(|e| e.into())
+    }
+
+    fn health(&self) -> Result<DeviceHealth> {
+        self.client.device_health(self.name).map_err(|e| e.into())
+    }
+}
+
+/// The low-level discovery of `devices`: an entry with `{#DEVICE}` and `{#METRIC}` for every
+/// metric a device has, so that templates can have an item prototype per metric. A device
+/// that does not answer has only `online`.
+pub fn discovery<D: Device>(devices: &[D]) -> serde_json::Value {
+    let mut data = Vec::new();
+    for device in devices {
+        let status = device.status().ok();
+        let health = device.health().ok();
+        for &metric in Metric::value_variants() {
+            let has = match metric {
+                Metric::Online => true,
+                _ if metric.needs_health() => health
+                    .as_ref()
+                    .and_then(|health| metric.of_health(health))
+                    .is_some(),
+                _ => status
+                    .as_ref()
+                    .and_then(|status| metric.of_status(status))
+                    .is_some(),
+            };
+            if has {
+                data.push(serde_json::json!({
+                    "{#DEVICE}": device.name(),
+                    "{#METRIC}": metric.name(),
+                }));
+            }
+        }
+    }
+    serde_json::json!({ "data": data })
+}
+
+/// The reading of `metric` on `device`, failing when the device does not answer or does not
+/// have it, which Zabbix shows on the item.
+pub fn get(device: &dyn Device, metric: Metric) -> Result<String> {
+    let value = match metric {
+        Metric::Online => Some(u8::from(device.status().is_ok()).to_string()),
+        _ if metric.needs_health() => metric.of_health(&device.health()?),
+        _ => metric.of_status(&device.status()?),
+    };
+    value.ok_or_else(|| anyhow!("{} has no {}", device.name(), metric.name()))
+}
@@ -0,0 +1,51 @@
+//! Server addresses as given on the command line and in the configuration.
+
+use control_dsc::endpoint::{self, DEFAULT_PORT};
+use std::net::SocketAddr;
+
+#[test]
+fn splits_hosts_and_ports() {
+    assert_eq!(endpoint::split("av1").unwrap(), ("av1", DEFAULT_PORT));
+    assert_eq!(endpoint::split("av1:14001").unwrap(), ("av1", 14001));
+    assert_eq!(endpoint::split("::1").unwrap(), ("::1", DEFAULT_PORT));
+    assert_eq!(endpoint::split("[::1]").unwrap(), ("::1", DEFAULT_PORT));
+    assert_eq!(endpoint::split("[::1]:14001").unwrap(), ("::1", 14001));
+    assert_eq!(endpoint::split_or("av1", 23).unwrap(), ("av1", 23));
+    assert_eq!(endpoint::split_or("av1:2023", 23).unwrap(), ("av1", 2023));
+    for invalid in &["", ":14000", "av1:", "av1:port", "[::1", "[::1]14001"] {
+        assert!(endpoint::split(invalid).is_err(), "{} accepted", invalid);
+    }
+}
+
+#[test]
+fn resolves_ip_addresses_with_the_default_port() {
+    let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+    assert_eq!(
+        endpoint::resolve("10.0.0.7").unwrap(),
+        [addr("10.0.0.7:14000")]
+    );
+    assert_eq!(
+        endpoint::resolve("[fd00::7]:14001").unwrap(),
+        [addr("[fd00::7]:14001")]
+    );
+}
+
+#[cfg(feature = "ssh")]
+#[test]
+fn parses_ssh_jump_hosts() {
+    use control_dsc::ssh::SshTunnel;
+
+    let parsed = |s: &str| s.parse::<SshTunnel>().unwrap().to_string();
+    assert_eq!(parsed("av@jump"), "av@jump");
+    assert_eq!(parsed("av@jump:22"), "av@jump");
+    assert_eq!(parsed("av@jump:2222"), "av@jump:2222");
+    assert_eq!(parsed("av@fd00::7"), "av@fd00::7");
+    assert_eq!(parsed("av@[fd00::7]:2222"), "av@[fd00::7]:2222");
+    for invalid in &["jump", "@jump", "av@", "av@jump:ssh"] {
+        assert!(
+            invalid.parse::<SshTunnel>().is_err(),
+            "{} accepted",
+            invalid
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Locks between processes, which also apply between threads of one.
+
+use control_dsc::error::ControlError;
+use control_dsc::lock;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn busy_device_fails_or_waits() {
+    // Any file the test can open stands in for the device node.
+    let path = std::env::temp_dir().join(format!("control-dsc-device-{}", std::process::id()));
+    std::fs::write(&path, b"").unwrap();
+    let device = path.to_str().unwrap().to_string();
+
+    let held = lock::lock("DSC 301 HD", &device, None).unwrap();
+    match lock::lock("DSC 301 HD", &device, None) {
+        Err(ControlError::Busy(name)) => assert_eq!(name, "DSC 301 HD"),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let waiting = {
+        let device = device.clone();
+        thread::spawn(move || lock::lock("DSC 301 HD", &device, Some(Duration::from_secs(10))))
+    };
+    thread::sleep(Duration::from_millis(200));
+    drop(held);
+    waiting.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn missing_device_is_an_error() {
+    match lock::lock("DSC 301 HD", "/nonexistent/ttyUSB9", None) {
+        Err(ControlError::Busy(_)) | Ok(_) => panic!("a missing device locked or busy"),
+        Err(_) => {}
+    }
+}
@@ -0,0 +1,83 @@
+//! Devices on the network, against a device faked on a loopback port.
+
+use control_dsc::error::ControlError;
+use control_dsc::network::NetworkDevice;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+const GREETING: &[u8] =
+    b"(c) Copyright 2024, Extron Electronics, IN1808, V1.00, 60-1081-01\r\nPassword:";
+
+/// A device that asks for `password` and answers selecting input 2. Returns its address and
+/// the thread faking it, which returns what it received once the connection is closed.
+fn fake_device(password: &'static str) -> (String, thread::JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let device = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(GREETING).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return received,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+            if received.ends_with(format!("{}\r", password).as_bytes()) {
+                stream.write_all(b"\r\nLogin Administrator\r\n").unwrap();
+            } else if received.ends_with(b"2!") {
+                stream.write_all(b"In2All\r\n").unwrap();
+            }
+        }
+    });
+    (address, device)
+}
+
+#[test]
+fn logs_in_and_selects_an_input() {
+    let (address, fake) = fake_device("secret");
+    let device = NetworkDevice::new("IN1808 hall", &address)
+        .password("secret")
+        .device();
+    assert_eq!(device.transport(), "tcp");
+    assert_eq!(device.device_path, format!("tcp:{}", address));
+    device.select(&"2".parse().unwrap()).unwrap();
+    drop(device);
+    assert_eq!(fake.join().unwrap(), b"secret\r2!");
+}
+
+#[test]
+fn keeps_the_connection_for_the_next_command() {
+    let (address, fake) = fake_device("secret");
+    let device = NetworkDevice::new("IN1808 hall", &address)
+        .password("secret")
+        .device();
+    // The fake device takes one connection only.
+    device.select(&"2".parse().unwrap()).unwrap();
+    device.clone().select(&"2".parse().unwrap()).unwrap();
+    drop(device);
+    assert_eq!(fake.join().unwrap(), b"secret\r2!2!");
+}
+
+#[test]
+fn fails_without_the_password_asked_for() {
+    let (address, fake) = fake_device("secret");
+    let device = NetworkDevice::new("IN1808 hall", &address).device();
+    let e = device.select(&"2".parse().unwrap()).unwrap_err();
+    assert!(matches!(&e, ControlError::Unauthorized(why) if why.contains("asks for a password")));
+    assert!(fake.join().unwrap().is_empty());
+}
+
+#[test]
+fn reports_devices_it_cannot_reach() {
+    // Bound and dropped, so that nothing listens on the port.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let device = NetworkDevice::new("IN1808 hall", &address).device();
+    let e = device.select(&"2".parse().unwrap()).unwrap_err();
+    assert_eq!(e.code(), "connection");
+}
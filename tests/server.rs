@@ -0,0 +1,1664 @@
+//! Runs a server on simulated devices and talks to it with the blocking client.
+
+use control_dsc::client::{AsyncClient, Client, Event};
+use control_dsc::error::ControlError;
+use control_dsc::extron::{Annotation, Genlock, Input, PipMode, SyncFormat};
+use control_dsc::health::{HealthAlert, Thresholds};
+use control_dsc::metrics::LATENCY_BUCKETS_MS;
+use control_dsc::schedule::Schedule;
+use control_dsc::server::{ServerBuilder, ServerEvent};
+use control_dsc::sim::{self, Fault, SimDevice};
+use control_dsc::sis::Plane;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+struct TestServer {
+    /// What the server finds on the next scan.
+    devices: Arc<Mutex<Vec<SimDevice>>>,
+    addr: std::net::SocketAddr,
+    client: Client,
+    cancel: CancellationToken,
+    thread: thread::JoinHandle<std::io::Result<()>>,
+}
+
+impl TestServer {
+    fn start(devices: Vec<SimDevice>) -> Self {
+        Self::start_with(devices, |builder| builder)
+    }
+
+    /// Starts a server set up further by `configure`.
+    fn start_with<F>(devices: Vec<SimDevice>, configure: F) -> Self
+    where
+        F: FnOnce(ServerBuilder) -> ServerBuilder + Send + 'static,
+    {
+        let devices = Arc::new(Mutex::new(devices));
+        let source = devices.clone();
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let (scanned_tx, scanned) = mpsc::channel();
+        let scanned_tx = Mutex::new(scanned_tx);
+        let cancel = CancellationToken::new();
+        let server_cancel = cancel.clone();
+
+        let thread = thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new()?;
+            // The devices of the test come first, before those of any other source.
+            let builder = ServerBuilder::new()
+                .device_source(move || sim::device_list(&source.lock().unwrap()));
+            runtime.block_on(
+                configure(builder)
+                    .listen("127.0.0.1:0".parse().unwrap())
+                    .on_event(move |event| match event {
+                        // Only the first address is waited for, that of the loopback listener.
+                        ServerEvent::Listening(addr) => {
+                            let _ = tx.lock().unwrap().send(*addr);
+                        }
+                        ServerEvent::DevicesScanned(_) => {
+                            let _ = scanned_tx.lock().unwrap().send(());
+                        }
+                        _ => {}
+                    })
+                    .cancel_token(server_cancel)
+                    .serve(),
+            )
+        });
+
+        let addr = rx.recv().expect("server did not start");
+        // Until the first scan is over, the server only lists what it found so far.
+        scanned.recv().expect("server did not scan");
+        let client = Client::with_servers(&addr.to_string()).unwrap().retries(0);
+        TestServer {
+            devices,
+            addr,
+            client,
+            cancel,
+            thread,
+        }
+    }
+
+    fn stop(self) {
+        self.client.stop().unwrap();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+fn input(s: &str) -> Input {
+    s.parse().unwrap()
+}
+
+fn scaler() -> SimDevice {
+    SimDevice::new("DSC 301 HD", &["HDMI", "DisplayPort", "VGA"]).with_audio()
+}
+
+#[test]
+fn lists_devices() {
+    let server = TestServer::start(vec![scaler(), SimDevice::new("SW4", &["A", "B"])]);
+    let mut names: Vec<_> = server
+        .client
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["DSC 301 HD", "SW4"]);
+    server.stop();
+}
+
+#[test]
+fn tells_devices_with_the_same_name_apart() {
+    let first = scaler();
+    let second = scaler();
+    let server = TestServer::start(vec![first.clone(), second.clone()]);
+    let mut names: Vec<_> = server
+        .client
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["DSC 301 HD", "DSC 301 HD#2"]);
+
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.select("DSC 301 HD#2", &input("3")).unwrap();
+    assert_eq!(first.input(), 2);
+    assert_eq!(second.input(), 3);
+    server.stop();
+}
+
+#[test]
+fn uses_only_the_devices_on_its_ports() {
+    let server = TestServer::start_with(
+        vec![scaler(), SimDevice::new("SW4", &["A", "B"])],
+        |builder| builder.device_ports(glob::Pattern::new("sim:SW*").unwrap()),
+    );
+    let names: Vec<_> = server
+        .client
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    assert_eq!(names, ["SW4"]);
+    assert!(server.client.select("DSC 301 HD", &input("2")).is_err());
+    server.stop();
+}
+
+#[test]
+fn lists_devices_a_page_at_a_time() {
+    let mut devices: Vec<_> = (1..=5)
+        .map(|n| SimDevice::new(&format!("SW{}", n), &["A", "B"]))
+        .collect();
+    devices.push(scaler());
+    let server = TestServer::start(devices);
+    let (page, total) = server.client.list_page("SW*", 1, 3).unwrap();
+    let names: Vec<_> = page.into_iter().map(|d| d.name).collect();
+    assert_eq!(names, ["SW2", "SW3", "SW4"]);
+    assert_eq!(total, 5);
+    assert!(server.client.list_page("[", 0, 0).is_err());
+    server.stop();
+}
+
+#[test]
+fn finds_devices_by_alias_and_path() {
+    let device = scaler();
+    let server = TestServer::start_with(vec![device.clone()], |builder| {
+        builder.alias("lobby", "DSC 301 HD")
+    });
+    server.client.select("lobby", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    server.client.select("sim:DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(device.input(), 3);
+    server.stop();
+}
+
+#[test]
+fn selects_input_by_number_and_name() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    server.client.select("DSC 301 HD", &input("VGA")).unwrap();
+    assert_eq!(device.input(), 3);
+    server.stop();
+}
+
+#[test]
+fn selects_audio_apart_from_video() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    server
+        .client
+        .select_plane("DSC 301 HD", &input("2"), Plane::Audio)
+        .unwrap();
+    assert_eq!((device.input(), device.audio_input()), (1, 2));
+    server
+        .client
+        .select_plane("DSC 301 HD", &input("VGA"), Plane::Video)
+        .unwrap();
+    assert_eq!((device.input(), device.audio_input()), (3, 2));
+    server.client.select("DSC 301 HD", &input("1")).unwrap();
+    assert_eq!((device.input(), device.audio_input()), (1, 1));
+    server.stop();
+}
+
+#[test]
+fn toggles_back_to_the_previous_input() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    assert!(server.client.toggle_input("DSC 301 HD").is_err());
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.select("DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(
+        server.client.toggle_input("DSC 301 HD").unwrap(),
+        input("2")
+    );
+    assert_eq!(device.input(), 2);
+    assert_eq!(
+        server.client.toggle_input("DSC 301 HD").unwrap(),
+        input("3")
+    );
+    assert_eq!(device.input(), 3);
+    // Switching on the front panel counts too, the history has the server hear it.
+    device.announce("In1All");
+    server.client.history("DSC 301 HD").unwrap();
+    assert_eq!(
+        server.client.toggle_input("DSC 301 HD").unwrap(),
+        input("3")
+    );
+    match server.client.toggle_input("nope") {
+        Err(ControlError::DeviceNotFound(name)) => assert_eq!(name, "nope"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn reports_unknown_device() {
+    let server = TestServer::start(vec![scaler()]);
+    match server.client.select("nope", &input("1")) {
+        Err(ControlError::DeviceNotFound(name)) => assert_eq!(name, "nope"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn selects_input_by_configured_label() {
+    let device = scaler();
+    let labels = vec![("laptop".to_string(), 2), ("VGA".to_string(), 1)];
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder
+            .alias("lobby", "DSC 301 HD")
+            .input_labels("lobby", labels.into_iter().collect())
+    });
+    server
+        .client
+        .select("DSC 301 HD", &input("Laptop"))
+        .unwrap();
+    assert_eq!(device.input(), 2);
+    // Labels go before the names stored in the device.
+    server.client.select("DSC 301 HD", &input("VGA")).unwrap();
+    assert_eq!(device.input(), 1);
+
+    let devices = server.client.list().unwrap();
+    assert_eq!(devices[0].labels()["laptop"], 2);
+    assert_eq!(devices[0].label_of(1), Some("VGA"));
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    let status = server.client.status("DSC 301 HD").unwrap();
+    assert_eq!(status.label.as_deref(), Some("laptop"));
+    server.stop();
+}
+
+#[test]
+fn reports_invalid_input() {
+    let server = TestServer::start(vec![scaler()]);
+    let e = server.client.select("DSC 301 HD", &input("9")).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Input 9 is out of range on DSC 301 HD (3 inputs)"
+    );
+    match e {
+        ControlError::InvalidInput {
+            device,
+            input,
+            code,
+            inputs,
+        } => {
+            assert_eq!(device, "DSC 301 HD");
+            assert_eq!(input, "9");
+            // Counted when the server found it, so refused without asking the device.
+            assert_eq!(code, None);
+            assert_eq!(inputs, Some(3));
+        }
+        other => panic!("unexpected error {:?}", other),
+    }
+    match server.client.select("DSC 301 HD", &input("S-Video")) {
+        Err(ControlError::InvalidInput { input, .. }) => assert_eq!(input, "S-Video"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match server.client.select("DSC 301 HD", &input("4")) {
+        Err(ControlError::InvalidInput { code: None, .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn rejects_malformed_inputs() {
+    for garbage in &["", "0", "65", "1!;rm", "\x1bCN", "In 1\r"] {
+        assert!(garbage.parse::<Input>().is_err(), "{:?} accepted", garbage);
+    }
+}
+
+#[test]
+fn sets_volume_and_mute() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    server.client.set_volume("DSC 301 HD", 30).unwrap();
+    server.client.set_mute("DSC 301 HD", true).unwrap();
+    assert_eq!(device.volume(), Some(30));
+    assert_eq!(device.mute(), Some(true));
+
+    let status = server.client.status("DSC 301 HD").unwrap();
+    assert_eq!(status.input, 1);
+    assert_eq!(status.volume, Some(30));
+    assert_eq!(status.mute, Some(true));
+    server.stop();
+}
+
+#[test]
+fn reports_missing_audio() {
+    let server = TestServer::start(vec![SimDevice::new("SW4", &["A", "B"])]);
+    let status = server.client.status("SW4").unwrap();
+    assert_eq!(status.volume, None);
+    assert_eq!(status.mute, None);
+    match server.client.set_mute("SW4", true) {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn powers_the_display_over_cec() {
+    let device = scaler().with_cec();
+    let server = TestServer::start(vec![device.clone(), SimDevice::new("SW4", &["A", "B"])]);
+    server.client.set_display_power("DSC 301 HD", true).unwrap();
+    assert_eq!(device.display(), Some(true));
+    server
+        .client
+        .set_display_power("DSC 301 HD", false)
+        .unwrap();
+    assert_eq!(device.display(), Some(false));
+    match server.client.set_display_power("SW4", true) {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn passes_commands_on_to_the_display() {
+    let device = scaler().with_display_port("PWR=01\r");
+    let server = TestServer::start(vec![device.clone(), SimDevice::new("SW4", &["A", "B"])]);
+    let answer = server
+        .client
+        .send_to_display("DSC 301 HD", b"PWR ON\r")
+        .unwrap();
+    assert_eq!(answer, b"PWR=01\r");
+    assert_eq!(device.display_received(), Some(b"PWR ON\r".to_vec()));
+    match server.client.send_to_display("SW4", b"PWR ON\r") {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn recalls_video_wall_presets() {
+    let processor = SimDevice::new("Quantum", &["A", "B", "C", "D"]).with_presets(&[4, 2]);
+    let server = TestServer::start(vec![processor.clone(), scaler()]);
+    assert_eq!(server.client.wall_layout("Quantum").unwrap().preset, 0);
+    server.client.recall_preset("Quantum", 2).unwrap();
+    assert_eq!(processor.preset(), 2);
+    let layout = server.client.wall_layout("Quantum").unwrap();
+    assert_eq!((layout.preset, layout.windows), (2, 2));
+    for result in [
+        server.client.recall_preset("Quantum", 3),
+        server.client.recall_preset("DSC 301 HD", 1),
+    ] {
+        match result {
+            Err(ControlError::Unsupported(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    server.stop();
+}
+
+#[test]
+fn recalls_window_layouts() {
+    let processor = SimDevice::new("MGP", &["A", "B", "C"]).with_presets(&[4, 2]);
+    let server = TestServer::start(vec![processor.clone(), scaler()]);
+    let windows = server.client.recall_layout("MGP", 1).unwrap();
+    assert_eq!(windows.layout, 1);
+    assert_eq!(windows.inputs, processor.windows());
+    assert_eq!(windows.inputs.len(), 4);
+    match server.client.recall_layout("DSC 301 HD", 1) {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn controls_annotators() {
+    let annotator = SimDevice::new("Annotator 300", &["HDMI"]).with_annotator();
+    let server = TestServer::start(vec![annotator.clone(), scaler()]);
+    server
+        .client
+        .annotate("Annotator 300", Annotation::Pointer)
+        .unwrap();
+    server
+        .client
+        .annotate("Annotator 300", Annotation::Freeze(true))
+        .unwrap();
+    server
+        .client
+        .annotate("Annotator 300", Annotation::Clear)
+        .unwrap();
+    let state = annotator.annotator().unwrap();
+    assert_eq!((state.tool, state.frozen, state.clears), (1, true, 1));
+    match server.client.annotate("DSC 301 HD", Annotation::Clear) {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn assigns_picture_in_picture_sources() {
+    let scaler = SimDevice::new("DSC 301 HD", &["HDMI", "DP", "VGA"]).with_pip();
+    let server = TestServer::start(vec![scaler.clone()]);
+    let status = server.client.status("DSC 301 HD").unwrap();
+    assert_eq!((status.pip, status.sources.len()), (Some(PipMode::Off), 0));
+    server
+        .client
+        .set_pip_mode("DSC 301 HD", PipMode::Pip)
+        .unwrap();
+    server
+        .client
+        .assign_source("DSC 301 HD", 2, &Input::Name("VGA".to_string()))
+        .unwrap();
+    let status = server.client.status("DSC 301 HD").unwrap();
+    assert_eq!(status.pip, Some(PipMode::Pip));
+    assert_eq!(status.sources, vec![1, 3]);
+    assert_eq!(scaler.pip(), Some(1));
+    match server
+        .client
+        .assign_source("DSC 301 HD", 3, &Input::Number(1))
+    {
+        Err(ControlError::InvalidInput { .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn sets_output_sync() {
+    let scaler = SimDevice::new("DSC 301 HD", &["HDMI", "DP", "VGA"]).with_sync();
+    let server = TestServer::start(vec![scaler.clone()]);
+    server
+        .client
+        .set_sync_format("DSC 301 HD", SyncFormat::TriLevel)
+        .unwrap();
+    server
+        .client
+        .set_genlock("DSC 301 HD", Genlock::SelectedInput)
+        .unwrap();
+    let sync = server.client.sync("DSC 301 HD").unwrap();
+    assert_eq!(
+        (sync.format, sync.genlock),
+        (SyncFormat::TriLevel, Genlock::SelectedInput)
+    );
+    assert_eq!(scaler.sync(), Some((3, 1)));
+    match server.client.set_genlock("DSC 301 HD", Genlock::Reference) {
+        Err(ControlError::Unsupported(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn rescan_finds_new_devices() {
+    let server = TestServer::start(vec![scaler()]);
+    assert_eq!(server.client.list().unwrap().len(), 1);
+    server
+        .devices
+        .lock()
+        .unwrap()
+        .push(SimDevice::new("SW4", &["A", "B"]));
+    server.client.rescan().unwrap();
+    assert_eq!(server.client.list().unwrap().len(), 2);
+    server.client.select("SW4", &input("B")).unwrap();
+    server.stop();
+}
+
+/// Starts a server whose scans hang after finding `devices` for as long as the returned flag
+/// is set.
+fn start_slow(devices: Vec<SimDevice>) -> (TestServer, Arc<AtomicBool>) {
+    let slow = Arc::new(AtomicBool::new(false));
+    let held = slow.clone();
+    let server = TestServer::start_with(devices, move |builder| {
+        builder.device_source(move || {
+            while held.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            sim::device_list(&[])
+        })
+    });
+    (server, slow)
+}
+
+#[test]
+fn lists_the_devices_found_so_far_while_scanning() {
+    let (server, slow) = start_slow(vec![scaler()]);
+    assert!(!server.client.list_scanning().unwrap().1);
+    server
+        .devices
+        .lock()
+        .unwrap()
+        .push(SimDevice::new("SW4", &["A", "B"]));
+    slow.store(true, Ordering::SeqCst);
+    let addr = server.addr.to_string();
+    let rescan = thread::spawn(move || Client::with_servers(&addr).unwrap().rescan());
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let listed = loop {
+        let (devices, scanning) = server.client.list_scanning().unwrap();
+        if scanning && devices.len() == 2 {
+            break devices;
+        }
+        assert!(Instant::now() < deadline, "the scan was not listed");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(listed.iter().any(|device| device.name == "SW4"));
+
+    slow.store(false, Ordering::SeqCst);
+    rescan.join().unwrap().unwrap();
+    let (devices, scanning) = server.client.list_scanning().unwrap();
+    assert_eq!((devices.len(), scanning), (2, false));
+    server.stop();
+}
+
+#[test]
+fn serves_the_devices_a_background_rescan_leaves_alone() {
+    let device = scaler();
+    let (server, slow) = start_slow(vec![device.clone()]);
+    server
+        .devices
+        .lock()
+        .unwrap()
+        .push(SimDevice::new("SW4", &["A", "B"]));
+    slow.store(true, Ordering::SeqCst);
+    let ticket = server.client.start_rescan().unwrap();
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    assert!(!server.client.scan_done(&ticket).unwrap());
+
+    slow.store(false, Ordering::SeqCst);
+    server.client.wait_scan(&ticket).unwrap();
+    assert!(server.client.scan_done(&ticket).unwrap());
+    server.client.select("SW4", &input("B")).unwrap();
+    server.stop();
+}
+
+#[test]
+fn reports_device_timeout() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    device.set_fault(Some(Fault::Timeout));
+    match server.client.select("DSC 301 HD", &input("2")) {
+        Err(ControlError::Timeout(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    device.set_fault(None);
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.stop();
+}
+
+#[test]
+fn reports_disconnected_device() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    device.set_fault(Some(Fault::Disconnect));
+    match server.client.set_volume("DSC 301 HD", 10) {
+        Err(ControlError::SerialIo(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.stop();
+}
+
+#[test]
+fn select_recovers_from_unplugging() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+
+    // Plugged in again, so that it comes back on another port.
+    device.set_fault(Some(Fault::Disconnect));
+    let replugged = scaler();
+    server.devices.lock().unwrap().push(replugged.clone());
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(replugged.input(), 2);
+
+    replugged.set_fault(Some(Fault::Disconnect));
+    match server.client.select("DSC 301 HD", &input("3")) {
+        Err(ControlError::DeviceNotFound(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(server.client.list().unwrap().is_empty());
+    server.stop();
+}
+
+#[test]
+fn reports_events_to_subscribers() {
+    let server = TestServer::start(vec![scaler()]);
+    let events = server.client.events().unwrap();
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.set_mute("DSC 301 HD", true).unwrap();
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::InputSelected {
+            device: "DSC 301 HD".to_string(),
+            input: input("2"),
+        }
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::MuteChanged {
+            device: "DSC 301 HD".to_string(),
+            mute: true,
+        }
+    );
+    server.stop();
+}
+
+#[test]
+fn watching_survives_a_server_restart() {
+    let server = TestServer::start(vec![scaler()]);
+    let addr = server.addr;
+    let watcher = Client::with_servers(&addr.to_string()).unwrap();
+    let events = watcher.watch().unwrap();
+    server.stop();
+
+    let server = TestServer::start_with(vec![scaler()], move |builder| builder.listen(addr));
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(10)).unwrap(),
+        Event::Reconnected
+    );
+    // The subscription is made again just after.
+    let selected = (0..50).any(|_| {
+        server.client.select("DSC 301 HD", &input("2")).unwrap();
+        matches!(
+            events.recv_timeout(Duration::from_millis(100)),
+            Ok(Event::InputSelected { .. })
+        )
+    });
+    assert!(selected);
+    server.stop();
+}
+
+#[test]
+fn learns_the_schema_version_of_the_server() {
+    let server = TestServer::start(vec![scaler()]);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let version = tokio::task::LocalSet::new().block_on(&runtime, async {
+        let client = AsyncClient::connect(server.addr).await.unwrap();
+        client.server_version().await
+    });
+    assert_eq!(version, Some(control_dsc::SCHEMA_VERSION));
+    server.stop();
+}
+
+#[test]
+fn lists_and_disconnects_clients() {
+    let server = TestServer::start(vec![scaler()]);
+    let other = Client::with_servers(&server.addr.to_string())
+        .unwrap()
+        .retries(0);
+    other.list().unwrap();
+    other.select("SW4", &input("1")).unwrap_err();
+
+    let sessions = server.client.sessions().unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions.iter().filter(|session| session.caller).count(), 1);
+    let session = sessions.iter().find(|session| !session.caller).unwrap();
+    assert_eq!(session.version, control_dsc::SCHEMA_VERSION);
+    assert!(session.calls >= 2, "{:?}", session);
+    assert_eq!(session.errors, 1);
+
+    server.client.disconnect(&session.peer).unwrap();
+    other.list().unwrap_err();
+    let gone = (0..50).any(|_| {
+        thread::sleep(Duration::from_millis(20));
+        server.client.sessions().unwrap().len() == 1
+    });
+    assert!(gone);
+
+    let e = server.client.disconnect(&session.peer).unwrap_err();
+    assert!(e.to_string().contains("No connection"), "{}", e);
+    server.stop();
+}
+
+/// An address of this machine other than a loopback one, if it has any. Nothing is sent.
+fn outside_address() -> Option<std::net::IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    Some(ip).filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+}
+
+#[test]
+fn serves_administrative_calls_only_locally_with_local_admin() {
+    let outside = match outside_address() {
+        Some(ip) => ip,
+        None => {
+            eprintln!("No address but loopback, skipping");
+            return;
+        }
+    };
+    let listener = std::net::TcpListener::bind((outside, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let device = scaler();
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder.local_admin().listen_on(listener)
+    });
+    let remote = Client::with_servers(&addr.to_string()).unwrap().retries(0);
+
+    assert!(matches!(remote.rescan(), Err(ControlError::NotAllowed(_))));
+    assert!(matches!(
+        remote.send_raw("DSC 301 HD", b"I"),
+        Err(ControlError::NotAllowed(_))
+    ));
+    assert!(remote.stop().is_err());
+    remote.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    server.client.rescan().unwrap();
+    server.stop();
+}
+
+#[cfg(unix)]
+#[test]
+fn requires_tokens_and_keeps_them_to_their_scope_and_devices() {
+    let dir = std::env::temp_dir().join(format!("control-dsc-tokens-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (tokens, socket) = (dir.join("tokens.json"), dir.join("admin.sock"));
+    let _ = std::fs::remove_file(&tokens);
+
+    let device = scaler();
+    let admin_socket = socket.clone();
+    let server = TestServer::start_with(
+        vec![device.clone(), SimDevice::new("SW4", &["A", "B"])],
+        move |builder| builder.tokens(tokens).admin_socket(admin_socket),
+    );
+    assert!(matches!(
+        server.client.list(),
+        Err(ControlError::Unauthorized(_))
+    ));
+
+    let admin = Client::with_socket(&socket).unwrap();
+    let (reader, read_secret) = admin
+        .create_token(control_dsc::tokens::Scope::Read, &[])
+        .unwrap();
+    let (_, control_secret) = admin
+        .create_token(control_dsc::tokens::Scope::Control, &["DSC*".to_string()])
+        .unwrap();
+    assert_eq!(admin.list_tokens().unwrap().len(), 2);
+
+    let addr = server.addr.to_string();
+    let read = Client::with_servers(&addr)
+        .unwrap()
+        .retries(0)
+        .token(&read_secret);
+    assert_eq!(read.list().unwrap().len(), 2);
+    assert!(matches!(
+        read.select("DSC 301 HD", &input("2")),
+        Err(ControlError::Unauthorized(_))
+    ));
+    assert!(matches!(read.rescan(), Err(ControlError::Unauthorized(_))));
+
+    let control = Client::with_servers(&addr)
+        .unwrap()
+        .retries(0)
+        .token(&control_secret);
+    let names: Vec<_> = control
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    assert_eq!(names, ["DSC 301 HD"]);
+    control.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    assert!(matches!(
+        control.status("SW4"),
+        Err(ControlError::Unauthorized(_))
+    ));
+    // Devices are checked by name, whichever key the client knows them by.
+    control.status("sim:DSC 301 HD").unwrap();
+    assert!(matches!(
+        control.status("sim:SW4"),
+        Err(ControlError::Unauthorized(_))
+    ));
+
+    admin.revoke_token(&reader.id).unwrap();
+    assert!(read.list().is_err());
+    assert_eq!(admin.list_tokens().unwrap().len(), 1);
+
+    server.cancel.cancel();
+    server.thread.join().unwrap().unwrap();
+    assert!(!socket.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn refuses_tokens_with_invalid_device_patterns() {
+    let path = std::env::temp_dir().join(format!(
+        "control-dsc-bad-tokens-{}.json",
+        std::process::id()
+    ));
+    let token = r#"[{"id": "1234", "scope": "control", "devices": ["DSC[301"],
+        "created": 0, "hash": "00"}]"#;
+    std::fs::write(&path, token).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(
+        ServerBuilder::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .tokens(path.clone())
+            .serve(),
+    );
+    let e = result.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("DSC[301"), "{}", e);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refuses_tokens_with_an_unguarded_dashboard() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(
+        ServerBuilder::new()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .listen_metrics("127.0.0.1:0".parse().unwrap())
+            .dashboard(Default::default())
+            .tokens(std::env::temp_dir().join("control-dsc-unused-tokens.json"))
+            .serve(),
+    );
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn serves_tls_and_tells_clients_apart_by_their_certificates() {
+    use control_dsc::tls::{ClientTls, ServerTls};
+    use control_dsc::tokens::Scope;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair,
+    };
+    use std::path::PathBuf;
+
+    let dir = std::env::temp_dir().join(format!("control-dsc-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    let ca_path = dir.join("ca.pem");
+    std::fs::write(&ca_path, ca.pem()).unwrap();
+    // A certificate the CA signed for `name`, and its key.
+    let issue = |name: &str, purpose| {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![purpose];
+        let certificate = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let paths = (
+            dir.join(format!("{}.pem", name)),
+            dir.join(format!("{}.key", name)),
+        );
+        std::fs::write(&paths.0, certificate.pem()).unwrap();
+        std::fs::write(&paths.1, key.serialize_pem()).unwrap();
+        paths
+    };
+    let (cert, key) = issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+    let hall = issue("panel-hall", ExtendedKeyUsagePurpose::ClientAuth);
+    let lobby = issue("panel-lobby", ExtendedKeyUsagePurpose::ClientAuth);
+    let console = issue("console", ExtendedKeyUsagePurpose::ClientAuth);
+
+    let tls = ServerTls::new(&cert, &key, Some(&ca_path))
+        .unwrap()
+        .allow(
+            "panel-hall",
+            Scope::Control,
+            vec![glob::Pattern::new("DSC*").unwrap()],
+        )
+        .allow("console", Scope::Admin, Vec::new());
+    let device = scaler();
+    let server = TestServer::start_with(
+        vec![device.clone(), SimDevice::new("SW4", &["A", "B"])],
+        move |builder| builder.tls(tls),
+    );
+    assert!(server.client.list().is_err());
+
+    let addr = server.addr.to_string();
+    let connect = |identity: Option<&(PathBuf, PathBuf)>| {
+        let identity = identity.map(|(cert, key)| (cert.as_path(), key.as_path()));
+        let tls = ClientTls::new(&ca_path, identity)
+            .unwrap()
+            .server_name("localhost");
+        Client::with_servers(&addr).unwrap().retries(0).tls(tls)
+    };
+    assert!(connect(None).list().is_err());
+
+    let hall = connect(Some(&hall));
+    let names: Vec<_> = hall.list().unwrap().into_iter().map(|d| d.name).collect();
+    assert_eq!(names, ["DSC 301 HD"]);
+    hall.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(device.input(), 2);
+    assert!(matches!(
+        hall.status("SW4"),
+        Err(ControlError::Unauthorized(_))
+    ));
+
+    // Signed by the CA, but not allowed anything.
+    let lobby = connect(Some(&lobby));
+    assert!(matches!(lobby.list(), Err(ControlError::Unauthorized(_))));
+
+    let console = connect(Some(&console));
+    let identities: Vec<_> = console
+        .sessions()
+        .unwrap()
+        .into_iter()
+        .filter_map(|session| session.identity)
+        .collect();
+    assert!(identities.contains(&"panel-hall".to_string()));
+    assert!(identities.contains(&"console".to_string()));
+
+    server.cancel.cancel();
+    server.thread.join().unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stops_on_cancel() {
+    let server = TestServer::start(vec![scaler()]);
+    server.client.list().unwrap();
+    server.cancel.cancel();
+    server.thread.join().unwrap().unwrap();
+    assert!(server.client.list().is_err());
+}
+
+#[test]
+fn restores_the_saved_state_on_start() {
+    let path = std::env::temp_dir().join(format!("control-dsc-state-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let state_file = path.clone();
+    let server = TestServer::start_with(vec![scaler()], move |builder| {
+        builder.state_file(state_file)
+    });
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.set_volume("DSC 301 HD", 40).unwrap();
+    server.stop();
+
+    // A fresh device, as after a power cycle.
+    let device = scaler();
+    let state_file = path.clone();
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder.state_file(state_file).restore_state()
+    });
+    let status = server.client.status("DSC 301 HD").unwrap();
+    assert_eq!(status.input, 2);
+    assert_eq!(status.volume, Some(40));
+    assert_eq!(device.input(), 2);
+    server.stop();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn keeps_the_journal_and_what_it_learned_across_restarts() {
+    let path =
+        std::env::temp_dir().join(format!("control-dsc-journal-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let device = scaler();
+    let state_file = path.clone();
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder.state_file(state_file)
+    });
+    device.announce("In3All");
+    server.client.device_log("DSC 301 HD").unwrap();
+    // Counted when the server found it.
+    assert!(matches!(
+        server.client.select("DSC 301 HD", &input("9")),
+        Err(ControlError::InvalidInput {
+            code: None,
+            inputs: Some(3),
+            ..
+        })
+    ));
+    server.stop();
+
+    let state_file = path.clone();
+    let server = TestServer::start_with(vec![scaler()], move |builder| {
+        builder.state_file(state_file)
+    });
+    let log = server.client.device_log("DSC 301 HD").unwrap();
+    let texts: Vec<_> = log.iter().map(|message| message.text.as_str()).collect();
+    assert_eq!(texts, ["In3All"]);
+    // Taken from the file rather than counted again.
+    assert!(matches!(
+        server.client.select("DSC 301 HD", &input("9")),
+        Err(ControlError::InvalidInput {
+            code: None,
+            inputs: Some(3),
+            ..
+        })
+    ));
+    server.stop();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn goes_back_to_the_schedule_after_a_hold() {
+    let device = scaler();
+    let schedule = Schedule {
+        device: "DSC 301 HD".to_string(),
+        periods: Vec::new(),
+        otherwise: Some(input("2")),
+    };
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder.schedule(schedule)
+    });
+    assert_eq!(server.client.status("DSC 301 HD").unwrap().input, 2);
+
+    server
+        .client
+        .hold("DSC 301 HD", Some(Duration::from_secs(600)))
+        .unwrap();
+    server.client.select("DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(device.input(), 3);
+    server.client.hold("DSC 301 HD", None).unwrap();
+    assert_eq!(device.input(), 2);
+
+    let e = server.client.hold("SW4", None).unwrap_err();
+    assert!(matches!(e, ControlError::Unsupported(_)), "{:?}", e);
+    server.stop();
+}
+
+#[test]
+fn refuses_control_commands_during_maintenance() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    let events = server.client.events().unwrap();
+    server
+        .client
+        .set_maintenance(Some("Rewiring rack 2"))
+        .unwrap();
+
+    let e = server.client.select("DSC 301 HD", &input("3")).unwrap_err();
+    assert!(
+        matches!(&e, ControlError::Maintenance(message) if message == "Rewiring rack 2"),
+        "{:?}",
+        e
+    );
+    assert_eq!(e.to_string(), "Maintenance in progress: Rewiring rack 2");
+    let e = server.client.set_volume("DSC 301 HD", 20).unwrap_err();
+    assert!(matches!(e, ControlError::Maintenance(_)), "{:?}", e);
+    assert_eq!(device.input(), 1);
+    assert_eq!(server.client.status("DSC 301 HD").unwrap().input, 1);
+
+    server.client.set_maintenance(None).unwrap();
+    server.client.select("DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(device.input(), 3);
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::MaintenanceStarted {
+            message: "Rewiring rack 2".to_string(),
+        }
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::MaintenanceEnded
+    );
+    server.stop();
+}
+
+#[test]
+fn selects_on_a_group_of_devices() {
+    let (hall, overflow) = (scaler(), SimDevice::new("Overflow", &["A", "B"]));
+    let server = TestServer::start(vec![hall.clone(), overflow.clone()]);
+    let names = ["DSC 301 HD", "Overflow", "Gone"].map(String::from);
+    let results = server.client.select_group(&names, &input("2")).unwrap();
+
+    assert_eq!(results.len(), 3);
+    assert!(results[0].1.is_ok() && results[1].1.is_ok());
+    assert!(matches!(results[2].1, Err(ControlError::DeviceNotFound(_))));
+    assert_eq!((hall.input(), overflow.input()), (2, 2));
+
+    let results = server
+        .client
+        .select_group_plane(&names[..2], &input("1"), Plane::Audio)
+        .unwrap();
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+    assert_eq!((hall.input(), hall.audio_input()), (2, 1));
+    assert_eq!((overflow.input(), overflow.audio_input()), (2, 1));
+    server.stop();
+}
+
+#[test]
+fn passes_raw_commands_to_the_device() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    let reply = server.client.send_raw("DSC 301 HD", b"2!").unwrap();
+    assert_eq!(reply, b"In2All\r\n");
+    assert_eq!(device.input(), 2);
+    let reply = server.client.send_raw("DSC 301 HD", b"\x1bCN\r").unwrap();
+    assert_eq!(reply, b"DSC 301 HD\r\n");
+    server.stop();
+}
+
+#[test]
+fn keeps_who_changed_the_input() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    assert!(server.client.history("DSC 301 HD").unwrap().is_empty());
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    device.announce("In3All");
+    // Switching the audio apart leaves the input of the device.
+    device.announce("In1Aud");
+    let history = server.client.history("DSC 301 HD").unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!((&history[0].from, &history[0].to), (&None, &input("2")));
+    assert!(history[0].by.starts_with("127.0.0.1:"));
+    assert_eq!(
+        (&history[1].from, &history[1].to),
+        (&Some(input("2")), &input("3"))
+    );
+    assert_eq!(history[1].by, "front panel");
+    assert!(history[0].time <= history[1].time);
+    assert!(matches!(
+        server.client.history("nope"),
+        Err(ControlError::DeviceNotFound(_))
+    ));
+    server.stop();
+}
+
+#[test]
+fn undoes_the_last_change() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.select("DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(server.client.undo("DSC 301 HD").unwrap(), "input 2");
+    assert_eq!(device.input(), 2);
+    // Only once.
+    assert!(server.client.undo("DSC 301 HD").is_err());
+
+    server.client.set_volume("DSC 301 HD", 30).unwrap();
+    server.client.set_volume("DSC 301 HD", 60).unwrap();
+    assert_eq!(server.client.undo("DSC 301 HD").unwrap(), "volume 30");
+    assert_eq!(device.volume(), Some(30));
+
+    // The front panel leaves nothing to undo.
+    server.client.select("DSC 301 HD", &input("1")).unwrap();
+    device.announce("In3All");
+    server.client.history("DSC 301 HD").unwrap();
+    assert!(server.client.undo("DSC 301 HD").is_err());
+    assert!(matches!(
+        server.client.undo("nope"),
+        Err(ControlError::DeviceNotFound(_))
+    ));
+    server.stop();
+}
+
+#[test]
+fn undoes_only_within_the_window() {
+    let device = scaler();
+    let server = TestServer::start_with(vec![device.clone()], |builder| {
+        builder.undo_window(Duration::from_millis(50))
+    });
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.client.select("DSC 301 HD", &input("3")).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let e = server.client.undo("DSC 301 HD").unwrap_err();
+    assert!(e.to_string().contains("Nothing changed on DSC 301 HD"));
+    assert_eq!(device.input(), 3);
+    server.stop();
+}
+
+#[test]
+fn keeps_what_devices_say_on_their_own() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    assert!(server.client.device_log("DSC 301 HD").unwrap().is_empty());
+    device.announce("In3All");
+    // Found while talking to the device, or by listening when the log is asked for.
+    server.client.status("DSC 301 HD").unwrap();
+    device.announce("E13");
+    let log = server.client.device_log("DSC 301 HD").unwrap();
+    let texts: Vec<_> = log.iter().map(|message| message.text.as_str()).collect();
+    assert_eq!(texts, ["In3All", "E13"]);
+    assert!(log[0].time <= log[1].time);
+    assert!(matches!(
+        server.client.device_log("nope"),
+        Err(ControlError::DeviceNotFound(_))
+    ));
+    server.stop();
+}
+
+/// The response to an HTTP request for `path` with `method` on `addr`, head and body.
+fn http(addr: std::net::SocketAddr, method: &str, path: &str) -> String {
+    http_with(addr, method, path, "")
+}
+
+/// The response to an HTTP request as with [`http`], with the header lines `headers`.
+fn http_with(addr: std::net::SocketAddr, method: &str, path: &str, headers: &str) -> String {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        method, path, headers
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+    http(addr, "GET", path)
+}
+
+#[test]
+fn counts_latencies_errors_and_reconnects() {
+    let device = scaler();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder
+            .listen_metrics("127.0.0.1:0".parse().unwrap())
+            .on_event(move |event| {
+                if let ServerEvent::Listening(addr) = event {
+                    tx.lock().unwrap().send(*addr).unwrap();
+                }
+            })
+    });
+    let metrics_addr = rx.iter().find(|addr| *addr != server.addr).unwrap();
+
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    // Looked up by name, which walks the inputs until the device answers E01.
+    assert!(server.client.select("DSC 301 HD", &input("DVI")).is_err());
+    device.set_fault(Some(Fault::Timeout));
+    assert!(server.client.select("DSC 301 HD", &input("3")).is_err());
+    device.set_fault(None);
+
+    let unplugged: Vec<_> = server.devices.lock().unwrap().drain(..).collect();
+    server.client.rescan().unwrap();
+    let stats = server.client.stats().unwrap();
+    assert_eq!(stats.len(), 1);
+    assert!(!stats[0].online);
+    *server.devices.lock().unwrap() = unplugged;
+    server.client.rescan().unwrap();
+
+    let stats = server.client.stats().unwrap();
+    let stats = &stats[0];
+    assert_eq!(stats.name, "DSC 301 HD");
+    assert!(stats.online);
+    assert_eq!(stats.latencies.len(), LATENCY_BUCKETS_MS.len() + 1);
+    assert!(stats.answered() >= 2);
+    assert!(stats.errors[&1] >= 1);
+    assert!(stats.timeouts >= 1);
+    assert_eq!(stats.reconnects, 1);
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    for line in &[
+        "control_dsc_device_up{device=\"DSC 301 HD\"} 1\n",
+        "control_dsc_device_errors_total{device=\"DSC 301 HD\",code=\"E01\"} ",
+        "control_dsc_device_reconnects_total{device=\"DSC 301 HD\"} 1\n",
+        "control_dsc_command_duration_seconds_bucket{device=\"DSC 301 HD\",le=\"+Inf\"} ",
+    ] {
+        assert!(response.contains(line), "{} not in {}", line, response);
+    }
+    assert!(http_get(metrics_addr, "/").starts_with("HTTP/1.1 404"));
+    server.stop();
+}
+
+#[test]
+fn dashboard_switches_inputs_and_recalls_scenes() {
+    use control_dsc::web::{Dashboard, Step};
+
+    let device = scaler();
+    let mut dashboard = Dashboard::default();
+    dashboard
+        .labels
+        .insert("DSC 301 HD".to_string(), [("laptop".to_string(), 2)].into());
+    dashboard.scenes.insert(
+        "Quiet Lecture".to_string(),
+        vec![Step::Select(input("laptop")), Step::Volume(20)],
+    );
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder
+            .listen_metrics("127.0.0.1:0".parse().unwrap())
+            .dashboard(dashboard)
+            .on_event(move |event| {
+                if let ServerEvent::Listening(addr) = event {
+                    tx.lock().unwrap().send(*addr).unwrap();
+                }
+            })
+    });
+    let http_addr = rx.iter().find(|addr| *addr != server.addr).unwrap();
+
+    let page = http_get(http_addr, "/");
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+    assert!(page.contains("text/html"));
+    let devices = http_get(http_addr, "/api/devices");
+    assert!(devices.starts_with("HTTP/1.1 200 OK\r\n"), "{}", devices);
+    let body: serde_json::Value =
+        serde_json::from_str(devices.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(body["devices"][0]["name"], "DSC 301 HD");
+    assert_eq!(body["devices"][0]["input"], 1);
+    assert_eq!(body["devices"][0]["inputs"][0]["label"], "laptop");
+    assert_eq!(body["scenes"][0], "Quiet Lecture");
+
+    let selected = http(http_addr, "POST", "/api/devices/DSC%20301%20HD/input/3");
+    assert!(selected.starts_with("HTTP/1.1 200 OK\r\n"), "{}", selected);
+    assert_eq!(device.input(), 3);
+    let recalled = http(
+        http_addr,
+        "POST",
+        "/api/devices/DSC%20301%20HD/scenes/Quiet%20Lecture",
+    );
+    assert!(recalled.starts_with("HTTP/1.1 200 OK\r\n"), "{}", recalled);
+    assert_eq!(device.input(), 2);
+    assert_eq!(device.volume(), Some(20));
+
+    let missing = http(http_addr, "POST", "/api/devices/Nope/input/1");
+    assert!(missing.starts_with("HTTP/1.1 404"), "{}", missing);
+    assert!(missing.contains("\"error\""));
+    server.stop();
+}
+
+#[test]
+fn dashboard_refuses_posts_from_other_sites() {
+    use control_dsc::web::Dashboard;
+
+    let device = scaler();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder
+            .listen_metrics("127.0.0.1:0".parse().unwrap())
+            .dashboard(Dashboard::default())
+            .on_event(move |event| {
+                if let ServerEvent::Listening(addr) = event {
+                    tx.lock().unwrap().send(*addr).unwrap();
+                }
+            })
+    });
+    let http_addr = rx.iter().find(|addr| *addr != server.addr).unwrap();
+    let path = "/api/devices/DSC%20301%20HD/input/3";
+
+    let foreign = http_with(http_addr, "POST", path, "Origin: http://evil.example\r\n");
+    assert!(foreign.starts_with("HTTP/1.1 403"), "{}", foreign);
+    let referred = http_with(
+        http_addr,
+        "POST",
+        path,
+        "Referer: http://evil.example/page\r\n",
+    );
+    assert!(referred.starts_with("HTTP/1.1 403"), "{}", referred);
+    assert_eq!(device.input(), 1);
+
+    let own = http_with(http_addr, "POST", path, "Origin: http://localhost\r\n");
+    assert!(own.starts_with("HTTP/1.1 200 OK\r\n"), "{}", own);
+    assert_eq!(device.input(), 3);
+    server.stop();
+}
+
+/// Collects what is traced, to check the spans it was traced in.
+#[derive(Clone, Default)]
+struct TraceBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn traces_requests_in_their_spans() {
+    let buffer = TraceBuffer::default();
+    let writer = buffer.clone();
+    tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+    let server = TestServer::start(vec![SimDevice::new("Traced", &["A", "B"])]);
+    server.client.select("Traced", &input("B")).unwrap();
+    server.stop();
+
+    let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let line = trace
+        .lines()
+        .find(|line| line.contains("command{device=\"Traced\" command=\"2!\"}"))
+        .unwrap_or_else(|| panic!("no select in {}", trace));
+    assert!(line.contains("connection{peer=127.0.0.1:"), "{}", line);
+    assert!(
+        line.contains("rpc{method=\"select_input\" device=\"Traced\"}"),
+        "{}",
+        line
+    );
+}
+
+#[test]
+fn dumps_its_state() {
+    let schedule = Schedule {
+        device: "DSC 301 HD".to_string(),
+        periods: Vec::new(),
+        otherwise: Some(input("3")),
+    };
+    let server = TestServer::start_with(vec![scaler()], move |builder| builder.schedule(schedule));
+    server
+        .client
+        .hold("DSC 301 HD", Some(Duration::from_secs(600)))
+        .unwrap();
+
+    let dump: serde_json::Value =
+        serde_json::from_str(&server.client.dump_state().unwrap()).unwrap();
+    assert_eq!(dump["devices"][0]["name"], "DSC 301 HD");
+    assert_eq!(dump["devices"][0]["transport"], "custom");
+    assert_eq!(dump["requests_queued"], 0);
+    assert_eq!(dump["connections"].as_array().unwrap().len(), 1);
+    assert_eq!(dump["schedules"][0]["device"], "DSC 301 HD");
+    assert_eq!(dump["schedules"][0]["scheduled"], "3");
+    assert!(dump["schedules"][0]["held_seconds"].as_u64().unwrap() > 500);
+    server.stop();
+}
+
+#[test]
+fn flags_devices_beyond_the_health_thresholds() {
+    let device = scaler();
+    device.set_temperature(70);
+    let (tx, alerts) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let server = TestServer::start_with(vec![device.clone()], move |builder| {
+        builder
+            .health(Thresholds {
+                max_temperature: Some(60),
+                max_errors: Some(1),
+                ..Thresholds::default()
+            })
+            .on_event(move |event| {
+                if let ServerEvent::HealthAlert { device, alert } = event {
+                    let _ = tx.lock().unwrap().send((device.clone(), alert.clone()));
+                }
+            })
+    });
+    let name = "DSC 301 HD".to_string();
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        alerts.recv_timeout(timeout).unwrap(),
+        (name.clone(), HealthAlert::Temperature(70))
+    );
+    device.announce("E13");
+    device.announce("E13");
+    server.client.device_log("DSC 301 HD").unwrap();
+    assert_eq!(
+        alerts.recv_timeout(timeout).unwrap(),
+        (name, HealthAlert::Errors(2))
+    );
+    server.stop();
+}
+
+#[test]
+fn reports_signal_loss_on_the_selected_input() {
+    let device = scaler();
+    let server = TestServer::start_with(vec![device.clone()], |builder| {
+        builder.health(Thresholds {
+            signal_loss: Some(Duration::from_secs(1)),
+            ..Thresholds::default()
+        })
+    });
+    let events = server.client.events().unwrap();
+    let name = "DSC 301 HD".to_string();
+    // Not selected, so of no concern.
+    device.set_signal(3, false);
+    device.set_signal(1, false);
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::SignalLost {
+            device: name.clone(),
+            input: 1,
+        }
+    );
+    device.set_signal(1, true);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        Event::SignalRestored {
+            device: name,
+            input: 1,
+        }
+    );
+    server.stop();
+}
+
+#[test]
+fn serves_the_devices_of_a_simulation_file() {
+    let devices = sim::parse_devices(
+        r#"
+        [[device]]
+        name = "IN1608"
+        inputs = ["HDMI 1", "HDMI 2"]
+        part_number = "60-1080-01"
+        audio = true
+        latency = 50
+
+        [[device]]
+        name = "Unplugged"
+        inputs = ["A"]
+        fault = "disconnect"
+        "#,
+    )
+    .unwrap();
+    let server = TestServer::start(devices);
+    let names: Vec<_> = server
+        .client
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|d| d.name)
+        .collect();
+    assert_eq!(names, ["IN1608"]);
+    let info = server.client.device_info("IN1608").unwrap();
+    assert_eq!(info.part_number.as_deref(), Some("60-1080-01"));
+
+    let started = Instant::now();
+    server.client.select("IN1608", &input("HDMI 2")).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    server.client.set_volume("IN1608", 20).unwrap();
+    assert_eq!(server.client.status("IN1608").unwrap().volume, Some(20));
+    assert!(sim::parse_devices("[[device]]\nname = \"X\"\n").is_err());
+    server.stop();
+}
+
+#[test]
+fn reports_part_number_and_firmware() {
+    let device = scaler();
+    let server = TestServer::start(vec![device]);
+    let info = server.client.device_info("DSC 301 HD").unwrap();
+    assert_eq!(info.part_number.as_deref(), Some(sim::PART_NUMBER));
+    assert_eq!(info.firmware.as_deref(), Some("1.00"));
+    assert!(matches!(
+        server.client.device_info("Nope"),
+        Err(ControlError::DeviceNotFound(_))
+    ));
+    server.stop();
+}
+
+#[test]
+fn reports_temperature_and_signal() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    let health = server.client.device_health("DSC 301 HD").unwrap();
+    assert_eq!(health.temperature, Some(40));
+    assert_eq!(health.signal, Some((1, true)));
+    device.set_temperature(72);
+    device.set_signal(1, false);
+    let health = server.client.device_health("DSC 301 HD").unwrap();
+    assert_eq!(health.temperature, Some(72));
+    assert_eq!(health.signal, Some((1, false)));
+    server.stop();
+}
+
+#[test]
+fn uploads_firmware_and_reports_the_new_version() {
+    let device = scaler();
+    let server = TestServer::start(vec![device.clone()]);
+    let image = b"1.05\nfirmware".repeat(300);
+    let (tx, progress) = mpsc::channel();
+    let update = server
+        .client
+        .upload_firmware("DSC 301 HD", &image, move |done, total| {
+            tx.send((done, total)).unwrap()
+        })
+        .unwrap();
+    assert_eq!(update.before, "1.00");
+    assert_eq!(update.after, "1.05");
+    assert_eq!(device.firmware(), "1.05");
+    let reports: Vec<_> = progress.try_iter().collect();
+    assert!(reports.len() > 1);
+    assert_eq!(reports.last(), Some(&(image.len(), image.len())));
+    // Still taking commands after the image.
+    server.client.select("DSC 301 HD", &input("2")).unwrap();
+    server.stop();
+}
+
+#[test]
+fn standby_takes_over_when_the_primary_stops() {
+    let on_primary = scaler();
+    let primary = TestServer::start(vec![on_primary.clone()]);
+    // The same scaler once a USB switch hands it to the standby.
+    let on_standby = scaler();
+    let addr = primary.addr;
+    let standby = TestServer::start_with(vec![on_standby.clone()], move |builder| {
+        builder.standby_of(addr)
+    });
+
+    let events = standby.client.events().unwrap();
+    standby.client.select("DSC 301 HD", &input("2")).unwrap();
+    assert_eq!(on_primary.input(), 2);
+    assert_eq!(on_standby.input(), 1);
+    // Passed on by the standby, so it knows the state.
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(5)).unwrap(),
+        Event::InputSelected {
+            device: "DSC 301 HD".to_string(),
+            input: input("2"),
+        }
+    );
+
+    primary.stop();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while on_standby.input() != 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(on_standby.input(), 2);
+    standby.client.select("DSC 301 HD", &input("3")).unwrap();
+    assert_eq!(on_standby.input(), 3);
+    standby.stop();
+}
@@ -0,0 +1,197 @@
+//! Properties of the SIS command encoder, the simulated device that parses commands, and the
+//! reply parser.
+
+use control_dsc::extron::{self, Annotation, ExtronDevice, Genlock, Input, PipMode, Port};
+use control_dsc::sim::SimDevice;
+use control_dsc::sis::{Command, Plane, Reply};
+use proptest::prelude::*;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn plane() -> impl Strategy<Value = Plane> {
+    prop_oneof![Just(Plane::All), Just(Plane::Video), Just(Plane::Audio)]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        Just(Command::Name),
+        any::<u32>().prop_map(Command::InputName),
+        (any::<u32>(), plane()).prop_map(|(n, plane)| Command::Select(n, plane)),
+        Just(Command::QueryInput),
+        any::<u8>().prop_map(Command::SetVolume),
+        Just(Command::QueryVolume),
+        any::<bool>().prop_map(Command::SetMute),
+        Just(Command::QueryMute),
+        Just(Command::FirmwareVersion),
+        Just(Command::PartNumber),
+        any::<u32>().prop_map(Command::UploadFirmware),
+        Just(Command::Temperature),
+        Just(Command::SignalPresence),
+        any::<bool>().prop_map(Command::SetDisplayPower),
+        Just(Command::QueryDisplayPower),
+        any::<u32>().prop_map(Command::InsertSerial),
+        any::<u32>().prop_map(Command::RecallPreset),
+        Just(Command::QueryLayout),
+        any::<u32>().prop_map(Command::RecallLayout),
+        any::<u32>().prop_map(Command::QueryWindow),
+        (any::<u32>(), any::<u32>())
+            .prop_map(|(window, input)| Command::AssignWindow { window, input }),
+        any::<u32>().prop_map(Command::SetPipMode),
+        Just(Command::QueryPipMode),
+        any::<u32>().prop_map(Command::SetSyncFormat),
+        Just(Command::QuerySyncFormat),
+        any::<u32>().prop_map(Command::SetGenlock),
+        Just(Command::QueryGenlock),
+        Just(Command::ClearAnnotations),
+        any::<u32>().prop_map(Command::SelectTool),
+        Just(Command::QueryTool),
+        any::<bool>().prop_map(Command::FreezeAnnotation),
+    ]
+}
+
+/// Plays back fixed bytes as whatever the device answers.
+struct Replay(Cursor<Vec<u8>>);
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for Replay {}
+
+/// Answers every select of input 2 right away and keeps the read timeouts it is given.
+struct Deadlines(Cursor<Vec<u8>>, Arc<Mutex<Vec<Duration>>>);
+
+impl Read for Deadlines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Deadlines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for Deadlines {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.1.lock().unwrap().push(timeout);
+        Ok(())
+    }
+}
+
+proptest! {
+    #[test]
+    fn commands_round_trip(command in command()) {
+        let encoded = command.encode();
+        prop_assert_eq!(Command::parse(&encoded), Some((Some(command), encoded.len())));
+    }
+
+    #[test]
+    fn command_streams_split_at_command_boundaries(commands in prop::collection::vec(command(), 0..16)) {
+        let mut stream: Vec<u8> = commands.iter().flat_map(Command::encode).collect();
+        let mut parsed = Vec::new();
+        while let Some((command, len)) = Command::parse(&stream) {
+            parsed.push(command.unwrap());
+            stream.drain(..len);
+        }
+        prop_assert!(stream.is_empty());
+        prop_assert_eq!(parsed, commands);
+    }
+
+    #[test]
+    fn command_parser_always_makes_progress(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Some((_, len)) = Command::parse(&bytes) {
+            prop_assert!(len >= 1 && len <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn simulated_device_follows_commands(input in 1..=3u32, level in 0..=100u8, mute in any::<bool>()) {
+        let sim = SimDevice::new("DSC", &["HDMI", "DisplayPort", "VGA"]).with_audio();
+        let device = sim.device();
+        device.select(&Input::Number(input)).unwrap();
+        device.set_volume(level).unwrap();
+        device.set_mute(mute).unwrap();
+        let status = device.status().unwrap();
+        prop_assert_eq!((status.input, status.volume, status.mute), (input, Some(level), Some(mute)));
+    }
+
+    #[test]
+    fn replies_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        let _ = Reply::parse(&bytes).number();
+    }
+
+    /// Every operation must end, with any result, whatever the device sends.
+    #[test]
+    fn reader_survives_any_reply(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+        let device = ExtronDevice::with_port("replay", "replay", move || {
+            Ok(Box::new(Replay(Cursor::new(bytes.clone()))))
+        });
+        let _ = device.select(&Input::Number(2));
+        let _ = device.select(&Input::Name("HDMI".to_string()));
+        let _ = device.select_plane(&Input::Number(3), Plane::Audio);
+        let _ = device.set_volume(60);
+        let _ = device.set_mute(true);
+        let _ = device.status();
+        let _ = device.temperature();
+        let _ = device.selected_signal();
+        let _ = device.set_display_power(true);
+        let _ = device.send_to_display(b"PWR ON\r");
+        let _ = device.wall_layout();
+        let _ = device.recall_preset(2);
+        let _ = device.recall_layout(2);
+        let _ = device.annotate(Annotation::Freeze(true));
+        let _ = device.set_pip_mode(PipMode::Quad);
+        let _ = device.assign_source(2, &Input::Number(3));
+        let _ = device.sync();
+        let _ = device.set_genlock(Genlock::Reference);
+    }
+}
+
+#[test]
+fn select_returns_on_the_acknowledgement() {
+    let device = ExtronDevice::with_port("replay", "replay", || {
+        let replies = b"Reconfig\r\nIn2All\r\nIn1All\r\n".to_vec();
+        Ok(Box::new(Replay(Cursor::new(replies))))
+    });
+    device.select(&Input::Number(2)).unwrap();
+    assert_eq!(device.take_unsolicited(), ["Reconfig", "In1All"]);
+}
+
+#[test]
+fn deadlines_follow_the_command_and_adapt() {
+    let timeouts = Arc::new(Mutex::new(Vec::new()));
+    let seen = timeouts.clone();
+    let device = ExtronDevice::with_port("replay", "replay", move || {
+        let replies = Cursor::new(b"In2All\r\n".to_vec());
+        Ok(Box::new(Deadlines(replies, seen.clone())))
+    });
+    device.select(&Input::Number(2)).unwrap();
+    extron::set_adaptive_deadlines(true);
+    device.select(&Input::Number(2)).unwrap();
+    device.select(&Input::Number(2)).unwrap();
+    extron::set_adaptive_deadlines(false);
+
+    // Never less than a fifth of the fixed deadline, however fast the device answers.
+    let timeouts = timeouts.lock().unwrap();
+    assert_eq!(timeouts[0], Command::Select(2, Plane::All).deadline());
+    assert_eq!(timeouts[2], Duration::from_millis(100));
+}
@@ -0,0 +1,57 @@
+//! Runs a server the way systemd does, on a socket bound for it and reporting to systemd.
+#![cfg(unix)]
+
+use control_dsc::client::Client;
+use control_dsc::server::ServerBuilder;
+use control_dsc::sim::{self, SimDevice};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// The next state the server told systemd.
+fn next_state(socket: &UnixDatagram) -> String {
+    let mut buf = [0; 256];
+    let n = socket.recv(&mut buf).expect("server did not notify");
+    String::from_utf8_lossy(&buf[..n]).to_string()
+}
+
+#[test]
+fn notifies_systemd_and_serves_its_socket() {
+    let path = std::env::temp_dir().join(format!("control-dsc-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let systemd = UnixDatagram::bind(&path).unwrap();
+    systemd
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "200000");
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let device = SimDevice::new("DSC 301 HD", &["HDMI", "DisplayPort"]);
+    let source = device.clone();
+    let thread = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(
+            ServerBuilder::new()
+                .listen_on(listener)
+                .device_source(move || sim::device_list(&[source.clone()]))
+                .serve(),
+        )
+    });
+
+    assert_eq!(next_state(&systemd), "READY=1");
+    assert_eq!(next_state(&systemd), "WATCHDOG=1");
+    assert_eq!(next_state(&systemd), "WATCHDOG=1");
+
+    let client = Client::with_servers(&addr.to_string()).unwrap();
+    client.select("DSC 301 HD", &"2".parse().unwrap()).unwrap();
+    assert_eq!(device.input(), 2);
+    client.stop().unwrap();
+    thread.join().unwrap().unwrap();
+    let mut state = next_state(&systemd);
+    while state == "WATCHDOG=1" {
+        state = next_state(&systemd);
+    }
+    assert_eq!(state, "STOPPING=1");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,37 @@
+//! Dumps of the traffic with devices, and of the calls to and from the server.
+
+use control_dsc::extron::Input;
+use control_dsc::extron_capnp::control_extron;
+use control_dsc::sim::SimDevice;
+use control_dsc::{rpc_trace, trace};
+
+#[test]
+fn dumps_traffic_of_each_device() {
+    let dir = std::env::temp_dir().join(format!("control-dsc-trace-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    trace::set_dir(Some(dir.clone()));
+    let device = SimDevice::new("DSC 301 HD", &["HDMI", "DisplayPort"]).device();
+    device.select(&Input::Number(2)).unwrap();
+    trace::set_dir(None);
+    device.select(&Input::Number(1)).unwrap();
+
+    let dump = std::fs::read_to_string(trace::path(&dir, "DSC 301 HD")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let lines: Vec<_> = dump.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("> 0000  32 21                                            |2!|"));
+    assert!(lines[1].contains("< 0000  49 6e 32 41 6c 6c 0d 0a "));
+    assert!(lines[1].ends_with("|In2All..|"));
+}
+
+#[test]
+fn shows_calls_without_their_data() {
+    let mut message = capnp::message::Builder::new_default();
+    let mut params = message.init_root::<control_extron::send_raw_params::Builder>();
+    params.set_name("DSC 301 HD");
+    params.set_command(&b"W1234CW\r"[..]);
+    assert_eq!(
+        rpc_trace::show(params.into_reader()),
+        "(name = \"DSC 301 HD\", command = <8 bytes>)"
+    );
+}